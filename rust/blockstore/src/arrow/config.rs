@@ -15,6 +15,55 @@ pub(crate) struct ArrowBlockfileProviderConfig {
 pub(crate) struct BlockManagerConfig {
     pub(crate) max_block_size_bytes: usize,
     pub(crate) block_cache_config: CacheConfig,
+    #[serde(default)]
+    pub(crate) logging: StorageLoggingConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LogLevel {
+    // case-insensitive
+    #[serde(alias = "off")]
+    Off,
+    #[serde(alias = "trace")]
+    Trace,
+    #[serde(alias = "debug")]
+    Debug,
+    #[serde(alias = "info")]
+    Info,
+}
+
+/// The levels the block manager logs reads of blocks from storage at. Errors
+/// are always logged at error level, whatever is configured here.
+/// # Fields
+/// - read_log_level: The level of the log line emitted for every block read
+///   from storage, or Off to not emit it. Defaults to Info.
+/// - read_span_level: The level of the span around reading a block's bytes,
+///   or Off to not create it. Defaults to Trace.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct StorageLoggingConfig {
+    #[serde(default = "StorageLoggingConfig::default_read_log_level")]
+    pub(crate) read_log_level: LogLevel,
+    #[serde(default = "StorageLoggingConfig::default_read_span_level")]
+    pub(crate) read_span_level: LogLevel,
+}
+
+impl StorageLoggingConfig {
+    fn default_read_log_level() -> LogLevel {
+        LogLevel::Info
+    }
+
+    fn default_read_span_level() -> LogLevel {
+        LogLevel::Trace
+    }
+}
+
+impl Default for StorageLoggingConfig {
+    fn default() -> Self {
+        StorageLoggingConfig {
+            read_log_level: StorageLoggingConfig::default_read_log_level(),
+            read_span_level: StorageLoggingConfig::default_read_span_level(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -1,7 +1,7 @@
 use super::{
     block::{delta::BlockDelta, Block},
     blockfile::{ArrowBlockfileReader, ArrowBlockfileWriter},
-    config::ArrowBlockfileProviderConfig,
+    config::{ArrowBlockfileProviderConfig, LogLevel, StorageLoggingConfig},
     sparse_index::SparseIndex,
     types::{ArrowReadableKey, ArrowReadableValue, ArrowWriteableKey, ArrowWriteableValue},
 };
@@ -124,12 +124,15 @@ impl Configurable<(ArrowBlockfileProviderConfig, Storage)> for ArrowBlockfilePro
                 return Err(e);
             }
         };
-        Ok(ArrowBlockfileProvider::new(
-            storage.clone(),
-            blockfile_config.block_manager_config.max_block_size_bytes,
-            block_cache,
-            sparse_index_cache,
-        ))
+        Ok(ArrowBlockfileProvider {
+            block_manager: BlockManager::new(
+                storage.clone(),
+                blockfile_config.block_manager_config.max_block_size_bytes,
+                block_cache,
+            )
+            .with_logging(blockfile_config.block_manager_config.logging),
+            sparse_index_manager: SparseIndexManager::new(storage.clone(), sparse_index_cache),
+        })
     }
 }
 
@@ -144,6 +147,7 @@ pub(super) struct BlockManager {
     block_cache: Cache<Uuid, Block>,
     storage: Storage,
     max_block_size_bytes: usize,
+    logging: StorageLoggingConfig,
 }
 
 impl BlockManager {
@@ -156,9 +160,15 @@ impl BlockManager {
             block_cache,
             storage,
             max_block_size_bytes,
+            logging: StorageLoggingConfig::default(),
         }
     }
 
+    /// Sets the levels that reads of blocks from storage are logged at.
+    pub(super) fn with_logging(self, logging: StorageLoggingConfig) -> Self {
+        Self { logging, ..self }
+    }
+
     pub(super) fn create<K: ArrowWriteableKey, V: ArrowWriteableValue>(&self) -> BlockDelta {
         let new_block_id = Uuid::new_v4();
         let block = BlockDelta::new::<K, V>(new_block_id);
@@ -221,7 +231,7 @@ impl BlockManager {
                     ).await;
                     match stream {
                         Ok(mut bytes) => {
-                            let read_block_span = tracing::trace_span!(parent: Span::current(), "BlockManager read bytes to end");
+                            let read_block_span = read_bytes_span(self.logging.read_span_level);
                             let buf = read_block_span.in_scope(|| async {
                                 let mut buf: Vec<u8> = Vec::new();
                                 while let Some(res) = bytes.next().await {
@@ -246,7 +256,7 @@ impl BlockManager {
                                     return None;
                                 }
                             };
-                            tracing::info!("Read {:?} bytes from s3", buf.len());
+                            log_read(self.logging.read_log_level, buf.len());
                             let deserialization_span = tracing::trace_span!(parent: Span::current(), "BlockManager deserialize block");
                             let block = deserialization_span.in_scope(|| Block::from_bytes(&buf, *id));
                             match block {
@@ -304,6 +314,32 @@ impl BlockManager {
     }
 }
 
+// The span around reading a block's bytes from storage, at the configured
+// level. The level of a span is fixed where it is created, hence the match.
+fn read_bytes_span(level: LogLevel) -> Span {
+    match level {
+        LogLevel::Off => Span::none(),
+        LogLevel::Trace => {
+            tracing::trace_span!(parent: Span::current(), "BlockManager read bytes to end")
+        }
+        LogLevel::Debug => {
+            tracing::debug_span!(parent: Span::current(), "BlockManager read bytes to end")
+        }
+        LogLevel::Info => {
+            tracing::info_span!(parent: Span::current(), "BlockManager read bytes to end")
+        }
+    }
+}
+
+fn log_read(level: LogLevel, bytes: usize) {
+    match level {
+        LogLevel::Off => {}
+        LogLevel::Trace => tracing::trace!("Read {:?} bytes from s3", bytes),
+        LogLevel::Debug => tracing::debug!("Read {:?} bytes from s3", bytes),
+        LogLevel::Info => tracing::info!("Read {:?} bytes from s3", bytes),
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum BlockFlushError {
     #[error("Not found")]
@@ -466,3 +502,70 @@ impl ChromaError for SparseIndexFlushError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arrow::config::TEST_MAX_BLOCK_SIZE_BYTES;
+    use chroma_cache::config::{CacheConfig, UnboundedCacheConfig};
+    use chroma_storage::local::LocalStorage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    // Counts the events this module emits.
+    struct CountEvents(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> Layer<S> for CountEvents {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            if event.metadata().target() == "chroma_blockstore::arrow::provider" {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Reads a block back from storage with the given logging config, and
+    // returns the number of events logged under the given level filter.
+    async fn count_read_logs(logging: StorageLoggingConfig, filter: LevelFilter) -> usize {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::Local(LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+        let cache = Cache::new(&CacheConfig::Unbounded(UnboundedCacheConfig {}));
+        let block_manager =
+            BlockManager::new(storage, TEST_MAX_BLOCK_SIZE_BYTES, cache).with_logging(logging);
+        let delta = block_manager.create::<&str, &str>();
+        delta.add("prefix", "key", "value");
+        let block = block_manager.commit::<&str, &str>(&delta);
+        block_manager.flush(&block).await.unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let subscriber =
+            tracing_subscriber::registry().with(CountEvents(count.clone()).with_filter(filter));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        // The block was not cached by the flush, so this reads it from storage.
+        assert!(block_manager.get(&delta.id).await.is_some());
+        count.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn test_read_log_level() {
+        let default = StorageLoggingConfig::default();
+        assert_eq!(count_read_logs(default, LevelFilter::INFO).await, 1);
+        // The per-read info log is suppressed at warn level.
+        assert_eq!(count_read_logs(default, LevelFilter::WARN).await, 0);
+
+        let debug = StorageLoggingConfig {
+            read_log_level: LogLevel::Debug,
+            ..default
+        };
+        assert_eq!(count_read_logs(debug, LevelFilter::INFO).await, 0);
+        assert_eq!(count_read_logs(debug, LevelFilter::DEBUG).await, 1);
+
+        let off = StorageLoggingConfig {
+            read_log_level: LogLevel::Off,
+            read_span_level: LogLevel::Off,
+        };
+        assert_eq!(count_read_logs(off, LevelFilter::TRACE).await, 0);
+    }
+}
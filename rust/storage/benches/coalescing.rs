@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use chroma_storage::admissioncontrolleds3::AdmissionControlledS3Storage;
+use chroma_storage::backend::{BackendStream, StorageBackend};
+use chroma_storage::{GetError, PutError};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+const WAITERS: usize = 1000;
+const KEYS: usize = 1000;
+
+// Holds every read back until the gate opens, so that all the waiters are
+// lined up behind one read before it completes.
+struct GatedBackend {
+    gate: Arc<Semaphore>,
+}
+
+#[async_trait]
+impl StorageBackend for GatedBackend {
+    async fn get(&self, _: &str) -> Result<BackendStream, GetError> {
+        self.gate.acquire().await.expect("gate closed").forget();
+        Ok(Box::new(futures::stream::iter(vec![Ok(vec![0; 1024])])))
+    }
+
+    async fn put_file(&self, _: &str, _: &str) -> Result<(), PutError> {
+        Ok(())
+    }
+
+    async fn put_bytes(&self, _: &str, _: Vec<u8>) -> Result<(), PutError> {
+        Ok(())
+    }
+}
+
+// Times how long the waiters take to get the result once the read is let
+// through, which is where they contend for outstanding_requests.
+async fn coalesced_wakeup() -> Duration {
+    let gate = Arc::new(Semaphore::new(0));
+    let storage = AdmissionControlledS3Storage::new(GatedBackend { gate: gate.clone() });
+    let gets = (0..WAITERS)
+        .map(|_| {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.get("key").await })
+        })
+        .collect::<Vec<_>>();
+    loop {
+        let stats = storage.stats();
+        if (stats.coalesced_hits + stats.distinct_fetches) as usize == WAITERS {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+    let start = Instant::now();
+    gate.add_permits(1);
+    for get in gets {
+        get.await.unwrap().unwrap();
+    }
+    start.elapsed()
+}
+
+// Times gets of as many distinct keys at once, each of which inserts and
+// removes an entry of its own, with outstanding_requests split into
+// `shards` shards.
+async fn distinct_keys(shards: usize) -> Duration {
+    let gate = Arc::new(Semaphore::new(KEYS));
+    let storage =
+        AdmissionControlledS3Storage::new(GatedBackend { gate }).with_coalescing_shards(shards);
+    let start = Instant::now();
+    let gets = (0..KEYS)
+        .map(|i| {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.get(&format!("key-{}", i)).await })
+        })
+        .collect::<Vec<_>>();
+    for get in gets {
+        get.await.unwrap().unwrap();
+    }
+    start.elapsed()
+}
+
+fn coalescing(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .build()
+        .unwrap();
+    c.bench_function("coalesced_wakeup_1000_waiters", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    total += coalesced_wakeup().await;
+                }
+                total
+            })
+        })
+    });
+    for shards in [1, 16] {
+        c.bench_function(&format!("distinct_keys_1000_gets_{}_shards", shards), |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        total += distinct_keys(shards).await;
+                    }
+                    total
+                })
+            })
+        });
+    }
+}
+
+criterion_group!(benches, coalescing);
+criterion_main!(benches);
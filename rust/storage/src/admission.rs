@@ -0,0 +1,259 @@
+// Adaptive concurrency control for requests to a storage backend. The limit
+// on requests in flight follows an additive-increase/multiplicative-decrease
+// policy: when too many recent requests fail with throttling or server errors
+// the limit is halved, and while requests succeed it grows by one each time a
+// full limit's worth of requests has completed. Throttling responses, with
+// which S3 signals that it is congested, weigh more heavily in the error rate
+// than other server errors, so a lower rate of throttling shrinks the limit.
+//
+// The limit is enforced with a semaphore. Growing the limit adds permits.
+// Shrinking it forgets permits that are free, and permits that are held by
+// requests in flight are forgotten as later requests acquire them, so the
+// reduction takes effect as soon as those requests complete.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Number of recent outcomes the error rate is computed over.
+const WINDOW_SIZE: usize = 50;
+// Outcomes required since the last decrease before the limit may shrink
+// again, so that one burst of errors halves the limit once rather than on
+// every error.
+const MIN_SAMPLES: usize = 10;
+// Fraction of overloaded outcomes in the window above which the limit shrinks.
+const ERROR_RATE_THRESHOLD: f64 = 0.2;
+// How many overloaded outcomes a single throttling response counts as.
+const THROTTLED_WEIGHT: usize = 3;
+
+/// The outcome of a request, as far as the load on the backend goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestOutcome {
+    /// The request succeeded or failed for reasons unrelated to load, such
+    /// as a missing key.
+    Success,
+    /// The backend failed the request with a server error, or did not
+    /// respond at all.
+    Overloaded,
+    /// The backend explicitly asked to slow down, e.g. with SlowDown.
+    Throttled,
+}
+
+impl RequestOutcome {
+    fn weight(self) -> usize {
+        match self {
+            RequestOutcome::Success => 0,
+            RequestOutcome::Overloaded => 1,
+            RequestOutcome::Throttled => THROTTLED_WEIGHT,
+        }
+    }
+}
+
+pub struct AdaptiveConcurrency {
+    semaphore: Arc<Semaphore>,
+    max_limit: usize,
+    state: Mutex<AdaptiveState>,
+}
+
+struct AdaptiveState {
+    limit: usize,
+    // Permits that must be forgotten as they are acquired to bring the
+    // semaphore down to the limit.
+    debt: usize,
+    window: VecDeque<RequestOutcome>,
+    successes_since_change: usize,
+}
+
+impl AdaptiveState {
+    fn error_rate(&self) -> f64 {
+        if self.window.is_empty() {
+            return 0.0;
+        }
+        let weight = self
+            .window
+            .iter()
+            .map(|outcome| outcome.weight())
+            .sum::<usize>();
+        weight as f64 / self.window.len() as f64
+    }
+}
+
+impl std::fmt::Debug for AdaptiveConcurrency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdaptiveConcurrency")
+            .field("limit", &self.limit())
+            .finish()
+    }
+}
+
+impl AdaptiveConcurrency {
+    /// Creates a controller that starts at, and never exceeds, `max_limit`
+    /// requests in flight. `max_limit` must be greater than zero.
+    pub fn new(max_limit: usize) -> AdaptiveConcurrency {
+        assert!(max_limit > 0, "concurrency limit must be positive");
+        AdaptiveConcurrency {
+            semaphore: Arc::new(Semaphore::new(max_limit)),
+            max_limit,
+            state: Mutex::new(AdaptiveState {
+                limit: max_limit,
+                debt: 0,
+                window: VecDeque::with_capacity(WINDOW_SIZE),
+                successes_since_change: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, AdaptiveState> {
+        self.state
+            .lock()
+            .expect("adaptive concurrency lock poisoned")
+    }
+
+    /// The current limit on requests in flight.
+    pub fn limit(&self) -> usize {
+        self.lock().limit
+    }
+
+    /// Waits until the number of requests in flight is below the current
+    /// limit. The permit must be held until the request has finished.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        loop {
+            let permit = self
+                .semaphore
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("request semaphore is never closed");
+            let mut state = self.lock();
+            if state.debt == 0 {
+                return permit;
+            }
+            state.debt -= 1;
+            permit.forget();
+        }
+    }
+
+    /// Records the outcome of a request.
+    pub fn record(&self, outcome: RequestOutcome) {
+        let mut state = self.lock();
+        if state.window.len() == WINDOW_SIZE {
+            state.window.pop_front();
+        }
+        state.window.push_back(outcome);
+
+        if outcome != RequestOutcome::Success {
+            state.successes_since_change = 0;
+            if state.window.len() >= MIN_SAMPLES && state.error_rate() > ERROR_RATE_THRESHOLD {
+                let decrease = state.limit - (state.limit / 2).max(1);
+                state.limit -= decrease;
+                state.window.clear();
+                let forgotten = match self.semaphore.try_acquire_many(decrease as u32) {
+                    Ok(permits) => {
+                        permits.forget();
+                        decrease
+                    }
+                    Err(_) => 0,
+                };
+                state.debt += decrease - forgotten;
+            }
+            return;
+        }
+
+        state.successes_since_change += 1;
+        if state.limit < self.max_limit
+            && state.successes_since_change >= state.limit
+            && state.error_rate() <= ERROR_RATE_THRESHOLD
+        {
+            state.limit += 1;
+            state.successes_since_change = 0;
+            if state.debt > 0 {
+                state.debt -= 1;
+            } else {
+                self.semaphore.add_permits(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_shrinks_on_errors_and_recovers() {
+        let controller = AdaptiveConcurrency::new(8);
+        for _ in 0..10 {
+            controller.record(RequestOutcome::Overloaded);
+        }
+        assert_eq!(controller.limit(), 4);
+        assert_eq!(controller.semaphore.available_permits(), 4);
+
+        // Recovery is gradual.
+        for _ in 0..4 {
+            controller.record(RequestOutcome::Success);
+        }
+        assert_eq!(controller.limit(), 5);
+        for _ in 0..100 {
+            controller.record(RequestOutcome::Success);
+        }
+        assert_eq!(controller.limit(), 8);
+        assert_eq!(controller.semaphore.available_permits(), 8);
+    }
+
+    #[test]
+    fn test_occasional_errors_do_not_shrink_the_limit() {
+        let controller = AdaptiveConcurrency::new(8);
+        for i in 0..100 {
+            controller.record(if i % 10 == 0 {
+                RequestOutcome::Overloaded
+            } else {
+                RequestOutcome::Success
+            });
+        }
+        assert_eq!(controller.limit(), 8);
+    }
+
+    #[test]
+    fn test_throttling_weighs_more_than_server_errors() {
+        // The lowest limit reached when one request in every eight fails with
+        // `failure`.
+        let lowest_limit = |failure: RequestOutcome| {
+            let controller = AdaptiveConcurrency::new(8);
+            let mut lowest_limit = controller.limit();
+            for i in 0..60 {
+                controller.record(if i % 8 == 0 {
+                    failure
+                } else {
+                    RequestOutcome::Success
+                });
+                lowest_limit = lowest_limit.min(controller.limit());
+            }
+            lowest_limit
+        };
+        // Below the threshold for server errors, but not for throttling.
+        assert_eq!(lowest_limit(RequestOutcome::Overloaded), 8);
+        assert!(lowest_limit(RequestOutcome::Throttled) < 8);
+    }
+
+    #[tokio::test]
+    async fn test_shrinking_with_requests_in_flight() {
+        let controller = AdaptiveConcurrency::new(4);
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(controller.acquire().await);
+        }
+        for _ in 0..10 {
+            controller.record(RequestOutcome::Overloaded);
+        }
+        assert_eq!(controller.limit(), 2);
+
+        // The permits of the requests in flight are forgotten as they are
+        // released and reacquired, leaving room for only two requests.
+        drop(permits);
+        let first = controller.acquire().await;
+        let second = controller.acquire().await;
+        assert_eq!(controller.semaphore.available_permits(), 0);
+        drop((first, second));
+        assert_eq!(controller.semaphore.available_permits(), 2);
+    }
+}
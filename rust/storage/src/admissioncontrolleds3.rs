@@ -0,0 +1,1004 @@
+// Coalesces concurrent gets of the same key into a single read from the
+// storage it wraps, by default S3-backed Storage. The first get of a key
+// starts the read and records it in outstanding_requests; gets of the key
+// that arrive while it is in flight join it and share its result instead of
+// reading the object again. The read removes the entry when it completes,
+// before any waiter sees its result, so a later get reads the object
+// afresh. Puts are not coalesced and go straight to storage.
+//
+// outstanding_requests is split into shards, each behind a lock of its own,
+// so that gets of different keys rarely wait for each other. A key always
+// hashes to the same shard, so gets of one key still find each other's
+// reads.
+//
+// Coalescing can be turned off, for benchmarks and consistency tests that
+// need to see every get reach storage. Gets then behave as
+// get_uncoalesced.
+//
+// With a coalescing TTL, a read in flight for longer than the TTL is no
+// longer joined, so that a stalled read does not hold up every later get of
+// its key. The next get starts a fresh read and takes over the entry.
+//
+// A read that never completes, say because its task leaked, would keep its
+// entry forever. An optional janitor scans the map periodically, evicts the
+// reads that have been in flight for longer than a max age and aborts them.
+// Ages are measured on a Clock, so tests can move time by hand.
+//
+// The read runs on a task of its own, so a waiter that is cancelled, even
+// the one that started the read, leaves it running for the others.
+//
+// Every waiter gets the same bytes, so a coalesced get returns the whole
+// object, read into memory, rather than a stream. Callers that want to
+// stream an object use the storage directly.
+
+use super::backend::StorageBackend;
+use super::clock::{Clock, TokioClock};
+use super::config::{AdmissionControlledS3StorageConfig, StorageConfig};
+use super::s3::S3Storage;
+use super::{GetError, PutError, Storage};
+use async_trait::async_trait;
+use chroma_config::Configurable;
+use chroma_error::{ChromaError, ErrorCodes};
+use futures::future::{BoxFuture, Shared};
+use futures::{FutureExt, StreamExt};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// Cloned into every waiter of a coalesced get, so it carries messages rather
+// than the errors it was built from.
+#[derive(Error, Debug, Clone)]
+pub enum AdmissionControlledS3StorageError {
+    #[error("No such key: {0}")]
+    NoSuchKey(String),
+    #[error("Error performing a get call from s3 storage: {0}")]
+    S3GetError(String),
+    #[error("Error reading from local storage: {0}")]
+    LocalReadError(String),
+    #[error("The read of a coalesced get was aborted: {0}")]
+    FetchAborted(String),
+}
+
+impl ChromaError for AdmissionControlledS3StorageError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            AdmissionControlledS3StorageError::NoSuchKey(_) => ErrorCodes::NotFound,
+            AdmissionControlledS3StorageError::S3GetError(_) => ErrorCodes::Internal,
+            AdmissionControlledS3StorageError::LocalReadError(_) => ErrorCodes::Internal,
+            AdmissionControlledS3StorageError::FetchAborted(_) => ErrorCodes::Internal,
+        }
+    }
+}
+
+type SharedFetch =
+    Shared<BoxFuture<'static, Result<Arc<Vec<u8>>, AdmissionControlledS3StorageError>>>;
+
+// The id tells a read's own entry apart from that of a later read of the
+// same key. gets counts the gets the read serves, the one that started it
+// included; it only changes under the lock, while the entry is in the map.
+struct OutstandingFetch {
+    id: u64,
+    started: Instant,
+    gets: Arc<AtomicU64>,
+    fetch: SharedFetch,
+    task: tokio::task::AbortHandle,
+}
+
+/// Picks the shard of outstanding_requests a key goes to. It must always
+/// return the same hash for the same key.
+pub type KeyHasher = Arc<dyn Fn(&str) -> u64 + Send + Sync>;
+
+fn default_key_hasher() -> KeyHasher {
+    let state = RandomState::new();
+    Arc::new(move |key: &str| state.hash_one(key))
+}
+
+fn default_shards() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+struct OutstandingRequests {
+    shards: Box<[Mutex<HashMap<String, OutstandingFetch>>]>,
+    hasher: KeyHasher,
+}
+
+impl OutstandingRequests {
+    fn new(shards: usize, hasher: KeyHasher) -> OutstandingRequests {
+        OutstandingRequests {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            hasher,
+        }
+    }
+
+    // Locks the shard that `key` goes to.
+    fn lock(&self, key: &str) -> MutexGuard<'_, HashMap<String, OutstandingFetch>> {
+        let shard = (self.hasher)(key) % self.shards.len() as u64;
+        self.shards[shard as usize]
+            .lock()
+            .expect("outstanding requests lock poisoned")
+    }
+
+    // Evicts and aborts the reads that started at least `max_age` before
+    // `now`. Their waiters get FetchAborted.
+    fn evict_older_than(&self, now: Instant, max_age: Duration) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().expect("outstanding requests lock poisoned");
+            shard.retain(|_, outstanding| {
+                let stale = now.saturating_duration_since(outstanding.started) >= max_age;
+                if stale {
+                    outstanding.task.abort();
+                }
+                !stale
+            });
+        }
+    }
+}
+
+// Scans `requests` for stuck reads every `interval` until the storage that
+// owns it is dropped. The first scan comes after a random fraction of the
+// interval, so that instances started together do not scan in step.
+async fn janitor(
+    requests: Weak<OutstandingRequests>,
+    clock: Arc<dyn Clock>,
+    interval: Duration,
+    max_age: Duration,
+) {
+    let fraction = RandomState::new().hash_one(()) as f64 / u64::MAX as f64;
+    clock.sleep(interval.mul_f64(fraction)).await;
+    loop {
+        let Some(requests) = requests.upgrade() else {
+            return;
+        };
+        requests.evict_older_than(clock.now(), max_age);
+        drop(requests);
+        clock.sleep(interval).await;
+    }
+}
+
+pub const FAN_OUT_BUCKETS: usize = 16;
+
+/// The fan-out of the coalesced reads that have completed: how many gets
+/// each of them served, the one that started it included. buckets[i]
+/// counts the reads that served from 2^i up to 2^(i+1) - 1 gets, and the
+/// last bucket also counts every read that served more.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FanOutHistogram {
+    pub buckets: [u64; FAN_OUT_BUCKETS],
+    // The number of reads recorded.
+    pub count: u64,
+    // The number of gets they served between them.
+    pub sum: u64,
+}
+
+/// A point-in-time copy of the coalescing counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoalescingStats {
+    // Every get, coalesced or not.
+    pub total_requests: u64,
+    // Gets that joined a read already in flight.
+    pub coalesced_hits: u64,
+    // Gets that started a read of their own.
+    pub distinct_fetches: u64,
+    pub fan_out: FanOutHistogram,
+}
+
+#[derive(Default)]
+struct CoalescingCounters {
+    total_requests: AtomicU64,
+    coalesced_hits: AtomicU64,
+    distinct_fetches: AtomicU64,
+    fan_out_buckets: [AtomicU64; FAN_OUT_BUCKETS],
+    fan_out_count: AtomicU64,
+    fan_out_sum: AtomicU64,
+}
+
+impl CoalescingCounters {
+    fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_fan_out(&self, gets: u64) {
+        let bucket = (gets.max(1).ilog2() as usize).min(FAN_OUT_BUCKETS - 1);
+        Self::increment(&self.fan_out_buckets[bucket]);
+        Self::increment(&self.fan_out_count);
+        self.fan_out_sum.fetch_add(gets, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CoalescingStats {
+        CoalescingStats {
+            total_requests: self.total_requests.load(Ordering::Relaxed),
+            coalesced_hits: self.coalesced_hits.load(Ordering::Relaxed),
+            distinct_fetches: self.distinct_fetches.load(Ordering::Relaxed),
+            fan_out: FanOutHistogram {
+                buckets: std::array::from_fn(|i| self.fan_out_buckets[i].load(Ordering::Relaxed)),
+                count: self.fan_out_count.load(Ordering::Relaxed),
+                sum: self.fan_out_sum.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+pub struct AdmissionControlledS3Storage<S: StorageBackend = Storage> {
+    storage: Arc<S>,
+    outstanding_requests: Arc<OutstandingRequests>,
+    next_fetch_id: Arc<AtomicU64>,
+    counters: Arc<CoalescingCounters>,
+    coalescing_ttl: Option<Duration>,
+    coalescing_enabled: bool,
+    clock: Arc<dyn Clock>,
+}
+
+// Derived Clone would require S: Clone, but only the Arc is cloned.
+impl<S: StorageBackend> Clone for AdmissionControlledS3Storage<S> {
+    fn clone(&self) -> Self {
+        AdmissionControlledS3Storage {
+            storage: self.storage.clone(),
+            outstanding_requests: self.outstanding_requests.clone(),
+            next_fetch_id: self.next_fetch_id.clone(),
+            counters: self.counters.clone(),
+            coalescing_ttl: self.coalescing_ttl,
+            coalescing_enabled: self.coalescing_enabled,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<S: StorageBackend + 'static> AdmissionControlledS3Storage<S> {
+    pub fn new(storage: S) -> AdmissionControlledS3Storage<S> {
+        AdmissionControlledS3Storage {
+            storage: Arc::new(storage),
+            outstanding_requests: Arc::new(OutstandingRequests::new(
+                default_shards(),
+                default_key_hasher(),
+            )),
+            next_fetch_id: Arc::new(AtomicU64::new(0)),
+            counters: Arc::new(CoalescingCounters::default()),
+            coalescing_ttl: None,
+            coalescing_enabled: true,
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    // The janitor holds on to the map and the clock it was started with.
+    fn assert_no_janitor(&self) {
+        debug_assert_eq!(
+            Arc::weak_count(&self.outstanding_requests),
+            0,
+            "with_janitor must come after the other builder methods"
+        );
+    }
+
+    /// Splits outstanding_requests into `shards` shards, at least one. The
+    /// default is one per CPU.
+    pub fn with_coalescing_shards(self, shards: usize) -> AdmissionControlledS3Storage<S> {
+        self.assert_no_janitor();
+        let hasher = self.outstanding_requests.hasher.clone();
+        AdmissionControlledS3Storage {
+            outstanding_requests: Arc::new(OutstandingRequests::new(shards, hasher)),
+            ..self
+        }
+    }
+
+    /// Picks the shard of each key with `hasher` rather than with a
+    /// randomly seeded SipHash.
+    pub fn with_key_hasher(self, hasher: KeyHasher) -> AdmissionControlledS3Storage<S> {
+        self.assert_no_janitor();
+        let shards = self.outstanding_requests.shards.len();
+        AdmissionControlledS3Storage {
+            outstanding_requests: Arc::new(OutstandingRequests::new(shards, hasher)),
+            ..self
+        }
+    }
+
+    /// Turns coalescing on or off. With it off every get reads from
+    /// storage on its own, as get_uncoalesced does. It is on by default.
+    pub fn with_coalescing(self, enabled: bool) -> AdmissionControlledS3Storage<S> {
+        AdmissionControlledS3Storage {
+            coalescing_enabled: enabled,
+            ..self
+        }
+    }
+
+    /// Stops gets from joining reads that have been in flight for longer
+    /// than `ttl`. They start a fresh read instead.
+    pub fn with_coalescing_ttl(self, ttl: Duration) -> AdmissionControlledS3Storage<S> {
+        AdmissionControlledS3Storage {
+            coalescing_ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Measures the age of reads, for the coalescing TTL and the janitor,
+    /// on `clock` rather than on the Tokio clock.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> AdmissionControlledS3Storage<S> {
+        self.assert_no_janitor();
+        AdmissionControlledS3Storage { clock, ..self }
+    }
+
+    /// Starts a janitor that every `interval` evicts the reads that have
+    /// been in flight for at least `max_age` and aborts them, failing their
+    /// waiters with FetchAborted, so that a read that never completes does
+    /// not hold its key forever. The janitor stops once every clone of the
+    /// storage has been dropped.
+    ///
+    /// Must be called from within a Tokio runtime, after the other builder
+    /// methods.
+    pub fn with_janitor(
+        self,
+        interval: Duration,
+        max_age: Duration,
+    ) -> AdmissionControlledS3Storage<S> {
+        tokio::spawn(janitor(
+            Arc::downgrade(&self.outstanding_requests),
+            self.clock.clone(),
+            interval,
+            max_age,
+        ));
+        self
+    }
+
+    /// Returns how many gets there have been, how many of them were
+    /// coalesced, and the fan-out of the reads they were coalesced onto.
+    /// The counters are atomics, so reading them never holds up a get.
+    pub fn stats(&self) -> CoalescingStats {
+        self.counters.snapshot()
+    }
+
+    async fn read_from_storage(
+        storage: Arc<S>,
+        key: String,
+    ) -> Result<Arc<Vec<u8>>, AdmissionControlledS3StorageError> {
+        let mut stream = storage.get(&key).await.map_err(|e| match e {
+            GetError::NoSuchKey(_) => AdmissionControlledS3StorageError::NoSuchKey(key.clone()),
+            GetError::LocalError(e) => AdmissionControlledS3StorageError::LocalReadError(e),
+            e => AdmissionControlledS3StorageError::S3GetError(e.to_string()),
+        })?;
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => buf.extend(chunk),
+                Err(GetError::LocalError(e)) => {
+                    return Err(AdmissionControlledS3StorageError::LocalReadError(e))
+                }
+                Err(e) => return Err(AdmissionControlledS3StorageError::S3GetError(e.to_string())),
+            }
+        }
+        Ok(Arc::new(buf))
+    }
+
+    // Removes the read's own entry before returning its result, so the
+    // entry is gone by the time any waiter wakes up. Waiters then never
+    // touch the map again, and a get that arrives after the result is out
+    // starts a read of its own. No get can join once the entry is removed
+    // or replaced, so the read's fan-out is final by then.
+    async fn fetch(
+        storage: Arc<S>,
+        requests: Arc<OutstandingRequests>,
+        counters: Arc<CoalescingCounters>,
+        key: String,
+        id: u64,
+        gets: Arc<AtomicU64>,
+    ) -> Result<Arc<Vec<u8>>, AdmissionControlledS3StorageError> {
+        let res = Self::read_from_storage(storage, key.clone()).await;
+        {
+            let mut requests = requests.lock(&key);
+            if requests
+                .get(&key)
+                .is_some_and(|outstanding| outstanding.id == id)
+            {
+                requests.remove(&key);
+            }
+        }
+        counters.record_fan_out(gets.load(Ordering::Relaxed));
+        res
+    }
+
+    /// Returns the object at `key`. A get of a key that is already being
+    /// read joins that read instead of starting another one, unless
+    /// coalescing is off.
+    pub async fn get(&self, key: &str) -> Result<Arc<Vec<u8>>, AdmissionControlledS3StorageError> {
+        if !self.coalescing_enabled {
+            return self.get_uncoalesced(key).await;
+        }
+        CoalescingCounters::increment(&self.counters.total_requests);
+        let fetch = {
+            let mut requests = self.outstanding_requests.lock(key);
+            // The entry of a read whose task died before it could remove
+            // it is stale, as is one older than the TTL, and is replaced
+            // rather than joined.
+            let maybe_inflight = requests.get(key).filter(|outstanding| {
+                outstanding.fetch.peek().is_none()
+                    && self.coalescing_ttl.is_none_or(|ttl| {
+                        self.clock
+                            .now()
+                            .saturating_duration_since(outstanding.started)
+                            < ttl
+                    })
+            });
+            match maybe_inflight {
+                Some(outstanding) => {
+                    CoalescingCounters::increment(&self.counters.coalesced_hits);
+                    CoalescingCounters::increment(&outstanding.gets);
+                    outstanding.fetch.clone()
+                }
+                None => {
+                    CoalescingCounters::increment(&self.counters.distinct_fetches);
+                    let id = self.next_fetch_id.fetch_add(1, Ordering::Relaxed);
+                    let gets = Arc::new(AtomicU64::new(1));
+                    let read = tokio::spawn(Self::fetch(
+                        self.storage.clone(),
+                        self.outstanding_requests.clone(),
+                        self.counters.clone(),
+                        key.to_string(),
+                        id,
+                        gets.clone(),
+                    ));
+                    let task = read.abort_handle();
+                    let fetch = read
+                        .map(|res| {
+                            res.unwrap_or_else(|e| {
+                                Err(AdmissionControlledS3StorageError::FetchAborted(
+                                    e.to_string(),
+                                ))
+                            })
+                        })
+                        .boxed()
+                        .shared();
+                    requests.insert(
+                        key.to_string(),
+                        OutstandingFetch {
+                            id,
+                            started: self.clock.now(),
+                            gets,
+                            fetch: fetch.clone(),
+                            task,
+                        },
+                    );
+                    fetch
+                }
+            }
+        };
+        fetch.await
+    }
+
+    /// Returns the object at `key` from a read of its own, which neither
+    /// joins a read in flight nor can be joined. For reads that must not
+    /// return what a read started before an earlier put saw, such as
+    /// checking a write.
+    pub async fn get_uncoalesced(
+        &self,
+        key: &str,
+    ) -> Result<Arc<Vec<u8>>, AdmissionControlledS3StorageError> {
+        CoalescingCounters::increment(&self.counters.total_requests);
+        CoalescingCounters::increment(&self.counters.distinct_fetches);
+        Self::read_from_storage(self.storage.clone(), key.to_string()).await
+    }
+
+    pub async fn put_file(&self, key: &str, path: &str) -> Result<(), PutError> {
+        self.storage.put_file(key, path).await
+    }
+
+    pub async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), PutError> {
+        self.storage.put_bytes(key, bytes).await
+    }
+}
+
+#[async_trait]
+impl Configurable<StorageConfig> for AdmissionControlledS3Storage {
+    async fn try_from_config(config: &StorageConfig) -> Result<Self, Box<dyn ChromaError>> {
+        let storage = S3Storage::try_from_config(config).await?;
+        Ok(AdmissionControlledS3Storage::new(Storage::S3(storage)))
+    }
+}
+
+#[async_trait]
+impl Configurable<AdmissionControlledS3StorageConfig> for AdmissionControlledS3Storage {
+    async fn try_from_config(
+        config: &AdmissionControlledS3StorageConfig,
+    ) -> Result<Self, Box<dyn ChromaError>> {
+        let storage = S3Storage::try_from_config(&config.storage).await?;
+        let mut storage = AdmissionControlledS3Storage::new(Storage::S3(storage))
+            .with_coalescing(config.coalescing_enabled);
+        if let Some(shards) = config.coalescing_shards {
+            storage = storage.with_coalescing_shards(shards);
+        }
+        if let Some(ttl_ms) = config.coalescing_ttl_ms {
+            storage = storage.with_coalescing_ttl(Duration::from_millis(ttl_ms));
+        }
+        if let Some(max_age_ms) = config.max_inflight_age_ms {
+            let interval_ms = config.janitor_interval_ms.unwrap_or(max_age_ms);
+            storage = storage.with_janitor(
+                Duration::from_millis(interval_ms),
+                Duration::from_millis(max_age_ms),
+            );
+        }
+        Ok(storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BackendStream;
+    use crate::clock::ManualClock;
+    use aws_smithy_runtime::client::http::test_util::{ReplayEvent, StaticReplayClient};
+    use aws_smithy_runtime_api::client::http::{
+        HttpClient, HttpConnector, HttpConnectorFuture, HttpConnectorSettings, SharedHttpConnector,
+    };
+    use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+    use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+    use aws_smithy_types::body::SdkBody;
+    use tokio::sync::Semaphore;
+
+    // Serves every key with the same bytes once the gate lets it through,
+    // and counts the reads, so that the wrapper can be tested without S3.
+    struct MockBackend {
+        gate: Semaphore,
+        fetches: AtomicU64,
+    }
+
+    impl MockBackend {
+        fn new() -> MockBackend {
+            MockBackend {
+                gate: Semaphore::new(0),
+                fetches: AtomicU64::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl StorageBackend for MockBackend {
+        async fn get(&self, _: &str) -> Result<BackendStream, GetError> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            self.gate.acquire().await.expect("gate closed").forget();
+            Ok(Box::new(futures::stream::iter(vec![Ok(b"mock".to_vec())])))
+        }
+
+        async fn put_file(&self, _: &str, _: &str) -> Result<(), PutError> {
+            Ok(())
+        }
+
+        async fn put_bytes(&self, _: &str, _: Vec<u8>) -> Result<(), PutError> {
+            Ok(())
+        }
+    }
+
+    // Replays its events, but holds each response back until the gate lets it
+    // through, so that a test can line gets up behind a read in flight.
+    #[derive(Clone, Debug)]
+    struct GatedReplayClient {
+        replay: StaticReplayClient,
+        gate: Arc<Semaphore>,
+    }
+
+    impl HttpConnector for GatedReplayClient {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let response = self.replay.call(request);
+            let gate = self.gate.clone();
+            HttpConnectorFuture::new(async move {
+                gate.acquire().await.expect("gate closed").forget();
+                response.await
+            })
+        }
+    }
+
+    impl HttpClient for GatedReplayClient {
+        fn http_connector(
+            &self,
+            _: &HttpConnectorSettings,
+            _: &RuntimeComponents,
+        ) -> SharedHttpConnector {
+            SharedHttpConnector::new(self.clone())
+        }
+    }
+
+    // Yields part of an object and then fails as a local disk would.
+    struct FailingLocalBackend;
+
+    #[async_trait]
+    impl StorageBackend for FailingLocalBackend {
+        async fn get(&self, _: &str) -> Result<BackendStream, GetError> {
+            Ok(Box::new(futures::stream::iter(vec![
+                Ok(b"partial".to_vec()),
+                Err(GetError::LocalError("disk error".to_string())),
+            ])))
+        }
+
+        async fn put_file(&self, _: &str, _: &str) -> Result<(), PutError> {
+            Ok(())
+        }
+
+        async fn put_bytes(&self, _: &str, _: Vec<u8>) -> Result<(), PutError> {
+            Ok(())
+        }
+    }
+
+    fn gated_s3_storage(bodies: &[&str]) -> (S3Storage, StaticReplayClient, Arc<Semaphore>) {
+        let events = bodies
+            .iter()
+            .map(|body| {
+                ReplayEvent::new(
+                    http::Request::builder()
+                        .uri("https://test.s3.us-east-1.amazonaws.com/")
+                        .body(SdkBody::empty())
+                        .unwrap(),
+                    http::Response::builder()
+                        .status(200)
+                        .body(SdkBody::from(body.to_string()))
+                        .unwrap(),
+                )
+            })
+            .collect();
+        let replay = StaticReplayClient::new(events);
+        let gate = Arc::new(Semaphore::new(0));
+        let cred = aws_sdk_s3::config::Credentials::new("test", "test", None, None, "test");
+        let config = aws_sdk_s3::config::Builder::new()
+            .credentials_provider(cred)
+            .behavior_version_latest()
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .http_client(GatedReplayClient {
+                replay: replay.clone(),
+                gate: gate.clone(),
+            })
+            .build();
+        let client = aws_sdk_s3::Client::from_conf(config);
+        (
+            S3Storage::new("test", client, 1024 * 1024 * 8),
+            replay,
+            gate,
+        )
+    }
+
+    fn is_idle<S: StorageBackend>(storage: &AdmissionControlledS3Storage<S>) -> bool {
+        storage
+            .outstanding_requests
+            .shards
+            .iter()
+            .all(|shard| shard.lock().unwrap().is_empty())
+    }
+
+    // Waits until `count` gets have either started a read or joined one.
+    async fn wait_for_gets<S: StorageBackend + 'static>(
+        storage: &AdmissionControlledS3Storage<S>,
+        count: u64,
+    ) {
+        loop {
+            let stats = storage.stats();
+            if stats.coalesced_hits + stats.distinct_fetches == count {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_gets_are_coalesced() {
+        let (s3, replay, gate) = gated_s3_storage(&["test data"]);
+        let storage = AdmissionControlledS3Storage::new(Storage::S3(s3));
+
+        let gets = (0..5)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.get("test").await })
+            })
+            .collect::<Vec<_>>();
+        wait_for_gets(&storage, 5).await;
+        gate.add_permits(1);
+        for get in gets {
+            let bytes = get.await.unwrap().unwrap();
+            assert_eq!(bytes.as_slice(), "test data".as_bytes());
+        }
+
+        let stats = storage.stats();
+        assert_eq!(stats.total_requests, 5);
+        assert_eq!(stats.coalesced_hits, 4);
+        assert_eq!(stats.distinct_fetches, 1);
+        assert_eq!(replay.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_after_completion_reads_again() {
+        let (s3, replay, gate) = gated_s3_storage(&["first", "second"]);
+        let storage = AdmissionControlledS3Storage::new(Storage::S3(s3));
+        gate.add_permits(2);
+
+        assert_eq!(storage.get("test").await.unwrap().as_slice(), b"first");
+        assert_eq!(storage.get("test").await.unwrap().as_slice(), b"second");
+        assert_eq!(storage.stats().distinct_fetches, 2);
+        assert_eq!(storage.stats().coalesced_hits, 0);
+        assert_eq!(replay.actual_requests().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalescing_over_a_mock_backend() {
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new());
+
+        let gets = (0..3)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.get("test").await })
+            })
+            .collect::<Vec<_>>();
+        wait_for_gets(&storage, 3).await;
+        storage.storage.gate.add_permits(1);
+        for get in gets {
+            assert_eq!(get.await.unwrap().unwrap().as_slice(), b"mock");
+        }
+        assert_eq!(storage.storage.fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(storage.stats().coalesced_hits, 2);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_first_waiter_does_not_abort_the_read() {
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new());
+
+        let first = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.get("test").await })
+        };
+        wait_for_gets(&storage, 1).await;
+        let second = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.get("test").await })
+        };
+        wait_for_gets(&storage, 2).await;
+        first.abort();
+        assert!(first.await.unwrap_err().is_cancelled());
+
+        storage.storage.gate.add_permits(1);
+        assert_eq!(second.await.unwrap().unwrap().as_slice(), b"mock");
+        assert_eq!(storage.storage.fetches.load(Ordering::Relaxed), 1);
+        assert!(is_idle(&storage));
+    }
+
+    #[tokio::test]
+    async fn test_local_error_mid_stream_is_a_local_read_error() {
+        let storage = AdmissionControlledS3Storage::new(FailingLocalBackend);
+
+        let err = storage.get("test").await.unwrap_err();
+        match &err {
+            AdmissionControlledS3StorageError::LocalReadError(e) => assert_eq!(e, "disk error"),
+            e => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(err.code(), ErrorCodes::Internal);
+    }
+
+    #[tokio::test]
+    async fn test_get_uncoalesced_does_not_join_a_read_in_flight() {
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new());
+
+        let coalesced = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.get("test").await })
+        };
+        wait_for_gets(&storage, 1).await;
+        storage.storage.gate.add_permits(2);
+        assert_eq!(
+            storage.get_uncoalesced("test").await.unwrap().as_slice(),
+            b"mock"
+        );
+        assert_eq!(coalesced.await.unwrap().unwrap().as_slice(), b"mock");
+
+        assert_eq!(storage.storage.fetches.load(Ordering::Relaxed), 2);
+        assert_eq!(storage.stats().distinct_fetches, 2);
+        assert_eq!(storage.stats().coalesced_hits, 0);
+    }
+
+    #[tokio::test]
+    async fn test_entry_is_removed_before_waiters_wake() {
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new());
+
+        let gets = (0..1000)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let res = storage.get("test").await;
+                    // Nothing has started another read, so the entry must
+                    // already be gone when any waiter returns.
+                    assert!(storage
+                        .outstanding_requests
+                        .lock("test")
+                        .get("test")
+                        .is_none());
+                    res
+                })
+            })
+            .collect::<Vec<_>>();
+        wait_for_gets(&storage, 1000).await;
+        storage.storage.gate.add_permits(1);
+        for get in gets {
+            assert_eq!(get.await.unwrap().unwrap().as_slice(), b"mock");
+        }
+
+        assert!(is_idle(&storage));
+        assert_eq!(storage.storage.fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(storage.stats().coalesced_hits, 999);
+    }
+
+    #[tokio::test]
+    async fn test_read_older_than_ttl_is_not_joined() {
+        let clock = Arc::new(ManualClock::new());
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new())
+            .with_clock(clock.clone())
+            .with_coalescing_ttl(Duration::from_millis(20));
+
+        let stalled = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.get("test").await })
+        };
+        wait_for_gets(&storage, 1).await;
+        clock.advance(Duration::from_millis(20));
+        let fresh = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.get("test").await })
+        };
+        wait_for_gets(&storage, 2).await;
+        assert_eq!(storage.stats().distinct_fetches, 2);
+
+        // The stalled read completes first, and leaves the fresh read's
+        // entry in place.
+        storage.storage.gate.add_permits(1);
+        assert_eq!(stalled.await.unwrap().unwrap().as_slice(), b"mock");
+        assert!(storage
+            .outstanding_requests
+            .lock("test")
+            .contains_key("test"));
+        storage.storage.gate.add_permits(1);
+        assert_eq!(fresh.await.unwrap().unwrap().as_slice(), b"mock");
+        assert!(is_idle(&storage));
+    }
+
+    #[tokio::test]
+    async fn test_read_within_ttl_is_joined() {
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new())
+            .with_coalescing_ttl(Duration::from_secs(3600));
+
+        let gets = (0..2)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.get("test").await })
+            })
+            .collect::<Vec<_>>();
+        wait_for_gets(&storage, 2).await;
+        storage.storage.gate.add_permits(1);
+        for get in gets {
+            assert_eq!(get.await.unwrap().unwrap().as_slice(), b"mock");
+        }
+        assert_eq!(storage.storage.fetches.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_is_recorded_per_read() {
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new());
+
+        let gets = (0..5)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.get("test").await })
+            })
+            .collect::<Vec<_>>();
+        wait_for_gets(&storage, 5).await;
+        storage.storage.gate.add_permits(2);
+        for get in gets {
+            get.await.unwrap().unwrap();
+        }
+        storage.get("test").await.unwrap();
+
+        // One read served five gets, the other served one.
+        let mut buckets = [0; FAN_OUT_BUCKETS];
+        buckets[0] = 1;
+        buckets[2] = 1;
+        assert_eq!(
+            storage.stats().fan_out,
+            FanOutHistogram {
+                buckets,
+                count: 2,
+                sum: 6,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coalescing_across_shards() {
+        // "a" goes to the first shard and "b" to the second.
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new())
+            .with_coalescing_shards(2)
+            .with_key_hasher(Arc::new(|key: &str| (key == "b") as u64));
+
+        let gets = ["a", "b", "a", "b", "a", "b"]
+            .into_iter()
+            .map(|key| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.get(key).await })
+            })
+            .collect::<Vec<_>>();
+        wait_for_gets(&storage, 6).await;
+        let keys = storage
+            .outstanding_requests
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().keys().cloned().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![vec!["a".to_string()], vec!["b".to_string()]]);
+
+        storage.storage.gate.add_permits(2);
+        for get in gets {
+            assert_eq!(get.await.unwrap().unwrap().as_slice(), b"mock");
+        }
+        assert_eq!(storage.storage.fetches.load(Ordering::Relaxed), 2);
+        assert_eq!(storage.stats().coalesced_hits, 4);
+        assert!(is_idle(&storage));
+    }
+
+    #[tokio::test]
+    async fn test_gets_are_independent_with_coalescing_disabled() {
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new()).with_coalescing(false);
+
+        let gets = (0..3)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.get("test").await })
+            })
+            .collect::<Vec<_>>();
+        wait_for_gets(&storage, 3).await;
+        assert!(is_idle(&storage));
+        storage.storage.gate.add_permits(3);
+        for get in gets {
+            assert_eq!(get.await.unwrap().unwrap().as_slice(), b"mock");
+        }
+
+        assert_eq!(storage.storage.fetches.load(Ordering::Relaxed), 3);
+        assert_eq!(storage.stats().distinct_fetches, 3);
+        assert_eq!(storage.stats().coalesced_hits, 0);
+    }
+
+    // Lets the janitor finish its current wait, and waits for it to scan
+    // and start the next one.
+    async fn run_janitor(clock: &ManualClock, interval: Duration) {
+        while clock.pending_sleeps() == 0 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(interval);
+        while clock.pending_sleeps() == 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_janitor_evicts_stuck_read_after_max_age() {
+        let clock = Arc::new(ManualClock::new());
+        let interval = Duration::from_secs(10);
+        let storage = AdmissionControlledS3Storage::new(MockBackend::new())
+            .with_clock(clock.clone())
+            .with_janitor(interval, Duration::from_secs(60));
+
+        // The gate is never opened, so the read never completes.
+        let stuck = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.get("test").await })
+        };
+        wait_for_gets(&storage, 1).await;
+        for _ in 0..5 {
+            run_janitor(&clock, interval).await;
+            assert!(!is_idle(&storage));
+        }
+        run_janitor(&clock, interval).await;
+        assert!(is_idle(&storage));
+        assert!(matches!(
+            stuck.await.unwrap(),
+            Err(AdmissionControlledS3StorageError::FetchAborted(_))
+        ));
+
+        // The next get starts a read of its own.
+        storage.storage.gate.add_permits(1);
+        assert_eq!(storage.get("test").await.unwrap().as_slice(), b"mock");
+        assert_eq!(storage.storage.fetches.load(Ordering::Relaxed), 2);
+    }
+}
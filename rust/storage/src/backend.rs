@@ -0,0 +1,93 @@
+// The operations a layer over storage, such as AdmissionControlledS3Storage,
+// needs from the storage it wraps, as a trait so that the layer can be
+// written once and run over any backend, or over a mock in tests. It is
+// implemented for the Storage enum rather than for each backend, so a layer
+// reaches a backend through the same code as every other caller of Storage,
+// and sees the Storage error types whichever backend it wraps.
+
+use super::stream::ByteStreamItem;
+use super::{GetError, PutError, Storage};
+use async_trait::async_trait;
+use futures::Stream;
+use std::sync::Arc;
+
+pub type BackendStream = Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>;
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Returns the object at `key` as a stream of its bytes. A missing key
+    /// is GetError::NoSuchKey.
+    async fn get(&self, key: &str) -> Result<BackendStream, GetError>;
+
+    /// Returns the object at `key` as get does, along with the number of
+    /// bytes it will yield if the backend knows it up front. Backends that
+    /// do not override it report no length.
+    async fn get_stream(&self, key: &str) -> Result<(Option<u64>, BackendStream), GetError> {
+        Ok((None, self.get(key).await?))
+    }
+
+    async fn put_file(&self, key: &str, path: &str) -> Result<(), PutError>;
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), PutError>;
+}
+
+// So that several layers can share one backend.
+#[async_trait]
+impl<S: StorageBackend + ?Sized> StorageBackend for Arc<S> {
+    async fn get(&self, key: &str) -> Result<BackendStream, GetError> {
+        (**self).get(key).await
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<(Option<u64>, BackendStream), GetError> {
+        (**self).get_stream(key).await
+    }
+
+    async fn put_file(&self, key: &str, path: &str) -> Result<(), PutError> {
+        (**self).put_file(key, path).await
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), PutError> {
+        (**self).put_bytes(key, bytes).await
+    }
+}
+
+#[async_trait]
+impl StorageBackend for Storage {
+    async fn get(&self, key: &str) -> Result<BackendStream, GetError> {
+        Storage::get(self, key).await
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<(Option<u64>, BackendStream), GetError> {
+        Storage::get_stream(self, key).await
+    }
+
+    async fn put_file(&self, key: &str, path: &str) -> Result<(), PutError> {
+        Storage::put_file(self, key, path).await
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), PutError> {
+        Storage::put_bytes(self, key, bytes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::LocalStorage;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn test_storage_through_the_trait() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: Arc<dyn StorageBackend> = Arc::new(Storage::Local(LocalStorage::new(
+            dir.path().to_str().unwrap(),
+        )));
+
+        storage.put_bytes("key", b"data".to_vec()).await.unwrap();
+        let stream = storage.get("key").await.unwrap();
+        assert_eq!(stream.try_concat().await.unwrap(), b"data");
+        let (len, stream) = storage.get_stream("key").await.unwrap();
+        assert_eq!(len, Some(4));
+        assert_eq!(stream.try_concat().await.unwrap(), b"data");
+    }
+}
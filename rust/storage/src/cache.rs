@@ -0,0 +1,326 @@
+// An in-memory LRU cache of whole objects, bounded by the total size of the
+// cached values rather than the number of entries. Objects larger than the
+// per-object cap are never cached so that a single large object cannot evict
+// the entire working set.
+//
+// Writes race with reads that populate the cache: a get that started before a
+// put may finish after the put invalidated the key. To avoid caching the stale
+// value, every invalidation bumps a generation counter and inserts carry the
+// generation observed before the read was issued. Inserts from a read that
+// overlapped any invalidation are dropped.
+//
+// The state is behind a single lock, which every method takes once and
+// releases before returning. It is never held across an await, nor while
+// another lock is taken, so the cache cannot take part in a lock-ordering
+// deadlock. Keep it that way: a method that needs anything but the state
+// must gather it before locking or act on it after unlocking.
+
+use super::stats::{StatsCounters, StorageStats};
+use lru::LruCache;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct ObjectCache {
+    state: Arc<Mutex<CacheState>>,
+    capacity_bytes: usize,
+    max_object_size_bytes: usize,
+    stats: Arc<StatsCounters>,
+}
+
+struct CacheState {
+    entries: LruCache<String, Arc<Vec<u8>>>,
+    size_bytes: usize,
+    generation: u64,
+}
+
+impl ObjectCache {
+    /// Creates a cache holding up to `capacity_bytes` of object data, where
+    /// no single object may be larger than `max_object_size_bytes`.
+    pub fn new(capacity_bytes: usize, max_object_size_bytes: usize) -> ObjectCache {
+        ObjectCache {
+            state: Arc::new(Mutex::new(CacheState {
+                entries: LruCache::unbounded(),
+                size_bytes: 0,
+                generation: 0,
+            })),
+            capacity_bytes,
+            max_object_size_bytes,
+            stats: Arc::new(StatsCounters::default()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().expect("object cache lock poisoned")
+    }
+
+    /// Returns the cached value for `key`, marking it as most recently used.
+    pub fn get(&self, key: &str) -> Option<Arc<Vec<u8>>> {
+        let value = self.lock().entries.get(key).cloned();
+        match value {
+            Some(_) => StatsCounters::increment(&self.stats.cache_hits),
+            None => StatsCounters::increment(&self.stats.cache_misses),
+        }
+        value
+    }
+
+    /// Whether an object of `size_bytes` is small enough to be cached.
+    pub fn admits(&self, size_bytes: usize) -> bool {
+        size_bytes <= self.max_object_size_bytes && size_bytes <= self.capacity_bytes
+    }
+
+    /// The current generation, to be passed to insert by a read that is about
+    /// to be issued.
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Caches `value` under `key`, evicting the least recently used entries
+    /// until it fits. Does nothing if the value is too large to be cached or
+    /// if any key was invalidated since `generation` was observed.
+    pub fn insert(&self, key: &str, value: Arc<Vec<u8>>, generation: u64) {
+        if !self.admits(value.len()) {
+            return;
+        }
+        let mut state = self.lock();
+        if state.generation != generation {
+            return;
+        }
+        self.insert_locked(&mut state, key, value);
+    }
+
+    fn insert_locked(&self, state: &mut CacheState, key: &str, value: Arc<Vec<u8>>) {
+        if let Some(old) = state.entries.put(key.to_string(), value.clone()) {
+            state.size_bytes -= old.len();
+        }
+        state.size_bytes += value.len();
+        StatsCounters::increment(&self.stats.cache_insertions);
+        while state.size_bytes > self.capacity_bytes {
+            match state.entries.pop_lru() {
+                Some((_, evicted)) => {
+                    state.size_bytes -= evicted.len();
+                    StatsCounters::increment(&self.stats.cache_evictions);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Removes `key` from the cache and discards any in-flight inserts.
+    pub fn invalidate(&self, key: &str) {
+        let mut state = self.lock();
+        state.generation += 1;
+        if let Some(old) = state.entries.pop(key) {
+            state.size_bytes -= old.len();
+            StatsCounters::increment(&self.stats.cache_invalidations);
+        }
+    }
+
+    /// Caches `value`, the payload a write just stored at `key`, in place of
+    /// any cached value, and discards in-flight inserts as invalidate does.
+    /// `generation` must be observed before the write is issued. If any key
+    /// was invalidated since, a concurrent write may have stored `key` after
+    /// this one, so `key` is only invalidated; the same goes for a value too
+    /// large to be cached.
+    pub fn replace(&self, key: &str, value: Arc<Vec<u8>>, generation: u64) {
+        let mut state = self.lock();
+        let unchanged = state.generation == generation;
+        state.generation += 1;
+        if let Some(old) = state.entries.pop(key) {
+            state.size_bytes -= old.len();
+            StatsCounters::increment(&self.stats.cache_invalidations);
+        }
+        if unchanged && self.admits(value.len()) {
+            self.insert_locked(&mut state, key, value);
+        }
+    }
+
+    /// The counters accumulated since the cache was created or last drained,
+    /// and the current size of the cache.
+    pub fn stats_snapshot(&self) -> StorageStats {
+        self.with_size(self.stats.snapshot())
+    }
+
+    /// Zeroes the counters.
+    pub fn reset_stats(&self) {
+        self.stats.drain();
+    }
+
+    /// Returns the counters and zeroes them in one step, so that no count is
+    /// lost between reading and resetting them.
+    pub fn drain_stats(&self) -> StorageStats {
+        self.with_size(self.stats.drain())
+    }
+
+    fn with_size(&self, stats: StorageStats) -> StorageStats {
+        let state = self.lock();
+        StorageStats {
+            cache_size_bytes: state.size_bytes as u64,
+            cache_entries: state.entries.len() as u64,
+            ..stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(size: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![0; size])
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_under_byte_budget() {
+        let cache = ObjectCache::new(100, 100);
+        cache.insert("a", value(40), cache.generation());
+        cache.insert("b", value(40), cache.generation());
+        // Touch a so that b is the least recently used.
+        assert!(cache.get("a").is_some());
+        cache.insert("c", value(40), cache.generation());
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.lock().size_bytes, 80);
+        assert_eq!(
+            cache.drain_stats(),
+            StorageStats {
+                cache_hits: 3,
+                cache_misses: 1,
+                cache_insertions: 3,
+                cache_evictions: 1,
+                cache_invalidations: 0,
+                cache_size_bytes: 80,
+                cache_entries: 2,
+            }
+        );
+        assert_eq!(
+            cache.stats_snapshot(),
+            StorageStats {
+                cache_size_bytes: 80,
+                cache_entries: 2,
+                ..StorageStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_large_objects_bypass_the_cache() {
+        let cache = ObjectCache::new(100, 10);
+        cache.insert("small", value(10), cache.generation());
+        cache.insert("large", value(11), cache.generation());
+
+        assert!(cache.get("small").is_some());
+        assert!(cache.get("large").is_none());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = ObjectCache::new(100, 100);
+        cache.insert("a", value(10), cache.generation());
+        cache.invalidate("a");
+
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.lock().size_bytes, 0);
+    }
+
+    #[test]
+    fn test_replace() {
+        let cache = ObjectCache::new(100, 10);
+        cache.insert("a", value(5), cache.generation());
+        let generation = cache.generation();
+        cache.replace("a", value(10), generation);
+        assert_eq!(cache.get("a").unwrap().len(), 10);
+
+        // Too large to cache, so the stale value is only removed.
+        cache.replace("a", value(11), cache.generation());
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.lock().size_bytes, 0);
+
+        // Another write may have landed after this one.
+        let generation = cache.generation();
+        cache.invalidate("b");
+        cache.replace("a", value(5), generation);
+        assert!(cache.get("a").is_none());
+
+        // Reads that were in flight during the write are not cached.
+        let generation = cache.generation();
+        cache.replace("a", value(5), cache.generation());
+        cache.insert("a", value(1), generation);
+        assert_eq!(cache.get("a").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_concurrent_access_does_not_deadlock() {
+        const THREADS: usize = 8;
+        const OPERATIONS: usize = 10_000;
+        let cache = ObjectCache::new(100, 50);
+        let (done, finished) = std::sync::mpsc::channel();
+        for thread in 0..THREADS {
+            let (cache, done) = (cache.clone(), done.clone());
+            std::thread::spawn(move || {
+                for i in 0..OPERATIONS {
+                    let key = ["a", "b", "c", "d"][(thread + i) % 4];
+                    let generation = cache.generation();
+                    match i % 4 {
+                        0 => {
+                            cache.get(key);
+                        }
+                        1 => cache.insert(key, value(i % 40), generation),
+                        2 => cache.invalidate(key),
+                        _ => cache.replace(key, value(i % 60), generation),
+                    }
+                }
+                done.send(()).unwrap();
+            });
+        }
+        // A deadlocked thread never reports back.
+        for _ in 0..THREADS {
+            finished
+                .recv_timeout(std::time::Duration::from_secs(30))
+                .expect("cache operations deadlocked");
+        }
+
+        let state = cache.lock();
+        assert!(state.size_bytes <= 100);
+        let cached_bytes = state.entries.iter().map(|(_, v)| v.len()).sum::<usize>();
+        assert_eq!(state.size_bytes, cached_bytes);
+    }
+
+    #[test]
+    fn test_stats_count_hits_and_misses() {
+        let cache = ObjectCache::new(100, 100);
+        assert!(cache.get("a").is_none());
+        cache.insert("a", value(10), cache.generation());
+        assert!(cache.get("a").is_some());
+
+        let expected = StorageStats {
+            cache_hits: 1,
+            cache_misses: 1,
+            cache_insertions: 1,
+            cache_size_bytes: 10,
+            cache_entries: 1,
+            ..StorageStats::default()
+        };
+        assert_eq!(cache.drain_stats(), expected);
+        // Draining zeroes the counters but not the size.
+        assert_eq!(
+            cache.stats_snapshot(),
+            StorageStats {
+                cache_size_bytes: 10,
+                cache_entries: 1,
+                ..StorageStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_insert_racing_invalidate_is_dropped() {
+        let cache = ObjectCache::new(100, 100);
+        let generation = cache.generation();
+        cache.invalidate("a");
+        cache.insert("a", value(10), generation);
+
+        assert!(cache.get("a").is_none());
+    }
+}
@@ -0,0 +1,204 @@
+// A circuit breaker for requests to a storage backend. After a number of
+// consecutive failures the circuit opens, and requests fail immediately
+// instead of each waiting out its own timeout against a backend that is down.
+// Once the cooldown has passed, a single request is let through as a probe:
+// if it succeeds the circuit closes, and if it fails the circuit opens for
+// another cooldown. A probe whose outcome is never recorded, e.g. because it
+// was cancelled, does not hold the circuit half-open forever; another probe
+// is let through after a further cooldown.
+
+use super::clock::{Clock, TokioClock};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests are let through.
+    Closed,
+    /// Requests fail without being sent.
+    Open,
+    /// A probe is in flight; other requests fail without being sent.
+    HalfOpen,
+}
+
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+    clock: Arc<dyn Clock>,
+}
+
+enum BreakerState {
+    Closed { consecutive_failures: u32 },
+    Open { since: Instant },
+    HalfOpen { since: Instant },
+}
+
+impl std::fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl CircuitBreaker {
+    /// Creates a closed breaker that opens after `failure_threshold`
+    /// consecutive failures and stays open for `cooldown`.
+    /// `failure_threshold` must be greater than zero.
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::with_clock(failure_threshold, cooldown, Arc::new(TokioClock))
+    }
+
+    /// Creates a breaker as new does, that times its cooldown by `clock`.
+    pub fn with_clock(
+        failure_threshold: u32,
+        cooldown: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> CircuitBreaker {
+        assert!(failure_threshold > 0, "failure threshold must be positive");
+        CircuitBreaker {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::Closed {
+                consecutive_failures: 0,
+            }),
+            clock,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state.lock().expect("circuit breaker lock poisoned")
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.lock() {
+            BreakerState::Closed { .. } => CircuitState::Closed,
+            BreakerState::Open { .. } => CircuitState::Open,
+            BreakerState::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns whether a request may be sent. Once the cooldown has passed,
+    /// the first caller is let through as the probe and moves the circuit to
+    /// half-open.
+    pub fn allow(&self) -> bool {
+        let mut state = self.lock();
+        let now = self.clock.now();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { since } | BreakerState::HalfOpen { since }
+                if now.saturating_duration_since(since) >= self.cooldown =>
+            {
+                *state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    /// Records the outcome of a request. Any success closes the circuit.
+    pub fn record(&self, failed: bool) {
+        let mut state = self.lock();
+        if !failed {
+            *state = BreakerState::Closed {
+                consecutive_failures: 0,
+            };
+            return;
+        }
+        match &mut *state {
+            BreakerState::Closed {
+                consecutive_failures,
+            } if *consecutive_failures + 1 < self.failure_threshold => {
+                *consecutive_failures += 1;
+            }
+            // Failures of requests sent before the circuit opened do not
+            // extend the cooldown.
+            BreakerState::Open { .. } => {}
+            BreakerState::Closed { .. } | BreakerState::HalfOpen { .. } => {
+                tracing::warn!("storage circuit breaker opened");
+                *state = BreakerState::Open {
+                    since: self.clock.now(),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record(true);
+        breaker.record(true);
+        // A success resets the count.
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(true);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+
+        breaker.record(true);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn test_probe_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record(true);
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(60));
+        // Only one probe is let through.
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.allow());
+
+        // A failed probe opens the circuit for another cooldown.
+        breaker.record(true);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn test_lost_probe_is_replaced() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record(true);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+
+        // The probe never reports back.
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    }
+
+    #[test]
+    fn test_cooldown_is_timed_by_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let breaker = CircuitBreaker::with_clock(1, Duration::from_secs(30), clock.clone());
+        breaker.record(true);
+
+        clock.advance(Duration::from_secs(29));
+        assert!(!breaker.allow());
+        clock.advance(Duration::from_secs(1));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+
+        // A lost probe is replaced once another cooldown has passed.
+        clock.advance(Duration::from_secs(30));
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    }
+}
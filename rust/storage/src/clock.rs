@@ -0,0 +1,161 @@
+// The time seen by the time-dependent parts of storage: the rate limiter, the
+// circuit breaker, the backoff between retries and the ageing of coalesced
+// gets. Storage runs on TokioClock; tests swap in a ManualClock and advance
+// it by hand, so that they neither sleep nor depend on how fast the machine
+// running them is.
+
+use aws_sdk_s3::config::{AsyncSleep, Sleep};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub type ClockSleep = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    fn now(&self) -> Instant;
+
+    /// Returns a future that resolves once `duration` has passed on this
+    /// clock.
+    fn sleep(&self, duration: Duration) -> ClockSleep;
+}
+
+#[derive(Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> ClockSleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+// Lets the S3 client sleep on a Clock, which it does between retries.
+#[derive(Debug)]
+pub(crate) struct SdkSleep(pub(crate) Arc<dyn Clock>);
+
+impl AsyncSleep for SdkSleep {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Sleep::new(self.0.sleep(duration))
+    }
+}
+
+/// A clock that only moves when advanced. Sleeps resolve once the clock has
+/// been advanced past their end.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug)]
+pub struct ManualClock {
+    state: std::sync::Mutex<ManualClockState>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug)]
+struct ManualClockState {
+    now: Instant,
+    // Every sleep requested so far, in order.
+    sleeps: Vec<Duration>,
+    // The end of each pending sleep, and the sender that wakes it.
+    sleepers: Vec<(Instant, tokio::sync::oneshot::Sender<()>)>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            state: std::sync::Mutex::new(ManualClockState {
+                now: Instant::now(),
+                sleeps: Vec::new(),
+                sleepers: Vec::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ManualClockState> {
+        self.state.lock().expect("manual clock lock poisoned")
+    }
+
+    /// Moves the clock forward by `duration`, waking the sleeps that end by
+    /// then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.lock();
+        state.now += duration;
+        let now = state.now;
+        let (woken, pending) = std::mem::take(&mut state.sleepers)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(end, _)| *end <= now);
+        state.sleepers = pending;
+        drop(state);
+        for (_, sender) in woken {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Every sleep requested so far, in order.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.lock().sleeps.clone()
+    }
+
+    /// The number of sleeps that have not ended yet.
+    pub fn pending_sleeps(&self) -> usize {
+        self.lock()
+            .sleepers
+            .iter()
+            .filter(|(_, sender)| !sender.is_closed())
+            .count()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.lock().now
+    }
+
+    fn sleep(&self, duration: Duration) -> ClockSleep {
+        let mut state = self.lock();
+        state.sleeps.push(duration);
+        if duration.is_zero() {
+            return Box::pin(std::future::ready(()));
+        }
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let end = state.now + duration;
+        state.sleepers.push((end, sender));
+        Box::pin(async move {
+            // Also resolves if the clock is dropped.
+            let _ = receiver.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_manual_sleep_ends_when_advanced_past() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut sleep = clock.sleep(Duration::from_secs(2));
+        assert!(futures::poll!(&mut sleep).is_pending());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(futures::poll!(&mut sleep).is_pending());
+        assert_eq!(clock.pending_sleeps(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert!(futures::poll!(&mut sleep).is_ready());
+        assert_eq!(clock.pending_sleeps(), 0);
+
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert_eq!(clock.sleeps(), vec![Duration::from_secs(2)]);
+    }
+}
@@ -0,0 +1,47 @@
+// Transparent compression of object payloads. The codec an object was written
+// with is recorded in its metadata, so reads decompress according to the
+// object rather than the reader's configuration.
+
+use super::config::CompressionCodec;
+use std::io::{Read, Write};
+
+impl CompressionCodec {
+    // The value stored in object metadata.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            CompressionCodec::Gzip => "gzip",
+            CompressionCodec::Zstd => "zstd",
+        }
+    }
+
+    pub(crate) fn from_metadata(value: &str) -> Option<CompressionCodec> {
+        match value {
+            "gzip" => Some(CompressionCodec::Gzip),
+            "zstd" => Some(CompressionCodec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            CompressionCodec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            CompressionCodec::Zstd => zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    pub(crate) fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            CompressionCodec::Gzip => {
+                let mut buf = Vec::new();
+                flate2::read::GzDecoder::new(bytes).read_to_end(&mut buf)?;
+                Ok(buf)
+            }
+            CompressionCodec::Zstd => zstd::decode_all(bytes),
+        }
+    }
+}
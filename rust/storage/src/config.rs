@@ -1,3 +1,5 @@
+use super::s3::{StorageConfigError, MIN_UPLOAD_PART_SIZE_BYTES};
+use aws_sdk_s3::config::AppName;
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
@@ -7,6 +9,8 @@ use serde::Deserialize;
 /// # Notes
 /// See config.rs in the root of the worker crate for an example of how to use
 /// config files to configure the worker.
+// Deserialized once at startup, so the size of the S3 variant does not matter.
+#[allow(clippy::large_enum_variant)]
 pub enum StorageConfig {
     // case-insensitive
     #[serde(alias = "s3")]
@@ -15,22 +19,434 @@ pub enum StorageConfig {
     Local(LocalStorageConfig),
 }
 
-#[derive(Deserialize, PartialEq, Debug)]
+impl StorageConfig {
+    /// Checks the config of the chosen storage, as try_from_config does
+    /// before building it.
+    pub fn validate(&self) -> Result<(), StorageConfigError> {
+        match self {
+            StorageConfig::S3(s3_config) => s3_config.validate(),
+            StorageConfig::Local(local_config) => local_config.validate(),
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq)]
+/// Where the s3 storage gets its credentials from.
+/// # Options
+/// - Minio: The fixed credentials of the test MinIO deployment.
+/// - AWS: The standard AWS provider chain, as for any AWS SDK client:
+///   environment, profile, web identity, then instance or task roles. Also
+///   accepted as Default.
+/// - Static: A fixed access key and secret key, with an optional session
+///   token. Never logged.
+/// - Profile: The named profile of the shared AWS config and credentials
+///   files.
 pub enum S3CredentialsConfig {
     Minio,
+    #[serde(alias = "Default")]
     AWS,
+    Static {
+        access_key: String,
+        secret_key: String,
+        session_token: Option<String>,
+    },
+    Profile {
+        name: String,
+    },
+}
+
+impl std::fmt::Debug for S3CredentialsConfig {
+    // Keeps static secrets out of logged configs.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            S3CredentialsConfig::Minio => write!(f, "Minio"),
+            S3CredentialsConfig::AWS => write!(f, "AWS"),
+            S3CredentialsConfig::Static {
+                access_key,
+                session_token,
+                ..
+            } => f
+                .debug_struct("Static")
+                .field("access_key", access_key)
+                .field("secret_key", &"<redacted>")
+                .field(
+                    "session_token",
+                    &session_token.as_ref().map(|_| "<redacted>"),
+                )
+                .finish(),
+            S3CredentialsConfig::Profile { name } => {
+                f.debug_struct("Profile").field("name", name).finish()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CompressionCodec {
+    // case-insensitive
+    #[serde(alias = "gzip")]
+    Gzip,
+    #[serde(alias = "zstd")]
+    Zstd,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum KeyCase {
+    // case-insensitive
+    #[default]
+    #[serde(alias = "none")]
+    None,
+    #[serde(alias = "lower")]
+    Lower,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub enum ServerSideEncryption {
+    // case-insensitive
+    #[serde(alias = "aes256")]
+    Aes256,
+    #[serde(alias = "aws_kms")]
+    AwsKms { key_id: String },
 }
 
 #[derive(Deserialize, Debug)]
 /// The configuration for the s3 storage type
 /// # Fields
 /// - bucket: The name of the bucket to use.
+/// - fallback_bucket: Optional bucket that gets read a key from when it is
+///   not found in bucket, e.g. while objects are migrated from it. Writes,
+///   deletes and other operations only ever touch bucket.
+/// - prefix: Optional prefix, e.g. a tenant name, that every key is stored
+///   under, so that several deployments can share a bucket. It is joined to
+///   keys with a single slash, and stripped from the keys list_prefix
+///   returns, so callers only ever see their own keys.
+/// - requester_pays: Whether reads acknowledge that the requester pays for
+///   them, as requester-pays buckets require; reads from such a bucket are
+///   denied otherwise. Applies to gets, heads, lists and presigned get URLs.
+///   Defaults to false.
+/// - connect_timeout_ms: Optional timeout for establishing a connection to S3.
+///   Defaults to the SDK's connect timeout.
+/// - read_timeout_ms: Optional timeout for the first byte of a response to
+///   arrive once a request has been sent. Also accepted as
+///   request_timeout_ms. Defaults to the SDK's read timeout, which is unset.
+///   Both timeouts govern the underlying HTTP layer and apply to each attempt
+///   of a request; retries and operation_timeout_ms sit on top of them.
+/// - upload_part_size_bytes: Optional size of the parts of a multipart
+///   upload. If unset, uploads of known size use parts of 5 MiB, the least S3
+///   accepts, grown as needed to keep within S3's limit of 10,000 parts, and
+///   put_stream, which cannot know the size up front, uses parts of 8 MiB.
+///   Setting it overrides the part size of every upload, which then fails
+///   for objects that would need more than 10,000 parts. Files uploaded with
+///   put_file are read from disk one part at a time, so the part size also
+///   bounds the memory an upload holds per part.
+/// - rate_limit_rps: Optional upper bound on the number of S3 GET/PUT requests
+///   issued per second. Requests over the limit wait for capacity.
+/// - max_bytes_per_second: Optional upper bound on the number of bytes gets
+///   read and puts write per second, counted together. Get streams are
+///   paced chunk by chunk, and uploads part by part, so a large transfer is
+///   spread out rather than delayed at its start. The first second worth of
+///   bytes is not delayed. No limit is applied if unset.
+/// - max_concurrent_requests: Optional upper bound on the number of S3
+///   requests in flight at once. A get stays in flight until its stream is
+///   drained or dropped.
+/// - max_idle_connections: Optional number of idle connections to S3 kept
+///   open for reuse. It does not limit the connections open at once: they
+///   are opened as requests need them either way, but those beyond this
+///   number are closed once their request is done, so the next request pays
+///   for a new handshake. Use max_concurrent_requests to bound the requests,
+///   and so the connections, in flight. Should be at least
+///   max_concurrent_requests, so that every request the limit lets through
+///   can reuse a connection. Cannot be combined with a custom HTTP client,
+///   which keeps its own connections. The SDK's default keeps every idle
+///   connection open if unset. Also accepted as max_connections, its former
+///   name.
+/// - adaptive_concurrency: Whether to lower the concurrency limit while S3
+///   responds with throttling or server errors, and raise it back towards
+///   max_concurrent_requests as requests succeed. Requires
+///   max_concurrent_requests. Defaults to false.
+/// - circuit_breaker_failure_threshold: Optional number of consecutive
+///   failed requests, counting throttling, server errors and requests that
+///   got no response, after which gets and puts fail with CircuitOpen
+///   without contacting S3. Once the cooldown has passed a single request is
+///   let through, and the circuit closes if it succeeds. No circuit breaker
+///   is used if unset.
+/// - circuit_breaker_cooldown_ms: Optional time the circuit stays open
+///   before a request is let through to probe S3. Defaults to 30 seconds.
+/// - max_retries: Optional number of times a request that failed with a
+///   transient error is retried. Defaults to the SDK's standard retry policy.
+/// - base_backoff_ms: Optional initial backoff between retries, which grows
+///   exponentially with jitter.
+/// - retry_budget: Optional number of retries that all requests together may
+///   make. Every retry spends one from the budget and every five successful
+///   requests refill one, up to this number. Once the budget is spent,
+///   failures are returned without being retried, so that retries cannot
+///   pile onto a struggling S3. Retries are only bounded per request if
+///   unset.
+/// - verify_checksums: Whether gets verify the body against the SHA-256 that
+///   put_bytes stores in the object metadata. Objects without a stored
+///   checksum are returned unverified. Defaults to false.
+/// - validate_content_length: Whether gets check that the body they read is
+///   as long as the Content-Length of the response, failing with
+///   TruncatedResponse if it is not. This catches bodies cut short by a
+///   connection reset. Defaults to false.
+/// - reject_empty_puts: Whether puts of an empty payload, through put_bytes
+///   and its variants, put_file or put_stream and its variants, fail with
+///   EmptyPayload. Otherwise they create an empty object, which gets read
+///   back as no bytes. Defaults to false.
+/// - decode_content_encoding: Whether gets decompress objects that S3 serves
+///   with Content-Encoding: gzip, as objects uploaded gzipped by other
+///   writers are. Such objects are returned as stored, still gzipped, if
+///   unset. Unrelated to compression, which is recorded in object metadata
+///   and always decoded. Defaults to false.
+/// - operation_timeout_ms: Optional deadline for a whole get or put, including
+///   draining the response stream of a get. Unlike connect_timeout_ms and
+///   read_timeout_ms this also bounds a connection that stays open but
+///   stops making progress.
+/// - hedge_after_ms: Optional delay after which a get that has not received
+///   a response issues a second, identical request, and uses whichever
+///   responds first. Both requests are subject to rate_limit_rps and
+///   max_concurrent_requests. No requests are hedged if unset.
+/// - multipart_threshold_bytes: Optional object size at or above which puts
+///   use a multipart upload. Defaults to upload_part_size_bytes, or 8 MiB if
+///   it is unset. Like upload_part_size_bytes, must be at least 5 MiB.
+/// - upload_concurrency: Optional number of parts of a multipart upload that
+///   are uploaded at once. Defaults to 1.
+/// - cache_capacity_bytes: Optional total size of objects kept in an
+///   in-memory LRU cache in front of S3. No cache is used if unset.
+/// - cache_max_object_size_bytes: Optional size above which objects bypass
+///   the cache. Defaults to cache_capacity_bytes.
+/// - sse: Optional server-side encryption, Aes256 or AwsKms with the key_id of
+///   the KMS key, that every written object is encrypted with. S3 decrypts on
+///   reads, so reads are unaffected. No encryption headers are sent if unset.
+/// - validate_on_startup: Whether try_from_config checks that the bucket is
+///   reachable with the configured credentials before returning, so a
+///   misconfigured bucket or credentials fail startup instead of the first
+///   request. The check does not read or write any objects. Defaults to
+///   false.
+/// - read_ahead: Whether get streams read ahead of their consumer, so that
+///   chunks keep arriving from S3 while the consumer works on earlier ones.
+///   Applies to objects streamed straight from S3, not to those served from
+///   the cache or decompressed in memory. Defaults to false.
+/// - read_ahead_chunks: Optional number of chunks a get stream buffers ahead
+///   of its consumer when read_ahead is set. Defaults to 4.
+/// - max_object_size_bytes: Optional size above which gets fail with
+///   ObjectTooLarge rather than read the object. Objects that S3 reports as
+///   too large are rejected before their body is read; objects read into
+///   memory, e.g. to be cached or decompressed, stop being read as soon as
+///   they pass the limit. No limit is applied if unset.
+/// - small_object_threshold_bytes: Optional size below which get streams read
+///   the whole object before yielding it as a single chunk, so that small
+///   objects are not handed over in many tiny reads. Larger objects are
+///   streamed as they arrive. Every object is streamed if unset.
+/// - parallel_download_threshold_bytes: Optional object size above which
+///   get_parallel fetches an object as concurrent range gets rather than a
+///   single get. Defaults to download_part_size_bytes.
+/// - download_part_size_bytes: Optional size of the range gets of
+///   get_parallel. Defaults to 8 MiB.
+/// - download_concurrency: Optional number of range gets get_parallel issues
+///   at once. Defaults to 4.
+/// - exists_list_threshold: Optional number of keys at or above which
+///   exists_many answers from a single listing of their common prefix rather
+///   than one HEAD per key. Defaults to 10.
+/// - endpoint_url: Optional S3 endpoint to use instead of the default AWS
+///   endpoint, e.g. for MinIO or localstack. The Minio credentials default to
+///   http://minio.chroma:9000.
+/// - force_path_style: Whether to address buckets as part of the path rather
+///   than the host name, as MinIO requires. Always on for the Minio
+///   credentials. Defaults to false.
+/// - use_transfer_acceleration: Whether to send requests to the
+///   s3-accelerate endpoint of the bucket, which routes them through the
+///   nearest edge location. Speeds up transfers from far away regions, and
+///   requires acceleration to be enabled on the bucket. Cannot be combined
+///   with endpoint_url, force_path_style or the Minio credentials. Defaults
+///   to false.
+/// - user_agent_suffix: Optional name added to the user agent of every
+///   request, as app/<name>, so that the requests of each service sharing a
+///   bucket can be told apart in S3 server access logs and CloudTrail. May
+///   only contain ASCII letters, digits and !#$%&'*+-.^_`|~.
+/// - normalize_keys: The case keys are converted to before use, None or
+///   Lower. With Lower, every method that takes a key, including list
+///   prefixes, lowercases it, so that services writing the same object with
+///   different casing cannot miss each other's writes with NoSuchKey. Listed
+///   keys are returned as stored. Enabling it on a bucket that already holds
+///   objects with uppercase keys orphans them: they can no longer be read,
+///   overwritten or deleted through this storage. Defaults to None, which
+///   leaves keys as they are.
+/// - compression: Optional codec, Gzip or Zstd, used to compress objects
+///   before upload. Gets decompress according to the codec recorded with each
+///   object, so objects written with a different codec, or none, still read
+///   correctly.
+/// # Notes
+/// Numeric options must be greater than zero when set. validate checks this
+/// and the other constraints above before the storage is built.
 pub struct S3StorageConfig {
     pub bucket: String,
+    pub fallback_bucket: Option<String>,
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub requester_pays: bool,
     pub credentials: S3CredentialsConfig,
-    pub connect_timeout_ms: u64,
-    pub request_timeout_ms: u64,
-    pub upload_part_size_bytes: usize,
+    pub connect_timeout_ms: Option<u64>,
+    #[serde(alias = "request_timeout_ms")]
+    pub read_timeout_ms: Option<u64>,
+    pub upload_part_size_bytes: Option<usize>,
+    pub rate_limit_rps: Option<u32>,
+    pub max_bytes_per_second: Option<u64>,
+    pub max_concurrent_requests: Option<usize>,
+    #[serde(alias = "max_connections")]
+    pub max_idle_connections: Option<usize>,
+    #[serde(default)]
+    pub adaptive_concurrency: bool,
+    pub circuit_breaker_failure_threshold: Option<u32>,
+    pub circuit_breaker_cooldown_ms: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub base_backoff_ms: Option<u64>,
+    pub retry_budget: Option<u32>,
+    #[serde(default)]
+    pub verify_checksums: bool,
+    #[serde(default)]
+    pub validate_content_length: bool,
+    #[serde(default)]
+    pub reject_empty_puts: bool,
+    #[serde(default)]
+    pub decode_content_encoding: bool,
+    pub operation_timeout_ms: Option<u64>,
+    pub hedge_after_ms: Option<u64>,
+    pub multipart_threshold_bytes: Option<usize>,
+    pub upload_concurrency: Option<usize>,
+    pub cache_capacity_bytes: Option<usize>,
+    pub cache_max_object_size_bytes: Option<usize>,
+    pub compression: Option<CompressionCodec>,
+    pub sse: Option<ServerSideEncryption>,
+    #[serde(default)]
+    pub validate_on_startup: bool,
+    #[serde(default)]
+    pub read_ahead: bool,
+    pub read_ahead_chunks: Option<usize>,
+    pub max_object_size_bytes: Option<usize>,
+    pub small_object_threshold_bytes: Option<usize>,
+    pub exists_list_threshold: Option<usize>,
+    pub parallel_download_threshold_bytes: Option<usize>,
+    pub download_part_size_bytes: Option<usize>,
+    pub download_concurrency: Option<usize>,
+    pub endpoint_url: Option<String>,
+    #[serde(default)]
+    pub force_path_style: bool,
+    #[serde(default)]
+    pub use_transfer_acceleration: bool,
+    pub user_agent_suffix: Option<String>,
+    #[serde(default)]
+    pub normalize_keys: KeyCase,
+}
+
+impl S3StorageConfig {
+    /// Checks the invariants of the config that its types cannot express, so
+    /// that a bad config file fails at startup rather than on the first
+    /// request. Returns StorageConfigError::InvalidField naming the first
+    /// field found invalid.
+    pub fn validate(&self) -> Result<(), StorageConfigError> {
+        let invalid = |field, reason: &str| {
+            Err(StorageConfigError::InvalidField {
+                field,
+                reason: reason.to_string(),
+            })
+        };
+        if self.bucket.is_empty() {
+            return invalid("bucket", "must not be empty");
+        }
+        let positive = [
+            ("connect_timeout_ms", self.connect_timeout_ms),
+            ("read_timeout_ms", self.read_timeout_ms),
+            ("operation_timeout_ms", self.operation_timeout_ms),
+            ("hedge_after_ms", self.hedge_after_ms),
+            ("rate_limit_rps", self.rate_limit_rps.map(u64::from)),
+            ("max_bytes_per_second", self.max_bytes_per_second),
+            (
+                "max_concurrent_requests",
+                self.max_concurrent_requests.map(|n| n as u64),
+            ),
+            (
+                "max_idle_connections",
+                self.max_idle_connections.map(|n| n as u64),
+            ),
+            (
+                "circuit_breaker_failure_threshold",
+                self.circuit_breaker_failure_threshold.map(u64::from),
+            ),
+            (
+                "circuit_breaker_cooldown_ms",
+                self.circuit_breaker_cooldown_ms,
+            ),
+            ("retry_budget", self.retry_budget.map(u64::from)),
+            (
+                "upload_concurrency",
+                self.upload_concurrency.map(|n| n as u64),
+            ),
+            (
+                "cache_capacity_bytes",
+                self.cache_capacity_bytes.map(|n| n as u64),
+            ),
+            (
+                "read_ahead_chunks",
+                self.read_ahead_chunks.map(|n| n as u64),
+            ),
+            (
+                "max_object_size_bytes",
+                self.max_object_size_bytes.map(|n| n as u64),
+            ),
+            (
+                "small_object_threshold_bytes",
+                self.small_object_threshold_bytes.map(|n| n as u64),
+            ),
+            (
+                "exists_list_threshold",
+                self.exists_list_threshold.map(|n| n as u64),
+            ),
+            (
+                "download_part_size_bytes",
+                self.download_part_size_bytes.map(|n| n as u64),
+            ),
+            (
+                "download_concurrency",
+                self.download_concurrency.map(|n| n as u64),
+            ),
+        ];
+        if let Some((field, _)) = positive.iter().find(|(_, value)| *value == Some(0)) {
+            return invalid(field, "must be greater than zero");
+        }
+        let part_sized = [
+            ("upload_part_size_bytes", self.upload_part_size_bytes),
+            ("multipart_threshold_bytes", self.multipart_threshold_bytes),
+        ];
+        if let Some((field, _)) = part_sized
+            .iter()
+            .find(|(_, value)| value.is_some_and(|value| value < MIN_UPLOAD_PART_SIZE_BYTES))
+        {
+            return invalid(
+                field,
+                "must be at least 5 MiB, the smallest part S3 accepts",
+            );
+        }
+        // The adaptive limit needs a ceiling to recover to.
+        if self.adaptive_concurrency && self.max_concurrent_requests.is_none() {
+            return invalid("adaptive_concurrency", "requires max_concurrent_requests");
+        }
+        if self.cache_max_object_size_bytes.is_some() && self.cache_capacity_bytes.is_none() {
+            return invalid(
+                "cache_max_object_size_bytes",
+                "requires cache_capacity_bytes",
+            );
+        }
+        if let Some(suffix) = &self.user_agent_suffix {
+            if AppName::new(suffix.clone()).is_err() {
+                return invalid(
+                    "user_agent_suffix",
+                    "must be non-empty ASCII letters, digits and !#$%&'*+-.^_`|~",
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -43,3 +459,71 @@ pub struct S3StorageConfig {
 pub struct LocalStorageConfig {
     pub root: String,
 }
+
+#[derive(Deserialize, Debug)]
+/// The configuration for the admission-controlled s3 storage
+/// # Fields
+/// - storage: The configuration of the storage it wraps, which must be S3.
+/// - coalescing_ttl_ms: Optional age after which a read that is still in
+///   flight is no longer joined. A get of the key then starts a fresh read,
+///   which later gets join instead, while the waiters of the old read keep
+///   waiting for it. A read that has completed is never joined, whatever its
+///   age. In-flight reads are joined however old they are if unset.
+/// - coalescing_shards: Optional number of shards the map of reads in flight
+///   is split into, each with a lock of its own, so that gets of different
+///   keys rarely contend. Defaults to the number of CPUs.
+/// - coalescing_enabled: Whether concurrent gets of a key are coalesced into
+///   one read. With it off every get reads from storage on its own, which
+///   benchmarks and consistency tests may need. Defaults to true.
+/// - max_inflight_age_ms: Optional age after which a read still in flight is
+///   presumed stuck. A janitor evicts it from the map of reads in flight and
+///   aborts it, failing its waiters, so that the next get of the key starts
+///   a fresh read. Unlike coalescing_ttl_ms this ends the old read. No
+///   janitor runs if unset.
+/// - janitor_interval_ms: Optional time between the janitor's scans for
+///   stuck reads. The first scan comes after a random fraction of it.
+///   Defaults to max_inflight_age_ms.
+pub struct AdmissionControlledS3StorageConfig {
+    pub storage: StorageConfig,
+    pub coalescing_ttl_ms: Option<u64>,
+    pub coalescing_shards: Option<usize>,
+    #[serde(default = "default_coalescing_enabled")]
+    pub coalescing_enabled: bool,
+    pub max_inflight_age_ms: Option<u64>,
+    pub janitor_interval_ms: Option<u64>,
+}
+
+fn default_coalescing_enabled() -> bool {
+    true
+}
+
+impl LocalStorageConfig {
+    /// Checks that the config names a root directory.
+    pub fn validate(&self) -> Result<(), StorageConfigError> {
+        if self.root.is_empty() {
+            return Err(StorageConfigError::InvalidField {
+                field: "root",
+                reason: "must not be empty".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_credentials_are_redacted() {
+        let credentials = S3CredentialsConfig::Static {
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+            session_token: Some("token".to_string()),
+        };
+        assert_eq!(
+            format!("{:?}", credentials),
+            r#"Static { access_key: "access", secret_key: "<redacted>", session_token: Some("<redacted>") }"#
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// Keys of stored objects. Wrapping them in their own type keeps a bucket
+// name or a local path from being passed where a key is expected. Keys are
+// converted from strings without checks, so that callers can keep passing
+// &str and String, and are validated when an operation is called with them.
+
+use super::config::KeyCase;
+use super::s3::StorageConfigError;
+use std::borrow::Cow;
+
+// The longest key S3 accepts, in bytes.
+const MAX_KEY_LENGTH_BYTES: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ObjectKey(String);
+
+impl ObjectKey {
+    /// Returns the key, or StorageConfigError::InvalidKey if it is empty,
+    /// starts with a slash, or is longer than S3 allows.
+    pub fn validate(&self) -> Result<&str, StorageConfigError> {
+        let invalid = |reason: &str| {
+            Err(StorageConfigError::InvalidKey(format!(
+                "{:?}: {}",
+                self.0, reason
+            )))
+        };
+        if self.0.is_empty() {
+            return invalid("empty");
+        }
+        if self.0.starts_with('/') {
+            return invalid("starts with a slash");
+        }
+        if self.0.len() > MAX_KEY_LENGTH_BYTES {
+            return invalid("longer than 1024 bytes");
+        }
+        Ok(&self.0)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl KeyCase {
+    /// Converts `key` to this case, borrowing it if it is already in it.
+    pub fn apply<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self {
+            KeyCase::Lower if key.chars().any(char::is_uppercase) => Cow::Owned(key.to_lowercase()),
+            _ => Cow::Borrowed(key),
+        }
+    }
+}
+
+impl From<&str> for ObjectKey {
+    fn from(key: &str) -> ObjectKey {
+        ObjectKey(key.to_string())
+    }
+}
+
+impl From<&String> for ObjectKey {
+    fn from(key: &String) -> ObjectKey {
+        ObjectKey(key.clone())
+    }
+}
+
+impl From<String> for ObjectKey {
+    fn from(key: String) -> ObjectKey {
+        ObjectKey(key)
+    }
+}
+
+impl std::fmt::Display for ObjectKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_keys() {
+        for key in ["key", "block/1234", "a", &"k".repeat(MAX_KEY_LENGTH_BYTES)] {
+            assert_eq!(ObjectKey::from(key).validate().unwrap(), key);
+        }
+        assert_eq!(
+            ObjectKey::from("sparse_index/id".to_string()).as_str(),
+            "sparse_index/id"
+        );
+    }
+
+    #[test]
+    fn test_key_case() {
+        assert_eq!(KeyCase::None.apply("Block/ABC"), "Block/ABC");
+        assert_eq!(KeyCase::Lower.apply("Block/ABC"), "block/abc");
+        assert!(matches!(
+            KeyCase::Lower.apply("block/abc"),
+            Cow::Borrowed("block/abc")
+        ));
+    }
+
+    #[test]
+    fn test_leading_slash_is_rejected() {
+        assert!(matches!(
+            ObjectKey::from("/block/1234").validate(),
+            Err(StorageConfigError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_over_length_key_is_rejected() {
+        let key = "k".repeat(MAX_KEY_LENGTH_BYTES + 1);
+        assert!(matches!(
+            ObjectKey::from(key).validate(),
+            Err(StorageConfigError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            ObjectKey::from("").validate(),
+            Err(StorageConfigError::InvalidKey(_))
+        ));
+    }
+}
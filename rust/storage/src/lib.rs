@@ -1,23 +1,53 @@
 use self::config::StorageConfig;
+use self::key::ObjectKey;
 use self::s3::S3GetError;
+use self::s3::StorageConfigError;
+use self::stats::StorageStats;
 use self::stream::ByteStreamItem;
+use bytes::{Bytes, BytesMut};
 use chroma_config::Configurable;
 use chroma_error::{ChromaError, ErrorCodes};
 
+pub mod admission;
+pub mod admissioncontrolleds3;
+pub mod backend;
+pub mod cache;
+pub mod circuit_breaker;
+pub mod clock;
+mod compression;
 pub mod config;
+pub mod key;
 pub mod local;
+#[cfg(feature = "test-util")]
+pub mod memory;
+pub mod metrics;
+pub mod rate_limit;
+pub mod retry;
+pub mod retry_budget;
 pub mod s3;
+mod shutdown;
+pub mod stats;
 pub mod stream;
 use futures::Stream;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
 use thiserror::Error;
 
+// Created once at startup and shared, so the size of the S3 variant does not
+// matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
 pub enum Storage {
     S3(s3::S3Storage),
     Local(local::LocalStorage),
+    #[cfg(feature = "test-util")]
+    InMemory(memory::InMemoryStorage),
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum GetError {
     #[error("No such key: {0}")]
     NoSuchKey(String),
@@ -25,14 +55,17 @@ pub enum GetError {
     S3Error(#[from] S3GetError),
     #[error("Local storage error: {0}")]
     LocalError(String),
+    #[error("{0}")]
+    InvalidKey(#[from] StorageConfigError),
 }
 
 impl ChromaError for GetError {
     fn code(&self) -> ErrorCodes {
         match self {
             GetError::NoSuchKey(_) => ErrorCodes::NotFound,
-            GetError::S3Error(_) => ErrorCodes::Internal,
+            GetError::S3Error(e) => e.code(),
             GetError::LocalError(_) => ErrorCodes::Internal,
+            GetError::InvalidKey(e) => e.code(),
         }
     }
 }
@@ -43,25 +76,144 @@ pub enum PutError {
     S3Error(#[from] s3::S3PutError),
     #[error("Local storage error: {0}")]
     LocalError(String),
+    #[error("{0}")]
+    InvalidKey(#[from] StorageConfigError),
 }
 
 impl ChromaError for PutError {
     fn code(&self) -> ErrorCodes {
         match self {
-            PutError::S3Error(_) => ErrorCodes::Internal,
+            PutError::S3Error(e) => e.code(),
             PutError::LocalError(_) => ErrorCodes::Internal,
+            PutError::InvalidKey(e) => e.code(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DeleteError {
+    #[error("S3 error: {0}")]
+    S3Error(#[from] s3::S3DeleteError),
+    #[error("Local storage error: {0}")]
+    LocalError(String),
+    #[error("{0}")]
+    InvalidKey(#[from] StorageConfigError),
+}
+
+impl ChromaError for DeleteError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            DeleteError::S3Error(e) => e.code(),
+            DeleteError::LocalError(_) => ErrorCodes::Internal,
+            DeleteError::InvalidKey(e) => e.code(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CopyError {
+    #[error("Copy source not found: {0}")]
+    SourceNotFound(String),
+    #[error("S3 error: {0}")]
+    S3Error(#[from] s3::S3CopyError),
+    #[error("Local storage error: {0}")]
+    LocalError(String),
+    #[error("{0}")]
+    InvalidKey(#[from] StorageConfigError),
+}
+
+impl ChromaError for CopyError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            CopyError::SourceNotFound(_) => ErrorCodes::NotFound,
+            CopyError::S3Error(e) => e.code(),
+            CopyError::LocalError(_) => ErrorCodes::Internal,
+            CopyError::InvalidKey(e) => e.code(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HeadError {
+    #[error("S3 error: {0}")]
+    S3Error(#[from] s3::S3HeadError),
+    #[error("Local storage error: {0}")]
+    LocalError(String),
+    #[error("{0}")]
+    InvalidKey(#[from] StorageConfigError),
+}
+
+impl ChromaError for HeadError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            HeadError::S3Error(e) => e.code(),
+            HeadError::LocalError(_) => ErrorCodes::Internal,
+            HeadError::InvalidKey(e) => e.code(),
         }
     }
 }
 
+#[cfg(feature = "serde")]
+#[derive(Error, Debug)]
+pub enum GetDeserializeError {
+    #[error("Failed to fetch object: {0}")]
+    GetError(#[from] GetError),
+    #[error("Failed to deserialize object: {0}")]
+    DeserializeError(#[from] bincode::Error),
+}
+
+#[cfg(feature = "serde")]
+impl ChromaError for GetDeserializeError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            GetDeserializeError::GetError(e) => e.code(),
+            GetDeserializeError::DeserializeError(_) => ErrorCodes::Internal,
+        }
+    }
+}
+
+/// Metadata about a stored object, as returned by head.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectMetadata {
+    pub size: u64,
+    // None for backends that do not track etags, such as local storage.
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+    // None for backends that do not track content types.
+    pub content_type: Option<String>,
+    // The user metadata the object was written with, without the x-amz-meta-
+    // prefix. Empty for backends that do not store user metadata.
+    pub metadata: HashMap<String, String>,
+}
+
 impl Storage {
+    /// Returns the object at `key` as a stream of its bytes. An empty object
+    /// yields an empty stream, while a missing key is GetError::NoSuchKey.
     pub async fn get(
         &self,
-        key: &str,
+        key: impl Into<ObjectKey>,
     ) -> Result<Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>, GetError> {
+        let (_, stream) = self.get_stream(key).await?;
+        Ok(stream)
+    }
+
+    /// Returns the object at `key` as a stream, along with the number of
+    /// bytes it will yield if the backend reports it.
+    pub async fn get_stream(
+        &self,
+        key: impl Into<ObjectKey>,
+    ) -> Result<
+        (
+            Option<u64>,
+            Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>,
+        ),
+        GetError,
+    > {
+        let key = key.into();
+        let key = key.validate()?;
         match self {
             Storage::S3(s3) => {
-                let res = s3.get(key).await;
+                let res = s3.get_stream(key).await;
                 match res {
                     Ok(res) => Ok(res),
                     Err(e) => match e {
@@ -70,17 +222,140 @@ impl Storage {
                     },
                 }
             }
-            Storage::Local(local) => {
-                let res = local.get(key).await;
-                match res {
-                    Ok(res) => Ok(res),
-                    Err(e) => Err(GetError::LocalError(e)),
+            Storage::Local(local) => local.get_stream(key).await,
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory.get_stream(key).await,
+        }
+    }
+
+    /// Reads the object at `key` in full. Returns None if the key does not
+    /// exist, so that callers only need to handle genuine failures as errors.
+    pub async fn get_optional(
+        &self,
+        key: impl Into<ObjectKey>,
+    ) -> Result<Option<Arc<Vec<u8>>>, GetError> {
+        let stream = match self.get(key).await {
+            Ok(stream) => stream,
+            Err(GetError::NoSuchKey(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let bytes: Vec<u8> = stream.try_concat().await?;
+        Ok(Some(Arc::new(bytes)))
+    }
+
+    /// Reads the object at `key` in full into a single Bytes. Unlike the
+    /// Arc<Vec<u8>> of get_optional, it can be sliced and cloned without
+    /// copying, so readers can hand out parts of the object cheaply. The
+    /// buffer is sized up front when the backend reports the length.
+    pub async fn get_bytes(&self, key: impl Into<ObjectKey>) -> Result<Bytes, GetError> {
+        let (content_length, mut stream) = self.get_stream(key).await?;
+        let mut bytes = BytesMut::with_capacity(content_length.unwrap_or(0) as usize);
+        while let Some(chunk) = stream.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+        Ok(bytes.freeze())
+    }
+
+    /// Reads the object at `key` in full and deserializes it from bincode.
+    /// A missing key is GetDeserializeError::GetError wrapping
+    /// GetError::NoSuchKey, while bytes that do not decode as a `T` are
+    /// GetDeserializeError::DeserializeError.
+    #[cfg(feature = "serde")]
+    pub async fn get_deserialized<T: serde::de::DeserializeOwned>(
+        &self,
+        key: impl Into<ObjectKey>,
+    ) -> Result<T, GetDeserializeError> {
+        let bytes: Vec<u8> = self.get(key).await?.try_concat().await?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    /// Reads the objects at `keys` in full, fetching up to `parallelism` of
+    /// them at once. Results are in the same order as `keys`, and a failure
+    /// to read one key does not affect the others. A key that appears more
+    /// than once is only fetched once. A `parallelism` of zero is taken as
+    /// one.
+    pub async fn get_many(
+        &self,
+        keys: Vec<String>,
+        parallelism: usize,
+    ) -> Vec<Result<Arc<Vec<u8>>, GetError>> {
+        let mut unique_keys = Vec::new();
+        let mut indices = HashMap::new();
+        let positions = keys
+            .into_iter()
+            .map(|key| {
+                *indices.entry(key.clone()).or_insert_with(|| {
+                    unique_keys.push(key);
+                    unique_keys.len() - 1
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let results = futures::stream::iter(unique_keys.iter())
+            .map(|key| async move {
+                let bytes: Vec<u8> = self.get(key).await?.try_concat().await?;
+                Ok(Arc::new(bytes))
+            })
+            .buffered(parallelism.max(1))
+            .collect::<Vec<_>>()
+            .await;
+        positions
+            .into_iter()
+            .map(|position| results[position].clone())
+            .collect()
+    }
+
+    pub async fn get_range(
+        &self,
+        key: impl Into<ObjectKey>,
+        start: u64,
+        end: u64,
+    ) -> Result<Arc<Vec<u8>>, GetError> {
+        let key = key.into();
+        let key = key.validate()?;
+        match self {
+            Storage::S3(s3) => match s3.get_range(key, start, end).await {
+                Ok(res) => Ok(res),
+                Err(S3GetError::NoSuchKey(_)) => Err(GetError::NoSuchKey(key.to_string())),
+                Err(e) => Err(GetError::S3Error(e)),
+            },
+            Storage::Local(local) => local.get_range(key, start, end).await,
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory.get_range(key, start, end).await,
+        }
+    }
+
+    /// Returns the bytes in `start..end` of the object at `key` as a stream.
+    /// An unsatisfiable range fails before the stream is returned. Only S3
+    /// streams the range; other backends yield it as a single chunk.
+    pub async fn get_range_stream(
+        &self,
+        key: impl Into<ObjectKey>,
+        start: u64,
+        end: u64,
+    ) -> Result<Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>, GetError> {
+        let key = key.into();
+        let key = key.validate()?;
+        let bytes = match self {
+            Storage::S3(s3) => {
+                return match s3.get_range_stream(key, start, end).await {
+                    Ok(stream) => Ok(stream),
+                    Err(S3GetError::NoSuchKey(_)) => Err(GetError::NoSuchKey(key.to_string())),
+                    Err(e) => Err(GetError::S3Error(e)),
                 }
             }
-        }
+            Storage::Local(local) => local.get_range(key, start, end).await?,
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory.get_range(key, start, end).await?,
+        };
+        Ok(Box::new(futures::stream::once(futures::future::ready(Ok(
+            bytes.to_vec(),
+        )))))
     }
 
-    pub async fn put_file(&self, key: &str, path: &str) -> Result<(), PutError> {
+    pub async fn put_file(&self, key: impl Into<ObjectKey>, path: &str) -> Result<(), PutError> {
+        let key = key.into();
+        let key = key.validate()?;
         match self {
             Storage::S3(s3) => s3
                 .put_file(key, path)
@@ -90,10 +365,21 @@ impl Storage {
                 .put_file(key, path)
                 .await
                 .map_err(|e| PutError::LocalError(e)),
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory
+                .put_file(key, path)
+                .await
+                .map_err(PutError::LocalError),
         }
     }
 
-    pub async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), PutError> {
+    pub async fn put_bytes(
+        &self,
+        key: impl Into<ObjectKey>,
+        bytes: Vec<u8>,
+    ) -> Result<(), PutError> {
+        let key = key.into();
+        let key = key.validate()?;
         match self {
             Storage::S3(s3) => s3
                 .put_bytes(key, bytes)
@@ -103,6 +389,92 @@ impl Storage {
                 .put_bytes(key, &bytes)
                 .await
                 .map_err(|e| PutError::LocalError(e)),
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory
+                .put_bytes(key, &bytes)
+                .await
+                .map_err(PutError::LocalError),
+        }
+    }
+
+    /// Writes `bytes` to `key` unless an object already exists there.
+    /// Returns true if the object was written.
+    pub async fn put_if_absent(
+        &self,
+        key: impl Into<ObjectKey>,
+        bytes: Vec<u8>,
+    ) -> Result<bool, PutError> {
+        let key = key.into();
+        let key = key.validate()?;
+        match self {
+            Storage::S3(s3) => s3
+                .put_if_absent(key, bytes)
+                .await
+                .map_err(PutError::S3Error),
+            Storage::Local(local) => local
+                .put_if_absent(key, &bytes)
+                .await
+                .map_err(PutError::LocalError),
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory
+                .put_if_absent(key, &bytes)
+                .await
+                .map_err(PutError::LocalError),
+        }
+    }
+
+    pub async fn head(
+        &self,
+        key: impl Into<ObjectKey>,
+    ) -> Result<Option<ObjectMetadata>, HeadError> {
+        let key = key.into();
+        let key = key.validate()?;
+        match self {
+            Storage::S3(s3) => s3.head(key).await.map_err(HeadError::S3Error),
+            Storage::Local(local) => local.head(key).await.map_err(HeadError::LocalError),
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory.head(key).await.map_err(HeadError::LocalError),
+        }
+    }
+
+    pub async fn copy(
+        &self,
+        src_key: impl Into<ObjectKey>,
+        dst_key: impl Into<ObjectKey>,
+    ) -> Result<(), CopyError> {
+        let (src_key, dst_key) = (src_key.into(), dst_key.into());
+        let (src_key, dst_key) = (src_key.validate()?, dst_key.validate()?);
+        match self {
+            Storage::S3(s3) => match s3.copy(src_key, dst_key).await {
+                Ok(_) => Ok(()),
+                Err(s3::S3CopyError::SourceNotFound(_)) => {
+                    Err(CopyError::SourceNotFound(src_key.to_string()))
+                }
+                Err(e) => Err(CopyError::S3Error(e)),
+            },
+            Storage::Local(local) => local.copy(src_key, dst_key).await,
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory.copy(src_key, dst_key).await,
+        }
+    }
+
+    /// Returns the storage counters and zeroes them in one step. Local and
+    /// in-memory storage keep no counters, so their stats are always zero.
+    pub fn drain_stats(&self) -> StorageStats {
+        match self {
+            Storage::S3(s3) => s3.drain_stats(),
+            _ => StorageStats::default(),
+        }
+    }
+
+    pub async fn delete(&self, key: impl Into<ObjectKey>) -> Result<(), DeleteError> {
+        let key = key.into();
+        let key = key.validate()?;
+        match self {
+            Storage::S3(s3) => s3.delete(key).await.map_err(DeleteError::S3Error),
+            Storage::Local(local) => local.delete(key).await.map_err(DeleteError::LocalError),
+            #[cfg(feature = "test-util")]
+            Storage::InMemory(memory) => memory.delete(key).await.map_err(DeleteError::LocalError),
         }
     }
 }
@@ -115,3 +487,190 @@ pub async fn from_config(config: &StorageConfig) -> Result<Storage, Box<dyn Chro
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_get_optional() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = Storage::Local(local::LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+
+        storage
+            .put_bytes("test", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let bytes = storage.get_optional("test").await.unwrap().unwrap();
+        assert_eq!(bytes.as_slice(), "test data".as_bytes());
+        assert!(storage.get_optional("missing").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_optional_empty_object() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = Storage::Local(local::LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+
+        storage.put_bytes("empty", Vec::new()).await.unwrap();
+        let bytes = storage.get_optional("empty").await.unwrap().unwrap();
+        assert!(bytes.is_empty());
+        assert!(matches!(
+            storage.get("missing").await,
+            Err(GetError::NoSuchKey(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_bytes() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = Storage::Local(local::LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+
+        storage
+            .put_bytes("test", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let bytes = storage.get_bytes("test").await.unwrap();
+        assert_eq!(&bytes[..], "test data".as_bytes());
+
+        // Clones and slices share the allocation of the object.
+        let clone = bytes.clone();
+        assert_eq!(clone.as_ptr(), bytes.as_ptr());
+        let data = bytes.slice(5..);
+        assert_eq!(&data[..], "data".as_bytes());
+        assert_eq!(data.as_ptr(), bytes[5..].as_ptr());
+
+        assert!(matches!(
+            storage.get_bytes("missing").await,
+            Err(GetError::NoSuchKey(_))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_get_deserialized() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct Record {
+            id: u64,
+            name: String,
+        }
+
+        let tmp_dir = tempdir().unwrap();
+        let storage = Storage::Local(local::LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+        let record = Record {
+            id: 7,
+            name: "seven".to_string(),
+        };
+        storage
+            .put_bytes("record", bincode::serialize(&record).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_deserialized::<Record>("record").await.unwrap(),
+            record
+        );
+        assert!(matches!(
+            storage.get_deserialized::<Record>("missing").await,
+            Err(GetDeserializeError::GetError(GetError::NoSuchKey(_)))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_get_deserialized_corrupt_bytes() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = Storage::Local(local::LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+        // A string whose length prefix runs past the end of the object.
+        storage
+            .put_bytes("corrupt", vec![0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, b'a'])
+            .await
+            .unwrap();
+        let res = storage.get_deserialized::<String>("corrupt").await;
+        assert!(matches!(res, Err(GetDeserializeError::DeserializeError(_))));
+    }
+
+    #[test]
+    fn test_error_codes_delegate_to_backend_errors() {
+        let key = || "key".to_string();
+        assert_eq!(
+            GetError::S3Error(S3GetError::Timeout(key())).code(),
+            ErrorCodes::DeadlineExceeded
+        );
+        assert_eq!(
+            PutError::S3Error(s3::S3PutError::Timeout(key())).code(),
+            ErrorCodes::DeadlineExceeded
+        );
+        assert_eq!(GetError::NoSuchKey(key()).code(), ErrorCodes::NotFound);
+        assert_eq!(GetError::LocalError(key()).code(), ErrorCodes::Internal);
+        assert_eq!(
+            DeleteError::InvalidKey(StorageConfigError::InvalidKey(key())).code(),
+            ErrorCodes::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_keys_are_rejected() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = Storage::Local(local::LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+
+        assert!(matches!(
+            storage.put_bytes("/key", b"data".to_vec()).await,
+            Err(PutError::InvalidKey(StorageConfigError::InvalidKey(_)))
+        ));
+        assert!(matches!(
+            storage.get("/key").await,
+            Err(GetError::InvalidKey(_))
+        ));
+        storage.put_bytes("key", b"data".to_vec()).await.unwrap();
+        assert!(matches!(
+            storage.copy("key", "k".repeat(2000)).await,
+            Err(CopyError::InvalidKey(_))
+        ));
+        // Owned keys are accepted as well as borrowed ones.
+        assert!(storage.head("key".to_string()).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_get_many_preserves_order() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = Storage::Local(local::LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+
+        let keys = (0..20).map(|i| format!("key-{}", i)).collect::<Vec<_>>();
+        for key in &keys {
+            storage
+                .put_bytes(key, key.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+        let results = storage.get_many(keys.clone(), 4).await;
+        assert_eq!(results.len(), keys.len());
+        for (key, result) in keys.iter().zip(results) {
+            assert_eq!(result.unwrap().as_slice(), key.as_bytes());
+        }
+
+        // A parallelism of zero still reads every key.
+        let results = storage.get_many(keys.clone(), 0).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_get_many_partial_failure() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = Storage::Local(local::LocalStorage::new(tmp_dir.path().to_str().unwrap()));
+
+        storage
+            .put_bytes("a", "a".as_bytes().to_vec())
+            .await
+            .unwrap();
+        storage
+            .put_bytes("c", "c".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let results = storage
+            .get_many(vec!["a".to_string(), "b".to_string(), "c".to_string()], 2)
+            .await;
+        assert_eq!(results[0].as_ref().unwrap().as_slice(), "a".as_bytes());
+        assert!(matches!(&results[1], Err(GetError::NoSuchKey(key)) if key == "b"));
+        assert_eq!(results[2].as_ref().unwrap().as_slice(), "c".as_bytes());
+    }
+}
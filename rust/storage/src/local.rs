@@ -1,10 +1,16 @@
 use super::stream::ByteStream;
 use super::stream::ByteStreamItem;
+use super::CopyError;
+use super::GetError;
+use super::ObjectMetadata;
 use super::{config::StorageConfig, s3::StorageConfigError};
 use async_trait::async_trait;
 use chroma_config::Configurable;
 use chroma_error::ChromaError;
 use futures::Stream;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct LocalStorage {
@@ -19,21 +25,77 @@ impl LocalStorage {
         };
     }
 
+    // Opens the file backing `key`, reporting a missing file as NoSuchKey so
+    // callers can treat local and s3 storage uniformly.
+    fn open(&self, key: &str) -> Result<std::fs::File, GetError> {
+        let file_path = format!("{}/{}", self.root, key);
+        tracing::debug!("Reading from path: {}", file_path);
+        std::fs::File::open(file_path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => GetError::NoSuchKey(key.to_string()),
+            _ => GetError::LocalError(e.to_string()),
+        })
+    }
+
     pub async fn get(
         &self,
         key: &str,
-    ) -> Result<Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>, String> {
-        let file_path = format!("{}/{}", self.root, key);
-        tracing::debug!("Reading from path: {}", file_path);
-        match std::fs::File::open(file_path) {
-            Ok(file) => {
-                let stream = file.byte_stream();
-                return Ok(Box::new(stream));
-            }
-            Err(e) => {
-                return Err::<_, String>(e.to_string());
-            }
+    ) -> Result<Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>, GetError> {
+        let (_, stream) = self.get_stream(key).await?;
+        Ok(stream)
+    }
+
+    /// Returns the file at `key` as get does, along with its size.
+    pub async fn get_stream(
+        &self,
+        key: &str,
+    ) -> Result<
+        (
+            Option<u64>,
+            Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>,
+        ),
+        GetError,
+    > {
+        let file = self.open(key)?;
+        let size = file
+            .metadata()
+            .map_err(|e| GetError::LocalError(e.to_string()))?
+            .len();
+        Ok((Some(size), Box::new(file.byte_stream())))
+    }
+
+    pub async fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Arc<Vec<u8>>, GetError> {
+        if start > end {
+            return Err(GetError::LocalError(format!(
+                "Range not satisfiable: {}..{}",
+                start, end
+            )));
+        }
+        let mut file = self.open(key)?;
+        let file_size = file
+            .metadata()
+            .map_err(|e| GetError::LocalError(e.to_string()))?
+            .len();
+        if start == end {
+            return Ok(Arc::new(Vec::new()));
         }
+        if start >= file_size {
+            return Err(GetError::LocalError(format!(
+                "Range not satisfiable: {}..{}",
+                start, end
+            )));
+        }
+        file.seek(SeekFrom::Start(start))
+            .map_err(|e| GetError::LocalError(e.to_string()))?;
+        let mut buf = Vec::new();
+        file.take(end - start)
+            .read_to_end(&mut buf)
+            .map_err(|e| GetError::LocalError(e.to_string()))?;
+        Ok(Arc::new(buf))
     }
 
     pub async fn put_bytes(&self, key: &str, bytes: &[u8]) -> Result<(), String> {
@@ -54,6 +116,68 @@ impl LocalStorage {
         }
     }
 
+    /// Writes `bytes` to `key` unless a file already exists there. Returns
+    /// true if the file was written.
+    pub async fn put_if_absent(&self, key: &str, bytes: &[u8]) -> Result<bool, String> {
+        let path = format!("{}/{}", self.root, key);
+        let as_path = std::path::Path::new(&path);
+        let parent = as_path.parent().unwrap();
+        std::fs::create_dir_all(parent).unwrap();
+        let mut file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => return Err(e.to_string()),
+        };
+        std::io::Write::write_all(&mut file, bytes).map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    /// Returns the metadata of the file at `key`, or None if it does not
+    /// exist.
+    pub async fn head(&self, key: &str) -> Result<Option<ObjectMetadata>, String> {
+        let path = format!("{}/{}", self.root, key);
+        match std::fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(ObjectMetadata {
+                size: metadata.len(),
+                etag: None,
+                last_modified: metadata.modified().ok(),
+                content_type: None,
+                metadata: HashMap::new(),
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub async fn copy(&self, src_key: &str, dst_key: &str) -> Result<(), CopyError> {
+        let src_path = format!("{}/{}", self.root, src_key);
+        let dst_path = format!("{}/{}", self.root, dst_key);
+        if let Some(parent) = std::path::Path::new(&dst_path).parent() {
+            std::fs::create_dir_all(parent).map_err(|e| CopyError::LocalError(e.to_string()))?;
+        }
+        match std::fs::copy(&src_path, &dst_path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(CopyError::SourceNotFound(src_key.to_string()))
+            }
+            Err(e) => Err(CopyError::LocalError(e.to_string())),
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        let path = format!("{}/{}", self.root, key);
+        tracing::debug!("Deleting path: {}", path);
+        match std::fs::remove_file(&path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
     pub async fn put_file(&self, key: &str, path: &str) -> Result<(), String> {
         let file = std::fs::read(path);
         match file {
@@ -72,6 +196,9 @@ impl Configurable<StorageConfig> for LocalStorage {
     async fn try_from_config(config: &StorageConfig) -> Result<Self, Box<dyn ChromaError>> {
         match &config {
             StorageConfig::Local(local_config) => {
+                local_config
+                    .validate()
+                    .map_err(|e| Box::new(e) as Box<dyn ChromaError>)?;
                 let storage = LocalStorage::new(&local_config.root);
                 return Ok(storage);
             }
@@ -81,3 +208,139 @@ impl Configurable<StorageConfig> for LocalStorage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::io::Write;
+    use tempfile::{tempdir, NamedTempFile};
+
+    async fn read_all(storage: &LocalStorage, key: &str) -> Vec<u8> {
+        let mut stream = storage.get(key).await.unwrap();
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk.unwrap());
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_put_bytes_get_round_trip() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = LocalStorage::new(tmp_dir.path().to_str().unwrap());
+
+        storage
+            .put_bytes("test", "test data".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(read_all(&storage, "test").await, "test data".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_put_if_absent_keeps_existing_file() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = LocalStorage::new(tmp_dir.path().to_str().unwrap());
+
+        assert!(storage
+            .put_if_absent("test", "first".as_bytes())
+            .await
+            .unwrap());
+        assert!(!storage
+            .put_if_absent("test", "second".as_bytes())
+            .await
+            .unwrap());
+        assert_eq!(read_all(&storage, "test").await, "first".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_get_stream_reports_size() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = LocalStorage::new(tmp_dir.path().to_str().unwrap());
+
+        storage
+            .put_bytes("test", "test data".as_bytes())
+            .await
+            .unwrap();
+        let (size, _) = storage.get_stream("test").await.unwrap();
+        assert_eq!(size, Some("test data".len() as u64));
+    }
+
+    #[tokio::test]
+    async fn test_put_file_get_round_trip() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = LocalStorage::new(tmp_dir.path().to_str().unwrap());
+
+        // Larger than the stream buffer so it is read back in several chunks
+        let contents = (0..20000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(&contents).unwrap();
+
+        storage
+            .put_file("test", temp_file.path().to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(read_all(&storage, "test").await, contents);
+    }
+
+    #[tokio::test]
+    async fn test_nested_key_creates_directories() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = LocalStorage::new(tmp_dir.path().to_str().unwrap());
+
+        storage
+            .put_bytes("a/b/c/test", "test data".as_bytes())
+            .await
+            .unwrap();
+        assert!(tmp_dir.path().join("a/b/c").is_dir());
+        assert_eq!(
+            read_all(&storage, "a/b/c/test").await,
+            "test data".as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_key_is_no_such_key() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = LocalStorage::new(tmp_dir.path().to_str().unwrap());
+
+        let res = storage.get("missing").await;
+        assert!(matches!(res, Err(GetError::NoSuchKey(key)) if key == "missing"));
+        let res = storage.get_range("missing", 0, 1).await;
+        assert!(matches!(res, Err(GetError::NoSuchKey(_))));
+    }
+
+    #[tokio::test]
+    async fn test_copy() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = LocalStorage::new(tmp_dir.path().to_str().unwrap());
+
+        storage.put_bytes("src", "source".as_bytes()).await.unwrap();
+        storage
+            .put_bytes("dst", "existing".as_bytes())
+            .await
+            .unwrap();
+        storage.copy("src", "dst").await.unwrap();
+        storage.copy("src", "a/b/dst").await.unwrap();
+        assert_eq!(read_all(&storage, "dst").await, "source".as_bytes());
+        assert_eq!(read_all(&storage, "a/b/dst").await, "source".as_bytes());
+
+        let res = storage.copy("missing", "dst").await;
+        assert!(matches!(res, Err(CopyError::SourceNotFound(key)) if key == "missing"));
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        let tmp_dir = tempdir().unwrap();
+        let storage = LocalStorage::new(tmp_dir.path().to_str().unwrap());
+
+        storage
+            .put_bytes("test", "test data".as_bytes())
+            .await
+            .unwrap();
+        let metadata = storage.head("test").await.unwrap().unwrap();
+        assert_eq!(metadata.size, "test data".len() as u64);
+        assert!(metadata.last_modified.is_some());
+        assert_eq!(storage.head("missing").await.unwrap(), None);
+    }
+}
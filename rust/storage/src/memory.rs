@@ -0,0 +1,225 @@
+// An in-memory storage backend for tests, available with the test-util
+// feature. Objects live in a map shared by all clones, so tests can hand a
+// clone to the code under test and inspect the other. Every operation can be
+// slowed down by a fixed latency, and failures can be scripted per key:
+// each operation on a key with injected errors fails with the next one
+// instead of running. Injected errors surface as the LocalError variant of
+// the operation's error type.
+
+use super::stream::ByteStreamItem;
+use super::CopyError;
+use super::GetError;
+use super::ObjectMetadata;
+use dashmap::DashMap;
+use futures::{future, stream, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, Default)]
+pub struct InMemoryStorage {
+    objects: Arc<DashMap<String, Arc<Vec<u8>>>>,
+    errors: Arc<DashMap<String, VecDeque<String>>>,
+    latency: Duration,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> InMemoryStorage {
+        InMemoryStorage::default()
+    }
+
+    /// Delays every operation by `latency` before it runs.
+    pub fn with_latency(self, latency: Duration) -> InMemoryStorage {
+        InMemoryStorage { latency, ..self }
+    }
+
+    /// Makes the next operations on `key` fail, one per error, in order.
+    /// Operations on `key` succeed again once the errors run out.
+    pub fn inject_errors(&self, key: &str, errors: impl IntoIterator<Item = String>) {
+        self.errors
+            .entry(key.to_string())
+            .or_default()
+            .extend(errors);
+    }
+
+    // Waits out the latency, then returns the next error injected for `key`.
+    async fn begin(&self, key: &str) -> Result<(), String> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        match self
+            .errors
+            .get_mut(key)
+            .and_then(|mut errors| errors.pop_front())
+        {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn object(&self, key: &str) -> Result<Arc<Vec<u8>>, GetError> {
+        self.objects
+            .get(key)
+            .map(|object| object.clone())
+            .ok_or_else(|| GetError::NoSuchKey(key.to_string()))
+    }
+
+    /// Returns the object at `key` as a single chunk, along with its size.
+    pub async fn get_stream(
+        &self,
+        key: &str,
+    ) -> Result<
+        (
+            Option<u64>,
+            Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>,
+        ),
+        GetError,
+    > {
+        self.begin(key).await.map_err(GetError::LocalError)?;
+        let object = self.object(key)?;
+        Ok((
+            Some(object.len() as u64),
+            Box::new(stream::once(future::ready(Ok(object.to_vec())))),
+        ))
+    }
+
+    pub async fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Arc<Vec<u8>>, GetError> {
+        self.begin(key).await.map_err(GetError::LocalError)?;
+        let object = self.object(key)?;
+        if start > end || (start < end && start >= object.len() as u64) {
+            return Err(GetError::LocalError(format!(
+                "Range not satisfiable: {}..{}",
+                start, end
+            )));
+        }
+        let end = end.min(object.len() as u64);
+        Ok(Arc::new(object[start as usize..end as usize].to_vec()))
+    }
+
+    pub async fn put_bytes(&self, key: &str, bytes: &[u8]) -> Result<(), String> {
+        self.begin(key).await?;
+        self.objects
+            .insert(key.to_string(), Arc::new(bytes.to_vec()));
+        Ok(())
+    }
+
+    /// Writes `bytes` to `key` unless an object already exists there.
+    /// Returns true if the object was written.
+    pub async fn put_if_absent(&self, key: &str, bytes: &[u8]) -> Result<bool, String> {
+        self.begin(key).await?;
+        match self.objects.entry(key.to_string()) {
+            dashmap::mapref::entry::Entry::Occupied(_) => Ok(false),
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(Arc::new(bytes.to_vec()));
+                Ok(true)
+            }
+        }
+    }
+
+    pub async fn put_file(&self, key: &str, path: &str) -> Result<(), String> {
+        let bytes = tokio::fs::read(path).await.map_err(|e| e.to_string())?;
+        self.put_bytes(key, &bytes).await
+    }
+
+    /// Returns the size of the object at `key`, or None if it does not exist.
+    pub async fn head(&self, key: &str) -> Result<Option<ObjectMetadata>, String> {
+        self.begin(key).await?;
+        Ok(self.objects.get(key).map(|object| ObjectMetadata {
+            size: object.len() as u64,
+            etag: None,
+            last_modified: None,
+            content_type: None,
+            metadata: HashMap::new(),
+        }))
+    }
+
+    pub async fn copy(&self, src_key: &str, dst_key: &str) -> Result<(), CopyError> {
+        self.begin(src_key).await.map_err(CopyError::LocalError)?;
+        let object = match self.objects.get(src_key) {
+            Some(object) => object.clone(),
+            None => return Err(CopyError::SourceNotFound(src_key.to_string())),
+        };
+        self.objects.insert(dst_key.to_string(), object);
+        Ok(())
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        self.begin(key).await?;
+        self.objects.remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    async fn read(storage: &InMemoryStorage, key: &str) -> Result<Vec<u8>, GetError> {
+        let (_, stream) = storage.get_stream(key).await?;
+        stream.try_concat().await
+    }
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let storage = InMemoryStorage::new();
+        storage.put_bytes("key", b"test data").await.unwrap();
+        assert_eq!(read(&storage, "key").await.unwrap(), b"test data");
+        assert_eq!(
+            storage.get_range("key", 5, 100).await.unwrap().as_slice(),
+            b"data"
+        );
+        assert_eq!(storage.head("key").await.unwrap().unwrap().size, 9);
+
+        // Clones share the objects.
+        let clone = storage.clone();
+        clone.copy("key", "copy").await.unwrap();
+        assert_eq!(read(&storage, "copy").await.unwrap(), b"test data");
+        assert!(!storage.put_if_absent("copy", b"other").await.unwrap());
+
+        storage.delete("key").await.unwrap();
+        assert!(matches!(
+            read(&storage, "key").await,
+            Err(GetError::NoSuchKey(_))
+        ));
+        assert!(storage.head("key").await.unwrap().is_none());
+        assert!(matches!(
+            storage.copy("key", "copy").await,
+            Err(CopyError::SourceNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_injected_errors() {
+        let storage = InMemoryStorage::new();
+        storage.put_bytes("key", b"test data").await.unwrap();
+        storage.inject_errors("key", ["first".to_string(), "second".to_string()]);
+
+        assert!(matches!(
+            read(&storage, "key").await,
+            Err(GetError::LocalError(message)) if message == "first"
+        ));
+        assert_eq!(
+            storage.put_bytes("key", b"new data").await,
+            Err("second".to_string())
+        );
+        // The failed put did not write, and the errors have run out.
+        assert_eq!(read(&storage, "key").await.unwrap(), b"test data");
+        // Other keys are unaffected.
+        storage.put_bytes("other", b"other data").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_injected_latency() {
+        let storage = InMemoryStorage::new().with_latency(Duration::from_millis(50));
+        let start = std::time::Instant::now();
+        storage.put_bytes("key", b"test data").await.unwrap();
+        read(&storage, "key").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
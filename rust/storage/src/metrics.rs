@@ -0,0 +1,34 @@
+// A hook for recording the latency and size of storage operations. The
+// storage layer does not depend on a metrics backend; callers that want
+// metrics install an implementation of StorageMetrics on the storage handle.
+// When none is installed nothing is timed or recorded.
+
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageOperation {
+    Get,
+    Put,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageOutcome {
+    Success,
+    Error,
+}
+
+pub trait StorageMetrics: Send + Sync {
+    /// Records a completed operation. `latency` covers the whole operation,
+    /// including time spent waiting for admission and reading the body of a
+    /// get. `bytes` is the payload size
+    /// transferred; for a get that failed partway it is the number of bytes
+    /// read before the failure, and for a failed put it is zero since the
+    /// amount that reached the server is not known.
+    fn record(
+        &self,
+        operation: StorageOperation,
+        outcome: StorageOutcome,
+        latency: Duration,
+        bytes: u64,
+    );
+}
@@ -0,0 +1,187 @@
+// A token bucket rate limiter used to bound the number of requests, or of
+// bytes, per second that we issue against a storage backend. Callers that find
+// the bucket empty reserve the tokens they need and sleep until they are
+// refilled, rather than erroring. The bucket is shared across clones so
+// cloning a storage handle does not multiply the effective rate.
+
+use super::clock::{Clock, ClockSleep, TokioClock};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct TokenBucket {
+    capacity: f64,
+    // May go negative, in which case it represents tokens that have been
+    // reserved by waiters but not yet refilled.
+    tokens: f64,
+    tokens_per_second: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.tokens_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter that admits `requests_per_second` requests per
+    /// second, with a burst capacity of one second worth of requests.
+    /// `requests_per_second` must be greater than zero.
+    pub fn new(requests_per_second: u32) -> RateLimiter {
+        RateLimiter::with_clock(requests_per_second, Arc::new(TokioClock))
+    }
+
+    /// Creates a rate limiter as new does, that refills and waits by `clock`.
+    pub fn with_clock(requests_per_second: u32, clock: Arc<dyn Clock>) -> RateLimiter {
+        RateLimiter::with_rate(requests_per_second as u64, clock)
+    }
+
+    /// Creates a rate limiter that admits `tokens_per_second` tokens per
+    /// second, e.g. bytes, with a burst capacity of one second worth of
+    /// tokens. `tokens_per_second` must be greater than zero.
+    pub fn with_rate(tokens_per_second: u64, clock: Arc<dyn Clock>) -> RateLimiter {
+        assert!(tokens_per_second > 0, "rate limit must be positive");
+        let rate = tokens_per_second as f64;
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                capacity: rate,
+                tokens: rate,
+                tokens_per_second: rate,
+                last_refill: clock.now(),
+            })),
+            clock,
+        }
+    }
+
+    /// Waits until a token is available and consumes it.
+    pub async fn acquire(&self) {
+        self.acquire_many(1).await;
+    }
+
+    /// Waits until `tokens` tokens are available and consumes them. More
+    /// tokens than the burst capacity are admitted once the bucket has
+    /// refilled enough to pay for them.
+    pub async fn acquire_many(&self, tokens: u64) {
+        if let Some(wait) = self.reserve(tokens) {
+            wait.await;
+        }
+    }
+
+    // Consumes `tokens` tokens, returning the sleep until they are refilled
+    // if the bucket did not hold them.
+    pub(crate) fn reserve(&self, tokens: u64) -> Option<ClockSleep> {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+            bucket.refill(self.clock.now());
+            bucket.tokens -= tokens as f64;
+            if bucket.tokens >= 0.0 {
+                return None;
+            }
+            Duration::from_secs_f64(-bucket.tokens / bucket.tokens_per_second)
+        };
+        Some(self.clock.sleep(wait))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::future::Future;
+    use std::pin::Pin;
+
+    // Polls each of `acquires` once, in order, appending the index of each
+    // one that completes to `granted`. Returns those still waiting.
+    async fn poll_in_order<F: Future<Output = ()>>(
+        acquires: Vec<(usize, Pin<Box<F>>)>,
+        granted: &mut Vec<usize>,
+    ) -> Vec<(usize, Pin<Box<F>>)> {
+        let mut waiting = Vec::new();
+        for (i, mut acquire) in acquires {
+            if futures::poll!(&mut acquire).is_ready() {
+                granted.push(i);
+            } else {
+                waiting.push((i, acquire));
+            }
+        }
+        waiting
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_spread_by_rate() {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::with_clock(50, clock.clone());
+        let acquires = (0..100).map(|i| (i, Box::pin(limiter.acquire()))).collect();
+
+        // The first second worth of requests is admitted as a burst.
+        let mut granted = Vec::new();
+        let mut waiting = poll_in_order(acquires, &mut granted).await;
+        assert_eq!(granted, (0..50).collect::<Vec<_>>());
+
+        // Each of the others reserves the next token to be refilled, so they
+        // wait 20ms longer each...
+        let waits = (1..=50)
+            .map(|i| Duration::from_millis(20 * i))
+            .collect::<Vec<_>>();
+        assert_eq!(clock.sleeps(), waits);
+
+        // ...and are admitted one at a time, in the order they arrived.
+        for admitted in 51..=100 {
+            clock.advance(Duration::from_millis(20));
+            waiting = poll_in_order(waiting, &mut granted).await;
+            assert_eq!(granted, (0..admitted).collect::<Vec<_>>());
+        }
+        assert!(waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clones_share_the_bucket() {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::with_clock(10, clock.clone());
+        let clone = limiter.clone();
+        for _ in 0..10 {
+            assert!(futures::poll!(Box::pin(limiter.acquire())).is_ready());
+        }
+
+        // The clone draws from the bucket the burst above emptied.
+        let mut acquire = Box::pin(clone.acquire());
+        assert!(futures::poll!(&mut acquire).is_pending());
+        clock.advance(Duration::from_millis(100));
+        assert!(futures::poll!(&mut acquire).is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_tokens_refill_with_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::with_clock(10, clock.clone());
+        for _ in 0..10 {
+            assert!(futures::poll!(Box::pin(limiter.acquire())).is_ready());
+        }
+
+        // The bucket is empty, so the next request waits for a token, which
+        // takes a tenth of a second to refill.
+        let mut acquire = Box::pin(limiter.acquire());
+        assert!(futures::poll!(&mut acquire).is_pending());
+        clock.advance(Duration::from_millis(50));
+        assert!(futures::poll!(&mut acquire).is_pending());
+        clock.advance(Duration::from_millis(50));
+        assert!(futures::poll!(&mut acquire).is_ready());
+        assert_eq!(clock.sleeps(), vec![Duration::from_millis(100)]);
+
+        // Time that passes refills the bucket, up to one second worth.
+        clock.advance(Duration::from_secs(5));
+        for _ in 0..10 {
+            assert!(futures::poll!(Box::pin(limiter.acquire())).is_ready());
+        }
+        assert!(futures::poll!(Box::pin(limiter.acquire())).is_pending());
+    }
+}
@@ -0,0 +1,83 @@
+// Which failed attempts get retried. The S3 client retries throttling, server
+// errors and attempts that got no response, and returns every other error at
+// once. Deployments that disagree can say so with their own RetryClassifier,
+// for example to retry a NoSuchKey while a newly written object may not be
+// visible yet. Its decisions replace the client's; the retry config still
+// bounds the attempts, and the retry budget, if any, still spends on them.
+
+use super::s3::THROTTLING_ERROR_CODES;
+
+/// What a classifier is told about a failed attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailedAttempt<'a> {
+    /// The HTTP status of the response, or None if the attempt got none.
+    pub status: Option<u16>,
+    /// The S3 error code in the response body, if there was one. Responses to
+    /// HEAD requests carry no body, so they only have a status.
+    pub code: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    Retryable,
+    Terminal,
+}
+
+pub trait RetryClassifier: Send + Sync + std::fmt::Debug {
+    fn classify(&self, attempt: &FailedAttempt<'_>) -> RetryDecision;
+}
+
+/// Retries what the S3 client retries on its own: attempts that got no
+/// response, throttling, server errors and request timeouts. Custom
+/// classifiers can defer to it for the errors they have no opinion on.
+#[derive(Debug, Default)]
+pub struct DefaultRetryClassifier;
+
+impl RetryClassifier for DefaultRetryClassifier {
+    fn classify(&self, attempt: &FailedAttempt<'_>) -> RetryDecision {
+        let status = match attempt.status {
+            Some(status) => status,
+            None => return RetryDecision::Retryable,
+        };
+        let retryable_code = attempt
+            .code
+            .is_some_and(|code| code == "RequestTimeout" || THROTTLING_ERROR_CODES.contains(&code));
+        if status == 429 || (500..600).contains(&status) || retryable_code {
+            RetryDecision::Retryable
+        } else {
+            RetryDecision::Terminal
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(status: Option<u16>, code: Option<&str>) -> RetryDecision {
+        DefaultRetryClassifier.classify(&FailedAttempt { status, code })
+    }
+
+    #[test]
+    fn test_default_classifier() {
+        assert_eq!(classify(None, None), RetryDecision::Retryable);
+        assert_eq!(classify(Some(429), None), RetryDecision::Retryable);
+        assert_eq!(
+            classify(Some(500), Some("InternalError")),
+            RetryDecision::Retryable
+        );
+        assert_eq!(
+            classify(Some(400), Some("RequestTimeout")),
+            RetryDecision::Retryable
+        );
+        assert_eq!(
+            classify(Some(404), Some("NoSuchKey")),
+            RetryDecision::Terminal
+        );
+        assert_eq!(
+            classify(Some(403), Some("AccessDenied")),
+            RetryDecision::Terminal
+        );
+        assert_eq!(classify(Some(404), None), RetryDecision::Terminal);
+    }
+}
@@ -0,0 +1,99 @@
+// A budget for the retries of all requests to a storage backend. Each
+// request retries transient errors on its own, so during an incident every
+// request in flight multiplies the load on a backend that is already
+// struggling. The budget bounds the retries across all of them: every retry
+// spends from it, and once it is empty failures are returned without being
+// retried. Successful requests refill it, a fraction of a retry at a time, so
+// that retries are bounded to a share of the requests that succeed.
+
+use std::sync::Mutex;
+
+// The number of successful requests that refill the budget by one retry.
+const SUCCESSES_PER_RETRY: u32 = 5;
+
+pub struct RetryBudget {
+    // Both counted in units of 1 / SUCCESSES_PER_RETRY of a retry.
+    capacity: u32,
+    tokens: Mutex<u32>,
+}
+
+impl std::fmt::Debug for RetryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryBudget")
+            .field("remaining", &self.remaining())
+            .finish()
+    }
+}
+
+impl RetryBudget {
+    /// Creates a full budget of `retries` retries. `retries` must be greater
+    /// than zero.
+    pub fn new(retries: u32) -> RetryBudget {
+        assert!(retries > 0, "retry budget must be positive");
+        let capacity = retries.saturating_mul(SUCCESSES_PER_RETRY);
+        RetryBudget {
+            capacity,
+            tokens: Mutex::new(capacity),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, u32> {
+        self.tokens.lock().expect("retry budget lock poisoned")
+    }
+
+    /// The number of retries left in the budget.
+    pub fn remaining(&self) -> u32 {
+        *self.lock() / SUCCESSES_PER_RETRY
+    }
+
+    /// Spends one retry from the budget. Returns false, leaving the budget
+    /// as is, if there is not a whole retry left in it.
+    pub fn try_spend(&self) -> bool {
+        let mut tokens = self.lock();
+        match tokens.checked_sub(SUCCESSES_PER_RETRY) {
+            Some(remaining) => {
+                *tokens = remaining;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Refills the budget for a successful request.
+    pub fn record_success(&self) {
+        let mut tokens = self.lock();
+        *tokens = (*tokens + 1).min(self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spending_exhausts_budget() {
+        let budget = RetryBudget::new(2);
+        assert!(budget.try_spend());
+        assert!(budget.try_spend());
+        assert_eq!(budget.remaining(), 0);
+        assert!(!budget.try_spend());
+    }
+
+    #[test]
+    fn test_successes_refill_budget() {
+        let budget = RetryBudget::new(1);
+        assert!(budget.try_spend());
+        for _ in 0..SUCCESSES_PER_RETRY - 1 {
+            budget.record_success();
+        }
+        assert!(!budget.try_spend());
+        budget.record_success();
+        assert!(budget.try_spend());
+
+        // The budget never holds more than its capacity.
+        for _ in 0..10 * SUCCESSES_PER_RETRY {
+            budget.record_success();
+        }
+        assert_eq!(budget.remaining(), 1);
+    }
+}
@@ -8,76 +8,1024 @@
 // Once we move to our own implementation of hnswlib we can support
 // streaming from s3.
 
+use super::admission::{AdaptiveConcurrency, RequestOutcome};
+use super::cache::ObjectCache;
+use super::circuit_breaker::{CircuitBreaker, CircuitState};
+use super::clock::{Clock, SdkSleep, TokioClock};
+use super::config::CompressionCodec;
+use super::config::KeyCase;
+use super::config::S3CredentialsConfig;
+use super::config::ServerSideEncryption;
 use super::config::StorageConfig;
+use super::metrics::{StorageMetrics, StorageOperation, StorageOutcome};
+use super::rate_limit::RateLimiter;
+use super::retry::{FailedAttempt, RetryClassifier, RetryDecision};
+use super::retry_budget::RetryBudget;
+use super::shutdown::RequestTracker;
+use super::stats::StorageStats;
 use super::stream::ByteStreamItem;
+use super::stream::ReadAheadStream;
 use super::stream::S3ByteStream;
+use super::GetError;
+use super::ObjectMetadata;
 use async_trait::async_trait;
 use aws_config::retry::RetryConfig;
-use aws_config::timeout::TimeoutConfigBuilder;
+use aws_config::timeout::{TimeoutConfig, TimeoutConfigBuilder};
 use aws_sdk_s3;
+use aws_sdk_s3::config::http::{HttpRequest, HttpResponse};
+use aws_sdk_s3::config::interceptors::{
+    BeforeTransmitInterceptorContextMut, FinalizerInterceptorContextRef, InterceptorContext,
+};
+use aws_sdk_s3::config::retry::{ClassifyRetry, RetryAction};
+use aws_sdk_s3::config::{
+    AppName, ConfigBag, HttpClient, Intercept, RuntimeComponents, SharedHttpClient,
+};
+use aws_sdk_s3::error::BoxError;
+use aws_sdk_s3::error::ProvideErrorMetadata;
 use aws_sdk_s3::error::SdkError;
 use aws_sdk_s3::operation::create_bucket::CreateBucketError;
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::operation::{RequestId, RequestIdExt};
+use aws_sdk_s3::presigning::PresigningConfig;
 use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::types::CompletedMultipartUpload;
 use aws_sdk_s3::types::CompletedPart;
+use aws_sdk_s3::types::Delete;
+use aws_sdk_s3::types::ObjectIdentifier;
+use aws_sdk_s3::types::{
+    CsvInput, CsvOutput, ExpressionType, FileHeaderInfo, InputSerialization, JsonInput, JsonOutput,
+    JsonType, OutputSerialization, ParquetInput, SelectObjectContentEventStream,
+};
+use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
+use aws_smithy_runtime_api::client::retries::classifiers::RetryClassifierPriority;
+use aws_smithy_runtime_api::client::retries::RequestAttempts;
+use aws_smithy_types::body::SdkBody;
 use aws_smithy_types::byte_stream::Length;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use chroma_config::Configurable;
 use chroma_error::ChromaError;
 use chroma_error::ErrorCodes;
+use futures::future;
 use futures::future::BoxFuture;
+use futures::stream;
 use futures::FutureExt;
 use futures::Stream;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::clone::Clone;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::ops::Range;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::{Instrument, Span};
 
 #[derive(Clone)]
 pub struct S3Storage {
     bucket: String,
     client: aws_sdk_s3::Client,
-    upload_part_size_bytes: usize,
+    // The part size of multipart uploads. If None, it is picked from the
+    // size of each upload.
+    upload_part_size_bytes: Option<usize>,
+    multipart_threshold_bytes: usize,
+    upload_concurrency: usize,
+    rate_limiter: Option<RateLimiter>,
+    // Paces the bytes read by gets and written by puts, counted together.
+    bandwidth_limiter: Option<RateLimiter>,
+    request_semaphore: Option<Arc<Semaphore>>,
+    adaptive_concurrency: Option<Arc<AdaptiveConcurrency>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    verify_checksums: bool,
+    // Whether gets check the length of the body against the Content-Length
+    // of the response.
+    validate_content_length: bool,
+    // Whether puts of an empty payload fail with EmptyPayload instead of
+    // creating an empty object.
+    reject_empty_puts: bool,
+    // Whether gets decompress objects served with Content-Encoding: gzip.
+    decode_content_encoding: bool,
+    operation_timeout: Option<Duration>,
+    cache: Option<ObjectCache>,
+    metrics: Option<Arc<dyn StorageMetrics>>,
+    compression: Option<CompressionCodec>,
+    sse: Option<ServerSideEncryption>,
+    max_single_copy_bytes: u64,
+    read_ahead_chunks: Option<usize>,
+    max_object_size_bytes: Option<usize>,
+    // Objects smaller than this are read in full by get_stream and yielded
+    // as a single chunk.
+    small_object_threshold_bytes: Option<usize>,
+    fallback_bucket: Option<String>,
+    // Ends in a single slash, and is never empty.
+    key_prefix: Option<String>,
+    key_case: KeyCase,
+    hedge_after: Option<Duration>,
+    requester_pays: bool,
+    requests: Arc<RequestTracker>,
+    exists_list_threshold: usize,
+    parallel_download_threshold_bytes: usize,
+    download_part_size_bytes: usize,
+    download_concurrency: usize,
+}
+
+// Aborts a multipart upload in the background when dropped, unless disarmed.
+struct AbortOnDrop {
+    upload: Option<(S3Storage, String, String)>,
+}
+
+impl AbortOnDrop {
+    fn disarm(mut self) {
+        self.upload = None;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        let (storage, key, upload_id) = match self.upload.take() {
+            Some(upload) => upload,
+            None => return,
+        };
+        tracing::warn!(
+            "multipart upload {} of {} dropped before completing, aborting it",
+            upload_id,
+            key
+        );
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    storage.abort_multipart_upload(&key, &upload_id).await;
+                });
+            }
+            Err(_) => tracing::error!(
+                "no runtime to abort multipart upload {} of {} on",
+                upload_id,
+                key
+            ),
+        }
+    }
+}
+
+// A GET response whose body has not been read yet.
+struct S3Object {
+    stream: S3ByteStream,
+    content_length: Option<i64>,
+    e_tag: Option<String>,
+    compression: Option<CompressionCodec>,
+    max_size_bytes: Option<usize>,
+}
+
+impl S3Object {
+    // Reads the body in full, decompressing it if it was stored compressed.
+    // Stops reading as soon as the body grows past max_size_bytes, so at most
+    // one chunk more than the limit is ever held.
+    async fn read(mut self) -> Result<Vec<u8>, S3GetError> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = self.stream.next().await {
+            let chunk = chunk.map_err(|e| match e {
+                GetError::S3Error(e) => e,
+                e => S3GetError::ByteStreamError(e.to_string()),
+            })?;
+            bytes.extend_from_slice(&chunk);
+            if let Some(limit) = self.max_size_bytes {
+                if bytes.len() > limit {
+                    return Err(S3GetError::ObjectTooLarge {
+                        limit,
+                        actual: bytes.len(),
+                    });
+                }
+            }
+        }
+        match self.compression {
+            Some(codec) => codec
+                .decompress(&bytes)
+                .map_err(|e| S3GetError::DecompressionError(e.to_string())),
+            None => Ok(bytes),
+        }
+    }
+}
+
+// The object metadata key under which put_bytes stores the hex encoded
+// SHA-256 of the payload.
+const CHECKSUM_METADATA_KEY: &str = "sha256";
+// Object metadata key recording the codec the stored payload is compressed
+// with. Objects without it are stored uncompressed.
+const COMPRESSION_METADATA_KEY: &str = "compression";
+// The number of keys a prefetch fetches at once.
+const PREFETCH_PARALLELISM: usize = 4;
+// The number of chunks a get stream reads ahead of its consumer when
+// read_ahead is set without read_ahead_chunks.
+const DEFAULT_READ_AHEAD_CHUNKS: usize = 4;
+// The part size of multipart uploads of unknown size when none is
+// configured.
+const DEFAULT_UPLOAD_PART_SIZE_BYTES: usize = 8 * 1024 * 1024;
+// The smallest part S3 accepts, other than the last part of an upload.
+pub(crate) const MIN_UPLOAD_PART_SIZE_BYTES: usize = 5 * 1024 * 1024;
+// The most parts S3 accepts in a multipart upload.
+const MAX_UPLOAD_PARTS: usize = 10_000;
+// The size of the range gets of get_parallel when none is configured.
+const DEFAULT_DOWNLOAD_PART_SIZE_BYTES: usize = 8 * 1024 * 1024;
+// The number of range gets get_parallel issues at once when none is
+// configured.
+const DEFAULT_DOWNLOAD_CONCURRENCY: usize = 4;
+// The number of keys at or above which exists_many lists their common prefix
+// when none is configured.
+const DEFAULT_EXISTS_LIST_THRESHOLD: usize = 10;
+// How many times more keys than were asked about exists_many lists before it
+// gives up on the listing as too sparse.
+const EXISTS_LIST_MAX_DENSITY: usize = 10;
+// The number of HEADs exists_many issues at once.
+const EXISTS_HEAD_PARALLELISM: usize = 4;
+// The most keys S3 deletes in a single DeleteObjects request.
+const DELETE_OBJECTS_MAX_KEYS: usize = 1000;
+// How long the circuit breaker stays open when no cooldown is configured.
+const DEFAULT_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+// The Content-Type of objects put without one.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+// The largest object S3 can copy in a single CopyObject request.
+const MAX_SINGLE_COPY_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+// Characters left unescaped in the key of a copy source.
+const COPY_SOURCE_KEY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+// Options applied to every request of an upload, whether it is sent as a
+// single PUT or as a multipart upload.
+#[derive(Default)]
+struct PutOptions {
+    metadata: HashMap<String, String>,
+    // Sends If-None-Match: * so that the put fails rather than overwrite an
+    // existing object. For a multipart upload the condition is checked when
+    // the upload is completed.
+    if_none_match: bool,
+    // Sends If-Match with this ETag so that the put fails if the object has
+    // been overwritten since it had the ETag, checked as if_none_match is.
+    if_match: Option<String>,
+    // The URL-encoded tag set of the object, as built by encode_tags.
+    tagging: Option<String>,
+    // The Content-Type of the object. DEFAULT_CONTENT_TYPE if unset.
+    content_type: Option<String>,
+    // The uncompressed payload, to be cached once the put succeeds. Only set
+    // for payloads small enough to be cached.
+    write_through: Option<Arc<Vec<u8>>>,
+}
+
+impl PutOptions {
+    fn content_type(&self) -> &str {
+        self.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE)
+    }
+}
+
+/// The ids S3 assigns a request, from its x-amz-request-id and x-amz-id-2
+/// response headers. AWS support needs both to look into a failed request.
+/// Displayed as a parenthesized suffix of the error message, or as nothing if
+/// S3 sent neither.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestIds {
+    pub request_id: Option<String>,
+    pub extended_request_id: Option<String>,
+}
+
+impl RequestIds {
+    fn of<E>(err: &SdkError<E, HttpResponse>) -> RequestIds {
+        RequestIds {
+            request_id: err.request_id().map(str::to_string),
+            extended_request_id: err.extended_request_id().map(str::to_string),
+        }
+    }
+}
+
+impl std::fmt::Display for RequestIds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.request_id.is_none() && self.extended_request_id.is_none() {
+            return Ok(());
+        }
+        write!(
+            f,
+            " (request id: {}, extended request id: {})",
+            self.request_id.as_deref().unwrap_or("unknown"),
+            self.extended_request_id.as_deref().unwrap_or("unknown")
+        )
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum S3PutError {
     #[error("S3 PUT error: {0}")]
     S3PutError(String),
+    // An error response from S3.
+    #[error("S3 PUT error: {message}{request_ids}")]
+    ServiceError {
+        message: String,
+        request_ids: RequestIds,
+    },
     #[error("S3 Dispatch failure error")]
     S3DispatchFailure,
+    #[error("S3 PUT timed out: {0}")]
+    Timeout(String),
+    #[error("Compression error: {0}")]
+    CompressionError(String),
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("Invalid tag: {0}")]
+    InvalidTag(String),
+    #[error("Invalid metadata: {0}")]
+    InvalidMetadata(String),
+    #[error("S3 circuit breaker is open")]
+    CircuitOpen,
+    #[error("S3 storage is shutting down")]
+    ShuttingDown,
+    #[error("Empty payload: {0}")]
+    EmptyPayload(String),
+    // Another write got to the object first.
+    #[error("Conflicting write: {0}")]
+    Conflict(String),
+    // A sized upload whose stream did not yield the declared number of bytes.
+    // More than declared is caught at the first chunk past it.
+    #[error("Stream length mismatch: declared {expected} bytes, stream yielded {actual}")]
+    ContentLengthMismatch { expected: u64, actual: u64 },
 }
 
 impl ChromaError for S3PutError {
     fn code(&self) -> ErrorCodes {
-        ErrorCodes::Internal
+        match self {
+            S3PutError::S3PutError(_) => ErrorCodes::Internal,
+            S3PutError::ServiceError { .. } => ErrorCodes::Internal,
+            S3PutError::S3DispatchFailure => ErrorCodes::Unavailable,
+            S3PutError::Timeout(_) => ErrorCodes::DeadlineExceeded,
+            S3PutError::CompressionError(_) => ErrorCodes::Internal,
+            S3PutError::PreconditionFailed(_) => ErrorCodes::FailedPrecondition,
+            S3PutError::InvalidTag(_) => ErrorCodes::InvalidArgument,
+            S3PutError::InvalidMetadata(_) => ErrorCodes::InvalidArgument,
+            S3PutError::CircuitOpen => ErrorCodes::Unavailable,
+            S3PutError::ShuttingDown => ErrorCodes::Unavailable,
+            S3PutError::EmptyPayload(_) => ErrorCodes::InvalidArgument,
+            S3PutError::Conflict(_) => ErrorCodes::Aborted,
+            S3PutError::ContentLengthMismatch { .. } => ErrorCodes::InvalidArgument,
+        }
     }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum S3GetError {
     #[error("S3 GET error: {0}")]
     S3GetError(String),
+    // An error response from S3.
+    #[error("S3 GET error: {message}{request_ids}")]
+    ServiceError {
+        message: String,
+        request_ids: RequestIds,
+    },
     #[error("No such key: {0}")]
     NoSuchKey(String),
+    // S3 refused the get with a 403. Note that S3 also answers a get of a
+    // missing key with a 403 rather than a 404 when the caller may not list
+    // the bucket, so this may hide a miss, but never the other way around.
+    #[error("Access denied: {message}{request_ids}")]
+    AccessDenied {
+        message: String,
+        request_ids: RequestIds,
+    },
     #[error("ByteStream error: {0}")]
     ByteStreamError(String),
+    #[error("Range not satisfiable: {0}")]
+    RangeNotSatisfiable(String),
+    #[error("Checksum mismatch: {0}")]
+    ChecksumMismatch(String),
+    #[error("S3 GET timed out: {0}")]
+    Timeout(String),
+    #[error("Decompression error: {0}")]
+    DecompressionError(String),
+    // `actual` is the size S3 reported for the object, or the number of bytes
+    // read before giving up if it did not report one.
+    #[error("Object of at least {actual} bytes exceeds the limit of {limit} bytes")]
+    ObjectTooLarge { limit: usize, actual: usize },
+    #[error("S3 circuit breaker is open")]
+    CircuitOpen,
+    #[error("S3 storage is shutting down")]
+    ShuttingDown,
+    #[error("Error writing object to file: {0}")]
+    FileError(String),
+    #[error("Object changed while it was read: {0}")]
+    ObjectChanged(String),
+    #[error("S3 GET cancelled: {0}")]
+    Cancelled(String),
+    #[error("Truncated response: expected {expected} bytes, got {actual}")]
+    TruncatedResponse { expected: u64, actual: u64 },
+    // The object still has the ETag a conditional get was made with.
+    // get_if_none_match returns it as Ok(None).
+    #[error("Object not modified")]
+    NotModified,
 }
 
 impl ChromaError for S3GetError {
     fn code(&self) -> ErrorCodes {
-        ErrorCodes::Internal
+        match self {
+            S3GetError::S3GetError(_) => ErrorCodes::Internal,
+            S3GetError::ServiceError { .. } => ErrorCodes::Internal,
+            S3GetError::NoSuchKey(_) => ErrorCodes::NotFound,
+            S3GetError::AccessDenied { .. } => ErrorCodes::PermissionDenied,
+            S3GetError::ByteStreamError(_) => ErrorCodes::Internal,
+            S3GetError::RangeNotSatisfiable(_) => ErrorCodes::OutOfRange,
+            // The stored bytes do not match what was written.
+            S3GetError::ChecksumMismatch(_) => ErrorCodes::DataLoss,
+            S3GetError::Timeout(_) => ErrorCodes::DeadlineExceeded,
+            S3GetError::DecompressionError(_) => ErrorCodes::DataLoss,
+            S3GetError::ObjectTooLarge { .. } => ErrorCodes::ResourceExhausted,
+            S3GetError::CircuitOpen => ErrorCodes::Unavailable,
+            S3GetError::ShuttingDown => ErrorCodes::Unavailable,
+            S3GetError::FileError(_) => ErrorCodes::Internal,
+            S3GetError::ObjectChanged(_) => ErrorCodes::Aborted,
+            S3GetError::Cancelled(_) => ErrorCodes::Cancelled,
+            // Most likely a connection reset partway through the body, which
+            // a retry may not run into.
+            S3GetError::TruncatedResponse { .. } => ErrorCodes::Unavailable,
+            S3GetError::NotModified => ErrorCodes::FailedPrecondition,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum S3DeleteError {
+    #[error("S3 DELETE error ({}): {message}", code.as_deref().unwrap_or("unknown"))]
+    S3DeleteError {
+        // The S3 error code, e.g. "AccessDenied", when the service returned one.
+        code: Option<String>,
+        message: String,
+    },
+    #[error("S3 storage is shutting down")]
+    ShuttingDown,
+}
+
+impl ChromaError for S3DeleteError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            S3DeleteError::S3DeleteError { .. } => ErrorCodes::Internal,
+            S3DeleteError::ShuttingDown => ErrorCodes::Unavailable,
+        }
+    }
+}
+
+/// The outcome of delete_many for each of its keys.
+#[derive(Debug, Default)]
+pub struct DeleteManyReport {
+    pub deleted: Vec<String>,
+    pub failed: Vec<(String, S3DeleteError)>,
+}
+
+/// Cancels the get returned along with it by get_cancellable, when cancel is
+/// called or when it is dropped.
+#[derive(Debug)]
+pub struct CancelHandle(future::AbortHandle);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.abort();
+    }
+}
+
+impl Drop for CancelHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// How long health waits for its probe before reporting storage unavailable.
+const HEALTH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether storage is usable, as reported by health.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StorageHealth {
+    Healthy,
+    /// The bucket is reachable, but requests have been failing enough to
+    /// open the circuit breaker.
+    Degraded {
+        reason: String,
+    },
+    /// The bucket is not reachable.
+    Unavailable {
+        reason: String,
+    },
+}
+
+/// A version of an object, as listed by list_versions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectVersion {
+    pub version_id: String,
+    pub size: u64,
+    pub is_latest: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum S3CopyError {
+    #[error("Copy source not found: {0}")]
+    SourceNotFound(String),
+    #[error("S3 COPY error: {0}")]
+    S3CopyError(String),
+    #[error("S3 storage is shutting down")]
+    ShuttingDown,
+}
+
+impl ChromaError for S3CopyError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            S3CopyError::SourceNotFound(_) => ErrorCodes::NotFound,
+            S3CopyError::S3CopyError(_) => ErrorCodes::Internal,
+            S3CopyError::ShuttingDown => ErrorCodes::Unavailable,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum S3RenameError {
+    #[error("Rename source not found: {0}")]
+    SourceNotFound(String),
+    // Nothing was renamed.
+    #[error("S3 RENAME error: copy failed: {0}")]
+    CopyFailed(S3CopyError),
+    // The copy was rolled back, so only the source remains.
+    #[error("S3 RENAME error: source delete failed and was rolled back: {0}")]
+    SourceDeleteFailed(S3DeleteError),
+    // Both the source and the copy remain.
+    #[error(
+        "S3 RENAME error: source delete failed ({delete_error}) and so did the rollback ({rollback_error}), {dst_key} duplicates {src_key}"
+    )]
+    RollbackFailed {
+        src_key: String,
+        dst_key: String,
+        delete_error: S3DeleteError,
+        rollback_error: S3DeleteError,
+    },
+    #[error("S3 storage is shutting down")]
+    ShuttingDown,
+}
+
+impl ChromaError for S3RenameError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            S3RenameError::SourceNotFound(_) => ErrorCodes::NotFound,
+            S3RenameError::CopyFailed(e) => e.code(),
+            S3RenameError::SourceDeleteFailed(e) => e.code(),
+            S3RenameError::RollbackFailed { .. } => ErrorCodes::Internal,
+            S3RenameError::ShuttingDown => ErrorCodes::Unavailable,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum S3HeadError {
+    #[error("S3 HEAD error: {0}")]
+    S3HeadError(String),
+    #[error("S3 storage is shutting down")]
+    ShuttingDown,
+}
+
+impl ChromaError for S3HeadError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            S3HeadError::S3HeadError(_) => ErrorCodes::Internal,
+            S3HeadError::ShuttingDown => ErrorCodes::Unavailable,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum S3PresignError {
+    #[error("Presigned URL expiry of {0:?} exceeds the S3 maximum of 7 days")]
+    ExpiryTooLong(Duration),
+    #[error("S3 presign error: {0}")]
+    S3PresignError(String),
+}
+
+impl ChromaError for S3PresignError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            S3PresignError::ExpiryTooLong(_) => ErrorCodes::InvalidArgument,
+            S3PresignError::S3PresignError(_) => ErrorCodes::Internal,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum S3ListError {
+    #[error("S3 LIST error: {0}")]
+    S3ListError(String),
+    #[error("S3 storage is shutting down")]
+    ShuttingDown,
+}
+
+impl ChromaError for S3ListError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            S3ListError::S3ListError(_) => ErrorCodes::Internal,
+            S3ListError::ShuttingDown => ErrorCodes::Unavailable,
+        }
+    }
+}
+
+/// The format of the objects that select queries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectFormat {
+    /// CSV with a header row, so that expressions can refer to columns by
+    /// name. Records are returned as CSV.
+    Csv,
+    /// One JSON document per line. Records are returned as JSON lines.
+    Json,
+    /// Records are returned as JSON lines.
+    Parquet,
+}
+
+impl SelectFormat {
+    fn serialization(self) -> (InputSerialization, OutputSerialization) {
+        let input = InputSerialization::builder();
+        let output = OutputSerialization::builder();
+        match self {
+            SelectFormat::Csv => (
+                input
+                    .csv(
+                        CsvInput::builder()
+                            .file_header_info(FileHeaderInfo::Use)
+                            .build(),
+                    )
+                    .build(),
+                output.csv(CsvOutput::builder().build()).build(),
+            ),
+            SelectFormat::Json => (
+                input
+                    .json(JsonInput::builder().r#type(JsonType::Lines).build())
+                    .build(),
+                output.json(JsonOutput::builder().build()).build(),
+            ),
+            SelectFormat::Parquet => (
+                input.parquet(ParquetInput::builder().build()).build(),
+                output.json(JsonOutput::builder().build()).build(),
+            ),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum S3SelectError {
+    #[error("No such key: {0}")]
+    NoSuchKey(String),
+    #[error("Invalid S3 Select expression: {0}")]
+    InvalidExpression(String),
+    #[error("S3 SELECT error: {0}")]
+    S3SelectError(String),
+    #[error("S3 storage is shutting down")]
+    ShuttingDown,
+}
+
+impl ChromaError for S3SelectError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            S3SelectError::NoSuchKey(_) => ErrorCodes::NotFound,
+            S3SelectError::InvalidExpression(_) => ErrorCodes::InvalidArgument,
+            S3SelectError::S3SelectError(_) => ErrorCodes::Internal,
+            S3SelectError::ShuttingDown => ErrorCodes::Unavailable,
+        }
+    }
+}
+
+// Whether S3 rejected a select for its expression, rather than for the object
+// or the request. S3 names the errors of parsing an expression Parse* and
+// Lexer*.
+fn is_expression_error(code: &str) -> bool {
+    code.starts_with("Parse")
+        || code.starts_with("Lexer")
+        || matches!(
+            code,
+            "InvalidExpressionType"
+                | "ExpressionTooLong"
+                | "InvalidColumnIndex"
+                | "InvalidTableAlias"
+                | "UnsupportedSqlOperation"
+                | "UnsupportedSyntax"
+        )
+}
+
+// Maps the error of a select, which may come from the request or from an
+// error event partway through the response.
+fn select_error<E, R>(key: &str, err: SdkError<E, R>) -> S3SelectError
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    tracing::error!("error selecting from {}: {}", key, err);
+    match err.code() {
+        Some("NoSuchKey") => S3SelectError::NoSuchKey(key.to_string()),
+        Some(code) if is_expression_error(code) => S3SelectError::InvalidExpression(format!(
+            "{}: {}",
+            code,
+            err.message().unwrap_or("no message")
+        )),
+        _ => S3SelectError::S3SelectError(err.to_string()),
     }
 }
 
+// Pagination state for list_prefix.
+enum ListState {
+    Start,
+    Continue(String),
+    Done,
+}
+
 impl S3Storage {
-    fn new(bucket: &str, client: aws_sdk_s3::Client, upload_part_size_bytes: usize) -> S3Storage {
+    pub(crate) fn new(
+        bucket: &str,
+        client: aws_sdk_s3::Client,
+        upload_part_size_bytes: usize,
+    ) -> S3Storage {
         return S3Storage {
             bucket: bucket.to_string(),
             client,
-            upload_part_size_bytes,
+            upload_part_size_bytes: Some(upload_part_size_bytes),
+            multipart_threshold_bytes: upload_part_size_bytes,
+            upload_concurrency: 1,
+            rate_limiter: None,
+            bandwidth_limiter: None,
+            request_semaphore: None,
+            adaptive_concurrency: None,
+            circuit_breaker: None,
+            verify_checksums: false,
+            validate_content_length: false,
+            reject_empty_puts: false,
+            decode_content_encoding: false,
+            operation_timeout: None,
+            cache: None,
+            metrics: None,
+            compression: None,
+            sse: None,
+            max_single_copy_bytes: MAX_SINGLE_COPY_BYTES,
+            read_ahead_chunks: None,
+            max_object_size_bytes: None,
+            small_object_threshold_bytes: None,
+            fallback_bucket: None,
+            key_prefix: None,
+            key_case: KeyCase::None,
+            hedge_after: None,
+            requester_pays: false,
+            requests: Arc::new(RequestTracker::default()),
+            exists_list_threshold: DEFAULT_EXISTS_LIST_THRESHOLD,
+            parallel_download_threshold_bytes: DEFAULT_DOWNLOAD_PART_SIZE_BYTES,
+            download_part_size_bytes: DEFAULT_DOWNLOAD_PART_SIZE_BYTES,
+            download_concurrency: DEFAULT_DOWNLOAD_CONCURRENCY,
+        };
+    }
+
+    // The key in the bucket of the object callers know as `key`.
+    fn object_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        let key = self.key_case.apply(key);
+        match &self.key_prefix {
+            Some(prefix) => Cow::Owned(format!("{}{}", prefix, key.trim_start_matches('/'))),
+            None => key,
+        }
+    }
+
+    // The key the object callers know as `key` is cached under, so that keys
+    // that normalize to the same object share an entry.
+    fn cache_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        self.key_case.apply(key)
+    }
+
+    // The part size of a multipart upload of `total_size_bytes`: the
+    // configured part size if there is one, and otherwise the smallest part
+    // size S3 accepts that keeps the upload within its part limit, in whole
+    // MiB.
+    fn upload_part_size_for(&self, total_size_bytes: usize) -> usize {
+        match self.upload_part_size_bytes {
+            Some(part_size_bytes) => part_size_bytes,
+            None => total_size_bytes
+                .div_ceil(MAX_UPLOAD_PARTS)
+                .next_multiple_of(1024 * 1024)
+                .max(MIN_UPLOAD_PART_SIZE_BYTES),
+        }
+    }
+
+    // The inverse of object_key.
+    fn logical_key<'a>(&self, object_key: &'a str) -> &'a str {
+        match &self.key_prefix {
+            Some(prefix) => object_key
+                .strip_prefix(prefix.as_str())
+                .unwrap_or(object_key),
+            None => object_key,
+        }
+    }
+
+    // Streams an object body to the caller, reading ahead of it if configured.
+    fn body_stream(
+        &self,
+        stream: S3ByteStream,
+    ) -> Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send> {
+        match self.read_ahead_chunks {
+            Some(chunks) => Box::new(ReadAheadStream::new(Box::new(stream), chunks)),
+            None => Box::new(stream),
+        }
+    }
+
+    // The encryption requested on every write, including copies. Parts of a
+    // multipart upload inherit it from the request that created the upload.
+    fn server_side_encryption(&self) -> Option<aws_sdk_s3::types::ServerSideEncryption> {
+        self.sse.as_ref().map(|sse| match sse {
+            ServerSideEncryption::Aes256 => aws_sdk_s3::types::ServerSideEncryption::Aes256,
+            ServerSideEncryption::AwsKms { .. } => aws_sdk_s3::types::ServerSideEncryption::AwsKms,
+        })
+    }
+
+    fn request_payer(&self) -> Option<aws_sdk_s3::types::RequestPayer> {
+        self.requester_pays
+            .then_some(aws_sdk_s3::types::RequestPayer::Requester)
+    }
+
+    fn ssekms_key_id(&self) -> Option<String> {
+        match &self.sse {
+            Some(ServerSideEncryption::AwsKms { key_id }) => Some(key_id.clone()),
+            _ => None,
+        }
+    }
+
+    /// Records the latency and size of every get and put in `metrics`.
+    pub fn with_metrics(self, metrics: Arc<dyn StorageMetrics>) -> S3Storage {
+        S3Storage {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    // Starts timing an operation if metrics are being recorded.
+    fn start_timer(&self) -> Option<Instant> {
+        self.metrics.as_ref().map(|_| Instant::now())
+    }
+
+    fn record(
+        &self,
+        operation: StorageOperation,
+        outcome: StorageOutcome,
+        start: Option<Instant>,
+        bytes: u64,
+    ) {
+        if let (Some(metrics), Some(start)) = (&self.metrics, start) {
+            metrics.record(operation, outcome, start.elapsed(), bytes);
+        }
+    }
+
+    /// Acquires a permit from the concurrency limiter, if one is configured.
+    /// The permit must be held until the request has finished.
+    async fn acquire_request_permit(&self) -> Option<OwnedSemaphorePermit> {
+        if let Some(adaptive_concurrency) = &self.adaptive_concurrency {
+            return Some(adaptive_concurrency.acquire().await);
+        }
+        match &self.request_semaphore {
+            Some(semaphore) => Some(
+                semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("request semaphore is never closed"),
+            ),
+            None => None,
+        }
+    }
+
+    /// The current limit on S3 requests in flight, if adaptive concurrency is
+    /// enabled.
+    pub fn effective_concurrency_limit(&self) -> Option<usize> {
+        self.adaptive_concurrency
+            .as_ref()
+            .map(|adaptive_concurrency| adaptive_concurrency.limit())
+    }
+
+    /// Shuts the storage down gracefully, for this handle and all its clones:
+    /// operations that have not started yet fail with ShuttingDown, and this
+    /// waits up to `timeout` for those in flight to finish, including reading
+    /// the body of gets and the pages of listings already returned to callers.
+    /// Gets served from the cache still succeed. Returns whether everything in
+    /// flight finished before the timeout.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.requests.shutdown(timeout).await
+    }
+
+    /// The state of the circuit breaker, if one is configured.
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.circuit_breaker
+            .as_ref()
+            .map(|circuit_breaker| circuit_breaker.state())
+    }
+
+    // Whether the circuit breaker lets a request through. Requests that
+    // start an operation check this; requests within an operation that has
+    // already started, such as the parts of a multipart upload, do not.
+    fn circuit_allows(&self) -> bool {
+        match &self.circuit_breaker {
+            Some(circuit_breaker) => circuit_breaker.allow(),
+            None => true,
+        }
+    }
+
+    // The point in time by which an operation started now must complete.
+    fn deadline(&self) -> Option<Instant> {
+        self.operation_timeout
+            .map(|operation_timeout| Instant::now() + operation_timeout)
+    }
+
+    /// Waits for the rate limiter, if one is configured, to admit a request.
+    async fn admit(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+    }
+
+    // Waits for the bandwidth limiter, if one is configured, to admit
+    // `bytes` bytes.
+    async fn admit_bytes(&self, bytes: u64) {
+        if let Some(bandwidth_limiter) = &self.bandwidth_limiter {
+            bandwidth_limiter.acquire_many(bytes).await;
+        }
+    }
+
+    /// Checks that the bucket exists and is reachable with the configured
+    /// credentials, so that a misconfiguration surfaces at startup rather
+    /// than on the first request. Only issues a HeadBucket, which reads no
+    /// objects and changes nothing.
+    pub async fn validate(&self) -> Result<(), StorageConfigError> {
+        let res = self.client.head_bucket().bucket(&self.bucket).send().await;
+        let e = match res {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
         };
+        let message = match e.raw_response().map(|response| response.status().as_u16()) {
+            Some(404) => format!("bucket {} does not exist", self.bucket),
+            Some(403) => format!(
+                "access to bucket {} denied with the configured credentials",
+                self.bucket
+            ),
+            _ => format!("bucket {} is not reachable: {}", self.bucket, e),
+        };
+        Err(StorageConfigError::ValidationFailed(message))
+    }
+
+    /// Reports whether storage is usable, for readiness probes. Probes the
+    /// bucket with a HeadBucket, which gives up after a couple of seconds
+    /// regardless of the configured timeouts, and takes the state of the
+    /// circuit breaker, if one is configured, from before the probe. A
+    /// successful probe closes an open circuit, so storage reported degraded
+    /// is reported healthy by the next call unless requests keep failing.
+    pub async fn health(&self) -> StorageHealth {
+        let circuit_state = self.circuit_state();
+        let probe = self.client.head_bucket().bucket(&self.bucket).send();
+        match tokio::time::timeout(HEALTH_PROBE_TIMEOUT, probe).await {
+            Err(_) => StorageHealth::Unavailable {
+                reason: format!(
+                    "bucket {} did not respond within {:?}",
+                    self.bucket, HEALTH_PROBE_TIMEOUT
+                ),
+            },
+            Ok(Err(e)) => StorageHealth::Unavailable {
+                reason: format!("bucket {} is not reachable: {}", self.bucket, e),
+            },
+            Ok(Ok(_)) => match circuit_state {
+                Some(CircuitState::Open) => StorageHealth::Degraded {
+                    reason: "circuit breaker is open".to_string(),
+                },
+                Some(CircuitState::HalfOpen) => StorageHealth::Degraded {
+                    reason: "circuit breaker is half-open".to_string(),
+                },
+                Some(CircuitState::Closed) | None => StorageHealth::Healthy,
+            },
+        }
+    }
+
+    /// Opens connections to S3 ahead of the first requests, so that they do
+    /// not pay for the TCP and TLS handshakes. As many connections are opened
+    /// as gets or puts use at once, by issuing that many concurrent
+    /// HeadBuckets, which read no objects and change nothing. Safe to call
+    /// at any time and more than once. Failures are only logged, since they
+    /// leave the connection pool no worse off than not warming it up.
+    pub async fn warmup(&self) {
+        let connections = self.upload_concurrency.max(self.download_concurrency);
+        let results = future::join_all(
+            (0..connections).map(|_| self.client.head_bucket().bucket(&self.bucket).send()),
+        )
+        .await;
+        if let Some(Err(e)) = results.iter().find(|res| res.is_err()) {
+            tracing::warn!(
+                "failed to warm up connections to bucket {}: {}",
+                self.bucket,
+                e
+            );
+        }
     }
 
     async fn create_bucket(&self) -> Result<(), String> {
@@ -118,327 +1066,8037 @@ impl S3Storage {
         }
     }
 
+    /// Returns the object at `key` as a stream of its bytes, as get_stream
+    /// does. A zero-byte object yields an empty stream, while a missing key
+    /// is S3GetError::NoSuchKey.
     pub async fn get(
         &self,
         key: &str,
     ) -> Result<Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>, S3GetError> {
-        let res = self
-            .client
-            .get_object()
-            .bucket(self.bucket.clone())
-            .key(key)
-            .send()
-            .await;
-        match res {
-            Ok(res) => {
-                let byte_stream = res.body;
-                return Ok(Box::new(S3ByteStream::new(byte_stream)));
-            }
-            Err(e) => {
-                tracing::error!("error: {}", e);
-                match e {
-                    SdkError::ServiceError(err) => {
-                        let inner = err.into_err();
-                        match inner {
-                            aws_sdk_s3::operation::get_object::GetObjectError::NoSuchKey(msg) => {
-                                 tracing::error!("no such key: {}", msg);
-                                return Err(S3GetError::NoSuchKey(msg.to_string()));
-                            }
-                            aws_sdk_s3::operation::get_object::GetObjectError::InvalidObjectState(msg) => {
-                                 tracing::error!("invalid object state: {}", msg);
-                                return Err(S3GetError::S3GetError(msg.to_string()));
-                            }
-                            aws_sdk_s3::operation::get_object::GetObjectError::Unhandled(_) =>  {
-                                 tracing::error!("unhandled error");
-                                return Err(S3GetError::S3GetError("unhandled error".to_string()));
-                            }
-                            _ => {
-                                 tracing::error!("error: {}", inner.to_string());
-                                return Err(S3GetError::S3GetError(inner.to_string()));
-                            }
-                        };
-                    }
-                    _ => {}
+        let (_, stream) = self.get_stream(key).await?;
+        Ok(stream)
+    }
+
+    /// Returns the object at `key` as a stream, along with the number of
+    /// bytes it will yield. The length is taken from the GET response before
+    /// the body is read, and is None if S3 did not report it. For compressed
+    /// objects it is the decompressed size.
+    ///
+    /// When a cache is configured, objects small enough to be cached are read
+    /// in full before being returned, and later gets are served from memory.
+    /// Compressed objects are also read in full so that they can be
+    /// decompressed, and objects S3 reports as smaller than
+    /// small_object_threshold_bytes are yielded as a single chunk rather than
+    /// as the many small chunks the body may arrive in.
+    ///
+    /// When checksum verification is enabled and the object was stored with
+    /// a checksum, the stream yields S3GetError::ChecksumMismatch after the
+    /// last chunk if the body does not match it. When an operation timeout is
+    /// configured it covers draining the whole stream, and an expired stream
+    /// yields S3GetError::Timeout.
+    pub async fn get_stream(
+        &self,
+        key: &str,
+    ) -> Result<
+        (
+            Option<u64>,
+            Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>,
+        ),
+        S3GetError,
+    > {
+        if let Some(bytes) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.cache_key(key)))
+        {
+            return Ok((
+                Some(bytes.len() as u64),
+                Box::new(stream::once(future::ready(Ok(bytes.to_vec())))),
+            ));
+        }
+
+        let generation = self.cache.as_ref().map(|cache| cache.generation());
+        let object = self.get_object(key).await?;
+        let cacheable = match (&self.cache, object.content_length) {
+            (Some(cache), Some(content_length)) => cache.admits(content_length.max(0) as usize),
+            _ => false,
+        };
+        let small = match (object.content_length, self.small_object_threshold_bytes) {
+            (Some(content_length), Some(threshold)) => (content_length.max(0) as usize) < threshold,
+            _ => false,
+        };
+        if !cacheable && !small && object.compression.is_none() {
+            let content_length = object
+                .content_length
+                .map(|content_length| content_length.max(0) as u64);
+            return Ok((content_length, self.body_stream(object.stream)));
+        }
+        let bytes = Arc::new(object.read().await?);
+        if let (Some(cache), Some(generation)) = (&self.cache, generation) {
+            cache.insert(&self.cache_key(key), bytes.clone(), generation);
+        }
+        Ok((
+            Some(bytes.len() as u64),
+            Box::new(stream::once(future::ready(Ok(bytes.to_vec())))),
+        ))
+    }
+
+    /// Returns the object at `key` as get does, but always fetched from S3:
+    /// the cache is neither consulted nor populated. Useful to check a write
+    /// made through another handle, which this handle's cache does not see.
+    pub async fn get_uncached(
+        &self,
+        key: &str,
+    ) -> Result<Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>, S3GetError> {
+        let object = self.get_object(key).await?;
+        if object.compression.is_none() {
+            return Ok(self.body_stream(object.stream));
+        }
+        let bytes = object.read().await?;
+        Ok(Box::new(stream::once(future::ready(Ok(bytes)))))
+    }
+
+    /// Returns a future that reads the whole object at `key`, and a handle
+    /// that cancels it. Cancelling stops the request and releases its permit
+    /// right away, even if the future is not polled again, and the future
+    /// then resolves to Cancelled. Gets are not shared between callers, so
+    /// the caller holding the handle is always the only one waiting on the
+    /// request. The future owns what it needs and may be spawned.
+    pub fn get_cancellable(
+        &self,
+        key: String,
+    ) -> (
+        impl Future<Output = Result<Arc<Vec<u8>>, S3GetError>> + Send + 'static,
+        CancelHandle,
+    ) {
+        let storage = self.clone();
+        let (fetch, abort_handle) = future::abortable({
+            let key = key.clone();
+            async move {
+                let (_, mut stream) = storage.get_stream(&key).await?;
+                let mut bytes = Vec::new();
+                while let Some(chunk) = stream.next().await {
+                    bytes.extend_from_slice(&chunk.map_err(|e| match e {
+                        GetError::S3Error(e) => e,
+                        e => S3GetError::ByteStreamError(e.to_string()),
+                    })?);
                 }
-                return Err(S3GetError::S3GetError(e.to_string()));
+                Ok(Arc::new(bytes))
             }
-        }
+        });
+        let fetch = fetch.map(move |res| match res {
+            Ok(res) => res,
+            Err(future::Aborted) => {
+                tracing::debug!("get of {} cancelled", key);
+                Err(S3GetError::Cancelled(key))
+            }
+        });
+        (fetch, CancelHandle(abort_handle))
+    }
+
+    /// Returns the version `version_id` of the object at `key`, which the
+    /// bucket must have versioning enabled to keep. Versions never change, but
+    /// the cache is keyed by key alone, so it is neither consulted nor
+    /// populated. Fails with NoSuchKey if there is no such version.
+    pub async fn get_version(
+        &self,
+        key: &str,
+        version_id: &str,
+    ) -> Result<Arc<Vec<u8>>, S3GetError> {
+        let _in_flight = self.requests.begin().ok_or(S3GetError::ShuttingDown)?;
+        let object = self
+            .get_object_once(&self.bucket, key, Some(version_id), None, None)
+            .await?;
+        Ok(Arc::new(object.read().await?))
+    }
+
+    /// Returns the object at `key` if its ETag is no longer `etag`, or None if
+    /// it is unchanged, in which case S3 sends no body. Use this to refresh a
+    /// copy cached by ETag without downloading it again. The cache of this
+    /// storage is neither consulted nor populated, as it does not track
+    /// ETags.
+    pub async fn get_if_none_match(
+        &self,
+        key: &str,
+        etag: &str,
+    ) -> Result<Option<Arc<Vec<u8>>>, S3GetError> {
+        let _in_flight = self.requests.begin().ok_or(S3GetError::ShuttingDown)?;
+        let object = match self
+            .get_object_once(&self.bucket, key, None, None, Some(etag))
+            .await
+        {
+            Ok(object) => object,
+            Err(S3GetError::NotModified) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(Arc::new(object.read().await?)))
+    }
+
+    /// Streams the object at `key` into the file at `path`, one chunk at a
+    /// time, and returns the number of bytes written. An existing file at
+    /// `path` is overwritten. If the get fails partway through, the
+    /// incomplete file is removed.
+    pub async fn get_to_file(&self, key: &str, path: &str) -> Result<u64, S3GetError> {
+        let (_, stream) = self.get_stream(key).await?;
+        let res = async {
+            let file = tokio::fs::File::create(path)
+                .await
+                .map_err(|e| file_error(path, e))?;
+            write_stream(file, stream, path).await
+        }
+        .await;
+        if res.is_err() {
+            if let Err(e) = tokio::fs::remove_file(path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("error removing incomplete download {}: {}", path, e);
+                }
+            }
+        }
+        res
+    }
+
+    /// Downloads the object at `key` into the file at `path` as get_to_file
+    /// does, but leaves what was written in place if the download fails, so
+    /// that calling this again resumes it: only the bytes past the end of the
+    /// file are fetched, with a range get. The ETag of the object being
+    /// downloaded is kept next to the file, at `path` with ".etag" appended,
+    /// until the download completes. The download restarts from the first
+    /// byte if the object has changed since, or if there is a file but no
+    /// ETag next to it. Compressed objects cannot be fetched from an offset
+    /// into their content, so they are always downloaded in full. Returns
+    /// the size of the downloaded file.
+    pub async fn resume_get_to_file(&self, key: &str, path: &str) -> Result<u64, S3GetError> {
+        let etag_path = format!("{}.etag", path);
+        let head = self
+            .head_object(key)
+            .await
+            .map_err(|e| S3GetError::S3GetError(e.to_string()))?
+            .ok_or_else(|| S3GetError::NoSuchKey(key.to_string()))?;
+        if head
+            .metadata()
+            .is_some_and(|metadata| metadata.contains_key(COMPRESSION_METADATA_KEY))
+        {
+            let written = self.get_to_file(key, path).await?;
+            remove_if_exists(&etag_path)
+                .await
+                .map_err(|e| file_error(&etag_path, e))?;
+            return Ok(written);
+        }
+
+        let size = head.content_length.unwrap_or_default().max(0) as u64;
+        let etag = head.e_tag();
+        let recorded_etag = tokio::fs::read_to_string(&etag_path).await.ok();
+        let offset = match tokio::fs::metadata(path).await {
+            Ok(metadata)
+                if etag.is_some() && recorded_etag.as_deref() == etag && metadata.len() <= size =>
+            {
+                metadata.len()
+            }
+            _ => 0,
+        };
+        let file = if offset > 0 {
+            tracing::info!("resuming download of {} to {} at {}", key, path, offset);
+            tokio::fs::OpenOptions::new().append(true).open(path).await
+        } else {
+            if let Some(etag) = etag {
+                tokio::fs::write(&etag_path, etag)
+                    .await
+                    .map_err(|e| file_error(&etag_path, e))?;
+            }
+            tokio::fs::File::create(path).await
+        };
+        let file = file.map_err(|e| file_error(path, e))?;
+
+        if offset < size {
+            let in_flight = self.requests.begin().ok_or(S3GetError::ShuttingDown)?;
+            // The whole object is fetched without a range, so that its checksum
+            // is verified.
+            let range = (offset > 0).then_some(offset..size);
+            let object = self
+                .get_object_once(&self.bucket, key, None, range, None)
+                .await?;
+            if object.e_tag.as_deref() != etag {
+                return Err(S3GetError::ObjectChanged(format!(
+                    "{}: changed since it was headed",
+                    key
+                )));
+            }
+            write_stream(file, object.stream.with_in_flight(in_flight), path).await?;
+        }
+        remove_if_exists(&etag_path)
+            .await
+            .map_err(|e| file_error(&etag_path, e))?;
+        Ok(size)
+    }
+
+    /// The cache counters accumulated since the storage was created or its
+    /// stats last drained, and the current size of the cache. All zero if no
+    /// cache is configured.
+    pub fn stats_snapshot(&self) -> StorageStats {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.stats_snapshot())
+            .unwrap_or_default()
+    }
+
+    /// Zeroes the cache counters.
+    pub fn reset_stats(&self) {
+        if let Some(cache) = &self.cache {
+            cache.reset_stats();
+        }
+    }
+
+    /// Returns the cache counters and zeroes them in one step, so that no
+    /// count is lost between reading and resetting them. Prefer this to a
+    /// stats_snapshot followed by reset_stats.
+    pub fn drain_stats(&self) -> StorageStats {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.drain_stats())
+            .unwrap_or_default()
+    }
+
+    /// Fetches `keys` into the cache in the background, up to
+    /// PREFETCH_PARALLELISM of them at once, and returns without waiting for
+    /// them. Keys that are already cached, or repeated, are fetched once at
+    /// most, and objects too large for the cache are not kept. Failed fetches
+    /// are logged rather than returned. Does nothing if no cache is configured.
+    /// The returned handle resolves once every key has been fetched, and may be
+    /// dropped.
+    pub fn prefetch(&self, mut keys: Vec<String>) -> tokio::task::JoinHandle<()> {
+        let storage = self.clone();
+        tokio::spawn(async move {
+            let cache = match &storage.cache {
+                Some(cache) => cache,
+                None => return,
+            };
+            let mut seen = HashSet::new();
+            keys.retain(|key| seen.insert(key.clone()));
+            stream::iter(keys)
+                .for_each_concurrent(PREFETCH_PARALLELISM, |key| {
+                    let storage = &storage;
+                    async move {
+                        if cache.get(&storage.cache_key(&key)).is_some() {
+                            return;
+                        }
+                        // A get of a cacheable object reads it in full and
+                        // inserts it, so the returned stream is not needed.
+                        if let Err(e) = storage.get(&key).await {
+                            tracing::warn!("failed to prefetch {}: {}", key, e);
+                        }
+                    }
+                })
+                .await;
+        })
+    }
+
+    // Issues the GET for `key`, returning the response before its body has
+    // been read. A key missing from the bucket is looked up in the fallback
+    // bucket, if there is one. The get counts as in flight until its body
+    // has been read.
+    async fn get_object(&self, key: &str) -> Result<S3Object, S3GetError> {
+        let in_flight = self.requests.begin().ok_or(S3GetError::ShuttingDown)?;
+        let (object, _) = self.get_object_in_flight(key).await?;
+        Ok(S3Object {
+            stream: object.stream.with_in_flight(in_flight),
+            ..object
+        })
+    }
+
+    // Issues the GET as get_object does, as part of an operation already in
+    // flight. Also returns whether the object was read from the fallback
+    // bucket.
+    async fn get_object_in_flight(&self, key: &str) -> Result<(S3Object, bool), S3GetError> {
+        match (
+            self.get_object_from(&self.bucket, key).await,
+            &self.fallback_bucket,
+        ) {
+            (Err(S3GetError::NoSuchKey(_)), Some(fallback_bucket)) => {
+                tracing::info!(
+                    "{} not found in bucket {}, reading it from fallback bucket {}",
+                    key,
+                    self.bucket,
+                    fallback_bucket
+                );
+                let object = self.get_object_from(fallback_bucket, key).await?;
+                Ok((object, true))
+            }
+            (res, _) => Ok((res?, false)),
+        }
+    }
+
+    // Issues the GET as get_object_once does. If hedge_after is set and no
+    // response has arrived by then, a second GET is issued, and whichever
+    // responds first is used; the other is cancelled.
+    async fn get_object_from(&self, bucket: &str, key: &str) -> Result<S3Object, S3GetError> {
+        let hedge_after = match self.hedge_after {
+            Some(hedge_after) => hedge_after,
+            None => return self.get_object_once(bucket, key, None, None, None).await,
+        };
+        let first = Box::pin(self.get_object_once(bucket, key, None, None, None));
+        let hedge = Box::pin(async move {
+            tokio::time::sleep(hedge_after).await;
+            tracing::debug!("no response for {} after {:?}, hedging", key, hedge_after);
+            self.get_object_once(bucket, key, None, None, None).await
+        });
+        match future::select(first, hedge).await {
+            future::Either::Left((res, _)) | future::Either::Right((res, _)) => res,
+        }
+    }
+
+    // Gets the object at `key`, or the given version of it if `version_id` is
+    // set. If `range` is set, only those bytes of the stored payload are
+    // fetched, and the object's checksum, which covers all of it, is not
+    // verified.
+    async fn get_object_once(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        range: Option<Range<u64>>,
+        if_none_match: Option<&str>,
+    ) -> Result<S3Object, S3GetError> {
+        let start = self.start_timer();
+        if !self.circuit_allows() {
+            self.record(StorageOperation::Get, StorageOutcome::Error, start, 0);
+            return Err(S3GetError::CircuitOpen);
+        }
+        let permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let deadline = self.deadline();
+        let res = with_deadline(
+            deadline,
+            self.client
+                .get_object()
+                .bucket(bucket)
+                .key(self.object_key(key))
+                .set_version_id(version_id.map(str::to_string))
+                .set_if_none_match(if_none_match.map(str::to_string))
+                .set_request_payer(self.request_payer())
+                // HTTP ranges are inclusive of the last byte.
+                .set_range(
+                    range
+                        .as_ref()
+                        .map(|range| format!("bytes={}-{}", range.start, range.end - 1)),
+                )
+                .send(),
+        )
+        .await;
+        let res = match res {
+            Some(res) => res,
+            None => {
+                self.record(StorageOperation::Get, StorageOutcome::Error, start, 0);
+                return Err(S3GetError::Timeout(key.to_string()));
+            }
+        };
+        match res {
+            Ok(mut res) => {
+                let expected_checksum = if self.verify_checksums && range.is_none() {
+                    res.metadata
+                        .as_mut()
+                        .and_then(|metadata| metadata.remove(CHECKSUM_METADATA_KEY))
+                } else {
+                    None
+                };
+                let compression = match res
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.get(COMPRESSION_METADATA_KEY))
+                {
+                    Some(codec) => match CompressionCodec::from_metadata(codec) {
+                        Some(codec) => Some(codec),
+                        None => {
+                            self.record(StorageOperation::Get, StorageOutcome::Error, start, 0);
+                            return Err(S3GetError::DecompressionError(format!(
+                                "{}: unknown codec {}",
+                                key, codec
+                            )));
+                        }
+                    },
+                    // Encoded at the HTTP layer by whoever wrote the object,
+                    // rather than compressed by put_bytes.
+                    None if self.decode_content_encoding
+                        && res.content_encoding().is_some_and(|encoding| {
+                            encoding.trim().eq_ignore_ascii_case("gzip")
+                        }) =>
+                    {
+                        Some(CompressionCodec::Gzip)
+                    }
+                    None => None,
+                };
+                if let (Some(limit), Some(content_length)) =
+                    (self.max_object_size_bytes, res.content_length)
+                {
+                    if content_length.max(0) as usize > limit {
+                        self.record(StorageOperation::Get, StorageOutcome::Error, start, 0);
+                        return Err(S3GetError::ObjectTooLarge {
+                            limit,
+                            actual: content_length as usize,
+                        });
+                    }
+                }
+                let mut stream = S3ByteStream::with_permit(res.body, permit);
+                if let (true, Some(content_length)) =
+                    (self.validate_content_length, res.content_length)
+                {
+                    stream = stream.validate_length(content_length.max(0) as u64);
+                }
+                if let Some(expected_checksum) = expected_checksum {
+                    stream = stream.verify_checksum(expected_checksum);
+                }
+                if let Some(deadline) = deadline {
+                    stream = stream.with_deadline(deadline);
+                }
+                if let Some(bandwidth_limiter) = &self.bandwidth_limiter {
+                    stream = stream.with_throttle(bandwidth_limiter.clone());
+                }
+                if let (Some(metrics), Some(start)) = (&self.metrics, start) {
+                    stream = stream.with_metrics(metrics.clone(), start);
+                }
+                return Ok(S3Object {
+                    stream,
+                    content_length: res.content_length,
+                    e_tag: res.e_tag,
+                    compression,
+                    max_size_bytes: self.max_object_size_bytes,
+                });
+            }
+            Err(e) => {
+                self.record(StorageOperation::Get, StorageOutcome::Error, start, 0);
+                return Err(get_object_error(e));
+            }
+        }
+    }
+
+    /// Fetches the bytes in `start..end` of the object at `key`. A range that
+    /// extends past the end of the object is truncated to the object's length,
+    /// while a range that starts at or past the end of the object returns
+    /// `S3GetError::RangeNotSatisfiable`. An empty range returns no bytes
+    /// without issuing a request.
+    pub async fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Arc<Vec<u8>>, S3GetError> {
+        self.get_range_if_match(key, start, end, None).await
+    }
+
+    /// Returns the bytes in `start..end` of the object at `key` as a stream,
+    /// for ranges too large to buffer. The range is checked as get_range
+    /// checks it, and a range that cannot be satisfied fails here rather than
+    /// once the stream is read. A range of a compressed object is yielded as
+    /// a single chunk, since the whole object has to be decompressed to
+    /// find it.
+    pub async fn get_range_stream(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>, S3GetError> {
+        if start > end {
+            return Err(S3GetError::RangeNotSatisfiable(format!(
+                "{}: {}..{}",
+                key, start, end
+            )));
+        }
+        if start == end {
+            return Ok(Box::new(stream::empty()));
+        }
+        if let Some(bytes) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.cache_key(key)))
+        {
+            let bytes = slice_range(key, &bytes, start, end)?;
+            return Ok(Box::new(stream::once(future::ready(Ok(bytes.to_vec())))));
+        }
+
+        let in_flight = self.requests.begin().ok_or(S3GetError::ShuttingDown)?;
+        let object = self
+            .get_object_once(&self.bucket, key, None, Some(start..end), None)
+            .await?;
+        if object.compression.is_some() {
+            drop(object);
+            let bytes = self.get_object(key).await?.read().await?;
+            let bytes = slice_range(key, &bytes, start, end)?;
+            return Ok(Box::new(stream::once(future::ready(Ok(bytes.to_vec())))));
+        }
+        Ok(self.body_stream(object.stream.with_in_flight(in_flight)))
+    }
+
+    // Fetches a range as get_range does. If `etag` is given, the range is
+    // only read from that version of the object, and the get fails with
+    // ObjectChanged once the object has been overwritten.
+    async fn get_range_if_match(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        etag: Option<&str>,
+    ) -> Result<Arc<Vec<u8>>, S3GetError> {
+        if start > end {
+            return Err(S3GetError::RangeNotSatisfiable(format!(
+                "{}: {}..{}",
+                key, start, end
+            )));
+        }
+        if start == end {
+            return Ok(Arc::new(Vec::new()));
+        }
+        if let Some(bytes) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.cache_key(key)))
+        {
+            return slice_range(key, &bytes, start, end);
+        }
+
+        let _in_flight = self.requests.begin().ok_or(S3GetError::ShuttingDown)?;
+        let timer = self.start_timer();
+        // The length of the range is known up front, so it is admitted whole.
+        self.admit_bytes(end - start).await;
+        let permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let res = with_deadline(self.deadline(), async {
+            let res = self
+                .client
+                .get_object()
+                .bucket(self.bucket.clone())
+                .key(self.object_key(key))
+                .set_request_payer(self.request_payer())
+                .set_if_match(etag.map(str::to_string))
+                // HTTP ranges are inclusive of the last byte.
+                .range(format!("bytes={}-{}", start, end - 1))
+                .send()
+                .await
+                .map_err(get_object_error)?;
+            let compressed = res
+                .metadata()
+                .is_some_and(|metadata| metadata.contains_key(COMPRESSION_METADATA_KEY));
+            if compressed {
+                return Ok(None);
+            }
+            let bytes = res
+                .body
+                .collect()
+                .await
+                .map_err(|e| S3GetError::ByteStreamError(e.to_string()))?;
+            Ok(Some(Arc::new(bytes.to_vec())))
+        })
+        .await
+        .unwrap_or_else(|| Err(S3GetError::Timeout(key.to_string())));
+        drop(permit);
+        let res = match res {
+            Ok(Some(bytes)) => Ok(bytes),
+            // A range of the compressed payload cannot be decompressed on its
+            // own, so read and decompress the whole object instead.
+            Ok(None) => {
+                let bytes = self.get_object(key).await?.read().await?;
+                return slice_range(key, &bytes, start, end);
+            }
+            Err(e) => Err(e),
+        };
+        match &res {
+            Ok(bytes) => self.record(
+                StorageOperation::Get,
+                StorageOutcome::Success,
+                timer,
+                bytes.len() as u64,
+            ),
+            Err(_) => self.record(StorageOperation::Get, StorageOutcome::Error, timer, 0),
+        }
+        res
+    }
+
+    /// Fetches the object at `key` in full. Objects larger than
+    /// parallel_download_threshold_bytes are fetched as concurrent range gets
+    /// of download_part_size_bytes each, at most download_concurrency at a
+    /// time, so that the download is not limited by the bandwidth of a
+    /// single connection; their size is learnt with a HEAD first. The parts
+    /// are all read from the version of the object the HEAD saw, so an
+    /// object overwritten during the download fails with ObjectChanged
+    /// rather than mixing two versions. Smaller objects, compressed objects
+    /// and objects only found in the fallback bucket are fetched with a
+    /// single get. The cache is used as it is by get.
+    pub async fn get_parallel(&self, key: &str) -> Result<Arc<Vec<u8>>, S3GetError> {
+        if let Some(bytes) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.cache_key(key)))
+        {
+            return Ok(bytes);
+        }
+
+        let generation = self.cache.as_ref().map(|cache| cache.generation());
+        let head = self
+            .head_object(key)
+            .await
+            .map_err(|e| S3GetError::S3GetError(e.to_string()))?;
+        let bytes = match head {
+            Some(head)
+                if head.content_length.unwrap_or_default().max(0) as usize
+                    > self.parallel_download_threshold_bytes
+                    && !head.metadata().is_some_and(|metadata| {
+                        metadata.contains_key(COMPRESSION_METADATA_KEY)
+                    }) =>
+            {
+                self.get_parts(key, head).await?
+            }
+            _ => self.get_object(key).await?.read().await?,
+        };
+        let bytes = Arc::new(bytes);
+        if let (Some(cache), Some(generation)) = (&self.cache, generation) {
+            cache.insert(&self.cache_key(key), bytes.clone(), generation);
+        }
+        Ok(bytes)
+    }
+
+    // Fetches the uncompressed object `head` describes as concurrent range
+    // gets, and assembles them in order.
+    async fn get_parts(&self, key: &str, head: HeadObjectOutput) -> Result<Vec<u8>, S3GetError> {
+        let size = head.content_length.unwrap_or_default().max(0) as usize;
+        if let Some(limit) = self.max_object_size_bytes {
+            if size > limit {
+                return Err(S3GetError::ObjectTooLarge {
+                    limit,
+                    actual: size,
+                });
+            }
+        }
+        let etag = head.e_tag();
+        let bytes = stream::iter((0..size).step_by(self.download_part_size_bytes))
+            .map(|start| {
+                let end = (start + self.download_part_size_bytes).min(size);
+                self.get_range_if_match(key, start as u64, end as u64, etag)
+            })
+            .buffered(self.download_concurrency)
+            .try_fold(Vec::with_capacity(size), |mut bytes, part| async move {
+                bytes.extend_from_slice(&part);
+                Ok(bytes)
+            })
+            .await?;
+
+        let expected_checksum = head
+            .metadata()
+            .and_then(|metadata| metadata.get(CHECKSUM_METADATA_KEY))
+            .filter(|_| self.verify_checksums);
+        if let Some(expected) = expected_checksum {
+            let actual = hex::encode(Sha256::digest(&bytes));
+            if &actual != expected {
+                return Err(S3GetError::ChecksumMismatch(format!(
+                    "expected {}, got {}",
+                    expected, actual
+                )));
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Uploads `bytes` to `key`, storing the SHA-256 of the payload in the
+    /// object metadata so that reads can verify it. If compression is
+    /// configured the payload is compressed first and the checksum covers the
+    /// compressed payload. Empty `bytes` create an empty object, which gets
+    /// read back as no bytes, unless reject_empty_puts is set, in which case
+    /// they fail with EmptyPayload.
+    pub async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), S3PutError> {
+        let span = put_span(key, Some(bytes.len()));
+        async move {
+            let (bytes, options) = self.prepare_bytes(key, bytes)?;
+            self.put_prepared_bytes(key, bytes, &options).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Uploads `bytes` to `key` as put_bytes does, but evicts `key` from the
+    /// cache instead of caching `bytes`. Once this returns Ok, the next get of
+    /// `key` through this storage fetches it from S3, and gets that were in
+    /// flight during the put cannot cache what they read. S3 reads after a
+    /// write are strongly consistent, so that get returns `bytes` unless
+    /// another write to `key` has replaced them. Caches of other S3Storage
+    /// instances are unaffected.
+    pub async fn put_then_invalidate(&self, key: &str, bytes: Vec<u8>) -> Result<(), S3PutError> {
+        let span = put_span(key, Some(bytes.len()));
+        async move {
+            let (bytes, mut options) = self.prepare_bytes(key, bytes)?;
+            // Without a payload to write through, a finished put invalidates.
+            options.write_through = None;
+            self.put_prepared_bytes(key, bytes, &options).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Uploads `bytes` to `key` as put_bytes does, unless an object already
+    /// exists at `key`. Returns true if the object was written and false if
+    /// an existing object was left in place.
+    pub async fn put_if_absent(&self, key: &str, bytes: Vec<u8>) -> Result<bool, S3PutError> {
+        let span = put_span(key, Some(bytes.len()));
+        async move {
+            let (bytes, mut options) = self.prepare_bytes(key, bytes)?;
+            options.if_none_match = true;
+            match self.put_prepared_bytes(key, bytes, &options).await {
+                Ok(()) => Ok(true),
+                Err(S3PutError::PreconditionFailed(_)) => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Appends `bytes` to the object at `key`, creating it if there is none.
+    /// S3 cannot append to an object, so the object is read in full and
+    /// written back with `bytes` added: every append costs a get and a put of
+    /// the whole object, which grow with it. Only use this for objects that
+    /// stay small, and batch records into fewer appends where possible. The
+    /// write is conditional on the object being unchanged since it was read,
+    /// so that of two concurrent appends one fails with Conflict rather than
+    /// dropping the bytes of the other. Retry the append on Conflict. An
+    /// object found only in the fallback bucket is appended to as if it were
+    /// missing from the bucket, where the result is written. An existing
+    /// object without an ETag cannot be appended to safely, and fails.
+    pub async fn append(&self, key: &str, bytes: &[u8]) -> Result<(), S3PutError> {
+        let span = put_span(key, Some(bytes.len()));
+        async move {
+            // Held across the read and the write, so that a shutdown in
+            // between does not refuse the write.
+            let _in_flight = self.requests.begin().ok_or(S3PutError::ShuttingDown)?;
+            let (existing, etag) = match self.get_object_in_flight(key).await {
+                Ok((object, from_fallback)) => {
+                    let etag = match (from_fallback, object.e_tag.clone()) {
+                        (true, _) => None,
+                        (false, Some(etag)) => Some(etag),
+                        (false, None) => {
+                            return Err(S3PutError::S3PutError(format!(
+                                "{}: existing object has no ETag to append against",
+                                key
+                            )))
+                        }
+                    };
+                    let existing = object
+                        .read()
+                        .await
+                        .map_err(|e| S3PutError::S3PutError(e.to_string()))?;
+                    (existing, etag)
+                }
+                Err(S3GetError::NoSuchKey(_)) => (Vec::new(), None),
+                Err(e) => return Err(S3PutError::S3PutError(e.to_string())),
+            };
+            let mut appended = existing;
+            appended.extend_from_slice(bytes);
+            let (appended, mut options) = self.prepare_bytes(key, appended)?;
+            match etag {
+                Some(etag) => options.if_match = Some(etag),
+                None => options.if_none_match = true,
+            }
+            let appended = Arc::new(Bytes::from(appended));
+            let res = self
+                .put_object_in_flight(key, appended.len(), &options, bytes_body(appended))
+                .await;
+            match res {
+                Err(S3PutError::PreconditionFailed(e)) => Err(S3PutError::Conflict(e)),
+                // What S3 returns to the loser of two conditional writes that
+                // race each other.
+                Err(S3PutError::ServiceError { message, .. })
+                    if message.starts_with("ConditionalRequestConflict") =>
+                {
+                    Err(S3PutError::Conflict(message))
+                }
+                res => res,
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Uploads `bytes` to `key` as put_bytes does, attaching `tags` to the
+    /// object. Tags must meet the S3 limits: at most 10 per object, keys of 1
+    /// to 128 characters not starting with "aws:", values of up to 256
+    /// characters, and only letters, digits, spaces and + - = . _ : / @.
+    /// Tags that do not are rejected with InvalidTag before anything is
+    /// uploaded.
+    pub async fn put_bytes_with_tags(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), S3PutError> {
+        let tagging = encode_tags(tags)?;
+        let span = put_span(key, Some(bytes.len()));
+        async move {
+            let (bytes, mut options) = self.prepare_bytes(key, bytes)?;
+            options.tagging = Some(tagging);
+            self.put_prepared_bytes(key, bytes, &options).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Uploads `bytes` to `key` as put_bytes does, with `content_type` as
+    /// the object's Content-Type rather than application/octet-stream.
+    pub async fn put_bytes_with_content_type(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        content_type: &str,
+    ) -> Result<(), S3PutError> {
+        let span = put_span(key, Some(bytes.len()));
+        async move {
+            let (bytes, mut options) = self.prepare_bytes(key, bytes)?;
+            options.content_type = Some(content_type.to_string());
+            self.put_prepared_bytes(key, bytes, &options).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Uploads `bytes` to `key` as put_bytes does, storing `metadata` as the
+    /// object's user metadata, which head returns. Keys must be lowercase
+    /// letters, digits, - and _, since S3 lowercases them, and must not be
+    /// sha256 or compression, which put_bytes keeps for itself. Values must be
+    /// printable ASCII without surrounding whitespace, and keys and values
+    /// together must fit in 2 KiB. Metadata that does not is rejected with
+    /// InvalidMetadata before anything is uploaded.
+    pub async fn put_bytes_with_metadata(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), S3PutError> {
+        validate_metadata(&metadata)?;
+        let span = put_span(key, Some(bytes.len()));
+        async move {
+            let (bytes, mut options) = self.prepare_bytes(key, bytes)?;
+            options.metadata.extend(metadata);
+            self.put_prepared_bytes(key, bytes, &options).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Returns the tags of the object at `key`.
+    pub async fn get_tags(&self, key: &str) -> Result<HashMap<String, String>, S3GetError> {
+        let _in_flight = self.requests.begin().ok_or(S3GetError::ShuttingDown)?;
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let res = self
+            .client
+            .get_object_tagging()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .set_request_payer(self.request_payer())
+            .send()
+            .await;
+        match res {
+            Ok(res) => Ok(res
+                .tag_set
+                .into_iter()
+                .map(|tag| (tag.key, tag.value))
+                .collect()),
+            Err(e) if e.code() == Some("NoSuchKey") => Err(S3GetError::NoSuchKey(key.to_string())),
+            Err(e) => Err(S3GetError::S3GetError(e.to_string())),
+        }
+    }
+
+    // Compresses `bytes` if configured and computes the metadata put_bytes
+    // stores alongside them.
+    fn prepare_bytes(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+    ) -> Result<(Vec<u8>, PutOptions), S3PutError> {
+        self.check_payload_size(key, bytes.len())?;
+        let write_through = match &self.cache {
+            Some(cache) if cache.admits(bytes.len()) => Some(Arc::new(bytes.clone())),
+            _ => None,
+        };
+        let mut metadata = HashMap::new();
+        let bytes = match self.compression {
+            Some(codec) => {
+                metadata.insert(
+                    COMPRESSION_METADATA_KEY.to_string(),
+                    codec.as_str().to_string(),
+                );
+                codec
+                    .compress(&bytes)
+                    .map_err(|e| S3PutError::CompressionError(e.to_string()))?
+            }
+            None => bytes,
+        };
+        metadata.insert(
+            CHECKSUM_METADATA_KEY.to_string(),
+            hex::encode(Sha256::digest(&bytes)),
+        );
+        Ok((
+            bytes,
+            PutOptions {
+                metadata,
+                write_through,
+                ..Default::default()
+            },
+        ))
+    }
+
+    fn check_payload_size(&self, key: &str, size: usize) -> Result<(), S3PutError> {
+        if size == 0 && self.reject_empty_puts {
+            return Err(S3PutError::EmptyPayload(key.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn put_prepared_bytes(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<(), S3PutError> {
+        let bytes = Arc::new(Bytes::from(bytes));
+        self.put_object(key, bytes.len(), options, bytes_body(bytes))
+            .await
+    }
+
+    /// Uploads the file at `path` to `key`. Without compression the file is
+    /// streamed from disk: each part is read as it is sent, so no more than
+    /// upload_concurrency parts are held in memory at once, and a file under
+    /// multipart_threshold_bytes is sent in a single PUT. With compression it
+    /// is read into memory and uploaded as by put_bytes. An empty file is
+    /// treated as empty bytes are by put_bytes.
+    pub async fn put_file(&self, key: &str, path: &str) -> Result<(), S3PutError> {
+        let span = put_span(key, None);
+        async move {
+            if self.compression.is_some() {
+                let bytes = tokio::fs::read(path)
+                    .await
+                    .map_err(|err| S3PutError::S3PutError(err.to_string()))?;
+                Span::current().record("bytes", bytes.len());
+                let (bytes, options) = self.prepare_bytes(key, bytes)?;
+                return self.put_prepared_bytes(key, bytes, &options).await;
+            }
+
+            let file_size = tokio::fs::metadata(path)
+                .await
+                .map_err(|err| S3PutError::S3PutError(err.to_string()))?
+                .len();
+            Span::current().record("bytes", file_size);
+            self.check_payload_size(key, file_size as usize)?;
+
+            let path = path.to_string();
+
+            self.put_object(
+                key,
+                file_size as usize,
+                &PutOptions::default(),
+                move |range| read_file_part(path.clone(), range),
+            )
+            .await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Uploads the chunks of `stream` to `key` without buffering the whole
+    /// object in memory. Chunks are gathered into parts of at least
+    /// upload_part_size_bytes, or 8 MiB if it is not configured, and no more
+    /// than upload_concurrency parts are buffered or in flight at once, so a
+    /// slow upload slows down reading from `stream`. A stream that ends before
+    /// filling the first part is uploaded in a single request, and one that
+    /// yields no bytes at all fails with EmptyPayload if reject_empty_puts is
+    /// set. If `stream` yields an error the upload is aborted. Objects written
+    /// this way are stored uncompressed and without a checksum, since neither
+    /// can be known before the stream has been read.
+    pub async fn put_stream<S, E>(&self, key: &str, stream: S) -> Result<(), S3PutError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send,
+        E: std::fmt::Display,
+    {
+        let _in_flight = self.requests.begin().ok_or(S3PutError::ShuttingDown)?;
+        let span = put_span(key, None);
+        let start = self.start_timer();
+        let res = with_deadline(
+            self.deadline(),
+            self.upload_stream(key, stream).instrument(span.clone()),
+        )
+        .await
+        .unwrap_or_else(|| Err(S3PutError::Timeout(key.to_string())));
+        if let Ok(total_size_bytes) = &res {
+            span.record("bytes", total_size_bytes);
+        }
+        self.finish_put(key, start, res, None)
+    }
+
+    /// Uploads the chunks of `stream`, which must yield exactly
+    /// `content_length` bytes, to `key` in a single PUT. The body is sent as
+    /// it is read, so this avoids both buffering the object and the extra
+    /// requests of a multipart upload, but S3 takes no more than 5 GiB in a
+    /// single PUT. If `stream` yields more or fewer bytes than declared the
+    /// upload is aborted with ContentLengthMismatch, and if it yields an
+    /// error the upload is aborted with that error. A body that has been
+    /// partly sent cannot be sent again, so a failed upload is not retried.
+    /// Objects written this way are stored uncompressed and without a
+    /// checksum, as by put_stream.
+    pub async fn put_stream_sized<S, E>(
+        &self,
+        key: &str,
+        stream: S,
+        content_length: u64,
+    ) -> Result<(), S3PutError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: std::fmt::Display,
+    {
+        let _in_flight = self.requests.begin().ok_or(S3PutError::ShuttingDown)?;
+        let span = put_span(key, Some(content_length as usize));
+        let start = self.start_timer();
+        self.check_payload_size(key, content_length as usize)?;
+        // Why the body was cut short, which the SDK only reports as a failure
+        // to send the request.
+        let body_error = Arc::new(std::sync::Mutex::new(None));
+        let body = std::sync::Mutex::new(Some(hyper::Body::wrap_stream(length_checked(
+            stream,
+            content_length,
+            body_error.clone(),
+        ))));
+        let res = with_deadline(
+            self.deadline(),
+            self.oneshot_upload(
+                key,
+                content_length as usize,
+                &PutOptions::default(),
+                move |_| {
+                    let body = body.lock().expect("body lock poisoned").take();
+                    future::ready(
+                        body.map(|body| ByteStream::new(SdkBody::from_body_0_4(body)))
+                            .ok_or_else(|| {
+                                S3PutError::S3PutError("stream already sent".to_string())
+                            }),
+                    )
+                    .boxed()
+                },
+            )
+            .instrument(span),
+        )
+        .await
+        .unwrap_or_else(|| Err(S3PutError::Timeout(key.to_string())));
+        let res = match body_error.lock().expect("body error lock poisoned").take() {
+            Some(e) => Err(e),
+            None => res,
+        };
+        self.finish_put(key, start, res.map(|_| content_length as usize), None)
+    }
+
+    async fn upload_stream<S, E>(&self, key: &str, stream: S) -> Result<usize, S3PutError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send,
+        E: std::fmt::Display,
+    {
+        // The size of the stream is not known up front, so the part size
+        // cannot be picked from it.
+        let part_size_bytes = self
+            .upload_part_size_bytes
+            .unwrap_or(DEFAULT_UPLOAD_PART_SIZE_BYTES);
+        let mut stream = Box::pin(stream);
+        let (first_part, ended) = read_part(&mut stream, part_size_bytes).await?;
+        if ended {
+            let total_size_bytes = first_part.len();
+            self.check_payload_size(key, total_size_bytes)?;
+            self.oneshot_upload(key, total_size_bytes, &PutOptions::default(), move |_| {
+                future::ready(Ok(ByteStream::from(first_part.clone()))).boxed()
+            })
+            .await?;
+            return Ok(total_size_bytes);
+        }
+
+        let upload_id = self
+            .create_multipart_upload(key, &PutOptions::default())
+            .await?;
+        let remaining_parts = stream::try_unfold(Some(stream), move |stream| async move {
+            let mut stream = match stream {
+                Some(stream) => stream,
+                None => return Ok(None),
+            };
+            let (part, ended) = read_part(&mut stream, part_size_bytes).await?;
+            match (part.is_empty(), ended) {
+                (true, _) => Ok(None),
+                (false, true) => Ok(Some((part, None))),
+                (false, false) => Ok(Some((part, Some(stream)))),
+            }
+        });
+        let guard = self.abort_on_drop(key, &upload_id);
+        let upload_id_ref = upload_id.as_str();
+        let res = stream::once(future::ready(Ok(first_part)))
+            .chain(remaining_parts)
+            .enumerate()
+            .map(|(part_index, part)| async move {
+                let part: Bytes = part?;
+                let part_size_bytes = part.len();
+                let part_number = part_index as i32 + 1; // Part numbers start at 1
+                let completed_part = self
+                    .upload_part(key, upload_id_ref, part_number, ByteStream::from(part))
+                    .await?;
+                Ok::<_, S3PutError>((completed_part, part_size_bytes))
+            })
+            // Only pulls the next part from the stream once fewer than
+            // upload_concurrency parts are in flight.
+            .buffered(self.upload_concurrency)
+            .try_collect::<Vec<_>>()
+            .await;
+        let res = match res {
+            Ok(parts) => {
+                let total_size_bytes = parts.iter().map(|(_, size)| size).sum();
+                let parts = parts.into_iter().map(|(part, _)| part).collect();
+                self.complete_multipart_upload(key, &upload_id, parts, &PutOptions::default())
+                    .await
+                    .map(|_| total_size_bytes)
+            }
+            Err(e) => Err(e),
+        };
+        guard.disarm();
+        if res.is_err() {
+            self.abort_multipart_upload(key, &upload_id).await;
+        }
+        res
+    }
+
+    async fn put_object(
+        &self,
+        key: &str,
+        total_size_bytes: usize,
+        options: &PutOptions,
+        create_bytestream_fn: impl Fn(
+            Range<usize>,
+        ) -> BoxFuture<'static, Result<ByteStream, S3PutError>>,
+    ) -> Result<(), S3PutError> {
+        let _in_flight = self.requests.begin().ok_or(S3PutError::ShuttingDown)?;
+        self.put_object_in_flight(key, total_size_bytes, options, create_bytestream_fn)
+            .await
+    }
+
+    // Uploads as put_object does, as part of an operation already in flight.
+    async fn put_object_in_flight(
+        &self,
+        key: &str,
+        total_size_bytes: usize,
+        options: &PutOptions,
+        create_bytestream_fn: impl Fn(
+            Range<usize>,
+        ) -> BoxFuture<'static, Result<ByteStream, S3PutError>>,
+    ) -> Result<(), S3PutError> {
+        let start = self.start_timer();
+        let generation = self.cache.as_ref().map(|cache| cache.generation());
+        let res = with_deadline(self.deadline(), async {
+            if total_size_bytes < self.multipart_threshold_bytes {
+                return self
+                    .oneshot_upload(key, total_size_bytes, options, create_bytestream_fn)
+                    .await;
+            }
+
+            self.multipart_upload(key, total_size_bytes, options, create_bytestream_fn)
+                .await
+        })
+        .await
+        .unwrap_or_else(|| Err(S3PutError::Timeout(key.to_string())));
+        let write_through = options.write_through.clone().zip(generation);
+        self.finish_put(key, start, res.map(|_| total_size_bytes), write_through)
+    }
+
+    // Updates the cached object and records metrics once a put of `key` has
+    // finished, returning the put's result. A successful put caches the
+    // `write_through` payload, when given with the cache generation observed
+    // before the put was issued; otherwise the cached object is invalidated.
+    fn finish_put(
+        &self,
+        key: &str,
+        start: Option<Instant>,
+        res: Result<usize, S3PutError>,
+        write_through: Option<(Arc<Vec<u8>>, u64)>,
+    ) -> Result<(), S3PutError> {
+        // Invalidate even if the put failed, since a put that timed out may
+        // still have completed on the server.
+        match (&self.cache, &res, write_through) {
+            (Some(cache), Ok(_), Some((value, generation))) => {
+                cache.replace(&self.cache_key(key), value, generation)
+            }
+            (Some(cache), _, _) => cache.invalidate(&self.cache_key(key)),
+            (None, _, _) => {}
+        }
+        match res {
+            Ok(total_size_bytes) => {
+                self.record(
+                    StorageOperation::Put,
+                    StorageOutcome::Success,
+                    start,
+                    total_size_bytes as u64,
+                );
+                Ok(())
+            }
+            Err(e) => {
+                self.record(StorageOperation::Put, StorageOutcome::Error, start, 0);
+                Err(e)
+            }
+        }
+    }
+
+    async fn oneshot_upload(
+        &self,
+        key: &str,
+        total_size_bytes: usize,
+        options: &PutOptions,
+        create_bytestream_fn: impl Fn(
+            Range<usize>,
+        ) -> BoxFuture<'static, Result<ByteStream, S3PutError>>,
+    ) -> Result<(), S3PutError> {
+        if !self.circuit_allows() {
+            return Err(S3PutError::CircuitOpen);
+        }
+        let body = create_bytestream_fn(0..total_size_bytes).await?;
+        self.admit_bytes(total_size_bytes as u64).await;
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let mut request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .set_metadata(Some(options.metadata.clone()))
+            .set_tagging(options.tagging.clone())
+            .content_type(options.content_type())
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.ssekms_key_id())
+            .content_length(total_size_bytes as i64)
+            .body(body)
+            .customize();
+        if options.if_none_match {
+            request = request.mutate_request(add_if_none_match);
+        }
+        if let Some(etag) = options.if_match.clone() {
+            request = request.mutate_request(move |request| add_if_match(request, &etag));
+        }
+        request.send().await.map_err(put_error)?;
+
+        Ok(())
+    }
+
+    async fn multipart_upload(
+        &self,
+        key: &str,
+        total_size_bytes: usize,
+        options: &PutOptions,
+        create_bytestream_fn: impl Fn(
+            Range<usize>,
+        ) -> BoxFuture<'static, Result<ByteStream, S3PutError>>,
+    ) -> Result<(), S3PutError> {
+        let upload_id = self.create_multipart_upload(key, options).await?;
+
+        let guard = self.abort_on_drop(key, &upload_id);
+        let res = self
+            .upload_parts_and_complete(
+                key,
+                &upload_id,
+                total_size_bytes,
+                options,
+                create_bytestream_fn,
+            )
+            .await;
+        guard.disarm();
+        if res.is_err() {
+            self.abort_multipart_upload(key, &upload_id).await;
+        }
+        res
+    }
+
+    async fn upload_parts_and_complete(
+        &self,
+        key: &str,
+        upload_id: &str,
+        total_size_bytes: usize,
+        options: &PutOptions,
+        create_bytestream_fn: impl Fn(
+            Range<usize>,
+        ) -> BoxFuture<'static, Result<ByteStream, S3PutError>>,
+    ) -> Result<(), S3PutError> {
+        let part_size_bytes = self.upload_part_size_for(total_size_bytes);
+        let mut part_count = (total_size_bytes / part_size_bytes) + 1;
+        let mut size_of_last_part = total_size_bytes % part_size_bytes;
+        if size_of_last_part == 0 {
+            size_of_last_part = part_size_bytes;
+            part_count -= 1;
+        }
+
+        let create_bytestream_fn = &create_bytestream_fn;
+        let upload_parts = stream::iter(0..part_count)
+            .map(|part_index| async move {
+                let this_part = if part_count - 1 == part_index {
+                    size_of_last_part
+                } else {
+                    part_size_bytes
+                };
+                let part_number = part_index as i32 + 1; // Part numbers start at 1
+                let offset = part_index * part_size_bytes;
+                let length = this_part;
+
+                let stream = create_bytestream_fn(offset..(offset + length)).await?;
+                self.upload_part(key, upload_id, part_number, stream).await
+            })
+            // Keeps parts in order while uploading up to the configured
+            // number of them at once.
+            .buffered(self.upload_concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        self.complete_multipart_upload(key, upload_id, upload_parts, options)
+            .await
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        options: &PutOptions,
+    ) -> Result<String, S3PutError> {
+        if !self.circuit_allows() {
+            return Err(S3PutError::CircuitOpen);
+        }
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        match self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .set_metadata(Some(options.metadata.clone()))
+            .set_tagging(options.tagging.clone())
+            .content_type(options.content_type())
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.ssekms_key_id())
+            .send()
+            .await
+            .map_err(put_error)?
+            .upload_id
+        {
+            Some(upload_id) => Ok(upload_id),
+            None => Err(S3PutError::S3PutError(
+                "Multipart upload creation response missing upload ID".to_string(),
+            )),
+        }
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        body: ByteStream,
+    ) -> Result<CompletedPart, S3PutError> {
+        let span = tracing::trace_span!(parent: Span::current(), "Storage put part", part_number);
+        async move {
+            self.admit_bytes(body.size_hint().0).await;
+            let _permit = self.acquire_request_permit().await;
+            self.admit().await;
+            let upload_part_res = self
+                .client
+                .upload_part()
+                .key(self.object_key(key))
+                .bucket(&self.bucket)
+                .upload_id(upload_id)
+                .body(body)
+                .part_number(part_number)
+                .send()
+                .await
+                .map_err(put_error)?;
+
+            Ok(CompletedPart::builder()
+                .e_tag(upload_part_res.e_tag.unwrap_or_default())
+                .part_number(part_number)
+                .build())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+        options: &PutOptions,
+    ) -> Result<(), S3PutError> {
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let mut request = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .upload_id(upload_id)
+            .customize();
+        if options.if_none_match {
+            request = request.mutate_request(add_if_none_match);
+        }
+        if let Some(etag) = options.if_match.clone() {
+            request = request.mutate_request(move |request| add_if_match(request, &etag));
+        }
+        request.send().await.map_err(put_error)?;
+
+        Ok(())
+    }
+
+    // Parts of an upload that is never completed or aborted are retained, and
+    // billed, by s3 indefinitely.
+    async fn abort_multipart_upload(&self, key: &str, upload_id: &str) {
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let res = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .upload_id(upload_id)
+            .send()
+            .await;
+        if let Err(e) = res {
+            tracing::error!(
+                "error aborting multipart upload {} of {}: {}",
+                upload_id,
+                key,
+                e
+            );
+        }
+    }
+
+    // Returns a guard that aborts the multipart upload if it is dropped while
+    // still armed, i.e. if the future driving the upload is dropped before the
+    // upload completes or is aborted.
+    fn abort_on_drop(&self, key: &str, upload_id: &str) -> AbortOnDrop {
+        AbortOnDrop {
+            upload: Some((self.clone(), key.to_string(), upload_id.to_string())),
+        }
+    }
+
+    /// Copies the object at `src_key` to `dst_key` within the bucket without
+    /// downloading it, overwriting any object already at `dst_key`. The
+    /// object's metadata is copied along with it. Objects too large for a
+    /// single server-side copy are copied in parts.
+    pub async fn copy(&self, src_key: &str, dst_key: &str) -> Result<(), S3CopyError> {
+        let _in_flight = self.requests.begin().ok_or(S3CopyError::ShuttingDown)?;
+        self.copy_in_flight(src_key, dst_key).await
+    }
+
+    // Copies as copy does, as part of an operation already in flight.
+    async fn copy_in_flight(&self, src_key: &str, dst_key: &str) -> Result<(), S3CopyError> {
+        let source = {
+            let _permit = self.acquire_request_permit().await;
+            self.admit().await;
+            self.client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(src_key))
+                .set_request_payer(self.request_payer())
+                .send()
+                .await
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => {
+                return Err(S3CopyError::SourceNotFound(src_key.to_string()));
+            }
+            Err(e) => return Err(S3CopyError::S3CopyError(e.to_string())),
+        };
+        let size = source.content_length.unwrap_or_default().max(0) as u64;
+        let copy_source = format!(
+            "{}/{}",
+            self.bucket,
+            utf8_percent_encode(&self.object_key(src_key), COPY_SOURCE_KEY)
+        );
+
+        let res = if size <= self.max_single_copy_bytes {
+            self.copy_object(&copy_source, src_key, dst_key).await
+        } else {
+            self.multipart_copy(
+                &copy_source,
+                dst_key,
+                size,
+                source.metadata,
+                source.content_type,
+            )
+            .await
+        };
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&self.cache_key(dst_key));
+        }
+        res
+    }
+
+    async fn copy_object(
+        &self,
+        copy_source: &str,
+        src_key: &str,
+        dst_key: &str,
+    ) -> Result<(), S3CopyError> {
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let res = self
+            .client
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(dst_key))
+            .copy_source(copy_source)
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.ssekms_key_id())
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            // The source may have been deleted since it was looked up.
+            Err(e) if e.code() == Some("NoSuchKey") => {
+                Err(S3CopyError::SourceNotFound(src_key.to_string()))
+            }
+            Err(e) => {
+                tracing::error!("error copying {} to {}: {}", src_key, dst_key, e);
+                Err(S3CopyError::S3CopyError(e.to_string()))
+            }
+        }
+    }
+
+    async fn multipart_copy(
+        &self,
+        copy_source: &str,
+        dst_key: &str,
+        size: u64,
+        metadata: Option<HashMap<String, String>>,
+        content_type: Option<String>,
+    ) -> Result<(), S3CopyError> {
+        let upload_id = {
+            let _permit = self.acquire_request_permit().await;
+            self.admit().await;
+            self.client
+                .create_multipart_upload()
+                .bucket(&self.bucket)
+                .key(self.object_key(dst_key))
+                .set_metadata(metadata)
+                .set_content_type(content_type)
+                .set_server_side_encryption(self.server_side_encryption())
+                .set_ssekms_key_id(self.ssekms_key_id())
+                .send()
+                .await
+                .map_err(|err| S3CopyError::S3CopyError(err.to_string()))?
+                .upload_id
+                .ok_or_else(|| {
+                    S3CopyError::S3CopyError(
+                        "Multipart upload creation response missing upload ID".to_string(),
+                    )
+                })?
+        };
+
+        let guard = self.abort_on_drop(dst_key, &upload_id);
+        let res = self
+            .copy_parts_and_complete(copy_source, dst_key, &upload_id, size)
+            .await;
+        guard.disarm();
+        if res.is_err() {
+            self.abort_multipart_upload(dst_key, &upload_id).await;
+        }
+        res
+    }
+
+    async fn copy_parts_and_complete(
+        &self,
+        copy_source: &str,
+        dst_key: &str,
+        upload_id: &str,
+        size: u64,
+    ) -> Result<(), S3CopyError> {
+        let part_size = self.upload_part_size_for(size as usize) as u64;
+        let part_count = size.div_ceil(part_size);
+        let parts = stream::iter(0..part_count)
+            .map(|part_index| async move {
+                let part_number = part_index as i32 + 1; // Part numbers start at 1
+                let offset = part_index * part_size;
+                let end = (offset + part_size).min(size);
+
+                let _permit = self.acquire_request_permit().await;
+                self.admit().await;
+                let res = self
+                    .client
+                    .upload_part_copy()
+                    .bucket(&self.bucket)
+                    .key(self.object_key(dst_key))
+                    .upload_id(upload_id)
+                    .copy_source(copy_source)
+                    // HTTP ranges are inclusive of the last byte.
+                    .copy_source_range(format!("bytes={}-{}", offset, end - 1))
+                    .part_number(part_number)
+                    .send()
+                    .await
+                    .map_err(|err| S3CopyError::S3CopyError(err.to_string()))?;
+
+                Ok::<_, S3CopyError>(
+                    CompletedPart::builder()
+                        .e_tag(
+                            res.copy_part_result
+                                .and_then(|result| result.e_tag)
+                                .unwrap_or_default(),
+                        )
+                        .part_number(part_number)
+                        .build(),
+                )
+            })
+            .buffered(self.upload_concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(self.object_key(dst_key))
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .upload_id(upload_id)
+            .send()
+            .await
+            .map_err(|err| S3CopyError::S3CopyError(err.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Returns a URL that downloads the object at `key` without further
+    /// credentials until `expires_in` has passed. The URL is signed locally
+    /// with the client's credentials, and stops working early if those are
+    /// temporary and expire first.
+    pub async fn presigned_get_url(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, S3PresignError> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .set_request_payer(self.request_payer())
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(|err| S3PresignError::S3PresignError(err.to_string()))?;
+        Ok(request.uri().to_string())
+    }
+
+    /// Returns a URL that uploads a single PUT body to `key` without further
+    /// credentials until `expires_in` has passed, as presigned_get_url does
+    /// for downloads.
+    pub async fn presigned_put_url(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, S3PresignError> {
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .presigned(presigning_config(expires_in)?)
+            .await
+            .map_err(|err| S3PresignError::S3PresignError(err.to_string()))?;
+        Ok(request.uri().to_string())
+    }
+
+    /// Fetches the metadata of the object at `key` without downloading it.
+    /// Returns None if the key does not exist.
+    pub async fn head(&self, key: &str) -> Result<Option<ObjectMetadata>, S3HeadError> {
+        let _in_flight = self.requests.begin().ok_or(S3HeadError::ShuttingDown)?;
+        Ok(self.head_object(key).await?.map(|res| ObjectMetadata {
+            size: res.content_length.unwrap_or_default().max(0) as u64,
+            etag: res.e_tag,
+            last_modified: res
+                .last_modified
+                .and_then(|last_modified| last_modified.try_into().ok()),
+            content_type: res.content_type,
+            metadata: res
+                .metadata
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(key, _)| !RESERVED_METADATA_KEYS.contains(&key.as_str()))
+                .collect(),
+        }))
+    }
+
+    // Issues the HEAD for `key`, returning None if it does not exist.
+    async fn head_object(&self, key: &str) -> Result<Option<HeadObjectOutput>, S3HeadError> {
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let res = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .set_request_payer(self.request_payer())
+            .send()
+            .await;
+        match res {
+            Ok(res) => Ok(Some(res)),
+            Err(e) => {
+                // HEAD responses have no body, so a missing key is only
+                // identified by its status code.
+                if e.as_service_error()
+                    .map(|e| e.is_not_found())
+                    .unwrap_or(false)
+                {
+                    return Ok(None);
+                }
+                tracing::error!("error heading {}: {}", key, e);
+                Err(S3HeadError::S3HeadError(e.to_string()))
+            }
+        }
+    }
+
+    /// Moves the object at `src_key` to `dst_key`, as a server-side copy
+    /// followed by a delete of the source. As with copy, an object already at
+    /// `dst_key` is overwritten.
+    ///
+    /// S3 has no rename, so this is not atomic: until it returns, readers
+    /// may see the object at both keys. If the copy fails nothing is
+    /// renamed. If the delete of the source fails the copy is rolled back by
+    /// deleting `dst_key`, leaving only the source, so a failed rename never
+    /// silently leaves a duplicate; note that an object `dst_key` held
+    /// before the rename is then gone too. Only if the rollback fails as well
+    /// are both left in place, which RollbackFailed reports.
+    pub async fn rename(&self, src_key: &str, dst_key: &str) -> Result<(), S3RenameError> {
+        // Held across the copy, the delete and the rollback, so that a
+        // shutdown cannot leave the rename half done.
+        let _in_flight = self.requests.begin().ok_or(S3RenameError::ShuttingDown)?;
+        match self.copy_in_flight(src_key, dst_key).await {
+            Ok(()) => {}
+            Err(S3CopyError::SourceNotFound(key)) => {
+                return Err(S3RenameError::SourceNotFound(key))
+            }
+            Err(e) => return Err(S3RenameError::CopyFailed(e)),
+        }
+        let delete_error = match self.delete_in_flight(src_key).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        tracing::error!(
+            "failed to delete {} after copying it to {}, rolling back: {}",
+            src_key,
+            dst_key,
+            delete_error
+        );
+        match self.delete_in_flight(dst_key).await {
+            Ok(()) => Err(S3RenameError::SourceDeleteFailed(delete_error)),
+            Err(rollback_error) => Err(S3RenameError::RollbackFailed {
+                src_key: src_key.to_string(),
+                dst_key: dst_key.to_string(),
+                delete_error,
+                rollback_error,
+            }),
+        }
+    }
+
+    /// Deletes the object at `key`. Deleting a key that does not exist is
+    /// not an error. A get whose stream is already open is unaffected and
+    /// will still read the full object.
+    pub async fn delete(&self, key: &str) -> Result<(), S3DeleteError> {
+        let _in_flight = self.requests.begin().ok_or(S3DeleteError::ShuttingDown)?;
+        self.delete_in_flight(key).await
+    }
+
+    // Deletes as delete does, as part of an operation already in flight.
+    async fn delete_in_flight(&self, key: &str) -> Result<(), S3DeleteError> {
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let res = self
+            .client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await;
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&self.cache_key(key));
+        }
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let code = e.code().map(|code| code.to_string());
+                // S3 itself returns success for missing keys, but some S3
+                // compatible stores report them as errors.
+                if code.as_deref() == Some("NoSuchKey") {
+                    return Ok(());
+                }
+                tracing::error!("error deleting {}: {}", key, e);
+                Err(S3DeleteError::S3DeleteError {
+                    code,
+                    message: e.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Deletes the object at `key` only if its ETag is still `etag`. Returns
+    /// true if it was deleted, and false if it was not: either it has been
+    /// overwritten since it had `etag`, or it no longer exists. Use this to
+    /// clean up an object without deleting a newer one written in its place.
+    pub async fn delete_if_match(&self, key: &str, etag: &str) -> Result<bool, S3DeleteError> {
+        let _in_flight = self.requests.begin().ok_or(S3DeleteError::ShuttingDown)?;
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let etag = etag.to_string();
+        let res = self
+            .client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .customize()
+            .mutate_request(move |request| add_if_match(request, &etag))
+            .send()
+            .await;
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&self.cache_key(key));
+        }
+        match res {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let code = e.code().map(|code| code.to_string());
+                if matches!(code.as_deref(), Some("PreconditionFailed" | "NoSuchKey")) {
+                    return Ok(false);
+                }
+                tracing::error!("error deleting {}: {}", key, e);
+                Err(S3DeleteError::S3DeleteError {
+                    code,
+                    message: e.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Deletes the objects at `keys` with as few DeleteObjects requests as
+    /// possible, each covering up to 1000 keys. Keys S3 fails to delete are
+    /// reported with their error rather than failing the call, so callers
+    /// can retry just those; as with delete, missing keys count as deleted.
+    /// Returns an error if a request fails as a whole. Keys deleted by
+    /// earlier requests stay deleted, and since deletes are idempotent the
+    /// whole call can safely be retried.
+    pub async fn delete_many(&self, keys: Vec<String>) -> Result<DeleteManyReport, S3DeleteError> {
+        let _in_flight = self.requests.begin().ok_or(S3DeleteError::ShuttingDown)?;
+        let mut report = DeleteManyReport::default();
+        for batch in keys.chunks(DELETE_OBJECTS_MAX_KEYS) {
+            let mut failed = self.delete_batch(batch).await?;
+            for key in batch {
+                match failed.remove(key) {
+                    Some(error) => report.failed.push((key.clone(), error)),
+                    None => report.deleted.push(key.clone()),
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // Deletes `keys` with a single DeleteObjects request and returns the
+    // keys S3 failed to delete, with their errors.
+    async fn delete_batch(
+        &self,
+        keys: &[String],
+    ) -> Result<HashMap<String, S3DeleteError>, S3DeleteError> {
+        let objects = keys
+            .iter()
+            .map(|key| {
+                ObjectIdentifier::builder()
+                    .key(self.object_key(key))
+                    .build()
+                    .map_err(|e| S3DeleteError::S3DeleteError {
+                        code: None,
+                        message: e.to_string(),
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // Quiet responses only list the keys that failed.
+        let delete = Delete::builder()
+            .set_objects(Some(objects))
+            .quiet(true)
+            .build()
+            .map_err(|e| S3DeleteError::S3DeleteError {
+                code: None,
+                message: e.to_string(),
+            })?;
+
+        let _permit = self.acquire_request_permit().await;
+        self.admit().await;
+        let res = self
+            .client
+            .delete_objects()
+            .bucket(&self.bucket)
+            .delete(delete)
+            .send()
+            .await;
+        if let Some(cache) = &self.cache {
+            for key in keys {
+                cache.invalidate(&self.cache_key(key));
+            }
+        }
+        let output = res.map_err(|e| {
+            tracing::error!("error deleting {} keys: {}", keys.len(), e);
+            S3DeleteError::S3DeleteError {
+                code: e.code().map(|code| code.to_string()),
+                message: e.to_string(),
+            }
+        })?;
+        Ok(output
+            .errors()
+            .iter()
+            // Some S3 compatible stores report missing keys as errors.
+            .filter(|error| error.code() != Some("NoSuchKey"))
+            .filter_map(|error| {
+                let key = self.logical_key(error.key()?).to_string();
+                tracing::error!("error deleting {}: {:?}", key, error.message());
+                Some((
+                    key,
+                    S3DeleteError::S3DeleteError {
+                        code: error.code().map(|code| code.to_string()),
+                        message: error.message().unwrap_or_default().to_string(),
+                    },
+                ))
+            })
+            .collect())
+    }
+
+    /// Returns whether an object exists at each of `keys`. When there are at
+    /// least exists_list_threshold keys and they share a common prefix, they
+    /// are answered from a listing of that prefix; otherwise, or if the
+    /// prefix turns out to hold many more objects than were asked about,
+    /// the keys not yet found are checked with one HEAD each.
+    pub async fn exists_many(
+        &self,
+        keys: Vec<String>,
+    ) -> Result<HashMap<String, bool>, S3HeadError> {
+        let _in_flight = self.requests.begin().ok_or(S3HeadError::ShuttingDown)?;
+        let mut exists = HashMap::with_capacity(keys.len());
+        // The keys asked about by the key they are listed under, which only
+        // differ when keys are normalized.
+        let mut remaining = HashMap::<String, HashSet<String>>::new();
+        for key in keys {
+            remaining
+                .entry(self.cache_key(&key).into_owned())
+                .or_default()
+                .insert(key);
+        }
+        let prefix = common_prefix(&remaining.keys().cloned().collect());
+        if remaining.len() >= self.exists_list_threshold && !prefix.is_empty() {
+            let max_listed = remaining.len() * EXISTS_LIST_MAX_DENSITY;
+            let mut listed = 0;
+            let mut keys = Box::pin(self.list_keys(&prefix));
+            let mut complete = true;
+            while let Some(key) = keys.next().await {
+                let key = key.map_err(|e| S3HeadError::S3HeadError(e.to_string()))?;
+                if let Some(asked) = remaining.remove(&key) {
+                    exists.extend(asked.into_iter().map(|key| (key, true)));
+                }
+                listed += 1;
+                if listed >= max_listed && !remaining.is_empty() {
+                    complete = false;
+                    break;
+                }
+            }
+            if complete {
+                exists.extend(
+                    remaining
+                        .drain()
+                        .flat_map(|(_, asked)| asked)
+                        .map(|key| (key, false)),
+                );
+            }
+        }
+
+        let heads = stream::iter(remaining)
+            .map(|(key, asked)| async move {
+                let metadata = self.head_object(&key).await?;
+                Ok::<_, S3HeadError>((asked, metadata.is_some()))
+            })
+            .buffer_unordered(EXISTS_HEAD_PARALLELISM)
+            .try_collect::<Vec<_>>()
+            .await?;
+        for (asked, found) in heads {
+            exists.extend(asked.into_iter().map(|key| (key, found)));
+        }
+        Ok(exists)
+    }
+
+    /// Lists the keys under `prefix`, following S3 continuation tokens
+    /// across pages. Pages are fetched lazily as the stream is polled, so a
+    /// caller that stops early does not fetch the remaining pages.
+    pub fn list_prefix(
+        &self,
+        prefix: &str,
+    ) -> impl Stream<Item = Result<String, S3ListError>> + Send + 'static {
+        self.track_stream(self.list_keys(prefix), S3ListError::ShuttingDown)
+    }
+
+    // Lists as list_prefix does, as part of an operation already in flight.
+    fn list_keys(
+        &self,
+        prefix: &str,
+    ) -> impl Stream<Item = Result<String, S3ListError>> + Send + 'static {
+        let storage = self.clone();
+        self.list_pages(prefix)
+            .map_ok(move |page| {
+                let keys = page
+                    .contents()
+                    .iter()
+                    .filter_map(|object| object.key())
+                    .map(|key| Ok(storage.logical_key(key).to_string()))
+                    .collect::<Vec<_>>();
+                stream::iter(keys)
+            })
+            .try_flatten()
+    }
+
+    /// Returns the number of objects under `prefix` and their total size in
+    /// bytes. The listing is summed one page at a time, so the keys are never
+    /// held in memory all at once.
+    pub async fn prefix_size(&self, prefix: &str) -> Result<(u64, u64), S3ListError> {
+        let _in_flight = self.requests.begin().ok_or(S3ListError::ShuttingDown)?;
+        self.list_pages(prefix)
+            .try_fold((0, 0), |(object_count, total_bytes), page| async move {
+                let page_bytes = page
+                    .contents()
+                    .iter()
+                    .map(|object| object.size().unwrap_or(0).max(0) as u64)
+                    .sum::<u64>();
+                Ok((
+                    object_count + page.contents().len() as u64,
+                    total_bytes + page_bytes,
+                ))
+            })
+            .await
+    }
+
+    // Runs `stream` as an operation of its own: it is admitted when this is
+    // called, or yields just `shutting_down` once shutdown has started, and
+    // counts as in flight until it ends or is dropped.
+    fn track_stream<T, E>(
+        &self,
+        stream: impl Stream<Item = Result<T, E>> + Send + 'static,
+        shutting_down: E,
+    ) -> impl Stream<Item = Result<T, E>> + Send + 'static
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let Some(in_flight) = self.requests.begin() else {
+            return stream::once(future::ready(Err(shutting_down))).left_stream();
+        };
+        stream::unfold(
+            (Box::pin(stream), in_flight),
+            |(mut stream, in_flight)| async move {
+                let item = stream.next().await?;
+                Some((item, (stream, in_flight)))
+            },
+        )
+        .right_stream()
+    }
+
+    // The pages of a listing of `prefix`, fetched lazily as the stream is
+    // polled.
+    fn list_pages(
+        &self,
+        prefix: &str,
+    ) -> impl Stream<Item = Result<ListObjectsV2Output, S3ListError>> + Send + 'static {
+        let storage = self.clone();
+        let prefix = prefix.to_string();
+        stream::try_unfold(ListState::Start, move |state| {
+            let storage = storage.clone();
+            let prefix = prefix.clone();
+            async move {
+                let continuation_token = match state {
+                    ListState::Start => None,
+                    ListState::Continue(token) => Some(token),
+                    ListState::Done => return Ok(None),
+                };
+                let _permit = storage.acquire_request_permit().await;
+                storage.admit().await;
+                let res = storage
+                    .client
+                    .list_objects_v2()
+                    .bucket(&storage.bucket)
+                    .prefix(storage.object_key(&prefix))
+                    .set_request_payer(storage.request_payer())
+                    .set_continuation_token(continuation_token)
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        tracing::error!("error listing prefix {}: {}", prefix, e);
+                        S3ListError::S3ListError(e.to_string())
+                    })?;
+                let next_state = match (res.is_truncated(), res.next_continuation_token()) {
+                    (Some(true), Some(token)) => ListState::Continue(token.to_string()),
+                    _ => ListState::Done,
+                };
+                Ok(Some((res, next_state)))
+            }
+        })
+    }
+
+    /// Streams the records of the object at `key` that match the SQL
+    /// `expression`, filtered by S3 Select so that only they are transferred.
+    /// The request is sent when the stream is first polled. Returns
+    /// S3SelectError::InvalidExpression if S3 cannot parse the expression.
+    pub fn select(
+        &self,
+        key: &str,
+        expression: &str,
+        input_format: SelectFormat,
+    ) -> impl Stream<Item = Result<Bytes, S3SelectError>> + Send + 'static {
+        let storage = self.clone();
+        let key = key.to_string();
+        let expression = expression.to_string();
+        let (input, output) = input_format.serialization();
+        let records = stream::once(async move {
+            let _permit = storage.acquire_request_permit().await;
+            storage.admit().await;
+            let res = storage
+                .client
+                .select_object_content()
+                .bucket(&storage.bucket)
+                .key(storage.object_key(&key))
+                .expression(expression)
+                .expression_type(ExpressionType::Sql)
+                .input_serialization(input)
+                .output_serialization(output)
+                .send()
+                .await
+                .map_err(|e| select_error(&key, e))?;
+            Ok::<_, S3SelectError>((key, res.payload))
+        })
+        .map_ok(|(key, payload)| {
+            stream::try_unfold(Some(payload), move |payload| {
+                let key = key.clone();
+                async move {
+                    let Some(mut payload) = payload else {
+                        return Ok(None);
+                    };
+                    loop {
+                        match payload.recv().await.map_err(|e| select_error(&key, e))? {
+                            Some(SelectObjectContentEventStream::Records(records)) => {
+                                if let Some(records) = records.payload {
+                                    return Ok(Some((
+                                        Bytes::from(records.into_inner()),
+                                        Some(payload),
+                                    )));
+                                }
+                            }
+                            Some(SelectObjectContentEventStream::End(_)) => return Ok(None),
+                            // Progress, stats and keep-alive events.
+                            Some(_) => {}
+                            // S3 ends every complete response with an end
+                            // event.
+                            None => {
+                                return Err(S3SelectError::S3SelectError(format!(
+                                    "{}: response ended before the end event",
+                                    key
+                                )))
+                            }
+                        }
+                    }
+                }
+            })
+        })
+        .try_flatten();
+        self.track_stream(records, S3SelectError::ShuttingDown)
+    }
+
+    /// Lists the versions of the object at `key`, newest first. Delete
+    /// markers are left out, as there is nothing to get from them. Lists a
+    /// single version with the id "null" if the bucket never had versioning
+    /// enabled, and nothing if there is no object at `key`.
+    pub async fn list_versions(&self, key: &str) -> Result<Vec<ObjectVersion>, S3ListError> {
+        let _in_flight = self.requests.begin().ok_or(S3ListError::ShuttingDown)?;
+        let object_key = self.object_key(key);
+        let mut versions = Vec::new();
+        let mut markers: Option<(Option<String>, Option<String>)> = None;
+        loop {
+            let (key_marker, version_id_marker) = markers.take().unwrap_or_default();
+            let _permit = self.acquire_request_permit().await;
+            self.admit().await;
+            let res = self
+                .client
+                .list_object_versions()
+                .bucket(&self.bucket)
+                // Versions are listed by prefix, which may match other keys.
+                .prefix(object_key.as_ref())
+                .set_request_payer(self.request_payer())
+                .set_key_marker(key_marker)
+                .set_version_id_marker(version_id_marker)
+                .send()
+                .await
+                .map_err(|e| {
+                    tracing::error!("error listing versions of {}: {}", key, e);
+                    S3ListError::S3ListError(e.to_string())
+                })?;
+            versions.extend(
+                res.versions()
+                    .iter()
+                    .filter(|version| version.key() == Some(object_key.as_ref()))
+                    .map(|version| ObjectVersion {
+                        version_id: version.version_id().unwrap_or("null").to_string(),
+                        size: version.size().unwrap_or(0).max(0) as u64,
+                        is_latest: version.is_latest().unwrap_or(false),
+                    }),
+            );
+            if res.is_truncated() != Some(true) {
+                return Ok(versions);
+            }
+            markers = Some((
+                res.next_key_marker().map(str::to_string),
+                res.next_version_id_marker().map(str::to_string),
+            ));
+        }
+    }
+}
+
+// The body of a put of `bytes`, for put_object.
+fn bytes_body(
+    bytes: Arc<Bytes>,
+) -> impl Fn(Range<usize>) -> BoxFuture<'static, Result<ByteStream, S3PutError>> {
+    move |range| {
+        let bytes = bytes.clone();
+        async move { Ok(ByteStream::from(bytes.slice(range))) }.boxed()
+    }
+}
+
+// Normalizes a configured key prefix to end in a single slash, so that it
+// joins cleanly with keys. An empty prefix is no prefix.
+fn key_prefix(prefix: &str) -> Option<String> {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        return None;
+    }
+    Some(format!("{}/", prefix))
+}
+
+fn file_error(path: &str, e: std::io::Error) -> S3GetError {
+    S3GetError::FileError(format!("{}: {}", path, e))
+}
+
+// Writes the chunks of `stream` to `file`, which was opened at `path`, and
+// returns the number of bytes written.
+async fn write_stream(
+    mut file: tokio::fs::File,
+    mut stream: impl Stream<Item = ByteStreamItem> + Unpin,
+    path: &str,
+) -> Result<u64, S3GetError> {
+    let mut written = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| match e {
+            GetError::S3Error(e) => e,
+            e => S3GetError::ByteStreamError(e.to_string()),
+        })?;
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| file_error(path, e))?;
+        written += chunk.len() as u64;
+    }
+    file.flush().await.map_err(|e| file_error(path, e))?;
+    Ok(written)
+}
+
+async fn remove_if_exists(path: &str) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Runs `future` to completion, or returns None if `deadline` passes first.
+async fn with_deadline<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+// Reads chunks from `stream` until at least `part_size_bytes` have been read
+// or the stream ends. Returns the bytes read and whether the stream ended.
+async fn read_part<S, E>(
+    stream: &mut Pin<Box<S>>,
+    part_size_bytes: usize,
+) -> Result<(Bytes, bool), S3PutError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::fmt::Display,
+{
+    let mut part = BytesMut::new();
+    while part.len() < part_size_bytes {
+        match stream.next().await {
+            Some(Ok(chunk)) => part.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                return Err(S3PutError::S3PutError(format!(
+                    "error reading stream: {}",
+                    e
+                )))
+            }
+            None => return Ok((part.freeze(), true)),
+        }
+    }
+    Ok((part.freeze(), false))
+}
+
+// Returns the bytes in `start..end` of `bytes`, with the same semantics as an
+// S3 range read.
+fn slice_range(key: &str, bytes: &[u8], start: u64, end: u64) -> Result<Arc<Vec<u8>>, S3GetError> {
+    if start >= bytes.len() as u64 {
+        return Err(S3GetError::RangeNotSatisfiable(format!(
+            "{}: {}..{}",
+            key, start, end
+        )));
+    }
+    let end = end.min(bytes.len() as u64);
+    Ok(Arc::new(bytes[start as usize..end as usize].to_vec()))
+}
+
+// The metadata keys storage sets itself, which user metadata may not use.
+const RESERVED_METADATA_KEYS: [&str; 2] = [CHECKSUM_METADATA_KEY, COMPRESSION_METADATA_KEY];
+// S3's limit on the user metadata of an object, summed over the bytes of
+// every key and value.
+const MAX_METADATA_BYTES: usize = 2048;
+
+// Validates `metadata` against the S3 limits, and checks that it reads back
+// as written.
+fn validate_metadata(metadata: &HashMap<String, String>) -> Result<(), S3PutError> {
+    let mut size = 0;
+    for (key, value) in metadata {
+        let valid_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !valid_key {
+            return Err(S3PutError::InvalidMetadata(format!(
+                "key {:?} is not lowercase letters, digits, - and _",
+                key
+            )));
+        }
+        if RESERVED_METADATA_KEYS.contains(&key.as_str()) {
+            return Err(S3PutError::InvalidMetadata(format!(
+                "key {} is reserved",
+                key
+            )));
+        }
+        let valid_value =
+            value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) && value.trim() == value;
+        if !valid_value {
+            return Err(S3PutError::InvalidMetadata(format!(
+                "value of {} is not printable ASCII without surrounding whitespace",
+                key
+            )));
+        }
+        size += key.len() + value.len();
+    }
+    if size > MAX_METADATA_BYTES {
+        return Err(S3PutError::InvalidMetadata(format!(
+            "{} bytes exceeds the maximum of {}",
+            size, MAX_METADATA_BYTES
+        )));
+    }
+    Ok(())
+}
+
+// S3's limits on object tags.
+const MAX_TAGS: usize = 10;
+const MAX_TAG_KEY_CHARS: usize = 128;
+const MAX_TAG_VALUE_CHARS: usize = 256;
+// Characters left unescaped in the keys and values of a tag set.
+const TAG_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+// Validates `tags` against the S3 limits and encodes them as the query string
+// S3 expects in the x-amz-tagging header.
+fn encode_tags(tags: &HashMap<String, String>) -> Result<String, S3PutError> {
+    if tags.len() > MAX_TAGS {
+        return Err(S3PutError::InvalidTag(format!(
+            "{} tags exceeds the maximum of {}",
+            tags.len(),
+            MAX_TAGS
+        )));
+    }
+    let valid_chars = |s: &str| {
+        s.chars()
+            .all(|c| c.is_alphanumeric() || " +-=._:/@".contains(c))
+    };
+    let mut tags = tags.iter().collect::<Vec<_>>();
+    tags.sort();
+    let mut encoded = Vec::with_capacity(tags.len());
+    for (tag_key, tag_value) in tags {
+        let key_chars = tag_key.chars().count();
+        if key_chars == 0 || key_chars > MAX_TAG_KEY_CHARS {
+            return Err(S3PutError::InvalidTag(format!(
+                "key of {} characters is not between 1 and {}",
+                key_chars, MAX_TAG_KEY_CHARS
+            )));
+        }
+        if tag_key.starts_with("aws:") {
+            return Err(S3PutError::InvalidTag(format!(
+                "key {} uses the reserved aws: prefix",
+                tag_key
+            )));
+        }
+        if tag_value.chars().count() > MAX_TAG_VALUE_CHARS {
+            return Err(S3PutError::InvalidTag(format!(
+                "value of {} exceeds {} characters",
+                tag_key, MAX_TAG_VALUE_CHARS
+            )));
+        }
+        if !valid_chars(tag_key) || !valid_chars(tag_value) {
+            return Err(S3PutError::InvalidTag(format!(
+                "{}={} contains characters S3 does not allow",
+                tag_key, tag_value
+            )));
+        }
+        encoded.push(format!(
+            "{}={}",
+            utf8_percent_encode(tag_key, TAG_COMPONENT),
+            utf8_percent_encode(tag_value, TAG_COMPONENT)
+        ));
+    }
+    Ok(encoded.join("&"))
+}
+
+// The longest expiry S3 accepts for a presigned URL.
+const MAX_PRESIGNED_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+fn presigning_config(expires_in: Duration) -> Result<PresigningConfig, S3PresignError> {
+    if expires_in > MAX_PRESIGNED_EXPIRY {
+        return Err(S3PresignError::ExpiryTooLong(expires_in));
+    }
+    PresigningConfig::expires_in(expires_in)
+        .map_err(|err| S3PresignError::S3PresignError(err.to_string()))
+}
+
+// The span covering a put of `key`. The byte count is left empty when it is
+// only known once the upload has started, and recorded then.
+fn put_span(key: &str, bytes: Option<usize>) -> Span {
+    let span = tracing::trace_span!(
+        parent: Span::current(),
+        "Storage put",
+        key,
+        bytes = tracing::field::Empty
+    );
+    if let Some(bytes) = bytes {
+        span.record("bytes", bytes);
+    }
+    span
+}
+
+// Opens the `range` of the file at `path` as the body of a part. The bytes are
+// read from disk as the body is sent rather than up front.
+fn read_file_part(
+    path: String,
+    range: Range<usize>,
+) -> BoxFuture<'static, Result<ByteStream, S3PutError>> {
+    async move {
+        ByteStream::read_from()
+            .path(path)
+            .offset(range.start as u64)
+            .length(Length::Exact(range.len() as u64))
+            .build()
+            .await
+            .map_err(|err| S3PutError::S3PutError(err.to_string()))
+    }
+    .boxed()
+}
+
+// Passes on the chunks of `stream`, ending it with an error as soon as it is
+// known not to yield exactly `content_length` bytes, or if it yields an
+// error. The reason is left in `body_error`.
+fn length_checked<S, E>(
+    stream: S,
+    content_length: u64,
+    body_error: Arc<std::sync::Mutex<Option<S3PutError>>>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: std::fmt::Display,
+{
+    let fail = move |e: S3PutError| {
+        let message = e.to_string();
+        *body_error.lock().expect("body error lock poisoned") = Some(e);
+        Err(std::io::Error::other(message))
+    };
+    stream::unfold(Some((Box::pin(stream), 0u64)), move |state| {
+        let fail = fail.clone();
+        async move {
+            let (mut stream, sent) = state?;
+            let mismatch = |actual| S3PutError::ContentLengthMismatch {
+                expected: content_length,
+                actual,
+            };
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    let sent = sent + chunk.len() as u64;
+                    if sent > content_length {
+                        return Some((fail(mismatch(sent)), None));
+                    }
+                    Some((Ok(chunk), Some((stream, sent))))
+                }
+                Some(Err(e)) => Some((fail(S3PutError::S3PutError(e.to_string())), None)),
+                None if sent < content_length => Some((fail(mismatch(sent)), None)),
+                None => None,
+            }
+        }
+    })
+}
+
+// The SDK does not model If-None-Match on PutObject or
+// CompleteMultipartUpload, so the header is added to the raw request.
+fn add_if_none_match(request: &mut HttpRequest) {
+    request.headers_mut().insert("If-None-Match", "*");
+}
+
+// Neither do they model If-Match, on those or on DeleteObject.
+fn add_if_match(request: &mut HttpRequest, etag: &str) {
+    request.headers_mut().insert("If-Match", etag.to_string());
+}
+
+// Maps the error of a request that writes an object, surfacing a failed
+// If-None-Match condition as PreconditionFailed.
+fn put_error<E>(err: SdkError<E, HttpResponse>) -> S3PutError
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    if err.code() == Some("PreconditionFailed") {
+        return S3PutError::PreconditionFailed(err.to_string());
+    }
+    if let SdkError::ServiceError(_) = &err {
+        return S3PutError::ServiceError {
+            message: format!(
+                "{}: {}",
+                err.code().unwrap_or("unknown"),
+                err.message().unwrap_or("no message")
+            ),
+            request_ids: RequestIds::of(&err),
+        };
+    }
+    S3PutError::S3PutError(err.to_string())
+}
+
+fn get_object_error(e: SdkError<GetObjectError>) -> S3GetError {
+    // The response to a get whose If-None-Match ETag still matches, which has
+    // no body to parse an error from.
+    if e.raw_response()
+        .is_some_and(|response| response.status().as_u16() == 304)
+    {
+        return S3GetError::NotModified;
+    }
+    tracing::error!("error: {}", e);
+    let request_ids = RequestIds::of(&e);
+    let status = e.raw_response().map(|response| response.status().as_u16());
+    match e {
+        SdkError::ServiceError(err) => {
+            let inner = err.into_err();
+            match inner {
+                // AccessDenied, or another refusal such as a bad signature.
+                inner if status == Some(403) => {
+                    tracing::error!("access denied: {}", inner);
+                    return S3GetError::AccessDenied {
+                        message: format!(
+                            "{}: {}",
+                            inner.code().unwrap_or("AccessDenied"),
+                            inner.message().unwrap_or("no message")
+                        ),
+                        request_ids,
+                    };
+                }
+                GetObjectError::NoSuchKey(msg) => {
+                    tracing::error!("no such key: {}", msg);
+                    return S3GetError::NoSuchKey(msg.to_string());
+                }
+                inner if inner.code() == Some("NoSuchVersion") => {
+                    tracing::error!("no such version: {}", inner);
+                    return S3GetError::NoSuchKey(inner.to_string());
+                }
+                GetObjectError::InvalidObjectState(msg) => {
+                    tracing::error!("invalid object state: {}", msg);
+                    return S3GetError::ServiceError {
+                        message: msg.to_string(),
+                        request_ids,
+                    };
+                }
+                inner if inner.code() == Some("InvalidRange") => {
+                    tracing::error!("range not satisfiable: {}", inner);
+                    return S3GetError::RangeNotSatisfiable(inner.to_string());
+                }
+                inner if inner.code() == Some("PreconditionFailed") => {
+                    tracing::error!("object changed: {}", inner);
+                    return S3GetError::ObjectChanged(inner.to_string());
+                }
+                GetObjectError::Unhandled(_) => {
+                    tracing::error!("unhandled error");
+                    return S3GetError::ServiceError {
+                        message: "unhandled error".to_string(),
+                        request_ids,
+                    };
+                }
+                _ => {
+                    tracing::error!("error: {}", inner.to_string());
+                    return S3GetError::ServiceError {
+                        message: inner.to_string(),
+                        request_ids,
+                    };
+                }
+            };
+        }
+        _ => {}
+    }
+    S3GetError::S3GetError(e.to_string())
+}
+
+// Points the client at `endpoint_url` if one is given, or at the
+// s3-accelerate endpoint of the bucket if transfer acceleration is used.
+// Acceleration is only served by the AWS endpoints, with the bucket in the
+// host name, so it conflicts with both a custom endpoint and path style. When
+// none of them is set the builder is left as is, so requests go to the default
+// AWS endpoints.
+fn with_endpoint(
+    builder: aws_sdk_s3::config::Builder,
+    endpoint_url: Option<&str>,
+    force_path_style: bool,
+    use_transfer_acceleration: bool,
+) -> Result<aws_sdk_s3::config::Builder, StorageConfigError> {
+    if use_transfer_acceleration {
+        let conflict = match (endpoint_url, force_path_style) {
+            (Some(_), _) => "endpoint_url",
+            (None, true) => "force_path_style",
+            (None, false) => return Ok(builder.accelerate(true)),
+        };
+        return Err(StorageConfigError::ConflictingOptions(format!(
+            "use_transfer_acceleration cannot be combined with {}",
+            conflict
+        )));
+    }
+    let builder = match endpoint_url {
+        Some(endpoint_url) => builder.endpoint_url(endpoint_url),
+        None => builder,
+    };
+    if force_path_style {
+        Ok(builder.force_path_style(true))
+    } else {
+        Ok(builder)
+    }
+}
+
+// Reports the outcome of every request attempt, including retries, to the
+// adaptive concurrency controller.
+#[derive(Debug)]
+struct AdaptiveConcurrencyInterceptor(Arc<AdaptiveConcurrency>);
+
+impl Intercept for AdaptiveConcurrencyInterceptor {
+    fn name(&self) -> &'static str {
+        "AdaptiveConcurrencyInterceptor"
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        self.0.record(attempt_outcome(context.response()));
+        Ok(())
+    }
+}
+
+// S3 error codes that ask the client to slow down.
+pub(crate) const THROTTLING_ERROR_CODES: &[&str] = &[
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "TooManyRequestsException",
+];
+
+// Classifies an attempt by what it says about the load on S3. 429s and
+// server errors whose code asks to slow down are throttling; other server
+// errors, and attempts that got no response at all, show that S3 is
+// overloaded or down.
+fn attempt_outcome(response: Option<&HttpResponse>) -> RequestOutcome {
+    let response = match response {
+        Some(response) => response,
+        None => return RequestOutcome::Overloaded,
+    };
+    let status = response.status();
+    if status.as_u16() == 429 {
+        return RequestOutcome::Throttled;
+    }
+    if !status.is_server_error() {
+        return RequestOutcome::Success;
+    }
+    // Error bodies have been read into memory by the time the attempt ends.
+    let throttled = response
+        .body()
+        .bytes()
+        .and_then(|body| std::str::from_utf8(body).ok())
+        .is_some_and(|body| {
+            THROTTLING_ERROR_CODES
+                .iter()
+                .any(|code| body.contains(&format!("<Code>{}</Code>", code)))
+        });
+    if throttled {
+        RequestOutcome::Throttled
+    } else {
+        RequestOutcome::Overloaded
+    }
+}
+
+fn with_adaptive_concurrency(
+    builder: aws_sdk_s3::config::Builder,
+    adaptive_concurrency: Option<&Arc<AdaptiveConcurrency>>,
+) -> aws_sdk_s3::config::Builder {
+    match adaptive_concurrency {
+        Some(adaptive_concurrency) => {
+            builder.interceptor(AdaptiveConcurrencyInterceptor(adaptive_concurrency.clone()))
+        }
+        None => builder,
+    }
+}
+
+// Records the outcome of every attempt with the circuit breaker. Client
+// errors such as a missing key show that S3 is up, so they count as
+// successes.
+#[derive(Debug)]
+struct CircuitBreakerInterceptor(Arc<CircuitBreaker>);
+
+impl Intercept for CircuitBreakerInterceptor {
+    fn name(&self) -> &'static str {
+        "CircuitBreakerInterceptor"
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        self.0
+            .record(attempt_outcome(context.response()) != RequestOutcome::Success);
+        Ok(())
+    }
+}
+
+fn with_circuit_breaker(
+    builder: aws_sdk_s3::config::Builder,
+    circuit_breaker: Option<&Arc<CircuitBreaker>>,
+) -> aws_sdk_s3::config::Builder {
+    match circuit_breaker {
+        Some(circuit_breaker) => {
+            builder.interceptor(CircuitBreakerInterceptor(circuit_breaker.clone()))
+        }
+        None => builder,
+    }
+}
+
+// Spends from the retry budget for every attempt that failed with throttling,
+// a server error or no response while the request has attempts left, and
+// refills it for every attempt that succeeded. Once the budget is empty the
+// request's attempts are capped at those it has made, so the retry strategy
+// returns the failure instead of retrying it.
+#[derive(Debug)]
+struct RetryBudgetInterceptor(Arc<RetryBudget>);
+
+impl Intercept for RetryBudgetInterceptor {
+    fn name(&self) -> &'static str {
+        "RetryBudgetInterceptor"
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if attempt_outcome(context.response()) == RequestOutcome::Success {
+            self.0.record_success();
+            return Ok(());
+        }
+        let attempts = cfg
+            .load::<RequestAttempts>()
+            .map(|attempts| attempts.attempts())
+            .unwrap_or(1);
+        let retry_config = match cfg.load::<RetryConfig>() {
+            Some(retry_config) => retry_config.clone(),
+            None => return Ok(()),
+        };
+        // A failed last attempt is not retried, so it spends nothing.
+        if attempts >= retry_config.max_attempts() || self.0.try_spend() {
+            return Ok(());
+        }
+        tracing::debug!("retry budget exhausted, not retrying");
+        cfg.interceptor_state()
+            .store_put(retry_config.with_max_attempts(attempts));
+        Ok(())
+    }
+}
+
+fn with_retry_budget(
+    builder: aws_sdk_s3::config::Builder,
+    retry_budget: Option<&Arc<RetryBudget>>,
+) -> aws_sdk_s3::config::Builder {
+    match retry_budget {
+        Some(retry_budget) => builder.interceptor(RetryBudgetInterceptor(retry_budget.clone())),
+        None => builder,
+    }
+}
+
+// The code in an S3 error body, such as "NoSuchKey".
+fn error_code(response: &HttpResponse) -> Option<&str> {
+    let body = std::str::from_utf8(response.body().bytes()?).ok()?;
+    let start = body.find("<Code>")? + "<Code>".len();
+    let end = start + body[start..].find("</Code>")?;
+    Some(&body[start..end])
+}
+
+// Asks a RetryClassifier whether to retry each failed attempt. It runs after
+// the client's own classifiers, so its decision is the one taken: retries are
+// forbidden for terminal errors, and retryable ones are retried as the kind of
+// error they are, which decides how much of the client's retry quota they use.
+#[derive(Debug)]
+struct CustomRetryClassifier(Arc<dyn RetryClassifier>);
+
+impl ClassifyRetry for CustomRetryClassifier {
+    fn classify_retry(&self, ctx: &InterceptorContext) -> RetryAction {
+        if !matches!(ctx.output_or_error(), Some(Err(_))) {
+            return RetryAction::NoActionIndicated;
+        }
+        let response = ctx.response();
+        let attempt = FailedAttempt {
+            status: response.map(|response| response.status().as_u16()),
+            code: response.and_then(error_code),
+        };
+        match self.0.classify(&attempt) {
+            RetryDecision::Terminal => RetryAction::RetryForbidden,
+            RetryDecision::Retryable => match attempt_outcome(response) {
+                RequestOutcome::Throttled => RetryAction::throttling_error(),
+                RequestOutcome::Overloaded if response.is_none() => RetryAction::transient_error(),
+                RequestOutcome::Overloaded => RetryAction::server_error(),
+                RequestOutcome::Success => RetryAction::client_error(),
+            },
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "CustomRetryClassifier"
+    }
+
+    fn priority(&self) -> RetryClassifierPriority {
+        RetryClassifierPriority::run_after(RetryClassifierPriority::transient_error_classifier())
+    }
+}
+
+fn with_retry_classifier(
+    builder: aws_sdk_s3::config::Builder,
+    retry_classifier: Option<&Arc<dyn RetryClassifier>>,
+) -> aws_sdk_s3::config::Builder {
+    match retry_classifier {
+        Some(retry_classifier) => {
+            builder.retry_classifier(CustomRetryClassifier(retry_classifier.clone()))
+        }
+        None => builder,
+    }
+}
+
+// Adds the app name to the User-Agent header, which is what S3 server access
+// logs and CloudTrail record. The SDK only adds it to x-amz-user-agent.
+#[derive(Debug)]
+struct UserAgentSuffixInterceptor(String);
+
+impl Intercept for UserAgentSuffixInterceptor {
+    fn name(&self) -> &'static str {
+        "UserAgentSuffixInterceptor"
+    }
+
+    fn modify_before_transmit(
+        &self,
+        context: &mut BeforeTransmitInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let headers = context.request_mut().headers_mut();
+        let user_agent = match headers.get("user-agent") {
+            Some(user_agent) => format!("{} {}", user_agent, self.0),
+            None => self.0.clone(),
+        };
+        headers.insert("user-agent", user_agent);
+        Ok(())
+    }
+}
+
+// Adds `suffix` to the user agents of every request, as app/<suffix>.
+fn with_user_agent_suffix(
+    builder: aws_sdk_s3::config::Builder,
+    suffix: Option<&str>,
+) -> aws_sdk_s3::config::Builder {
+    // The suffix has been validated with the rest of the config.
+    match suffix.and_then(|suffix| AppName::new(suffix.to_string()).ok()) {
+        Some(app_name) => builder
+            .interceptor(UserAgentSuffixInterceptor(format!("app/{}", app_name)))
+            .app_name(app_name),
+        None => builder,
+    }
+}
+
+// Has the client sleep on `clock` between retries.
+fn with_clock(
+    builder: aws_sdk_s3::config::Builder,
+    clock: Arc<dyn Clock>,
+) -> aws_sdk_s3::config::Builder {
+    builder.sleep_impl(SdkSleep(clock))
+}
+
+// The SDK's default HTTP client, keeping at most `max_idle_connections` idle
+// connections to each host open for reuse.
+fn pooled_http_client(max_idle_connections: usize) -> SharedHttpClient {
+    let mut hyper_builder = hyper::Client::builder();
+    hyper_builder.pool_max_idle_per_host(max_idle_connections);
+    HyperClientBuilder::new()
+        .hyper_builder(hyper_builder)
+        .build_https()
+}
+
+// Loads the shared AWS config, taking credentials from `credentials` and
+// everything else, such as the region, from the environment.
+async fn load_aws_config(
+    credentials: &S3CredentialsConfig,
+    http_client: Option<SharedHttpClient>,
+) -> aws_config::SdkConfig {
+    let mut loader = aws_config::from_env();
+    if let Some(http_client) = http_client {
+        loader = loader.http_client(http_client);
+    }
+    let loader = match credentials {
+        S3CredentialsConfig::Static {
+            access_key,
+            secret_key,
+            session_token,
+        } => loader.credentials_provider(aws_sdk_s3::config::Credentials::new(
+            access_key,
+            secret_key,
+            session_token.clone(),
+            None,
+            "chroma-static",
+        )),
+        S3CredentialsConfig::Profile { name } => loader.profile_name(name),
+        S3CredentialsConfig::Minio | S3CredentialsConfig::AWS => loader,
+    };
+    loader.load().await
+}
+
+// The longest prefix shared by all of `keys`.
+fn common_prefix(keys: &HashSet<String>) -> String {
+    let mut keys = keys.iter();
+    let mut prefix = match keys.next() {
+        Some(key) => key.as_str(),
+        None => return String::new(),
+    };
+    for key in keys {
+        let len = prefix
+            .char_indices()
+            .zip(key.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((i, _), _)| i)
+            .unwrap_or(prefix.len().min(key.len()));
+        prefix = &prefix[..len];
+    }
+    prefix.to_string()
+}
+
+// Builds the HTTP timeouts of the client. Timeouts that are not configured
+// are left unset so that the SDK defaults apply.
+fn timeout_config(connect_timeout_ms: Option<u64>, read_timeout_ms: Option<u64>) -> TimeoutConfig {
+    let mut builder = TimeoutConfigBuilder::default();
+    if let Some(connect_timeout_ms) = connect_timeout_ms {
+        builder = builder.connect_timeout(Duration::from_millis(connect_timeout_ms));
+    }
+    if let Some(read_timeout_ms) = read_timeout_ms {
+        builder = builder.read_timeout(Duration::from_millis(read_timeout_ms));
+    }
+    builder.build()
+}
+
+// The SDK's standard retry strategy retries transient errors (5xx, throttling
+// and timeouts) with exponential backoff and jitter, while errors such as
+// NoSuchKey fail immediately.
+fn retry_config(max_retries: Option<u32>, base_backoff_ms: Option<u64>) -> RetryConfig {
+    let mut retry_config = RetryConfig::standard();
+    if let Some(max_retries) = max_retries {
+        retry_config = retry_config.with_max_attempts(max_retries + 1);
+    }
+    if let Some(base_backoff_ms) = base_backoff_ms {
+        retry_config = retry_config.with_initial_backoff(Duration::from_millis(base_backoff_ms));
+    }
+    retry_config
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum StorageConfigError {
+    #[error("Invalid storage config")]
+    InvalidStorageConfig,
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+    #[error("Failed to create bucket: {0}")]
+    FailedToCreateBucket(String),
+    #[error("Storage validation failed: {0}")]
+    ValidationFailed(String),
+    #[error("Conflicting storage config: {0}")]
+    ConflictingOptions(String),
+    #[error("Invalid storage config: {field} {reason}")]
+    InvalidField { field: &'static str, reason: String },
+}
+
+impl ChromaError for StorageConfigError {
+    fn code(&self) -> ErrorCodes {
+        match self {
+            StorageConfigError::InvalidStorageConfig => ErrorCodes::InvalidArgument,
+            StorageConfigError::InvalidKey(_) => ErrorCodes::InvalidArgument,
+            StorageConfigError::FailedToCreateBucket(_) => ErrorCodes::Internal,
+            StorageConfigError::ValidationFailed(_) => ErrorCodes::FailedPrecondition,
+            StorageConfigError::ConflictingOptions(_) => ErrorCodes::InvalidArgument,
+            StorageConfigError::InvalidField { .. } => ErrorCodes::InvalidArgument,
+        }
+    }
+}
+
+#[async_trait]
+impl Configurable<StorageConfig> for S3Storage {
+    async fn try_from_config(config: &StorageConfig) -> Result<Self, Box<dyn ChromaError>> {
+        S3Storage::from_config(config, None, None, Arc::new(TokioClock)).await
+    }
+}
+
+impl S3Storage {
+    /// Builds the storage as try_from_config does, but sends every request,
+    /// including those that load credentials, through `http_client` instead
+    /// of the SDK's default client. Use this to go through a proxy or to
+    /// trust a private CA.
+    pub async fn try_from_config_with_http_client(
+        config: &StorageConfig,
+        http_client: impl HttpClient + 'static,
+    ) -> Result<S3Storage, Box<dyn ChromaError>> {
+        S3Storage::from_config(
+            config,
+            Some(SharedHttpClient::new(http_client)),
+            None,
+            Arc::new(TokioClock),
+        )
+        .await
+    }
+
+    /// Builds the storage as try_from_config does, but has
+    /// `retry_classifier` decide which failed attempts are retried, instead
+    /// of the client's own rules.
+    pub async fn try_from_config_with_retry_classifier(
+        config: &StorageConfig,
+        retry_classifier: impl RetryClassifier + 'static,
+    ) -> Result<S3Storage, Box<dyn ChromaError>> {
+        S3Storage::from_config(
+            config,
+            None,
+            Some(Arc::new(retry_classifier)),
+            Arc::new(TokioClock),
+        )
+        .await
+    }
+
+    // The rate limiter, the circuit breaker and the client's retry backoff
+    // all keep time by `clock`.
+    async fn from_config(
+        config: &StorageConfig,
+        http_client: Option<SharedHttpClient>,
+        retry_classifier: Option<Arc<dyn RetryClassifier>>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<S3Storage, Box<dyn ChromaError>> {
+        match &config {
+            StorageConfig::S3(s3_config) => {
+                s3_config
+                    .validate()
+                    .map_err(|e| Box::new(e) as Box<dyn ChromaError>)?;
+                let adaptive_concurrency = match (
+                    s3_config.adaptive_concurrency,
+                    s3_config.max_concurrent_requests,
+                ) {
+                    (true, Some(limit)) => Some(Arc::new(AdaptiveConcurrency::new(limit))),
+                    (_, _) => None,
+                };
+                let circuit_breaker = match (
+                    s3_config.circuit_breaker_failure_threshold,
+                    s3_config.circuit_breaker_cooldown_ms,
+                ) {
+                    (Some(failure_threshold), cooldown_ms) => {
+                        Some(Arc::new(CircuitBreaker::with_clock(
+                            failure_threshold,
+                            cooldown_ms
+                                .map(Duration::from_millis)
+                                .unwrap_or(DEFAULT_CIRCUIT_BREAKER_COOLDOWN),
+                            clock.clone(),
+                        )))
+                    }
+                    (None, _) => None,
+                };
+                let http_client = match (http_client, s3_config.max_idle_connections) {
+                    (Some(_), Some(_)) => {
+                        return Err(Box::new(StorageConfigError::ConflictingOptions(
+                            "max_idle_connections cannot be combined with a custom HTTP client"
+                                .to_string(),
+                        )))
+                    }
+                    (None, Some(max_idle_connections)) => {
+                        Some(pooled_http_client(max_idle_connections))
+                    }
+                    (http_client, None) => http_client,
+                };
+                if let (Some(max_idle_connections), Some(max_concurrent_requests)) = (
+                    s3_config.max_idle_connections,
+                    s3_config.max_concurrent_requests,
+                ) {
+                    if max_idle_connections < max_concurrent_requests {
+                        tracing::warn!(
+                            "max_idle_connections ({}) is below max_concurrent_requests ({}), \
+                             requests beyond it will open a new connection each",
+                            max_idle_connections,
+                            max_concurrent_requests
+                        );
+                    }
+                }
+                let retry_budget = s3_config
+                    .retry_budget
+                    .map(|retries| Arc::new(RetryBudget::new(retries)));
+                let client = match &s3_config.credentials {
+                    // Minio is always addressed at a custom endpoint, path style.
+                    super::config::S3CredentialsConfig::Minio
+                        if s3_config.use_transfer_acceleration =>
+                    {
+                        return Err(Box::new(StorageConfigError::ConflictingOptions(
+                            "use_transfer_acceleration cannot be combined with the Minio \
+                             credentials"
+                                .to_string(),
+                        )))
+                    }
+                    super::config::S3CredentialsConfig::Minio => {
+                        // Set up credentials assuming minio is running locally
+                        let cred = aws_sdk_s3::config::Credentials::new(
+                            "minio",
+                            "minio123",
+                            None,
+                            None,
+                            "loaded-from-env",
+                        );
+
+                        let retry_config =
+                            retry_config(s3_config.max_retries, s3_config.base_backoff_ms);
+
+                        // Set up s3 client
+                        let mut config = aws_sdk_s3::config::Builder::new();
+                        config.set_http_client(http_client);
+                        let config = config
+                            .endpoint_url(
+                                s3_config
+                                    .endpoint_url
+                                    .as_deref()
+                                    .unwrap_or("http://minio.chroma:9000"),
+                            )
+                            .credentials_provider(cred)
+                            .behavior_version_latest()
+                            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+                            .force_path_style(true)
+                            .timeout_config(timeout_config(
+                                s3_config.connect_timeout_ms,
+                                s3_config.read_timeout_ms,
+                            ))
+                            .retry_config(retry_config);
+                        let config =
+                            with_adaptive_concurrency(config, adaptive_concurrency.as_ref());
+                        let config = with_circuit_breaker(config, circuit_breaker.as_ref());
+                        let config = with_retry_budget(config, retry_budget.as_ref());
+                        let config = with_retry_classifier(config, retry_classifier.as_ref());
+                        let config = with_clock(config, clock.clone());
+                        let config =
+                            with_user_agent_suffix(config, s3_config.user_agent_suffix.as_deref());
+                        aws_sdk_s3::Client::from_conf(config.build())
+                    }
+                    credentials => {
+                        let config = load_aws_config(credentials, http_client).await;
+                        let retry_config =
+                            retry_config(s3_config.max_retries, s3_config.base_backoff_ms);
+                        let config = config.to_builder().retry_config(retry_config).build();
+                        // Set on the S3 config, rather than the shared config,
+                        // so that unset timeouts keep the loaded defaults.
+                        let config = aws_sdk_s3::config::Builder::from(&config).timeout_config(
+                            timeout_config(s3_config.connect_timeout_ms, s3_config.read_timeout_ms),
+                        );
+                        let config = with_endpoint(
+                            config,
+                            s3_config.endpoint_url.as_deref(),
+                            s3_config.force_path_style,
+                            s3_config.use_transfer_acceleration,
+                        )
+                        .map_err(|e| Box::new(e) as Box<dyn ChromaError>)?;
+                        let config =
+                            with_adaptive_concurrency(config, adaptive_concurrency.as_ref());
+                        let config = with_circuit_breaker(config, circuit_breaker.as_ref());
+                        let config = with_retry_budget(config, retry_budget.as_ref());
+                        let config = with_retry_classifier(config, retry_classifier.as_ref());
+                        let config = with_clock(config, clock.clone());
+                        let config =
+                            with_user_agent_suffix(config, s3_config.user_agent_suffix.as_deref());
+                        aws_sdk_s3::Client::from_conf(config.build())
+                    }
+                };
+                let rate_limiter = s3_config
+                    .rate_limit_rps
+                    .map(|rps| RateLimiter::with_clock(rps, clock.clone()));
+                let bandwidth_limiter = s3_config.max_bytes_per_second.map(|bytes_per_second| {
+                    RateLimiter::with_rate(bytes_per_second, clock.clone())
+                });
+                let request_semaphore = match s3_config.max_concurrent_requests {
+                    // The adaptive controller enforces the limit itself.
+                    Some(_) if adaptive_concurrency.is_some() => None,
+                    Some(limit) => Some(Arc::new(Semaphore::new(limit))),
+                    None => None,
+                };
+                let cache = s3_config.cache_capacity_bytes.map(|capacity_bytes| {
+                    ObjectCache::new(
+                        capacity_bytes,
+                        s3_config
+                            .cache_max_object_size_bytes
+                            .unwrap_or(capacity_bytes),
+                    )
+                });
+                let upload_part_size_bytes = s3_config
+                    .upload_part_size_bytes
+                    .unwrap_or(DEFAULT_UPLOAD_PART_SIZE_BYTES);
+                let default_storage =
+                    S3Storage::new(&s3_config.bucket, client, upload_part_size_bytes);
+                let storage = S3Storage {
+                    upload_part_size_bytes: s3_config.upload_part_size_bytes,
+                    multipart_threshold_bytes: s3_config
+                        .multipart_threshold_bytes
+                        .unwrap_or(default_storage.multipart_threshold_bytes),
+                    upload_concurrency: s3_config
+                        .upload_concurrency
+                        .unwrap_or(default_storage.upload_concurrency),
+                    rate_limiter,
+                    bandwidth_limiter,
+                    request_semaphore,
+                    adaptive_concurrency,
+                    circuit_breaker,
+                    verify_checksums: s3_config.verify_checksums,
+                    validate_content_length: s3_config.validate_content_length,
+                    reject_empty_puts: s3_config.reject_empty_puts,
+                    decode_content_encoding: s3_config.decode_content_encoding,
+                    operation_timeout: s3_config.operation_timeout_ms.map(Duration::from_millis),
+                    cache,
+                    compression: s3_config.compression,
+                    sse: s3_config.sse.clone(),
+                    read_ahead_chunks: match (s3_config.read_ahead, s3_config.read_ahead_chunks) {
+                        (true, chunks) => Some(chunks.unwrap_or(DEFAULT_READ_AHEAD_CHUNKS)),
+                        (false, _) => None,
+                    },
+                    fallback_bucket: s3_config.fallback_bucket.clone(),
+                    requester_pays: s3_config.requester_pays,
+                    key_prefix: s3_config.prefix.as_deref().and_then(key_prefix),
+                    key_case: s3_config.normalize_keys,
+                    hedge_after: s3_config.hedge_after_ms.map(Duration::from_millis),
+                    max_object_size_bytes: s3_config.max_object_size_bytes,
+                    small_object_threshold_bytes: s3_config.small_object_threshold_bytes,
+                    exists_list_threshold: s3_config
+                        .exists_list_threshold
+                        .unwrap_or(default_storage.exists_list_threshold),
+                    parallel_download_threshold_bytes: s3_config
+                        .parallel_download_threshold_bytes
+                        .or(s3_config.download_part_size_bytes)
+                        .unwrap_or(default_storage.parallel_download_threshold_bytes),
+                    download_part_size_bytes: s3_config
+                        .download_part_size_bytes
+                        .unwrap_or(default_storage.download_part_size_bytes),
+                    download_concurrency: s3_config
+                        .download_concurrency
+                        .unwrap_or(default_storage.download_concurrency),
+                    ..default_storage
+                };
+                // for minio we create the bucket since it is only used for testing
+                match &s3_config.credentials {
+                    super::config::S3CredentialsConfig::Minio => {
+                        let res = storage.create_bucket().await;
+                        match res {
+                            Ok(_) => {}
+                            Err(e) => {
+                                return Err(Box::new(StorageConfigError::FailedToCreateBucket(e)));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                if s3_config.validate_on_startup {
+                    if let Err(e) = storage.validate().await {
+                        return Err(Box::new(e));
+                    }
+                }
+
+                Ok(storage)
+            }
+            _ => Err(Box::new(StorageConfigError::InvalidStorageConfig)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GetError;
+    use aws_sdk_s3::operation::upload_part::UploadPartOutput;
+    use aws_smithy_runtime::client::http::test_util::{ReplayEvent, StaticReplayClient};
+    use futures::StreamExt;
+    use rand::{Rng, SeedableRng};
+    use std::io::Write;
+    use tempfile::{tempdir, NamedTempFile};
+
+    fn get_s3_client() -> aws_sdk_s3::Client {
+        // Set up credentials assuming minio is running locally
+        let cred = aws_sdk_s3::config::Credentials::new(
+            "minio",
+            "minio123",
+            None,
+            None,
+            "loaded-from-env",
+        );
+
+        // Set up s3 client
+        let config = aws_sdk_s3::config::Builder::new()
+            .endpoint_url("http://127.0.0.1:9000".to_string())
+            .credentials_provider(cred)
+            .behavior_version_latest()
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .force_path_style(true)
+            .build();
+
+        aws_sdk_s3::Client::from_conf(config)
+    }
+
+    // Builds a client that replays the given request/response pairs in order
+    // instead of talking to s3.
+    fn get_mock_s3_client(events: Vec<ReplayEvent>) -> (aws_sdk_s3::Client, StaticReplayClient) {
+        get_mock_s3_client_with_retries(events, RetryConfig::disabled())
+    }
+
+    fn get_mock_s3_client_with_retries(
+        events: Vec<ReplayEvent>,
+        retry_config: RetryConfig,
+    ) -> (aws_sdk_s3::Client, StaticReplayClient) {
+        let http_client = StaticReplayClient::new(events);
+        let config = mock_s3_config(&http_client)
+            .retry_config(retry_config)
+            .build();
+
+        (aws_sdk_s3::Client::from_conf(config), http_client)
+    }
+
+    fn mock_s3_config(http_client: &StaticReplayClient) -> aws_sdk_s3::config::Builder {
+        let cred = aws_sdk_s3::config::Credentials::new("test", "test", None, None, "test");
+        aws_sdk_s3::config::Builder::new()
+            .credentials_provider(cred)
+            .behavior_version_latest()
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .retry_config(RetryConfig::disabled())
+            .http_client(http_client.clone())
+    }
+
+    fn mock_event(status: u16, body: &str) -> ReplayEvent {
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://test.s3.us-east-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(status)
+                .body(SdkBody::from(body.to_string()))
+                .unwrap(),
+        )
+    }
+
+    fn list_page(keys: &[&str], next_token: Option<&str>) -> ReplayEvent {
+        list_page_with_sizes(
+            &keys.iter().map(|key| (*key, 1)).collect::<Vec<_>>(),
+            next_token,
+        )
+    }
+
+    fn list_page_with_sizes(objects: &[(&str, u64)], next_token: Option<&str>) -> ReplayEvent {
+        let contents = objects
+            .iter()
+            .map(|(key, size)| {
+                format!(
+                    "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                    key, size
+                )
+            })
+            .collect::<String>();
+        let truncated = match next_token {
+            Some(token) => format!(
+                "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                token
+            ),
+            None => "<IsTruncated>false</IsTruncated>".to_string(),
+        };
+        mock_event(
+            200,
+            &format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                <Name>test</Name><KeyCount>{}</KeyCount>{}{}</ListBucketResult>",
+                objects.len(),
+                truncated,
+                contents
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_list_prefix_pages() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            list_page(&["prefix/a", "prefix/b"], Some("token-1")),
+            list_page(&["prefix/c"], Some("token-2")),
+            list_page(&["prefix/d"], None),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let keys = storage
+            .list_prefix("prefix/")
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(keys, vec!["prefix/a", "prefix/b", "prefix/c", "prefix/d"]);
+
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 3);
+        assert!(!requests[0].uri().contains("continuation-token"));
+        assert!(requests[1].uri().contains("continuation-token=token-1"));
+        assert!(requests[2].uri().contains("continuation-token=token-2"));
+    }
+
+    #[tokio::test]
+    async fn test_exists_many_lists_dense_prefix() {
+        let (client, http_client) = get_mock_s3_client(vec![list_page(
+            &["dir/key-0", "dir/key-2", "dir/key-3"],
+            None,
+        )]);
+        let storage = S3Storage {
+            exists_list_threshold: 3,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let keys = (0..3).map(|i| format!("dir/key-{}", i)).collect();
+        let exists = storage.exists_many(keys).await.unwrap();
+        assert_eq!(
+            exists,
+            HashMap::from([
+                ("dir/key-0".to_string(), true),
+                ("dir/key-1".to_string(), false),
+                ("dir/key-2".to_string(), true),
+            ])
+        );
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].uri().contains("prefix=dir%2Fkey-"));
+    }
+
+    #[tokio::test]
+    async fn test_exists_many_heads_sparse_keys() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("", &[("content-length", "9")]),
+            mock_event(404, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        // Too few keys to be worth a listing.
+        let exists = storage
+            .exists_many(vec!["a".to_string(), "b".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(exists.len(), 2);
+        assert_eq!(exists.values().filter(|exists| **exists).count(), 1);
+        assert_eq!(http_client.actual_requests().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_exists_many_falls_back_to_heads_when_listing_is_sparse() {
+        let listed = (0..20)
+            .map(|i| format!("dir/other-{}", i))
+            .collect::<Vec<_>>();
+        let listed = listed.iter().map(String::as_str).collect::<Vec<_>>();
+        let (client, http_client) = get_mock_s3_client(vec![
+            list_page(&listed, Some("token")),
+            get_event("", &[("content-length", "9")]),
+            get_event("", &[("content-length", "9")]),
+        ]);
+        let storage = S3Storage {
+            exists_list_threshold: 2,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let exists = storage
+            .exists_many(vec!["dir/a".to_string(), "dir/b".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(exists.len(), 2);
+        assert!(exists.values().all(|exists| *exists));
+        // The listing was abandoned after its first page.
+        assert_eq!(http_client.actual_requests().count(), 3);
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect();
+        assert_eq!(common_prefix(&keys(&["dir/a", "dir/ab", "dir/b"])), "dir/");
+        assert_eq!(common_prefix(&keys(&["dir/a", "dir"])), "dir");
+        assert_eq!(common_prefix(&keys(&["a", "b"])), "");
+        assert_eq!(common_prefix(&keys(&["é1", "é2"])), "é");
+    }
+
+    #[tokio::test]
+    async fn test_list_prefix_stops_early() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            list_page(&["prefix/a", "prefix/b"], Some("token-1")),
+            list_page(&["prefix/c"], None),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let keys = storage
+            .list_prefix("prefix/")
+            .take(2)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(keys, vec!["prefix/a", "prefix/b"]);
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_prefix_size_sums_all_pages() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            list_page_with_sizes(&[("prefix/a", 10), ("prefix/b", 0)], Some("token-1")),
+            list_page_with_sizes(&[("prefix/c", 5 << 30)], Some("token-2")),
+            list_page_with_sizes(&[], None),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let (object_count, total_bytes) = storage.prefix_size("prefix/").await.unwrap();
+        assert_eq!(object_count, 3);
+        assert_eq!(total_bytes, 10 + (5 << 30));
+        assert_eq!(http_client.actual_requests().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_list_prefix_empty() {
+        let (client, _) = get_mock_s3_client(vec![list_page(&[], None)]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let keys = storage
+            .list_prefix("prefix/")
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_prefix_error() {
+        let (client, _) = get_mock_s3_client(vec![mock_event(
+            500,
+            "<Error><Code>InternalError</Code><Message>boom</Message></Error>",
+        )]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage.list_prefix("prefix/").try_collect::<Vec<_>>().await;
+        assert!(matches!(res, Err(S3ListError::S3ListError(_))));
+    }
+
+    // A page of ListObjectVersions results, listing (key, version id) pairs
+    // newest first. The version "latest" is marked as the latest one.
+    fn versions_page(versions: &[(&str, &str)], next_markers: Option<(&str, &str)>) -> ReplayEvent {
+        let contents = versions
+            .iter()
+            .map(|(key, version_id)| {
+                format!(
+                    "<Version><Key>{}</Key><VersionId>{}</VersionId>\
+                     <IsLatest>{}</IsLatest><Size>{}</Size></Version>",
+                    key,
+                    version_id,
+                    *version_id == "latest",
+                    version_id.len()
+                )
+            })
+            .collect::<String>();
+        let truncated = match next_markers {
+            Some((key_marker, version_id_marker)) => format!(
+                "<IsTruncated>true</IsTruncated><NextKeyMarker>{}</NextKeyMarker>\
+                 <NextVersionIdMarker>{}</NextVersionIdMarker>",
+                key_marker, version_id_marker
+            ),
+            None => "<IsTruncated>false</IsTruncated>".to_string(),
+        };
+        mock_event(
+            200,
+            &format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                <ListVersionsResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                <Name>test</Name>{}{}</ListVersionsResult>",
+                truncated, contents
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_list_versions_of_key_written_several_times() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            versions_page(&[("key", "latest"), ("key", "v2")], Some(("key", "v2"))),
+            // "key-2" shares the prefix but is another object.
+            versions_page(&[("key", "v1"), ("key-2", "v9")], None),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let versions = storage.list_versions("key").await.unwrap();
+        let version = |version_id: &str| ObjectVersion {
+            version_id: version_id.to_string(),
+            size: version_id.len() as u64,
+            is_latest: version_id == "latest",
+        };
+        assert_eq!(
+            versions,
+            vec![version("latest"), version("v2"), version("v1")]
+        );
+
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].uri().contains("prefix=key"));
+        assert!(!requests[0].uri().contains("key-marker"));
+        assert!(requests[1].uri().contains("key-marker=key"));
+        assert!(requests[1].uri().contains("version-id-marker=v2"));
+    }
+
+    #[tokio::test]
+    async fn test_get_version_requests_version() {
+        let (client, http_client) = get_mock_s3_client(vec![get_event(b"first write", &[])]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let bytes = storage.get_version("key", "v1").await.unwrap();
+        assert_eq!(*bytes, b"first write");
+        let request = http_client.actual_requests().next().unwrap();
+        assert!(request.uri().contains("versionId=v1"), "{}", request.uri());
+    }
+
+    #[tokio::test]
+    async fn test_get_version_not_found() {
+        let (client, _) = get_mock_s3_client(vec![mock_event(
+            404,
+            "<Error><Code>NoSuchVersion</Code><Message>no such version</Message></Error>",
+        )]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        assert!(matches!(
+            storage.get_version("key", "v1").await,
+            Err(S3GetError::NoSuchKey(_))
+        ));
+    }
+
+    fn delete_result(errors: &[(&str, &str)]) -> ReplayEvent {
+        let errors = errors
+            .iter()
+            .map(|(key, code)| {
+                format!(
+                    "<Error><Key>{}</Key><Code>{}</Code><Message>failed</Message></Error>",
+                    key, code
+                )
+            })
+            .collect::<String>();
+        mock_event(
+            200,
+            &format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                <DeleteResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">{}</DeleteResult>",
+                errors
+            ),
+        )
+    }
+
+    fn keys(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("key-{}", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_delete_if_match_deletes_matching_object() {
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(204, "")]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        assert!(storage.delete_if_match("test", "\"v1\"").await.unwrap());
+        let request = http_client.actual_requests().next().unwrap();
+        assert_eq!(request.method(), "DELETE");
+        assert_eq!(request.headers().get("if-match"), Some("\"v1\""));
+    }
+
+    #[tokio::test]
+    async fn test_delete_if_match_declines_stale_etag() {
+        let (client, _) = get_mock_s3_client(vec![
+            mock_event(
+                412,
+                "<Error><Code>PreconditionFailed</Code><Message>At least one of the pre-conditions you specified did not hold</Message></Error>",
+            ),
+            mock_event(
+                404,
+                "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>",
+            ),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        // Overwritten since, and then deleted by someone else.
+        assert!(!storage.delete_if_match("test", "\"v1\"").await.unwrap());
+        assert!(!storage.delete_if_match("test", "\"v1\"").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete_many_single_batch() {
+        let (client, http_client) = get_mock_s3_client(vec![delete_result(&[])]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let report = storage.delete_many(keys(3)).await.unwrap();
+        assert_eq!(report.deleted, keys(3));
+        assert!(report.failed.is_empty());
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_delete_many_spans_batches() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            delete_result(&[]),
+            delete_result(&[]),
+            delete_result(&[]),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let report = storage.delete_many(keys(2500)).await.unwrap();
+        assert_eq!(report.deleted, keys(2500));
+        let batch_sizes = http_client
+            .actual_requests()
+            .map(|request| {
+                let body = std::str::from_utf8(request.body().bytes().unwrap()).unwrap();
+                body.matches("<Key>").count()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(batch_sizes, vec![1000, 1000, 500]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_many_reports_failed_keys() {
+        let (client, _) = get_mock_s3_client(vec![delete_result(&[
+            ("key-1", "AccessDenied"),
+            ("key-2", "NoSuchKey"),
+        ])]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let report = storage.delete_many(keys(3)).await.unwrap();
+        // Missing keys count as deleted.
+        assert_eq!(report.deleted, vec!["key-0", "key-2"]);
+        assert_eq!(report.failed.len(), 1);
+        let (key, S3DeleteError::S3DeleteError { code, .. }) = &report.failed[0] else {
+            panic!("unexpected error: {:?}", report.failed[0]);
+        };
+        assert_eq!(key, "key-1");
+        assert_eq!(code.as_deref(), Some("AccessDenied"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_many_request_failure() {
+        let (client, _) = get_mock_s3_client(vec![mock_event(
+            403,
+            "<Error><Code>AccessDenied</Code><Message>denied</Message></Error>",
+        )]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage.delete_many(keys(3)).await;
+        assert!(matches!(
+            res,
+            Err(S3DeleteError::S3DeleteError { code: Some(code), .. }) if code == "AccessDenied"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_key_prefix_round_trip() {
+        let (client, http_client) =
+            get_mock_s3_client(vec![mock_event(200, ""), mock_event(200, "test data")]);
+        let storage = S3Storage {
+            key_prefix: key_prefix("tenant-a/"),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        storage
+            .put_bytes("/key", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let stream = storage.get("/key").await.unwrap();
+        assert_eq!(read_all(stream).await.unwrap(), "test data".as_bytes());
+
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        for request in requests {
+            assert!(
+                request
+                    .uri()
+                    .starts_with("https://test.s3.us-east-1.amazonaws.com/tenant-a/key"),
+                "{}",
+                request.uri()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_normalized_keys_round_trip() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, ""),
+            mock_event(200, "test data"),
+            mock_event(200, ""),
+        ]);
+        let storage = S3Storage {
+            key_prefix: key_prefix("Tenant-A/"),
+            key_case: KeyCase::Lower,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        storage
+            .put_bytes("Block/ABC", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let stream = storage.get("block/abc").await.unwrap();
+        assert_eq!(read_all(stream).await.unwrap(), "test data".as_bytes());
+        storage.delete("BLOCK/abc").await.unwrap();
+
+        // The prefix is configured, not a key, so it keeps its case.
+        for request in http_client.actual_requests() {
+            assert!(
+                request
+                    .uri()
+                    .starts_with("https://test.s3.us-east-1.amazonaws.com/Tenant-A/block/abc"),
+                "{}",
+                request.uri()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_normalized_keys_share_cache_entry() {
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage {
+            key_case: KeyCase::Lower,
+            cache: Some(ObjectCache::new(1024, 1024)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        storage
+            .put_bytes("Block/ABC", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let stream = storage.get("block/abc").await.unwrap();
+        assert_eq!(read_all(stream).await.unwrap(), "test data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_prefix_strips_key_prefix() {
+        let (client, http_client) = get_mock_s3_client(vec![list_page(
+            &["tenant-a/prefix/a", "tenant-a/prefix/b"],
+            None,
+        )]);
+        let storage = S3Storage {
+            key_prefix: key_prefix("tenant-a"),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let keys = storage
+            .list_prefix("prefix/")
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(keys, vec!["prefix/a", "prefix/b"]);
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert!(requests[0].uri().contains("prefix=tenant-a%2Fprefix%2F"));
+    }
+
+    #[test]
+    fn test_key_prefix_is_normalized() {
+        assert_eq!(key_prefix("tenant-a"), Some("tenant-a/".to_string()));
+        assert_eq!(key_prefix("/tenant-a//"), Some("tenant-a/".to_string()));
+        assert_eq!(key_prefix("a/b/"), Some("a/b/".to_string()));
+        assert_eq!(key_prefix(""), None);
+        assert_eq!(key_prefix("/"), None);
+    }
+
+    #[test]
+    fn test_large_upload_part_size_stays_within_part_limit() {
+        let (client, _) = get_mock_s3_client(vec![]);
+        let storage = S3Storage {
+            upload_part_size_bytes: None,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        // Up to S3's largest object, 5 TiB.
+        for total_size_bytes in [100 << 30, (100 << 30) + 1, 5 << 40] {
+            let part_size_bytes = storage.upload_part_size_for(total_size_bytes);
+            assert!(part_size_bytes > MIN_UPLOAD_PART_SIZE_BYTES);
+            assert!(total_size_bytes.div_ceil(part_size_bytes) <= MAX_UPLOAD_PARTS);
+        }
+    }
+
+    #[test]
+    fn test_medium_upload_uses_minimum_part_size() {
+        let (client, _) = get_mock_s3_client(vec![]);
+        let storage = S3Storage {
+            upload_part_size_bytes: None,
+            ..S3Storage::new("test", client.clone(), 1024 * 1024 * 8)
+        };
+        for total_size_bytes in [
+            6 << 20,
+            100 << 20,
+            MIN_UPLOAD_PART_SIZE_BYTES * MAX_UPLOAD_PARTS,
+        ] {
+            assert_eq!(
+                storage.upload_part_size_for(total_size_bytes),
+                MIN_UPLOAD_PART_SIZE_BYTES
+            );
+        }
+
+        // A configured part size is used as is.
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        assert_eq!(storage.upload_part_size_for(100 << 20), 1024 * 1024 * 8);
+    }
+
+    // The string headers and the payload of an event stream message.
+    type SelectMessage<'a> = (Vec<(&'static str, &'static str)>, &'a [u8]);
+
+    // A SelectObjectContent response carrying `messages`.
+    fn select_event(messages: Vec<SelectMessage<'_>>) -> ReplayEvent {
+        use aws_smithy_types::event_stream::{Header, HeaderValue, Message};
+        let mut body = Vec::new();
+        for (headers, payload) in messages {
+            let message = headers.into_iter().fold(
+                Message::new(payload.to_vec()),
+                |message, (name, value)| {
+                    message.add_header(Header::new(name, HeaderValue::String(value.into())))
+                },
+            );
+            aws_smithy_eventstream::frame::write_message_to(&message, &mut body).unwrap();
+        }
+        get_event(
+            body,
+            &[("content-type", "application/vnd.amazon.eventstream")],
+        )
+    }
+
+    fn select_records_event(payload: &[u8]) -> SelectMessage<'_> {
+        (
+            vec![
+                (":message-type", "event"),
+                (":event-type", "Records"),
+                (":content-type", "application/octet-stream"),
+            ],
+            payload,
+        )
+    }
+
+    fn select_end_event() -> SelectMessage<'static> {
+        (
+            vec![(":message-type", "event"), (":event-type", "End")],
+            b"",
+        )
+    }
+
+    #[tokio::test]
+    async fn test_select_streams_filtered_records() {
+        let (client, http_client) = get_mock_s3_client(vec![select_event(vec![
+            select_records_event(b"id,name\n1,a\n"),
+            (
+                vec![(":message-type", "event"), (":event-type", "Cont")],
+                b"",
+            ),
+            select_records_event(b"3,c\n"),
+            select_end_event(),
+        ])]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let records = storage
+            .select(
+                "table.csv",
+                "SELECT * FROM S3Object s WHERE s.id <> '2'",
+                SelectFormat::Csv,
+            )
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![Bytes::from("id,name\n1,a\n"), Bytes::from("3,c\n")]
+        );
+        let request = http_client.actual_requests().next().unwrap();
+        assert!(request.uri().contains("select&select-type=2"));
+        let body = std::str::from_utf8(request.body().bytes().unwrap()).unwrap();
+        assert!(body.contains("<FileHeaderInfo>USE</FileHeaderInfo>"));
+    }
+
+    #[tokio::test]
+    async fn test_select_surfaces_expression_errors() {
+        let (client, _) = get_mock_s3_client(vec![
+            mock_event(
+                400,
+                "<Error><Code>ParseUnexpectedToken</Code><Message>Unexpected token</Message></Error>",
+            ),
+            select_event(vec![select_records_event(b"{}\n")]),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage
+            .select("table.json", "SELEC *", SelectFormat::Json)
+            .try_collect::<Vec<_>>()
+            .await;
+        assert!(matches!(res, Err(S3SelectError::InvalidExpression(_))));
+
+        // A response cut off before its end event is an error too, rather
+        // than a silently short result.
+        let res = storage
+            .select("table.json", "SELECT * FROM S3Object", SelectFormat::Json)
+            .try_collect::<Vec<_>>()
+            .await;
+        assert!(matches!(res, Err(S3SelectError::S3SelectError(_))));
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let message = || "message".to_string();
+        let cases: Vec<(Box<dyn ChromaError>, ErrorCodes)> = vec![
+            (
+                Box::new(S3PutError::Conflict(message())),
+                ErrorCodes::Aborted,
+            ),
+            (
+                Box::new(S3PutError::ContentLengthMismatch {
+                    expected: 2,
+                    actual: 1,
+                }),
+                ErrorCodes::InvalidArgument,
+            ),
+            (
+                Box::new(S3GetError::S3GetError(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3GetError::ServiceError {
+                    message: message(),
+                    request_ids: RequestIds::default(),
+                }),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3GetError::NoSuchKey(message())),
+                ErrorCodes::NotFound,
+            ),
+            (
+                Box::new(S3GetError::ByteStreamError(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3GetError::RangeNotSatisfiable(message())),
+                ErrorCodes::OutOfRange,
+            ),
+            (
+                Box::new(S3GetError::ChecksumMismatch(message())),
+                ErrorCodes::DataLoss,
+            ),
+            (
+                Box::new(S3GetError::Timeout(message())),
+                ErrorCodes::DeadlineExceeded,
+            ),
+            (
+                Box::new(S3GetError::DecompressionError(message())),
+                ErrorCodes::DataLoss,
+            ),
+            (
+                Box::new(S3GetError::ObjectTooLarge {
+                    limit: 1,
+                    actual: 2,
+                }),
+                ErrorCodes::ResourceExhausted,
+            ),
+            (Box::new(S3GetError::CircuitOpen), ErrorCodes::Unavailable),
+            (Box::new(S3GetError::ShuttingDown), ErrorCodes::Unavailable),
+            (
+                Box::new(S3GetError::FileError(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3GetError::NotModified),
+                ErrorCodes::FailedPrecondition,
+            ),
+            (
+                Box::new(S3GetError::AccessDenied {
+                    message: message(),
+                    request_ids: RequestIds::default(),
+                }),
+                ErrorCodes::PermissionDenied,
+            ),
+            (
+                Box::new(S3GetError::ObjectChanged(message())),
+                ErrorCodes::Aborted,
+            ),
+            (
+                Box::new(S3GetError::Cancelled(message())),
+                ErrorCodes::Cancelled,
+            ),
+            (
+                Box::new(S3GetError::TruncatedResponse {
+                    expected: 2,
+                    actual: 1,
+                }),
+                ErrorCodes::Unavailable,
+            ),
+            (
+                Box::new(S3PutError::S3PutError(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3PutError::ServiceError {
+                    message: message(),
+                    request_ids: RequestIds::default(),
+                }),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3PutError::S3DispatchFailure),
+                ErrorCodes::Unavailable,
+            ),
+            (
+                Box::new(S3PutError::Timeout(message())),
+                ErrorCodes::DeadlineExceeded,
+            ),
+            (
+                Box::new(S3PutError::CompressionError(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3PutError::PreconditionFailed(message())),
+                ErrorCodes::FailedPrecondition,
+            ),
+            (
+                Box::new(S3PutError::InvalidTag(message())),
+                ErrorCodes::InvalidArgument,
+            ),
+            (
+                Box::new(S3PutError::InvalidMetadata(message())),
+                ErrorCodes::InvalidArgument,
+            ),
+            (Box::new(S3PutError::CircuitOpen), ErrorCodes::Unavailable),
+            (Box::new(S3PutError::ShuttingDown), ErrorCodes::Unavailable),
+            (
+                Box::new(S3PutError::EmptyPayload(message())),
+                ErrorCodes::InvalidArgument,
+            ),
+            (
+                Box::new(S3DeleteError::S3DeleteError {
+                    code: None,
+                    message: message(),
+                }),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3CopyError::SourceNotFound(message())),
+                ErrorCodes::NotFound,
+            ),
+            (
+                Box::new(S3CopyError::S3CopyError(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3RenameError::SourceNotFound(message())),
+                ErrorCodes::NotFound,
+            ),
+            (
+                Box::new(S3RenameError::CopyFailed(S3CopyError::S3CopyError(
+                    message(),
+                ))),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3RenameError::SourceDeleteFailed(
+                    S3DeleteError::S3DeleteError {
+                        code: None,
+                        message: message(),
+                    },
+                )),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3RenameError::RollbackFailed {
+                    src_key: message(),
+                    dst_key: message(),
+                    delete_error: S3DeleteError::S3DeleteError {
+                        code: None,
+                        message: message(),
+                    },
+                    rollback_error: S3DeleteError::S3DeleteError {
+                        code: None,
+                        message: message(),
+                    },
+                }),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3HeadError::S3HeadError(message())),
+                ErrorCodes::Internal,
+            ),
+            (Box::new(S3HeadError::ShuttingDown), ErrorCodes::Unavailable),
+            (
+                Box::new(S3DeleteError::ShuttingDown),
+                ErrorCodes::Unavailable,
+            ),
+            (Box::new(S3CopyError::ShuttingDown), ErrorCodes::Unavailable),
+            (
+                Box::new(S3RenameError::ShuttingDown),
+                ErrorCodes::Unavailable,
+            ),
+            (
+                Box::new(S3PresignError::ExpiryTooLong(Duration::from_secs(1))),
+                ErrorCodes::InvalidArgument,
+            ),
+            (
+                Box::new(S3PresignError::S3PresignError(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3ListError::S3ListError(message())),
+                ErrorCodes::Internal,
+            ),
+            (Box::new(S3ListError::ShuttingDown), ErrorCodes::Unavailable),
+            (
+                Box::new(S3SelectError::NoSuchKey(message())),
+                ErrorCodes::NotFound,
+            ),
+            (
+                Box::new(S3SelectError::InvalidExpression(message())),
+                ErrorCodes::InvalidArgument,
+            ),
+            (
+                Box::new(S3SelectError::S3SelectError(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(S3SelectError::ShuttingDown),
+                ErrorCodes::Unavailable,
+            ),
+            (
+                Box::new(StorageConfigError::InvalidStorageConfig),
+                ErrorCodes::InvalidArgument,
+            ),
+            (
+                Box::new(StorageConfigError::InvalidKey(message())),
+                ErrorCodes::InvalidArgument,
+            ),
+            (
+                Box::new(StorageConfigError::FailedToCreateBucket(message())),
+                ErrorCodes::Internal,
+            ),
+            (
+                Box::new(StorageConfigError::ValidationFailed(message())),
+                ErrorCodes::FailedPrecondition,
+            ),
+            (
+                Box::new(StorageConfigError::ConflictingOptions(message())),
+                ErrorCodes::InvalidArgument,
+            ),
+            (
+                Box::new(StorageConfigError::InvalidField {
+                    field: "bucket",
+                    reason: message(),
+                }),
+                ErrorCodes::InvalidArgument,
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.code(), code, "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_retries_transient_errors() {
+        let slow_down = "<Error><Code>SlowDown</Code><Message>slow down</Message></Error>";
+        let (client, http_client) = get_mock_s3_client_with_retries(
+            vec![
+                mock_event(503, slow_down),
+                mock_event(503, slow_down),
+                mock_event(200, "test data"),
+            ],
+            retry_config(Some(2), Some(1)),
+        );
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let mut stream = storage.get("test").await.unwrap();
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(buf, "test data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget_stops_retries_once_exhausted() {
+        let internal_error =
+            "<Error><Code>InternalError</Code><Message>internal error</Message></Error>";
+        let http_client =
+            StaticReplayClient::new((0..5).map(|_| mock_event(500, internal_error)).collect());
+        let budget = Arc::new(RetryBudget::new(2));
+        let config = with_retry_budget(
+            mock_s3_config(&http_client).retry_config(retry_config(Some(2), Some(1))),
+            Some(&budget),
+        );
+        let client = aws_sdk_s3::Client::from_conf(config.build());
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        // The first get is retried twice, which exhausts the budget.
+        assert!(storage.get("test").await.is_err());
+        assert_eq!(http_client.actual_requests().count(), 3);
+        assert_eq!(budget.remaining(), 0);
+
+        // Later failures are returned without being retried.
+        for _ in 0..2 {
+            assert!(storage.get("test").await.is_err());
+        }
+        assert_eq!(http_client.actual_requests().count(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_retry_backoff_is_timed_by_clock() {
+        let internal_error =
+            "<Error><Code>InternalError</Code><Message>internal error</Message></Error>";
+        let http_client = StaticReplayClient::new(vec![
+            mock_event(500, internal_error),
+            mock_event(500, internal_error),
+            get_event("test data", &[]),
+        ]);
+        let clock = Arc::new(crate::clock::ManualClock::new());
+        let retry_config = RetryConfig::standard()
+            .with_max_attempts(3)
+            .with_initial_backoff(Duration::from_secs(1))
+            // Without jitter, so that the backoff is exact.
+            .with_use_static_exponential_base(true);
+        let config = with_clock(
+            mock_s3_config(&http_client)
+                .retry_config(retry_config)
+                // Both would otherwise sleep on the clock too, for their
+                // throughput checks and credential load timeouts.
+                .stalled_stream_protection(
+                    aws_sdk_s3::config::StalledStreamProtectionConfig::disabled(),
+                )
+                .identity_cache(aws_sdk_s3::config::IdentityCache::no_cache()),
+            clock.clone(),
+        );
+        let storage = S3Storage::new(
+            "test",
+            aws_sdk_s3::Client::from_conf(config.build()),
+            1024 * 1024 * 8,
+        );
+
+        let get = tokio::spawn(async move { read_all(storage.get("test").await.unwrap()).await });
+        for (attempts, backoff) in [(1, Duration::from_secs(1)), (2, Duration::from_secs(2))] {
+            while clock.pending_sleeps() == 0 {
+                tokio::task::yield_now().await;
+            }
+            // The retry waits for the whole backoff.
+            clock.advance(backoff - Duration::from_millis(1));
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(http_client.actual_requests().count(), attempts);
+            clock.advance(Duration::from_millis(1));
+        }
+        assert_eq!(get.await.unwrap().unwrap(), "test data".as_bytes());
+        assert_eq!(
+            clock.sleeps(),
+            vec![Duration::from_secs(1), Duration::from_secs(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_service_errors_carry_request_ids() {
+        let event = || {
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri("https://test.s3.us-east-1.amazonaws.com/")
+                    .body(SdkBody::empty())
+                    .unwrap(),
+                http::Response::builder()
+                    .status(500)
+                    .header("x-amz-request-id", "4442587FB7D0A2F9")
+                    .header("x-amz-id-2", "vlR7PnpV2Ce81l0PRw6jlUpck7Jo5ZsQjryTjKlc5aLWGVHPZLj5NeC6qMa0emYBDXOo6QBU0Wo=")
+                    .body(SdkBody::from(
+                        "<Error><Code>InternalError</Code><Message>internal error</Message></Error>",
+                    ))
+                    .unwrap(),
+            )
+        };
+        let (client, _) = get_mock_s3_client(vec![event(), event()]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        let expected = RequestIds {
+            request_id: Some("4442587FB7D0A2F9".to_string()),
+            extended_request_id: Some(
+                "vlR7PnpV2Ce81l0PRw6jlUpck7Jo5ZsQjryTjKlc5aLWGVHPZLj5NeC6qMa0emYBDXOo6QBU0Wo="
+                    .to_string(),
+            ),
+        };
+
+        let get_error = storage.get("test").await.err().unwrap();
+        let put_error = storage
+            .put_bytes("test", "test data".as_bytes().to_vec())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&get_error, S3GetError::ServiceError { request_ids, .. } if *request_ids == expected)
+        );
+        assert!(
+            matches!(&put_error, S3PutError::ServiceError { request_ids, .. } if *request_ids == expected)
+        );
+        for message in [get_error.to_string(), put_error.to_string()] {
+            assert!(
+                message.contains("request id: 4442587FB7D0A2F9"),
+                "{}",
+                message
+            );
+            assert!(
+                message.contains("extended request id: vlR7PnpV2Ce81l0PRw6jlUpck7Jo5ZsQjryTjKlc5aLWGVHPZLj5NeC6qMa0emYBDXOo6QBU0Wo="),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_does_not_retry_no_such_key() {
+        let (client, http_client) = get_mock_s3_client_with_retries(
+            vec![mock_event(
+                404,
+                "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>",
+            )],
+            retry_config(Some(2), Some(1)),
+        );
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage.get("test").await;
+        assert!(matches!(res, Err(S3GetError::NoSuchKey(_))));
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    // Retries a NoSuchKey, as a deployment might while a newly written object
+    // may not be visible yet.
+    #[derive(Debug)]
+    struct RetryNoSuchKey;
+
+    impl RetryClassifier for RetryNoSuchKey {
+        fn classify(&self, attempt: &FailedAttempt<'_>) -> RetryDecision {
+            match attempt.code {
+                Some("NoSuchKey") => RetryDecision::Retryable,
+                _ => crate::retry::DefaultRetryClassifier.classify(attempt),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_classifier_decides_what_is_retried() {
+        let no_such_key = "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>";
+        let http_client = StaticReplayClient::new(vec![
+            mock_event(404, no_such_key),
+            mock_event(404, no_such_key),
+            get_event("test data", &[]),
+            mock_event(404, no_such_key),
+            mock_event(404, no_such_key),
+            mock_event(404, no_such_key),
+            mock_event(
+                403,
+                "<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>",
+            ),
+        ]);
+        let retry_classifier: Arc<dyn RetryClassifier> = Arc::new(RetryNoSuchKey);
+        let config = with_retry_classifier(
+            mock_s3_config(&http_client).retry_config(retry_config(Some(2), Some(1))),
+            Some(&retry_classifier),
+        );
+        let client = aws_sdk_s3::Client::from_conf(config.build());
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        // The object shows up on the second retry.
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "test data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 3);
+
+        // Retries are still bounded by the retry config.
+        let res = storage.get("missing").await;
+        assert!(matches!(res, Err(S3GetError::NoSuchKey(_))));
+        assert_eq!(http_client.actual_requests().count(), 6);
+
+        // Errors the classifier calls terminal are not retried.
+        let res = storage.get("denied").await;
+        assert!(matches!(res, Err(S3GetError::AccessDenied { .. })));
+        assert_eq!(http_client.actual_requests().count(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_get_tells_access_denied_from_no_such_key() {
+        let (client, _) = get_mock_s3_client(vec![
+            mock_event(
+                403,
+                "<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>",
+            ),
+            mock_event(
+                404,
+                "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>",
+            ),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage.get("test").await;
+        assert!(
+            matches!(&res, Err(S3GetError::AccessDenied { message, .. }) if message == "AccessDenied: Access Denied"),
+            "{:?}",
+            res.err()
+        );
+        assert!(matches!(
+            storage.get("test").await,
+            Err(S3GetError::NoSuchKey(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_put_retries_transient_errors() {
+        let internal_error =
+            "<Error><Code>InternalError</Code><Message>internal error</Message></Error>";
+        let (client, http_client) = get_mock_s3_client_with_retries(
+            vec![
+                mock_event(500, internal_error),
+                mock_event(500, internal_error),
+                mock_event(200, ""),
+            ],
+            retry_config(Some(2), Some(1)),
+        );
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage
+            .put_bytes("test", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        assert_eq!(http_client.actual_requests().count(), 3);
+    }
+
+    #[derive(Debug)]
+    struct CapturedSpan {
+        name: String,
+        parent: Option<u64>,
+        fields: HashMap<String, String>,
+    }
+
+    // Records every span created while it is the default subscriber, in
+    // order, along with the values recorded on it. Span ids are reused once a
+    // span closes, so a recorded value belongs to the latest span with its id.
+    #[derive(Clone, Default)]
+    struct SpanCapture(Arc<std::sync::Mutex<Vec<(u64, CapturedSpan)>>>);
+
+    struct FieldRecorder<'a>(&'a mut HashMap<String, String>);
+
+    impl tracing::field::Visit for FieldRecorder<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanCapture {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldRecorder(&mut fields));
+            self.0.lock().unwrap().push((
+                id.into_u64(),
+                CapturedSpan {
+                    name: attrs.metadata().name().to_string(),
+                    parent: attrs.parent().map(|parent| parent.into_u64()),
+                    fields,
+                },
+            ));
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut spans = self.0.lock().unwrap();
+            if let Some((_, span)) = spans
+                .iter_mut()
+                .rev()
+                .find(|(span_id, _)| *span_id == id.into_u64())
+            {
+                values.record(&mut FieldRecorder(&mut span.fields));
+            }
+        }
+    }
+
+    impl SpanCapture {
+        fn named(&self, name: &str) -> Vec<(u64, Option<u64>, HashMap<String, String>)> {
+            let spans = self.0.lock().unwrap();
+            spans
+                .iter()
+                .filter(|(_, span)| span.name == name)
+                .map(|(id, span)| (*id, span.parent, span.fields.clone()))
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_records_spans() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let capture = SpanCapture::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+
+        let (client, _) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        storage
+            .put_bytes("small", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let puts = capture.named("Storage put");
+        assert_eq!(puts.len(), 1);
+        assert_eq!(puts[0].2.get("key").map(String::as_str), Some("small"));
+        assert_eq!(puts[0].2.get("bytes").map(String::as_str), Some("9"));
+        assert!(capture.named("Storage put part").is_empty());
+
+        let (client, _) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT),
+        ]);
+        let storage = S3Storage::new("test", client, 4);
+        let temp_file = write_temp_file("0123456789".as_bytes());
+        storage
+            .put_file("large", temp_file.path().to_str().unwrap())
+            .await
+            .unwrap();
+        let puts = capture.named("Storage put");
+        assert_eq!(puts.len(), 2);
+        let (put_id, _, fields) = &puts[1];
+        assert_eq!(fields.get("key").map(String::as_str), Some("large"));
+        assert_eq!(fields.get("bytes").map(String::as_str), Some("10"));
+        let parts = capture.named("Storage put part");
+        assert_eq!(
+            parts
+                .iter()
+                .map(|(_, parent, fields)| (*parent, fields.get("part_number").cloned()))
+                .collect::<Vec<_>>(),
+            (1..=3)
+                .map(|part_number| (Some(*put_id), Some(part_number.to_string())))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let internal_error =
+            "<Error><Code>InternalError</Code><Message>internal error</Message></Error>";
+        let (client, _) = get_mock_s3_client(vec![
+            mock_event(200, ""),
+            mock_event(500, internal_error),
+            mock_event(200, ""),
+        ]);
+        let circuit_breaker = Arc::new(CircuitBreaker::new(1, Duration::from_secs(60)));
+        let storage = S3Storage {
+            circuit_breaker: Some(circuit_breaker.clone()),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        assert_eq!(storage.health().await, StorageHealth::Healthy);
+        assert!(matches!(
+            storage.health().await,
+            StorageHealth::Unavailable { reason } if reason.contains("not reachable")
+        ));
+
+        circuit_breaker.record(true);
+        assert_eq!(
+            storage.health().await,
+            StorageHealth::Degraded {
+                reason: "circuit breaker is open".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, ""),
+            mock_event(404, ""),
+            mock_event(403, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage.validate().await.unwrap();
+        let res = storage.validate().await;
+        assert!(
+            matches!(&res, Err(StorageConfigError::ValidationFailed(message)) if message == "bucket test does not exist"),
+            "{:?}",
+            res
+        );
+        let res = storage.validate().await;
+        assert!(
+            matches!(&res, Err(StorageConfigError::ValidationFailed(message)) if message.contains("denied")),
+            "{:?}",
+            res
+        );
+        assert!(http_client
+            .actual_requests()
+            .all(|request| request.method() == "HEAD"));
+    }
+
+    #[tokio::test]
+    async fn test_presigned_urls() {
+        let (client, http_client) = get_mock_s3_client(vec![]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let url = storage
+            .presigned_get_url("path/to/key", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert!(url.starts_with("https://test.s3.us-east-1.amazonaws.com/path/to/key?"));
+        assert!(url.contains("X-Amz-Expires=3600"));
+        assert!(url.contains("X-Amz-Signature="));
+
+        let url = storage
+            .presigned_put_url("path/to/key", Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(url.starts_with("https://test.s3.us-east-1.amazonaws.com/path/to/key?"));
+        assert!(url.contains("X-Amz-Expires=60"));
+
+        // Signing happens locally.
+        assert_eq!(http_client.actual_requests().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_presigned_url_rejects_expiry_over_a_week() {
+        let (client, _) = get_mock_s3_client(vec![]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        assert!(storage.presigned_get_url("test", week).await.is_ok());
+        let res = storage
+            .presigned_get_url("test", week + Duration::from_secs(1))
+            .await;
+        assert!(matches!(res, Err(S3PresignError::ExpiryTooLong(_))));
+        let res = storage
+            .presigned_put_url("test", week + Duration::from_secs(1))
+            .await;
+        assert!(matches!(res, Err(S3PresignError::ExpiryTooLong(_))));
+    }
+
+    // A config for a storage that sends its requests to a custom endpoint,
+    // with every option left at its default.
+    fn mock_storage_config() -> crate::config::S3StorageConfig {
+        crate::config::S3StorageConfig {
+            bucket: "test".to_string(),
+            fallback_bucket: None,
+            prefix: None,
+            credentials: S3CredentialsConfig::Minio,
+            connect_timeout_ms: None,
+            read_timeout_ms: None,
+            upload_part_size_bytes: None,
+            rate_limit_rps: None,
+            max_bytes_per_second: None,
+            max_concurrent_requests: None,
+            max_idle_connections: None,
+            adaptive_concurrency: false,
+            circuit_breaker_failure_threshold: None,
+            circuit_breaker_cooldown_ms: None,
+            max_retries: Some(0),
+            base_backoff_ms: None,
+            retry_budget: None,
+            verify_checksums: false,
+            validate_content_length: false,
+            reject_empty_puts: false,
+            decode_content_encoding: false,
+            operation_timeout_ms: None,
+            hedge_after_ms: None,
+            requester_pays: false,
+            multipart_threshold_bytes: None,
+            upload_concurrency: None,
+            cache_capacity_bytes: None,
+            cache_max_object_size_bytes: None,
+            compression: None,
+            sse: None,
+            validate_on_startup: false,
+            read_ahead: false,
+            read_ahead_chunks: None,
+            max_object_size_bytes: None,
+            small_object_threshold_bytes: None,
+            exists_list_threshold: None,
+            parallel_download_threshold_bytes: None,
+            download_part_size_bytes: None,
+            download_concurrency: None,
+            endpoint_url: Some("http://injected.test:9000".to_string()),
+            force_path_style: false,
+            use_transfer_acceleration: false,
+            user_agent_suffix: None,
+            normalize_keys: KeyCase::None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_try_from_config_with_http_client() {
+        let config = StorageConfig::S3(mock_storage_config());
+        // The Minio credentials create the bucket on startup.
+        let http_client =
+            StaticReplayClient::new(vec![mock_event(200, ""), get_event("test data", &[])]);
+        let storage = S3Storage::try_from_config_with_http_client(&config, http_client.clone())
+            .await
+            .unwrap();
+
+        let mut stream = storage.get("key").await.unwrap();
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(buf, "test data".as_bytes());
+        // The requests went through the injected client, to the configured
+        // endpoint.
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1]
+            .uri()
+            .starts_with("http://injected.test:9000/test/key"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_configs_are_rejected() {
+        let cases = vec![
+            (
+                crate::config::S3StorageConfig {
+                    bucket: "".to_string(),
+                    ..mock_storage_config()
+                },
+                "bucket must not be empty",
+            ),
+            (
+                crate::config::S3StorageConfig {
+                    read_timeout_ms: Some(0),
+                    ..mock_storage_config()
+                },
+                "read_timeout_ms must be greater than zero",
+            ),
+            (
+                crate::config::S3StorageConfig {
+                    multipart_threshold_bytes: Some(1024 * 1024),
+                    ..mock_storage_config()
+                },
+                "multipart_threshold_bytes must be at least 5 MiB, the smallest part S3 accepts",
+            ),
+            (
+                crate::config::S3StorageConfig {
+                    adaptive_concurrency: true,
+                    ..mock_storage_config()
+                },
+                "adaptive_concurrency requires max_concurrent_requests",
+            ),
+            (
+                crate::config::S3StorageConfig {
+                    cache_max_object_size_bytes: Some(1024),
+                    ..mock_storage_config()
+                },
+                "cache_max_object_size_bytes requires cache_capacity_bytes",
+            ),
+        ];
+        for (config, reason) in cases {
+            // Rejected before the Minio bucket is created, so nothing is
+            // sent.
+            let res = S3Storage::try_from_config(&StorageConfig::S3(config)).await;
+            assert_eq!(
+                res.err().map(|e| e.to_string()),
+                Some(format!("Invalid storage config: {}", reason))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_suffix() {
+        let config = StorageConfig::S3(crate::config::S3StorageConfig {
+            user_agent_suffix: Some("query-service".to_string()),
+            ..mock_storage_config()
+        });
+        // The Minio credentials create the bucket on startup.
+        let http_client =
+            StaticReplayClient::new(vec![mock_event(200, ""), get_event("test data", &[])]);
+        let storage = S3Storage::try_from_config_with_http_client(&config, http_client.clone())
+            .await
+            .unwrap();
+        read_all(storage.get("key").await.unwrap()).await.unwrap();
+
+        for request in http_client.actual_requests() {
+            let user_agent = request.headers().get("x-amz-user-agent").unwrap();
+            assert!(user_agent.contains("app/query-service"), "{}", user_agent);
+            let user_agent = request.headers().get("user-agent").unwrap();
+            assert!(user_agent.contains("app/query-service"), "{}", user_agent);
+        }
+
+        let config = StorageConfig::S3(crate::config::S3StorageConfig {
+            user_agent_suffix: Some("query service".to_string()),
+            ..mock_storage_config()
+        });
+        assert!(matches!(
+            config.validate(),
+            Err(StorageConfigError::InvalidField {
+                field: "user_agent_suffix",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_max_idle_connections_from_config() {
+        let static_credentials = || S3CredentialsConfig::Static {
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+            session_token: None,
+        };
+        // Builds the client with its own connection pool, without sending a
+        // request.
+        let config = StorageConfig::S3(crate::config::S3StorageConfig {
+            credentials: static_credentials(),
+            max_concurrent_requests: Some(32),
+            max_idle_connections: Some(64),
+            ..mock_storage_config()
+        });
+        S3Storage::try_from_config(&config).await.unwrap();
+
+        let config = StorageConfig::S3(crate::config::S3StorageConfig {
+            credentials: static_credentials(),
+            max_idle_connections: Some(0),
+            ..mock_storage_config()
+        });
+        assert!(S3Storage::try_from_config(&config).await.is_err());
+
+        // A custom client keeps its own connections.
+        let config = StorageConfig::S3(crate::config::S3StorageConfig {
+            max_idle_connections: Some(64),
+            ..mock_storage_config()
+        });
+        let res =
+            S3Storage::try_from_config_with_http_client(&config, StaticReplayClient::new(vec![]))
+                .await;
+        assert_eq!(
+            res.err().map(|e| e.code()),
+            Some(ErrorCodes::InvalidArgument)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_aws_config_for_each_credentials_source() {
+        use aws_sdk_s3::config::ProvideCredentials;
+
+        let config = load_aws_config(
+            &S3CredentialsConfig::Static {
+                access_key: "access".to_string(),
+                secret_key: "secret".to_string(),
+                session_token: Some("token".to_string()),
+            },
+            None,
+        )
+        .await;
+        let credentials = config
+            .credentials_provider()
+            .unwrap()
+            .provide_credentials()
+            .await
+            .unwrap();
+        assert_eq!(credentials.access_key_id(), "access");
+        assert_eq!(credentials.secret_access_key(), "secret");
+        assert_eq!(credentials.session_token(), Some("token"));
+
+        for credentials in [
+            S3CredentialsConfig::AWS,
+            S3CredentialsConfig::Profile {
+                name: "chroma-test".to_string(),
+            },
+        ] {
+            // Credentials are resolved lazily, so these build even where
+            // no such credentials exist.
+            let config = load_aws_config(&credentials, None).await;
+            assert!(config.credentials_provider().is_some());
+            let _client = aws_sdk_s3::Client::from_conf(
+                aws_sdk_s3::config::Builder::from(&config)
+                    .region(aws_sdk_s3::config::Region::new("us-east-1"))
+                    .build(),
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_builds_with_timeouts() {
+        let (_, http_client) = get_mock_s3_client(vec![mock_event(200, "test data")]);
+        let config = mock_s3_config(&http_client)
+            .timeout_config(timeout_config(Some(1500), Some(2500)))
+            .build();
+        let timeouts = config.timeout_config().unwrap();
+        assert_eq!(
+            timeouts.connect_timeout(),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(timeouts.read_timeout(), Some(Duration::from_millis(2500)));
+
+        let storage = S3Storage::new(
+            "test",
+            aws_sdk_s3::Client::from_conf(config),
+            1024 * 1024 * 8,
+        );
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "test data".as_bytes());
+    }
+
+    #[test]
+    fn test_unset_timeouts_keep_defaults() {
+        let (_, http_client) = get_mock_s3_client(vec![]);
+        let config = mock_s3_config(&http_client)
+            .timeout_config(timeout_config(Some(1500), None))
+            .timeout_config(timeout_config(None, Some(2500)))
+            .build();
+        let timeouts = config.timeout_config().unwrap();
+        assert_eq!(
+            timeouts.connect_timeout(),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(timeouts.read_timeout(), Some(Duration::from_millis(2500)));
+    }
+
+    fn get_event(body: impl AsRef<[u8]>, headers: &[(&str, &str)]) -> ReplayEvent {
+        let mut response = http::Response::builder().status(200);
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://test.s3.us-east-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            response
+                .body(SdkBody::from(body.as_ref().to_vec()))
+                .unwrap(),
+        )
+    }
+
+    async fn read_all(
+        mut stream: Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>,
+    ) -> Result<Vec<u8>, GetError> {
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn test_get_rejects_truncated_body() {
+        let (client, _) =
+            get_mock_s3_client(vec![get_event(b"trunc", &[("content-length", "100")])]);
+        let storage = S3Storage {
+            validate_content_length: true,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let res = read_all(storage.get("test").await.unwrap()).await;
+        assert!(matches!(
+            res,
+            Err(GetError::S3Error(S3GetError::TruncatedResponse {
+                expected: 100,
+                actual: 5
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_put_bytes_stores_checksum() {
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage
+            .put_bytes("test", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let request = http_client.actual_requests().next().unwrap();
+        assert_eq!(
+            request.headers().get("x-amz-meta-sha256"),
+            Some(hex::encode(Sha256::digest("test data".as_bytes())).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_empty_put_creates_empty_object() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, ""),
+            get_event("", &[("content-length", "0")]),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage.put_bytes("test", Vec::new()).await.unwrap();
+        let request = http_client.actual_requests().next().unwrap();
+        assert_eq!(request.headers().get("content-length"), Some("0"));
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reject_empty_puts() {
+        let (client, http_client) = get_mock_s3_client(vec![]);
+        let storage = S3Storage {
+            reject_empty_puts: true,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let res = storage.put_bytes("test", Vec::new()).await;
+        assert!(matches!(res, Err(S3PutError::EmptyPayload(key)) if key == "test"));
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let res = storage
+            .put_file("test", file.path().to_str().unwrap())
+            .await;
+        assert!(matches!(res, Err(S3PutError::EmptyPayload(_))));
+        let res = storage
+            .put_stream("test", stream::iter(Vec::<Result<Bytes, String>>::new()))
+            .await;
+        assert!(matches!(res, Err(S3PutError::EmptyPayload(_))));
+        let empty_chunk = stream::iter(vec![Ok::<_, String>(Bytes::new())]);
+        let res = storage.put_stream("test", empty_chunk).await;
+        assert!(matches!(res, Err(S3PutError::EmptyPayload(_))));
+        assert_eq!(http_client.actual_requests().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_put_bytes_with_tags() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, ""),
+            mock_event(
+                200,
+                "<Tagging><TagSet><Tag><Key>cost center</Key><Value>db/1</Value></Tag><Tag><Key>team</Key><Value>storage</Value></Tag></TagSet></Tagging>",
+            ),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        let tags = HashMap::from([
+            ("team".to_string(), "storage".to_string()),
+            ("cost center".to_string(), "db/1".to_string()),
+        ]);
+
+        storage
+            .put_bytes_with_tags("test", "test data".as_bytes().to_vec(), &tags)
+            .await
+            .unwrap();
+        let request = http_client.actual_requests().next().unwrap();
+        assert_eq!(
+            request.headers().get("x-amz-tagging"),
+            Some("cost%20center=db%2F1&team=storage")
+        );
+
+        assert_eq!(storage.get_tags("test").await.unwrap(), tags);
+    }
+
+    #[tokio::test]
+    async fn test_put_bytes_with_content_type() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, ""),
+            mock_event(200, ""),
+            get_event("", &[("content-type", "text/html")]),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage
+            .put_bytes_with_content_type("test", b"<html></html>".to_vec(), "text/html")
+            .await
+            .unwrap();
+        storage
+            .put_bytes("default", b"test data".to_vec())
+            .await
+            .unwrap();
+        let content_types = http_client
+            .actual_requests()
+            .map(|request| request.headers().get("content-type").map(str::to_string))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            content_types,
+            vec![
+                Some("text/html".to_string()),
+                Some("application/octet-stream".to_string())
+            ]
+        );
+
+        let metadata = storage.head("test").await.unwrap().unwrap();
+        assert_eq!(metadata.content_type.as_deref(), Some("text/html"));
+    }
+
+    #[tokio::test]
+    async fn test_put_bytes_with_metadata_round_trips() {
+        let metadata = HashMap::from([
+            ("owner".to_string(), "compactor".to_string()),
+            ("segment-id".to_string(), "1234".to_string()),
+            ("format_version".to_string(), "v2 (beta)".to_string()),
+        ]);
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        storage
+            .put_bytes_with_metadata("test", "test data".as_bytes().to_vec(), metadata.clone())
+            .await
+            .unwrap();
+
+        // Answer the head with the metadata headers the put sent, including
+        // the checksum put_bytes adds.
+        let request = http_client.actual_requests().next().unwrap();
+        let headers = request
+            .headers()
+            .iter()
+            .filter(|(name, _)| name.starts_with("x-amz-meta-"))
+            .collect::<Vec<_>>();
+        assert_eq!(headers.len(), metadata.len() + 1);
+        let (client, _) = get_mock_s3_client(vec![get_event("", &headers)]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        let head = storage.head("test").await.unwrap().unwrap();
+        assert_eq!(head.metadata, metadata);
+    }
+
+    #[tokio::test]
+    async fn test_put_bytes_with_invalid_metadata() {
+        let (client, http_client) = get_mock_s3_client(vec![]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let invalid = [
+            HashMap::from([("Owner".to_string(), "value".to_string())]),
+            HashMap::from([(String::new(), "value".to_string())]),
+            HashMap::from([("key:1".to_string(), "value".to_string())]),
+            HashMap::from([("sha256".to_string(), "value".to_string())]),
+            HashMap::from([("key".to_string(), "valu\u{e9}".to_string())]),
+            HashMap::from([("key".to_string(), " value".to_string())]),
+            HashMap::from([("key".to_string(), "v".repeat(MAX_METADATA_BYTES))]),
+        ];
+        for metadata in invalid {
+            let res = storage
+                .put_bytes_with_metadata("test", "test data".as_bytes().to_vec(), metadata.clone())
+                .await;
+            assert!(
+                matches!(res, Err(S3PutError::InvalidMetadata(_))),
+                "{:?}",
+                metadata
+            );
+        }
+        assert_eq!(http_client.actual_requests().count(), 0);
+
+        // The size limit is inclusive.
+        let metadata = HashMap::from([("k".to_string(), "v".repeat(MAX_METADATA_BYTES - 1))]);
+        assert!(validate_metadata(&metadata).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_put_bytes_with_invalid_tags() {
+        let (client, http_client) = get_mock_s3_client(vec![]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let invalid = [
+            HashMap::from([("k".repeat(129), "value".to_string())]),
+            HashMap::from([(String::new(), "value".to_string())]),
+            HashMap::from([("key".to_string(), "v".repeat(257))]),
+            HashMap::from([("aws:key".to_string(), "value".to_string())]),
+            HashMap::from([("key".to_string(), "value&more".to_string())]),
+            (0..11)
+                .map(|i| (format!("key-{}", i), "value".to_string()))
+                .collect(),
+        ];
+        for tags in invalid {
+            let res = storage
+                .put_bytes_with_tags("test", "test data".as_bytes().to_vec(), &tags)
+                .await;
+            assert!(matches!(res, Err(S3PutError::InvalidTag(_))), "{:?}", tags);
+        }
+        assert_eq!(http_client.actual_requests().count(), 0);
+
+        // The limits are inclusive.
+        let tags = HashMap::from([("k".repeat(128), "v".repeat(256))]);
+        assert!(encode_tags(&tags).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_puts_request_server_side_encryption() {
+        let kms = ServerSideEncryption::AwsKms {
+            key_id: "test-key".to_string(),
+        };
+        let cases = [
+            (None, None, None),
+            (Some(ServerSideEncryption::Aes256), Some("AES256"), None),
+            (Some(kms), Some("aws:kms"), Some("test-key")),
+        ];
+        for (sse, expected_sse, expected_key_id) in cases {
+            let (client, http_client) = get_mock_s3_client(vec![
+                mock_event(200, ""),
+                mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+                mock_event(200, ""),
+                mock_event(200, ""),
+                mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT),
+            ]);
+            let storage = S3Storage {
+                sse,
+                ..S3Storage::new("test", client, 8)
+            };
+
+            storage
+                .put_bytes("small", "test".as_bytes().to_vec())
+                .await
+                .unwrap();
+            storage
+                .put_bytes("large", "0123456789".as_bytes().to_vec())
+                .await
+                .unwrap();
+
+            let requests = http_client.actual_requests().collect::<Vec<_>>();
+            assert_eq!(requests.len(), 5);
+            // The single PUT and the request creating the multipart upload
+            // carry the encryption; the parts inherit it.
+            for request in [requests[0], requests[1]] {
+                assert_eq!(
+                    request.headers().get("x-amz-server-side-encryption"),
+                    expected_sse
+                );
+                assert_eq!(
+                    request
+                        .headers()
+                        .get("x-amz-server-side-encryption-aws-kms-key-id"),
+                    expected_key_id
+                );
+            }
+            for request in &requests[2..] {
+                assert_eq!(request.headers().get("x-amz-server-side-encryption"), None);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_if_absent_writes_missing_object() {
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let written = storage
+            .put_if_absent("test", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        assert!(written);
+        let request = http_client.actual_requests().next().unwrap();
+        assert_eq!(request.headers().get("if-none-match"), Some("*"));
+    }
+
+    #[tokio::test]
+    async fn test_put_if_absent_leaves_existing_object() {
+        let (client, _) = get_mock_s3_client(vec![mock_event(
+            412,
+            "<Error><Code>PreconditionFailed</Code><Message>At least one of the pre-conditions you specified did not hold</Message></Error>",
+        )]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let written = storage
+            .put_if_absent("test", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        assert!(!written);
+    }
+
+    #[tokio::test]
+    async fn test_append_creates_missing_object() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(
+                404,
+                "<Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message></Error>",
+            ),
+            mock_event(200, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage.append("test", b"abc").await.unwrap();
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].headers().get("if-none-match"), Some("*"));
+        assert_eq!(requests[1].headers().get("if-match"), None);
+        assert_eq!(requests[1].body().bytes(), Some(&b"abc"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_append_extends_existing_object() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("abc", &[("etag", "\"v1\"")]),
+            mock_event(200, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage.append("test", b"def").await.unwrap();
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].headers().get("if-match"), Some("\"v1\""));
+        assert_eq!(requests[1].headers().get("if-none-match"), None);
+        assert_eq!(requests[1].body().bytes(), Some(&b"abcdef"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_append_rejects_existing_object_without_etag() {
+        let (client, http_client) = get_mock_s3_client(vec![get_event("abc", &[])]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage.append("test", b"def").await;
+        assert!(matches!(res, Err(S3PutError::S3PutError(_))));
+        // Nothing is written back.
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_append_to_object_in_fallback_bucket() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(
+                404,
+                "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>",
+            ),
+            get_event("abc", &[("etag", "\"v1\"")]),
+            mock_event(200, ""),
+        ]);
+        let storage = S3Storage {
+            fallback_bucket: Some("fallback".to_string()),
+            ..S3Storage::new("primary", client, 1024 * 1024 * 8)
+        };
+
+        storage.append("test", b"def").await.unwrap();
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].uri().starts_with("https://fallback."));
+        // Written to the primary bucket, which has no object yet.
+        assert!(requests[2].uri().starts_with("https://primary."));
+        assert_eq!(requests[2].headers().get("if-none-match"), Some("*"));
+        assert_eq!(requests[2].headers().get("if-match"), None);
+        assert_eq!(requests[2].body().bytes(), Some(&b"abcdef"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_append_is_rejected_once_shutting_down() {
+        let (client, http_client) = get_mock_s3_client(vec![]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        assert!(storage.shutdown(Duration::ZERO).await);
+
+        let res = storage.append("test", b"abc").await;
+        assert!(matches!(res, Err(S3PutError::ShuttingDown)));
+        assert_eq!(http_client.actual_requests().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_appends_conflict() {
+        let http_client = AppendServingClient::new(b"abc".to_vec());
+        let storage = S3Storage::new("test", http_client.client(), 1024 * 1024 * 8);
+
+        // Both appends read the object before either writes it back.
+        let (first, second) = tokio::join!(
+            storage.append("test", b"def"),
+            storage.append("test", b"ghi")
+        );
+        let (written, retried) = match (first, second) {
+            (Ok(()), Err(S3PutError::Conflict(_))) => (b"def", b"ghi"),
+            (Err(S3PutError::Conflict(_)), Ok(())) => (b"ghi", b"def"),
+            res => panic!("expected exactly one conflict, got {:?}", res),
+        };
+        let mut expected = b"abc".to_vec();
+        expected.extend_from_slice(written);
+        assert_eq!(http_client.object(), expected);
+
+        storage.append("test", retried).await.unwrap();
+        expected.extend_from_slice(retried);
+        assert_eq!(http_client.object(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_put_if_absent_multipart_conditions_completion() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT),
+        ]);
+        let storage = S3Storage::new("test", client, 8);
+
+        let written = storage
+            .put_if_absent("test", "0123456789".as_bytes().to_vec())
+            .await
+            .unwrap();
+        assert!(written);
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].headers().get("if-none-match"), None);
+        assert_eq!(requests[3].headers().get("if-none-match"), Some("*"));
+    }
+
+    #[tokio::test]
+    async fn test_get_verifies_matching_checksum() {
+        let checksum = hex::encode(Sha256::digest("test data".as_bytes()));
+        let (client, _) = get_mock_s3_client(vec![get_event(
+            "test data",
+            &[("x-amz-meta-sha256", &checksum)],
+        )]);
+        let storage = S3Storage {
+            verify_checksums: true,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "test data".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_get_detects_corrupted_payload() {
+        let checksum = hex::encode(Sha256::digest("test data".as_bytes()));
+        let (client, _) = get_mock_s3_client(vec![get_event(
+            "test dat4",
+            &[("x-amz-meta-sha256", &checksum)],
+        )]);
+        let storage = S3Storage {
+            verify_checksums: true,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let res = read_all(storage.get("test").await.unwrap()).await;
+        assert!(matches!(
+            res,
+            Err(GetError::S3Error(S3GetError::ChecksumMismatch(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_without_checksum_metadata_is_not_verified() {
+        let (client, _) = get_mock_s3_client(vec![get_event("test data", &[])]);
+        let storage = S3Storage {
+            verify_checksums: true,
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "test data".as_bytes());
+    }
+
+    // A body that never produces any data, as if the connection hung.
+    struct StalledBody;
+
+    impl http_body::Body for StalledBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_data(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<Self::Data, Self::Error>>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_trailers(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    fn stalled_event() -> ReplayEvent {
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://test.s3.us-east-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(SdkBody::from_body_0_4(StalledBody))
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_times_out_on_stalled_stream() {
+        let (client, _) = get_mock_s3_client(vec![stalled_event()]);
+        let storage = S3Storage {
+            operation_timeout: Some(Duration::from_millis(100)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let start = std::time::Instant::now();
+        let stream = storage.get("test").await.unwrap();
+        let res = read_all(stream).await;
+        assert!(matches!(
+            res,
+            Err(GetError::S3Error(S3GetError::Timeout(_)))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    // A body of `chunks` chunks of `chunk_size` bytes with no content length,
+    // counting how many chunks have been read from it.
+    struct CountingBody {
+        chunks: usize,
+        chunk_size: usize,
+        read: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl http_body::Body for CountingBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_data(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<Self::Data, Self::Error>>> {
+            let read = self.read.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if read >= self.chunks {
+                return std::task::Poll::Ready(None);
+            }
+            std::task::Poll::Ready(Some(Ok(Bytes::from(vec![0; self.chunk_size]))))
+        }
+
+        fn poll_trailers(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+            std::task::Poll::Ready(Ok(None))
+        }
+    }
+
+    // A body that yields the given chunks, one per poll.
+    struct ChunkedBody(std::collections::VecDeque<Result<Bytes, std::io::Error>>);
+
+    impl http_body::Body for ChunkedBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_data(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<Self::Data, Self::Error>>> {
+            std::task::Poll::Ready(self.0.pop_front())
+        }
+
+        fn poll_trailers(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+            std::task::Poll::Ready(Ok(None))
+        }
+    }
+
+    fn chunked_event(chunks: Vec<Result<Bytes, std::io::Error>>) -> ReplayEvent {
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://test.s3.us-east-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(SdkBody::from_body_0_4(ChunkedBody(chunks.into())))
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_stream_yields_small_objects_in_one_chunk() {
+        let event = |chunks: usize| {
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri("https://test.s3.us-east-1.amazonaws.com/")
+                    .body(SdkBody::empty())
+                    .unwrap(),
+                http::Response::builder()
+                    .status(200)
+                    .header("content-length", (chunks * 100).to_string())
+                    .body(SdkBody::from_body_0_4(ChunkedBody(
+                        (0..chunks).map(|_| Ok(Bytes::from(vec![0; 100]))).collect(),
+                    )))
+                    .unwrap(),
+            )
+        };
+        let (client, _) = get_mock_s3_client(vec![event(4), event(20)]);
+        let storage = S3Storage {
+            small_object_threshold_bytes: Some(1000),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        for (key, chunks) in [("small", 1), ("large", 20)] {
+            let (_, stream) = storage.get_stream(key).await.unwrap();
+            let stream = stream.try_collect::<Vec<_>>().await.unwrap();
+            assert_eq!(stream.len(), chunks, "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_limit_paces_gets_and_puts() {
+        let rate = 100_000;
+        let chunks = (0..6)
+            .map(|_| Ok(Bytes::from(vec![0; rate / 4])))
+            .collect::<Vec<_>>();
+        let (client, _) = get_mock_s3_client(vec![chunked_event(chunks), mock_event(200, "")]);
+        let storage = S3Storage {
+            bandwidth_limiter: Some(RateLimiter::with_rate(rate as u64, Arc::new(TokioClock))),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        // The first second worth of bytes is read as a burst, the remaining
+        // half second worth at the limit.
+        let start = std::time::Instant::now();
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf.len(), rate / 4 * 6);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+
+        // Clones share the limit, which the get has used up.
+        let start = std::time::Instant::now();
+        storage
+            .clone()
+            .put_bytes("test", vec![0; rate / 2])
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_get_if_none_match() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(304, ""),
+            get_event(b"new data", &[("etag", "\"v2\"")]),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        // Unchanged.
+        assert_eq!(
+            storage.get_if_none_match("test", "\"v1\"").await.unwrap(),
+            None
+        );
+        // Overwritten since.
+        assert_eq!(
+            storage
+                .get_if_none_match("test", "\"v1\"")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some(&b"new data".to_vec())
+        );
+        assert!(http_client
+            .actual_requests()
+            .all(|request| request.headers().get("if-none-match") == Some("\"v1\"")));
+    }
+
+    #[tokio::test]
+    async fn test_get_to_file() {
+        let chunks = (0..4u8)
+            .map(|i| Bytes::from(vec![i; 1000]))
+            .collect::<Vec<_>>();
+        let expected = chunks.concat();
+        let (client, _) =
+            get_mock_s3_client(vec![chunked_event(chunks.into_iter().map(Ok).collect())]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("object");
+        let path = path.to_str().unwrap();
+
+        assert_eq!(storage.get_to_file("test", path).await.unwrap(), 4000);
+        assert_eq!(std::fs::read(path).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_failed_get_to_file_removes_file() {
+        let (client, _) = get_mock_s3_client(vec![chunked_event(vec![
+            Ok(Bytes::from(vec![0; 1000])),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection reset",
+            )),
+        ])]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("object");
+
+        let res = storage.get_to_file("test", path.to_str().unwrap()).await;
+        assert!(matches!(res, Err(S3GetError::ByteStreamError(_))));
+        assert!(!path.exists());
+    }
+
+    // A request as RangeServingClient saw it: the method, and the Range and
+    // If-Match headers.
+    type ServedRequest = (String, Option<String>, Option<String>);
+
+    // Serves a single object, honouring the Range and If-Match headers of
+    // gets, and records the requests it receives. Each response is delayed a
+    // little so that concurrent requests overlap.
+    #[derive(Clone, Debug)]
+    struct RangeServingClient {
+        object: Arc<Vec<u8>>,
+        etag: Arc<std::sync::Mutex<String>>,
+        // Whether the object is overwritten as soon as it has been headed.
+        overwrite_after_head: bool,
+        requests: Arc<std::sync::Mutex<Vec<ServedRequest>>>,
+        in_flight: Arc<std::sync::atomic::AtomicUsize>,
+        max_in_flight: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl RangeServingClient {
+        fn new(object: Vec<u8>) -> RangeServingClient {
+            RangeServingClient {
+                object: Arc::new(object),
+                etag: Arc::new(std::sync::Mutex::new("\"v1\"".to_string())),
+                overwrite_after_head: false,
+                requests: Arc::default(),
+                in_flight: Arc::default(),
+                max_in_flight: Arc::default(),
+            }
+        }
+
+        fn client(&self) -> aws_sdk_s3::Client {
+            let config = mock_s3_config(&StaticReplayClient::new(vec![]))
+                .http_client(self.clone())
+                .build();
+            aws_sdk_s3::Client::from_conf(config)
+        }
+
+        fn requests(&self) -> Vec<ServedRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn respond(&self, request: &HttpRequest) -> HttpResponse {
+            let header = |name: &str| request.headers().get(name).map(str::to_string);
+            let (range, if_match) = (header("range"), header("if-match"));
+            self.requests.lock().unwrap().push((
+                request.method().to_string(),
+                range.clone(),
+                if_match.clone(),
+            ));
+            let mut etag = self.etag.lock().unwrap();
+            if request.method() == "HEAD" {
+                let mut response = HttpResponse::new(200.try_into().unwrap(), SdkBody::empty());
+                response
+                    .headers_mut()
+                    .insert("content-length", self.object.len().to_string());
+                response.headers_mut().insert("etag", etag.clone());
+                if self.overwrite_after_head {
+                    *etag = "\"v2\"".to_string();
+                }
+                return response;
+            }
+            if if_match.is_some_and(|if_match| if_match != *etag) {
+                return HttpResponse::new(
+                    412.try_into().unwrap(),
+                    SdkBody::from(
+                        "<Error><Code>PreconditionFailed</Code>\
+                         <Message>At least one of the preconditions you specified did not hold\
+                         </Message></Error>",
+                    ),
+                );
+            }
+            let (status, body) = match range {
+                Some(range) => {
+                    let (start, end) = range
+                        .strip_prefix("bytes=")
+                        .and_then(|range| range.split_once('-'))
+                        .unwrap();
+                    let (start, end): (usize, usize) =
+                        (start.parse().unwrap(), end.parse().unwrap());
+                    if start >= self.object.len() {
+                        return HttpResponse::new(
+                            416.try_into().unwrap(),
+                            SdkBody::from(
+                                "<Error><Code>InvalidRange</Code>\
+                                 <Message>The requested range is not satisfiable</Message>\
+                                 </Error>",
+                            ),
+                        );
+                    }
+                    let end = (end + 1).min(self.object.len());
+                    (206, self.object[start..end].to_vec())
+                }
+                None => (200, self.object.to_vec()),
+            };
+            let mut response = HttpResponse::new(status.try_into().unwrap(), SdkBody::from(body));
+            response.headers_mut().insert("etag", etag.clone());
+            response
+        }
+    }
+
+    impl aws_smithy_runtime_api::client::http::HttpConnector for RangeServingClient {
+        fn call(
+            &self,
+            request: HttpRequest,
+        ) -> aws_smithy_runtime_api::client::http::HttpConnectorFuture {
+            let client = self.clone();
+            aws_smithy_runtime_api::client::http::HttpConnectorFuture::new(async move {
+                let in_flight = client
+                    .in_flight
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                    + 1;
+                client
+                    .max_in_flight
+                    .fetch_max(in_flight, std::sync::atomic::Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                client
+                    .in_flight
+                    .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(client.respond(&request))
+            })
+        }
+    }
+
+    impl aws_smithy_runtime_api::client::http::HttpClient for RangeServingClient {
+        fn http_connector(
+            &self,
+            _: &aws_smithy_runtime_api::client::http::HttpConnectorSettings,
+            _: &RuntimeComponents,
+        ) -> aws_smithy_runtime_api::client::http::SharedHttpConnector {
+            aws_smithy_runtime_api::client::http::SharedHttpConnector::new(self.clone())
+        }
+    }
+
+    // Serves GETs and conditional PUTs of a single object, whose ETag changes
+    // with every write. GETs are answered late enough that concurrent appends
+    // all read the object before any of them writes it.
+    #[derive(Clone, Debug)]
+    struct AppendServingClient {
+        // The object and its ETag.
+        object: Arc<std::sync::Mutex<(Vec<u8>, u64)>>,
+    }
+
+    impl AppendServingClient {
+        fn new(object: Vec<u8>) -> AppendServingClient {
+            AppendServingClient {
+                object: Arc::new(std::sync::Mutex::new((object, 1))),
+            }
+        }
+
+        fn client(&self) -> aws_sdk_s3::Client {
+            let config = mock_s3_config(&StaticReplayClient::new(vec![]))
+                .http_client(self.clone())
+                .build();
+            aws_sdk_s3::Client::from_conf(config)
+        }
+
+        fn object(&self) -> Vec<u8> {
+            self.object.lock().unwrap().0.clone()
+        }
+
+        fn respond(&self, request: &HttpRequest) -> HttpResponse {
+            let mut object = self.object.lock().unwrap();
+            let etag = format!("\"v{}\"", object.1);
+            if request.method() == "GET" {
+                let mut response =
+                    HttpResponse::new(200.try_into().unwrap(), SdkBody::from(object.0.clone()));
+                response.headers_mut().insert("etag", etag);
+                return response;
+            }
+            if request.headers().get("if-match") != Some(etag.as_str()) {
+                return HttpResponse::new(
+                    412.try_into().unwrap(),
+                    SdkBody::from(
+                        "<Error><Code>PreconditionFailed</Code>\
+                         <Message>At least one of the preconditions you specified did not hold\
+                         </Message></Error>",
+                    ),
+                );
+            }
+            *object = (request.body().bytes().unwrap().to_vec(), object.1 + 1);
+            HttpResponse::new(200.try_into().unwrap(), SdkBody::empty())
+        }
+    }
+
+    impl aws_smithy_runtime_api::client::http::HttpConnector for AppendServingClient {
+        fn call(
+            &self,
+            request: HttpRequest,
+        ) -> aws_smithy_runtime_api::client::http::HttpConnectorFuture {
+            let client = self.clone();
+            aws_smithy_runtime_api::client::http::HttpConnectorFuture::new(async move {
+                // The object is read as the GET arrives, and sent later.
+                let response = client.respond(&request);
+                if request.method() == "GET" {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Ok(response)
+            })
+        }
+    }
+
+    impl aws_smithy_runtime_api::client::http::HttpClient for AppendServingClient {
+        fn http_connector(
+            &self,
+            _: &aws_smithy_runtime_api::client::http::HttpConnectorSettings,
+            _: &RuntimeComponents,
+        ) -> aws_smithy_runtime_api::client::http::SharedHttpConnector {
+            aws_smithy_runtime_api::client::http::SharedHttpConnector::new(self.clone())
+        }
+    }
+
+    // Serves HEADs with an empty response and GETs with "test data", as if
+    // over a pool of connections: a request that finds no idle connection
+    // first waits out `handshake` to open one.
+    #[derive(Clone, Debug)]
+    struct HandshakeClient {
+        handshake: Duration,
+        idle_connections: Arc<std::sync::Mutex<usize>>,
+        methods: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl HandshakeClient {
+        fn new(handshake: Duration) -> HandshakeClient {
+            HandshakeClient {
+                handshake,
+                idle_connections: Arc::default(),
+                methods: Arc::default(),
+            }
+        }
+
+        fn client(&self) -> aws_sdk_s3::Client {
+            let config = mock_s3_config(&StaticReplayClient::new(vec![]))
+                .http_client(self.clone())
+                .build();
+            aws_sdk_s3::Client::from_conf(config)
+        }
+    }
+
+    impl aws_smithy_runtime_api::client::http::HttpConnector for HandshakeClient {
+        fn call(
+            &self,
+            request: HttpRequest,
+        ) -> aws_smithy_runtime_api::client::http::HttpConnectorFuture {
+            let client = self.clone();
+            aws_smithy_runtime_api::client::http::HttpConnectorFuture::new(async move {
+                client
+                    .methods
+                    .lock()
+                    .unwrap()
+                    .push(request.method().to_string());
+                let reused = {
+                    let mut idle = client.idle_connections.lock().unwrap();
+                    match idle.checked_sub(1) {
+                        Some(remaining) => {
+                            *idle = remaining;
+                            true
+                        }
+                        None => false,
+                    }
+                };
+                if !reused {
+                    tokio::time::sleep(client.handshake).await;
+                }
+                let body = match request.method() {
+                    "GET" => SdkBody::from("test data"),
+                    _ => SdkBody::empty(),
+                };
+                // The connection is idle again once the response is sent.
+                *client.idle_connections.lock().unwrap() += 1;
+                Ok(HttpResponse::new(200.try_into().unwrap(), body))
+            })
+        }
+    }
+
+    impl aws_smithy_runtime_api::client::http::HttpClient for HandshakeClient {
+        fn http_connector(
+            &self,
+            _: &aws_smithy_runtime_api::client::http::HttpConnectorSettings,
+            _: &RuntimeComponents,
+        ) -> aws_smithy_runtime_api::client::http::SharedHttpConnector {
+            aws_smithy_runtime_api::client::http::SharedHttpConnector::new(self.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warmup_primes_connections() {
+        let handshake = Duration::from_millis(200);
+        let timed_get = |storage: S3Storage| async move {
+            let start = std::time::Instant::now();
+            let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+            assert_eq!(buf, "test data".as_bytes());
+            start.elapsed()
+        };
+
+        let cold = HandshakeClient::new(handshake);
+        let storage = S3Storage::new("test", cold.client(), 1024 * 1024 * 8);
+        assert!(timed_get(storage).await >= handshake);
+
+        let warm = HandshakeClient::new(handshake);
+        let storage = S3Storage::new("test", warm.client(), 1024 * 1024 * 8);
+        storage.warmup().await;
+        storage.warmup().await;
+        assert!(timed_get(storage).await < handshake);
+        // Warming up only issued HEADs.
+        let methods = warm.methods.lock().unwrap().clone();
+        assert!(methods[..methods.len() - 1]
+            .iter()
+            .all(|method| method == "HEAD"));
+    }
+
+    fn random_object(size: usize) -> Vec<u8> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(62);
+        (0..size).map(|_| rng.gen()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_resume_get_to_file_downloads_fresh() {
+        let object = random_object(1000);
+        let http_client = RangeServingClient::new(object.clone());
+        let storage = S3Storage::new("test", http_client.client(), 1024 * 1024 * 8);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("object");
+        let path = path.to_str().unwrap();
+
+        assert_eq!(
+            storage.resume_get_to_file("test", path).await.unwrap(),
+            1000
+        );
+        assert_eq!(std::fs::read(path).unwrap(), object);
+        assert!(!dir.path().join("object.etag").exists());
+        let requests = http_client.requests();
+        assert_eq!(requests[1], ("GET".to_string(), None, None));
+    }
+
+    #[tokio::test]
+    async fn test_resume_get_to_file_fetches_only_the_rest() {
+        let object = random_object(1000);
+        let http_client = RangeServingClient::new(object.clone());
+        let storage = S3Storage::new("test", http_client.client(), 1024 * 1024 * 8);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("object");
+        // What an interrupted download left behind.
+        std::fs::write(&path, &object[..400]).unwrap();
+        std::fs::write(dir.path().join("object.etag"), "\"v1\"").unwrap();
+
+        let size = storage
+            .resume_get_to_file("test", path.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(size, 1000);
+        assert_eq!(std::fs::read(&path).unwrap(), object);
+        assert!(!dir.path().join("object.etag").exists());
+        let requests = http_client.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].1.as_deref(), Some("bytes=400-999"));
+    }
+
+    #[tokio::test]
+    async fn test_resume_get_to_file_restarts_when_object_changed() {
+        let object = random_object(1000);
+        let http_client = RangeServingClient::new(object.clone());
+        let storage = S3Storage::new("test", http_client.client(), 1024 * 1024 * 8);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("object");
+        // Left behind by a download of an earlier version of the object.
+        std::fs::write(&path, vec![0; 400]).unwrap();
+        std::fs::write(dir.path().join("object.etag"), "\"v0\"").unwrap();
+
+        let size = storage
+            .resume_get_to_file("test", path.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(size, 1000);
+        assert_eq!(std::fs::read(&path).unwrap(), object);
+        let requests = http_client.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1], ("GET".to_string(), None, None));
+    }
+
+    #[tokio::test]
+    async fn test_get_range_stream_yields_only_the_range() {
+        let object = random_object(1000);
+        let http_client = RangeServingClient::new(object.clone());
+        let storage = S3Storage::new("test", http_client.client(), 1024 * 1024 * 8);
+
+        let stream = storage.get_range_stream("test", 100, 700).await.unwrap();
+        assert_eq!(read_all(stream).await.unwrap(), object[100..700]);
+        let ranges = http_client
+            .requests()
+            .into_iter()
+            .map(|(_, range, _)| range)
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![Some("bytes=100-699".to_string())]);
+
+        // Both fail before a stream is returned.
+        let res = storage.get_range_stream("test", 1000, 1100).await;
+        assert!(matches!(res, Err(S3GetError::RangeNotSatisfiable(_))));
+        let res = storage.get_range_stream("test", 700, 100).await;
+        assert!(matches!(res, Err(S3GetError::RangeNotSatisfiable(_))));
+        assert_eq!(http_client.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_parallel_assembles_range_gets() {
+        let object = random_object(1000);
+        let http_client = RangeServingClient::new(object.clone());
+        let storage = S3Storage {
+            parallel_download_threshold_bytes: 128,
+            download_part_size_bytes: 128,
+            download_concurrency: 3,
+            ..S3Storage::new("test", http_client.client(), 1024 * 1024 * 8)
+        };
+
+        let bytes = storage.get_parallel("test").await.unwrap();
+        assert_eq!(*bytes, object);
+
+        let requests = http_client.requests();
+        assert_eq!(requests[0].0, "HEAD");
+        let mut ranges = requests[1..]
+            .iter()
+            .map(|(method, range, if_match)| {
+                assert_eq!(method, "GET");
+                assert_eq!(if_match.as_deref(), Some("\"v1\""));
+                range.clone().unwrap()
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|range| range[6..range.find('-').unwrap()].parse::<usize>().unwrap());
+        let expected = (0..1000)
+            .step_by(128)
+            .map(|start| format!("bytes={}-{}", start, (start + 127).min(999)))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, expected);
+        let max_in_flight = http_client
+            .max_in_flight
+            .load(std::sync::atomic::Ordering::SeqCst);
+        assert!(max_in_flight > 1 && max_in_flight <= 3, "{}", max_in_flight);
+    }
+
+    #[tokio::test]
+    async fn test_get_parallel_reads_small_object_with_single_get() {
+        let object = random_object(100);
+        let http_client = RangeServingClient::new(object.clone());
+        let storage = S3Storage {
+            parallel_download_threshold_bytes: 128,
+            download_part_size_bytes: 128,
+            ..S3Storage::new("test", http_client.client(), 1024 * 1024 * 8)
+        };
+
+        assert_eq!(*storage.get_parallel("test").await.unwrap(), object);
+        let requests = http_client.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1], ("GET".to_string(), None, None));
+    }
+
+    #[tokio::test]
+    async fn test_get_parallel_fails_when_object_changes() {
+        let http_client = RangeServingClient {
+            overwrite_after_head: true,
+            ..RangeServingClient::new(random_object(1000))
+        };
+        let storage = S3Storage {
+            parallel_download_threshold_bytes: 128,
+            download_part_size_bytes: 128,
+            ..S3Storage::new("test", http_client.client(), 1024 * 1024 * 8)
+        };
+
+        assert!(matches!(
+            storage.get_parallel("test").await,
+            Err(S3GetError::ObjectChanged(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_rejects_object_reported_too_large() {
+        let (client, _) =
+            get_mock_s3_client(vec![get_event(vec![0; 100], &[("Content-Length", "100")])]);
+        let storage = S3Storage {
+            max_object_size_bytes: Some(64),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let res = storage.get("test").await;
+        assert!(matches!(
+            res,
+            Err(S3GetError::ObjectTooLarge {
+                limit: 64,
+                actual: 100
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_stops_once_object_exceeds_limit() {
+        let read = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let body = CountingBody {
+            chunks: 100,
+            chunk_size: 16,
+            read: read.clone(),
+        };
+        let (client, _) = get_mock_s3_client(vec![ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://test.s3.us-east-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(SdkBody::from_body_0_4(body))
+                .unwrap(),
+        )]);
+        let storage = S3Storage {
+            max_object_size_bytes: Some(64),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let res = storage.get_object("test").await.unwrap().read().await;
+        assert!(matches!(
+            res,
+            Err(S3GetError::ObjectTooLarge {
+                limit: 64,
+                actual: 80
+            })
+        ));
+        // Five chunks take the object past the limit; the rest are never read.
+        assert_eq!(read.load(std::sync::atomic::Ordering::SeqCst), 5);
+    }
+
+    // Reads "key" from a storage with a fallback bucket, returning the result
+    // and the buckets that were requested, in order.
+    async fn get_with_fallback(
+        events: Vec<ReplayEvent>,
+    ) -> (Result<Vec<u8>, S3GetError>, Vec<String>) {
+        let (client, http_client) = get_mock_s3_client(events);
+        let storage = S3Storage {
+            fallback_bucket: Some("fallback".to_string()),
+            ..S3Storage::new("primary", client, 1024 * 1024 * 8)
+        };
+        let res = match storage.get("key").await {
+            Ok(stream) => Ok(read_all(stream).await.unwrap()),
+            Err(e) => Err(e),
+        };
+        let buckets = http_client
+            .actual_requests()
+            .map(|request| {
+                let host = request.uri().trim_start_matches("https://");
+                host[..host.find('.').unwrap()].to_string()
+            })
+            .collect();
+        (res, buckets)
+    }
+
+    #[tokio::test]
+    async fn test_get_falls_back_to_fallback_bucket() {
+        let no_such_key = "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>";
+
+        let (res, buckets) = get_with_fallback(vec![get_event("primary data", &[])]).await;
+        assert_eq!(res.unwrap(), b"primary data");
+        assert_eq!(buckets, vec!["primary"]);
+
+        let (res, buckets) = get_with_fallback(vec![
+            mock_event(404, no_such_key),
+            get_event("fallback data", &[]),
+        ])
+        .await;
+        assert_eq!(res.unwrap(), b"fallback data");
+        assert_eq!(buckets, vec!["primary", "fallback"]);
+
+        let (res, buckets) = get_with_fallback(vec![
+            mock_event(404, no_such_key),
+            mock_event(404, no_such_key),
+        ])
+        .await;
+        assert!(matches!(res, Err(S3GetError::NoSuchKey(_))));
+        assert_eq!(buckets, vec!["primary", "fallback"]);
+    }
+
+    // Delays the response to each request by the next of `delays`, or not at
+    // all once they run out, before replaying it from `inner`.
+    #[derive(Clone, Debug)]
+    struct DelayedReplayClient {
+        inner: StaticReplayClient,
+        delays: Arc<std::sync::Mutex<std::collections::VecDeque<Duration>>>,
+    }
+
+    impl aws_smithy_runtime_api::client::http::HttpConnector for DelayedReplayClient {
+        fn call(
+            &self,
+            request: HttpRequest,
+        ) -> aws_smithy_runtime_api::client::http::HttpConnectorFuture {
+            let delay = self.delays.lock().unwrap().pop_front().unwrap_or_default();
+            let response = self.inner.call(request);
+            aws_smithy_runtime_api::client::http::HttpConnectorFuture::new(async move {
+                tokio::time::sleep(delay).await;
+                response.await
+            })
+        }
+    }
+
+    impl aws_smithy_runtime_api::client::http::HttpClient for DelayedReplayClient {
+        fn http_connector(
+            &self,
+            _: &aws_smithy_runtime_api::client::http::HttpConnectorSettings,
+            _: &RuntimeComponents,
+        ) -> aws_smithy_runtime_api::client::http::SharedHttpConnector {
+            aws_smithy_runtime_api::client::http::SharedHttpConnector::new(self.clone())
+        }
+    }
+
+    fn get_delayed_mock_s3_client(
+        events: Vec<(Duration, ReplayEvent)>,
+    ) -> (aws_sdk_s3::Client, StaticReplayClient) {
+        let (delays, events): (_, Vec<_>) = events.into_iter().unzip();
+        let inner = StaticReplayClient::new(events);
+        let http_client = DelayedReplayClient {
+            inner: inner.clone(),
+            delays: Arc::new(std::sync::Mutex::new(delays)),
+        };
+        let config = mock_s3_config(&inner).http_client(http_client).build();
+        (aws_sdk_s3::Client::from_conf(config), inner)
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_get_stops_request() {
+        let (client, _) =
+            get_delayed_mock_s3_client(vec![(Duration::from_secs(10), get_event(b"data", &[]))]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let (fetch, handle) = storage.get_cancellable("key".to_string());
+        let fetch = tokio::spawn(fetch);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(handle);
+
+        let res = tokio::time::timeout(Duration::from_secs(1), fetch)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(res, Err(S3GetError::Cancelled(_))));
+        // The request is no longer in flight.
+        assert!(storage.shutdown(Duration::ZERO).await);
+    }
+
+    #[tokio::test]
+    async fn test_cancelling_one_get_leaves_others_running() {
+        let (client, _) = get_delayed_mock_s3_client(vec![
+            (Duration::from_millis(200), get_event(b"data", &[])),
+            (Duration::from_millis(200), get_event(b"data", &[])),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let (cancelled, cancelled_handle) = storage.get_cancellable("key".to_string());
+        let (kept, _kept_handle) = storage.get_cancellable("key".to_string());
+        let (cancelled, kept) = (tokio::spawn(cancelled), tokio::spawn(kept));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cancelled_handle.cancel();
+
+        assert!(matches!(
+            cancelled.await.unwrap(),
+            Err(S3GetError::Cancelled(_))
+        ));
+        assert_eq!(*kept.await.unwrap().unwrap(), b"data");
+    }
+
+    #[tokio::test]
+    async fn test_get_hedges_slow_request() {
+        let (client, http_client) = get_delayed_mock_s3_client(vec![
+            (Duration::from_secs(10), get_event("slow data", &[])),
+            (Duration::ZERO, get_event("fast data", &[])),
+        ]);
+        let storage = S3Storage {
+            hedge_after: Some(Duration::from_millis(50)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let start = std::time::Instant::now();
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "fast data".as_bytes());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(http_client.actual_requests().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_requests_in_flight() {
+        let (client, http_client) = get_delayed_mock_s3_client(vec![(
+            Duration::from_millis(200),
+            get_event("slow data", &[]),
+        )]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let slow_get = tokio::spawn({
+            let storage = storage.clone();
+            async move { read_all(storage.get("test").await.unwrap()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let shutdown = tokio::spawn({
+            let storage = storage.clone();
+            async move { storage.shutdown(Duration::from_secs(5)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // New requests are rejected without reaching S3.
+        assert!(matches!(
+            storage.get("other").await,
+            Err(S3GetError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.put_bytes("other", b"data".to_vec()).await,
+            Err(S3PutError::ShuttingDown)
+        ));
+        assert!(!shutdown.is_finished());
+
+        assert!(shutdown.await.unwrap());
+        assert_eq!(slow_get.await.unwrap().unwrap(), b"slow data");
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_rejects_every_operation() {
+        let (client, http_client) = get_mock_s3_client(vec![]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+        assert!(storage.shutdown(Duration::ZERO).await);
+
+        assert!(matches!(
+            storage.get_tags("key").await,
+            Err(S3GetError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.head("key").await,
+            Err(S3HeadError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.exists_many(vec!["key".to_string()]).await,
+            Err(S3HeadError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.copy("src", "dst").await,
+            Err(S3CopyError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.rename("src", "dst").await,
+            Err(S3RenameError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.delete("key").await,
+            Err(S3DeleteError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.delete_if_match("key", "\"etag\"").await,
+            Err(S3DeleteError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.delete_many(vec!["key".to_string()]).await,
+            Err(S3DeleteError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.prefix_size("prefix").await,
+            Err(S3ListError::ShuttingDown)
+        ));
+        assert!(matches!(
+            storage.list_versions("key").await,
+            Err(S3ListError::ShuttingDown)
+        ));
+        let listed = storage.list_prefix("prefix").collect::<Vec<_>>().await;
+        assert!(matches!(listed[..], [Err(S3ListError::ShuttingDown)]));
+        let selected = storage
+            .select("key", "SELECT * FROM s3object", SelectFormat::Csv)
+            .collect::<Vec<_>>()
+            .await;
+        assert!(matches!(selected[..], [Err(S3SelectError::ShuttingDown)]));
+        assert_eq!(http_client.actual_requests().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_rename_to_finish() {
+        let (client, http_client) = get_delayed_mock_s3_client(vec![
+            (
+                Duration::from_millis(200),
+                get_event("", &[("content-length", "9")]),
+            ),
+            (
+                Duration::ZERO,
+                mock_event(
+                    200,
+                    "<CopyObjectResult><ETag>\"etag\"</ETag></CopyObjectResult>",
+                ),
+            ),
+            (Duration::ZERO, mock_event(204, "")),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let rename = tokio::spawn({
+            let storage = storage.clone();
+            async move { storage.rename("src", "dst").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The copy and the delete still go out once shutdown has started.
+        assert!(storage.shutdown(Duration::from_secs(5)).await);
+        rename.await.unwrap().unwrap();
+        assert_eq!(http_client.actual_requests().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_listing_counts_as_in_flight_until_it_ends() {
+        let (client, _) = get_mock_s3_client(vec![mock_event(
+            200,
+            "<ListBucketResult><Contents><Key>prefix/a</Key></Contents></ListBucketResult>",
+        )]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let mut keys = Box::pin(storage.list_prefix("prefix"));
+        assert!(!storage.shutdown(Duration::from_millis(10)).await);
+        assert_eq!(keys.next().await.unwrap().unwrap(), "prefix/a");
+        assert!(keys.next().await.is_none());
+        assert!(storage.shutdown(Duration::ZERO).await);
+    }
+
+    #[tokio::test]
+    async fn test_get_does_not_hedge_fast_request() {
+        let (client, http_client) =
+            get_delayed_mock_s3_client(vec![(Duration::ZERO, get_event("test data", &[]))]);
+        let storage = S3Storage {
+            hedge_after: Some(Duration::from_millis(50)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "test data".as_bytes());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_range_times_out_on_stalled_stream() {
+        let (client, _) = get_mock_s3_client(vec![stalled_event()]);
+        let storage = S3Storage {
+            operation_timeout: Some(Duration::from_millis(100)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let res = storage.get_range("test", 0, 10).await;
+        assert!(matches!(res, Err(S3GetError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_put_times_out_on_stalled_response() {
+        let (client, _) = get_mock_s3_client(vec![stalled_event()]);
+        let storage = S3Storage {
+            operation_timeout: Some(Duration::from_millis(100)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let res = storage
+            .put_bytes("test", "test data".as_bytes().to_vec())
+            .await;
+        assert!(matches!(res, Err(S3PutError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_head_present_key() {
+        let (client, _) = get_mock_s3_client(vec![get_event(
+            "",
+            &[
+                ("content-length", "9"),
+                ("etag", "\"etag\""),
+                ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ],
+        )]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let metadata = storage.head("test").await.unwrap().unwrap();
+        assert_eq!(metadata.size, 9);
+        assert_eq!(metadata.etag.as_deref(), Some("\"etag\""));
+        assert_eq!(
+            metadata.last_modified,
+            Some(std::time::UNIX_EPOCH + Duration::from_secs(1445412480))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_head_absent_key() {
+        let (client, _) = get_mock_s3_client(vec![mock_event(404, "")]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        assert_eq!(storage.head("test").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_requester_pays() {
+        for requester_pays in [false, true] {
+            let (client, http_client) =
+                get_mock_s3_client(vec![get_event("test data", &[]), mock_event(404, "")]);
+            let storage = S3Storage {
+                requester_pays,
+                ..S3Storage::new("test", client, 1024 * 1024 * 8)
+            };
+
+            read_all(storage.get("test").await.unwrap()).await.unwrap();
+            storage.head("test").await.unwrap();
+            for request in http_client.actual_requests() {
+                let header = request.headers().get("x-amz-request-payer");
+                assert_eq!(header, requester_pays.then_some("requester"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_is_served_from_cache() {
+        let (client, http_client) =
+            get_mock_s3_client(vec![get_event("test data", &[("content-length", "9")])]);
+        let storage = S3Storage {
+            cache: Some(ObjectCache::new(1024, 1024)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "test data".as_bytes());
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "test data".as_bytes());
+        let range = storage.get_range("test", 5, 100).await.unwrap();
+        assert_eq!(range.as_slice(), "data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 1);
+
+        let stats = storage.drain_stats();
+        assert_eq!(stats.cache_misses, 1);
+        assert_eq!(stats.cache_hits, 2);
+        assert_eq!(stats.cache_insertions, 1);
+        assert_eq!((stats.cache_size_bytes, stats.cache_entries), (9, 1));
+        assert_eq!(
+            storage.stats_snapshot(),
+            StorageStats {
+                cache_size_bytes: 9,
+                cache_entries: 1,
+                ..StorageStats::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_large_objects_bypass_cache() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("test data", &[("content-length", "9")]),
+            get_event("test data", &[("content-length", "9")]),
+        ]);
+        let storage = S3Storage {
+            cache: Some(ObjectCache::new(1024, 8)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        for _ in 0..2 {
+            let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+            assert_eq!(buf, "test data".as_bytes());
+        }
+        assert_eq!(http_client.actual_requests().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_put_invalidates_cache() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("old data", &[("content-length", "8")]),
+            mock_event(200, ""),
+            get_event("new data", &[("content-length", "8")]),
+        ]);
+        let storage = S3Storage {
+            cache: Some(ObjectCache::new(1024, 1024)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "old data".as_bytes());
+        // Streamed puts do not write through, so the next get refetches.
+        storage
+            .put_stream("test", chunk_stream(vec![Ok("new data")]))
+            .await
+            .unwrap();
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "new data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_put_bytes_writes_through_cache() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("old data", &[("content-length", "8")]),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            get_event("large data", &[("content-length", "10")]),
+        ]);
+        let storage = S3Storage {
+            cache: Some(ObjectCache::new(1024, 8)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "old data".as_bytes());
+        storage
+            .put_bytes("test", "new data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        storage.reset_stats();
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "new data".as_bytes());
+        assert_eq!(storage.drain_stats().cache_hits, 1);
+        assert_eq!(http_client.actual_requests().count(), 2);
+
+        // Objects too large for the cache only invalidate the cached one.
+        storage
+            .put_bytes("test", "large data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "large data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_get_many_fetches_duplicate_keys_once() {
+        let (client, http_client) =
+            get_mock_s3_client(vec![get_event("a", &[]), get_event("b", &[])]);
+        let storage = crate::Storage::S3(S3Storage::new("test", client, 1024 * 1024 * 8));
+
+        let keys = ["a", "b", "a", "a"].map(String::from).to_vec();
+        let results = storage.get_many(keys, 1).await;
+        let results = results
+            .into_iter()
+            .map(|result| result.unwrap().as_ref().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            vec![b"a".to_vec(), b"b".to_vec(), b"a".to_vec(), b"a".to_vec()]
+        );
+        assert_eq!(http_client.actual_requests().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_concurrency_backs_off_on_throttling() {
+        let slow_down = "<Error><Code>SlowDown</Code><Message>slow down</Message></Error>";
+        let mut events = (0..20)
+            .map(|_| mock_event(503, slow_down))
+            .collect::<Vec<_>>();
+        events.extend((0..40).map(|_| get_event("test data", &[])));
+        let http_client = StaticReplayClient::new(events);
+        let adaptive_concurrency = Arc::new(AdaptiveConcurrency::new(8));
+        let config =
+            with_adaptive_concurrency(mock_s3_config(&http_client), Some(&adaptive_concurrency));
+        let storage = S3Storage {
+            adaptive_concurrency: Some(adaptive_concurrency),
+            ..S3Storage::new(
+                "test",
+                aws_sdk_s3::Client::from_conf(config.build()),
+                1024 * 1024 * 8,
+            )
+        };
+        assert_eq!(storage.effective_concurrency_limit(), Some(8));
+
+        for _ in 0..20 {
+            assert!(storage.get("test").await.is_err());
+        }
+        let limit = storage.effective_concurrency_limit().unwrap();
+        assert!(limit < 8, "limit is {}", limit);
+
+        for _ in 0..40 {
+            read_all(storage.get("test").await.unwrap()).await.unwrap();
+        }
+        assert_eq!(storage.effective_concurrency_limit(), Some(8));
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_concurrency_penalizes_slow_down_more_than_server_errors() {
+        // The lowest limit reached when one get in every eight fails with
+        // `code`.
+        let lowest_limit = |code: &'static str| async move {
+            let error = format!(
+                "<Error><Code>{}</Code><Message>failed</Message></Error>",
+                code
+            );
+            let events = (0..60)
+                .map(|i| match i % 8 {
+                    0 => mock_event(503, &error),
+                    _ => get_event("test data", &[]),
+                })
+                .collect::<Vec<_>>();
+            let http_client = StaticReplayClient::new(events);
+            let adaptive_concurrency = Arc::new(AdaptiveConcurrency::new(8));
+            let config = with_adaptive_concurrency(
+                mock_s3_config(&http_client),
+                Some(&adaptive_concurrency),
+            );
+            let storage = S3Storage {
+                adaptive_concurrency: Some(adaptive_concurrency),
+                ..S3Storage::new(
+                    "test",
+                    aws_sdk_s3::Client::from_conf(config.build()),
+                    1024 * 1024 * 8,
+                )
+            };
+            let mut lowest_limit = 8;
+            for _ in 0..60 {
+                if let Ok(stream) = storage.get("test").await {
+                    read_all(stream).await.unwrap();
+                }
+                lowest_limit = lowest_limit.min(storage.effective_concurrency_limit().unwrap());
+            }
+            lowest_limit
+        };
+
+        assert_eq!(lowest_limit("ServiceUnavailable").await, 8);
+        assert!(lowest_limit("SlowDown").await < 8);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_fails_fast_while_open() {
+        let unavailable = "<Error><Code>ServiceUnavailable</Code><Message>down</Message></Error>";
+        let mut events = (0..3)
+            .map(|_| mock_event(503, unavailable))
+            .collect::<Vec<_>>();
+        events.push(get_event("test data", &[]));
+        let http_client = StaticReplayClient::new(events);
+        let circuit_breaker = Arc::new(CircuitBreaker::new(3, Duration::from_millis(100)));
+        let config = with_circuit_breaker(mock_s3_config(&http_client), Some(&circuit_breaker));
+        let storage = S3Storage {
+            circuit_breaker: Some(circuit_breaker),
+            ..S3Storage::new(
+                "test",
+                aws_sdk_s3::Client::from_conf(config.build()),
+                1024 * 1024 * 8,
+            )
+        };
+        assert_eq!(storage.circuit_state(), Some(CircuitState::Closed));
+
+        for _ in 0..3 {
+            assert!(matches!(
+                storage.get("test").await,
+                Err(S3GetError::ServiceError { .. })
+            ));
+        }
+        assert_eq!(storage.circuit_state(), Some(CircuitState::Open));
+
+        // Requests fail without reaching S3.
+        assert!(matches!(
+            storage.get("test").await,
+            Err(S3GetError::CircuitOpen)
+        ));
+        assert!(matches!(
+            storage.put_bytes("test", b"test data".to_vec()).await,
+            Err(S3PutError::CircuitOpen)
+        ));
+        assert_eq!(http_client.actual_requests().count(), 3);
+
+        // After the cooldown a successful probe closes the circuit.
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        let stream = storage.get("test").await.unwrap();
+        assert_eq!(read_all(stream).await.unwrap(), b"test data");
+        assert_eq!(storage.circuit_state(), Some(CircuitState::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_override() {
+        let http_client = StaticReplayClient::new(vec![get_event("test data", &[])]);
+        let config = with_endpoint(
+            mock_s3_config(&http_client),
+            Some("http://localhost:9000"),
+            true,
+            false,
+        )
+        .unwrap();
+        let storage = S3Storage::new(
+            "test",
+            aws_sdk_s3::Client::from_conf(config.build()),
+            1024 * 1024 * 8,
+        );
+
+        read_all(storage.get("key").await.unwrap()).await.unwrap();
+        let request = http_client.actual_requests().next().unwrap();
+        assert!(request.uri().starts_with("http://localhost:9000/test/key"));
+    }
+
+    #[tokio::test]
+    async fn test_default_endpoint() {
+        let http_client = StaticReplayClient::new(vec![get_event("test data", &[])]);
+        let config = with_endpoint(mock_s3_config(&http_client), None, false, false).unwrap();
+        let storage = S3Storage::new(
+            "test",
+            aws_sdk_s3::Client::from_conf(config.build()),
+            1024 * 1024 * 8,
+        );
+
+        read_all(storage.get("key").await.unwrap()).await.unwrap();
+        let request = http_client.actual_requests().next().unwrap();
+        assert!(request
+            .uri()
+            .starts_with("https://test.s3.us-east-1.amazonaws.com/key"));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_acceleration_endpoint() {
+        let http_client = StaticReplayClient::new(vec![get_event("test data", &[])]);
+        let config = with_endpoint(mock_s3_config(&http_client), None, false, true).unwrap();
+        let storage = S3Storage::new(
+            "test",
+            aws_sdk_s3::Client::from_conf(config.build()),
+            1024 * 1024 * 8,
+        );
+
+        read_all(storage.get("key").await.unwrap()).await.unwrap();
+        let request = http_client.actual_requests().next().unwrap();
+        assert!(
+            request
+                .uri()
+                .starts_with("https://test.s3-accelerate.amazonaws.com/key"),
+            "{}",
+            request.uri()
+        );
+    }
+
+    #[test]
+    fn test_transfer_acceleration_conflicts() {
+        let http_client = StaticReplayClient::new(vec![]);
+        for (endpoint_url, force_path_style) in
+            [(Some("http://localhost:9000"), false), (None, true)]
+        {
+            let res = with_endpoint(
+                mock_s3_config(&http_client),
+                endpoint_url,
+                force_path_style,
+                true,
+            );
+            assert!(matches!(
+                res,
+                Err(StorageConfigError::ConflictingOptions(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("", &[("content-length", "9")]),
+            mock_event(
+                200,
+                "<CopyObjectResult><ETag>\"etag\"</ETag></CopyObjectResult>",
+            ),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage.copy("src key", "dst").await.unwrap();
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].headers().get("x-amz-copy-source"),
+            Some("test/src%20key")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("", &[("content-length", "9")]),
+            mock_event(
+                200,
+                "<CopyObjectResult><ETag>\"etag\"</ETag></CopyObjectResult>",
+            ),
+            mock_event(204, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage.rename("src", "dst").await.unwrap();
+        let requests = http_client
+            .actual_requests()
+            .map(|request| (request.method().to_string(), request.uri().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[2],
+            (
+                "DELETE".to_string(),
+                "https://test.s3.us-east-1.amazonaws.com/src?x-id=DeleteObject".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_missing_source() {
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(404, "")]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage.rename("src", "dst").await;
+        assert!(matches!(res, Err(S3RenameError::SourceNotFound(key)) if key == "src"));
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rename_rolls_back_copy_if_source_delete_fails() {
+        let internal_error =
+            "<Error><Code>InternalError</Code><Message>internal error</Message></Error>";
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("", &[("content-length", "9")]),
+            mock_event(
+                200,
+                "<CopyObjectResult><ETag>\"etag\"</ETag></CopyObjectResult>",
+            ),
+            mock_event(500, internal_error),
+            mock_event(204, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage.rename("src", "dst").await;
+        assert!(matches!(res, Err(S3RenameError::SourceDeleteFailed(_))));
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[3].method(), "DELETE");
+        assert_eq!(
+            requests[3].uri(),
+            "https://test.s3.us-east-1.amazonaws.com/dst?x-id=DeleteObject"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_missing_source() {
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(404, "")]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let res = storage.copy("src", "dst").await;
+        assert!(matches!(res, Err(S3CopyError::SourceNotFound(key)) if key == "src"));
+        assert_eq!(http_client.actual_requests().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_copy_large_object_in_parts() {
+        let copy_part_result = "<CopyPartResult><ETag>\"etag\"</ETag></CopyPartResult>";
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("", &[("content-length", "10")]),
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, copy_part_result),
+            mock_event(200, copy_part_result),
+            mock_event(200, copy_part_result),
+            mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT),
+        ]);
+        let storage = S3Storage {
+            max_single_copy_bytes: 4,
+            ..S3Storage::new("test", client, 4)
+        };
+
+        storage.copy("src", "dst").await.unwrap();
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 6);
+        let ranges = requests[2..5]
+            .iter()
+            .map(|request| request.headers().get("x-amz-copy-source-range").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec!["bytes=0-3", "bytes=4-7", "bytes=8-9"]);
+    }
+
+    async fn test_compression_round_trip(codec: CompressionCodec) {
+        let payload = "test data ".repeat(100).into_bytes();
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage {
+            compression: Some(codec),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+        storage.put_bytes("test", payload.clone()).await.unwrap();
+
+        let request = http_client.actual_requests().next().unwrap();
+        assert_eq!(
+            request.headers().get("x-amz-meta-compression"),
+            Some(codec.as_str())
+        );
+        let stored = request.body().bytes().unwrap().to_vec();
+        assert!(stored.len() < payload.len());
+
+        // A range read of a compressed object falls back to reading the whole
+        // object, hence the extra response.
+        let headers = [("x-amz-meta-compression", codec.as_str())];
+        let (client, _) = get_mock_s3_client(vec![
+            get_event(&stored, &headers),
+            get_event(&stored, &headers),
+            get_event(&stored, &headers),
+        ]);
+        let storage = S3Storage {
+            compression: Some(codec),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, payload);
+        let range = storage.get_range("test", 10, 20).await.unwrap();
+        assert_eq!(range.as_slice(), &payload[10..20]);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_round_trip() {
+        test_compression_round_trip(CompressionCodec::Gzip).await;
+    }
+
+    #[tokio::test]
+    async fn test_zstd_round_trip() {
+        test_compression_round_trip(CompressionCodec::Zstd).await;
+    }
+
+    #[tokio::test]
+    async fn test_put_then_invalidate_reads_new_bytes() {
+        let (client, http_client) = get_delayed_mock_s3_client(vec![
+            // A get that is still in flight when the put finishes.
+            (
+                Duration::from_millis(200),
+                get_event("old data", &[("content-length", "8")]),
+            ),
+            (Duration::ZERO, mock_event(200, "")),
+            (
+                Duration::ZERO,
+                get_event("new data", &[("content-length", "8")]),
+            ),
+        ]);
+        let storage = S3Storage {
+            cache: Some(ObjectCache::new(1024, 1024)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let concurrent_get = tokio::spawn({
+            let storage = storage.clone();
+            async move { read_all(storage.get("test").await.unwrap()).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        storage
+            .put_then_invalidate("test", "new data".as_bytes().to_vec())
+            .await
+            .unwrap();
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "new data".as_bytes());
+
+        // The get that started before the put reads the old bytes, but does
+        // not cache them.
+        assert_eq!(concurrent_get.await.unwrap(), "old data".as_bytes());
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "new data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_get_uncached_always_fetches() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("old data", &[("content-length", "8")]),
+            get_event("new data", &[("content-length", "8")]),
+        ]);
+        let storage = S3Storage {
+            cache: Some(ObjectCache::new(1024, 1024)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "old data".as_bytes());
+        // As if another writer had replaced the object since.
+        let buf = read_all(storage.get_uncached("test").await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(buf, "new data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 2);
+
+        // The fresh read leaves the cache as it was.
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "old data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_populates_cache() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            get_event("test data", &[("content-length", "9")]),
+            mock_event(
+                404,
+                "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>",
+            ),
+        ]);
+        let storage = S3Storage {
+            cache: Some(ObjectCache::new(1024, 1024)),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        storage
+            .prefetch(vec!["test".to_string(), "test".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(http_client.actual_requests().count(), 1);
+        let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+        assert_eq!(buf, "test data".as_bytes());
+        assert_eq!(http_client.actual_requests().count(), 1);
+
+        // A failed prefetch is only logged.
+        storage.prefetch(vec!["missing".to_string()]).await.unwrap();
+        assert_eq!(http_client.actual_requests().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_decodes_gzip_content_encoding() {
+        let encoded = CompressionCodec::Gzip.compress(b"test data").unwrap();
+        for (decode, expected) in [(true, b"test data".to_vec()), (false, encoded.clone())] {
+            let (client, _) =
+                get_mock_s3_client(vec![get_event(&encoded, &[("content-encoding", "gzip")])]);
+            let storage = S3Storage {
+                decode_content_encoding: decode,
+                ..S3Storage::new("test", client, 1024 * 1024 * 8)
+            };
+            let buf = read_all(storage.get("test").await.unwrap()).await.unwrap();
+            assert_eq!(buf, expected, "decode_content_encoding: {}", decode);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_object_is_not_missing() {
+        let no_such_key = "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>";
+        let empty_gzip = CompressionCodec::Gzip.compress(&[]).unwrap();
+        // Each case is read without a cache, with one, and as an object
+        // stored compressed, since each takes a different path through get.
+        let cases: Vec<(&str, Vec<ReplayEvent>, Option<ObjectCache>)> = vec![
+            (
+                "uncached",
+                vec![get_event("", &[("content-length", "0")])],
+                None,
+            ),
+            (
+                "cached",
+                vec![get_event("", &[("content-length", "0")])],
+                Some(ObjectCache::new(1024, 1024)),
+            ),
+            (
+                "compressed",
+                vec![get_event(
+                    &empty_gzip,
+                    &[("x-amz-meta-compression", "gzip")],
+                )],
+                None,
+            ),
+        ];
+        for (case, events, cache) in cases {
+            let (client, _) = get_mock_s3_client(events);
+            let storage = S3Storage {
+                cache: cache.clone(),
+                ..S3Storage::new("test", client, 1024 * 1024 * 8)
+            };
+            let buf = read_all(storage.get("empty").await.unwrap()).await.unwrap();
+            assert!(buf.is_empty(), "{}", case);
+            if cache.is_some() {
+                // Served from the cache, without another request.
+                let buf = read_all(storage.get("empty").await.unwrap()).await.unwrap();
+                assert!(buf.is_empty(), "{}", case);
+            }
+        }
+
+        for cache in [None, Some(ObjectCache::new(1024, 1024))] {
+            let (client, _) = get_mock_s3_client(vec![mock_event(404, no_such_key)]);
+            let storage = S3Storage {
+                cache,
+                ..S3Storage::new("test", client, 1024 * 1024 * 8)
+            };
+            let res = storage.get("missing").await;
+            assert!(matches!(res, Err(S3GetError::NoSuchKey(_))));
+        }
+
+        let (client, _) = get_mock_s3_client(vec![
+            get_event("", &[("content-length", "0")]),
+            mock_event(404, no_such_key),
+        ]);
+        let storage = crate::Storage::S3(S3Storage::new("test", client, 1024 * 1024 * 8));
+        let bytes = storage.get_optional("empty").await.unwrap();
+        assert_eq!(bytes.map(|bytes| bytes.len()), Some(0));
+        assert!(storage.get_optional("missing").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_stream_reports_content_length() {
+        let compressed = CompressionCodec::Gzip
+            .compress("compressed".as_bytes())
+            .unwrap();
+        let compressed_length = compressed.len().to_string();
+        let (client, _) = get_mock_s3_client(vec![
+            get_event("test data", &[("content-length", "9")]),
+            get_event("no length", &[]),
+            get_event(
+                &compressed,
+                &[
+                    ("content-length", &compressed_length),
+                    ("x-amz-meta-compression", "gzip"),
+                ],
+            ),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        let (content_length, stream) = storage.get_stream("test").await.unwrap();
+        let buf = read_all(stream).await.unwrap();
+        assert_eq!(content_length, Some(buf.len() as u64));
+        assert_eq!(buf, "test data".as_bytes());
+
+        let (content_length, stream) = storage.get_stream("no-length").await.unwrap();
+        assert_eq!(content_length, None);
+        read_all(stream).await.unwrap();
+
+        // The length of a compressed object is that of the decompressed body.
+        let (content_length, stream) = storage.get_stream("compressed").await.unwrap();
+        let buf = read_all(stream).await.unwrap();
+        assert_eq!(content_length, Some(buf.len() as u64));
+        assert_eq!(buf, "compressed".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_get_decompresses_by_object_metadata() {
+        let compressed = CompressionCodec::Gzip
+            .compress("compressed".as_bytes())
+            .unwrap();
+        let (client, _) = get_mock_s3_client(vec![
+            get_event("uncompressed", &[]),
+            get_event(&compressed, &[("x-amz-meta-compression", "gzip")]),
+        ]);
+        // The configured codec only applies to puts.
+        let storage = S3Storage {
+            compression: Some(CompressionCodec::Zstd),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let buf = read_all(storage.get("uncompressed").await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(buf, "uncompressed".as_bytes());
+        let buf = read_all(storage.get("compressed").await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(buf, "compressed".as_bytes());
+    }
+
+    #[derive(Default)]
+    struct FakeMetrics {
+        samples: std::sync::Mutex<Vec<(StorageOperation, StorageOutcome, Duration, u64)>>,
+    }
+
+    impl StorageMetrics for FakeMetrics {
+        fn record(
+            &self,
+            operation: StorageOperation,
+            outcome: StorageOutcome,
+            latency: Duration,
+            bytes: u64,
+        ) {
+            self.samples
+                .lock()
+                .unwrap()
+                .push((operation, outcome, latency, bytes));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_records_metrics() {
+        let (client, _) = get_mock_s3_client(vec![get_event("test data", &[])]);
+        let metrics = Arc::new(FakeMetrics::default());
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8).with_metrics(metrics.clone());
+
+        let stream = storage.get("test").await.unwrap();
+        // Nothing is recorded until the body has been read.
+        assert!(metrics.samples.lock().unwrap().is_empty());
+        read_all(stream).await.unwrap();
+
+        let samples = metrics.samples.lock().unwrap();
+        assert_eq!(samples.len(), 1);
+        let (operation, outcome, _, bytes) = samples[0];
+        assert_eq!(operation, StorageOperation::Get);
+        assert_eq!(outcome, StorageOutcome::Success);
+        assert_eq!(bytes, "test data".len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_failed_get_records_error() {
+        let (client, _) = get_mock_s3_client(vec![mock_event(
+            404,
+            "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>",
+        )]);
+        let metrics = Arc::new(FakeMetrics::default());
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8).with_metrics(metrics.clone());
+
+        assert!(storage.get("test").await.is_err());
+
+        let samples = metrics.samples.lock().unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].0, StorageOperation::Get);
+        assert_eq!(samples[0].1, StorageOutcome::Error);
+    }
+
+    #[tokio::test]
+    async fn test_put_records_metrics() {
+        let (client, _) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let metrics = Arc::new(FakeMetrics::default());
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8).with_metrics(metrics.clone());
+
+        storage
+            .put_bytes("test", "test data".as_bytes().to_vec())
+            .await
+            .unwrap();
+
+        let samples = metrics.samples.lock().unwrap();
+        assert_eq!(samples.len(), 1);
+        let (operation, outcome, _, bytes) = samples[0];
+        assert_eq!(operation, StorageOperation::Put);
+        assert_eq!(outcome, StorageOutcome::Success);
+        assert_eq!(bytes, "test data".len() as u64);
+    }
+
+    const CREATE_MULTIPART_UPLOAD_RESULT: &str = "<InitiateMultipartUploadResult><Bucket>test</Bucket><Key>test</Key><UploadId>upload-id</UploadId></InitiateMultipartUploadResult>";
+    const COMPLETE_MULTIPART_UPLOAD_RESULT: &str = "<CompleteMultipartUploadResult><Bucket>test</Bucket><Key>test</Key><ETag>\"etag\"</ETag></CompleteMultipartUploadResult>";
+
+    #[tokio::test]
+    async fn test_put_uploads_parts_concurrently() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT),
+        ]);
+        let storage = S3Storage {
+            upload_concurrency: 3,
+            ..S3Storage::new("test", client, 4)
+        };
+
+        storage
+            .put_bytes("test", "0123456789".as_bytes().to_vec())
+            .await
+            .unwrap();
+
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 5);
+        assert!(requests[0].uri().contains("uploads"));
+        let mut parts = requests[1..4]
+            .iter()
+            .map(|request| {
+                let part_number = request
+                    .uri()
+                    .split(['?', '&'])
+                    .find_map(|param| param.strip_prefix("partNumber="))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                (part_number, request.body().bytes().unwrap().to_vec())
+            })
+            .collect::<Vec<_>>();
+        parts.sort();
+        assert_eq!(
+            parts.iter().map(|(number, _)| *number).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            parts
+                .into_iter()
+                .flat_map(|(_, body)| body)
+                .collect::<Vec<_>>(),
+            "0123456789".as_bytes()
+        );
+        assert!(requests[4].uri().contains("uploadId=upload-id"));
+    }
+
+    fn write_temp_file(contents: &[u8]) -> NamedTempFile {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(contents).unwrap();
+        temp_file
+    }
+
+    #[tokio::test]
+    async fn test_put_file_uploads_parts_from_disk() {
+        let contents = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let temp_file = write_temp_file(&contents);
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT),
+        ]);
+        let storage = S3Storage::new("test", client, 4096);
+
+        storage
+            .put_file("test", temp_file.path().to_str().unwrap())
+            .await
+            .unwrap();
+
+        // The parts are streamed from the file, so the recorded requests only
+        // carry their lengths.
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 5);
+        let part_lengths = requests[1..4]
+            .iter()
+            .map(|request| request.headers().get("content-length").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(part_lengths, vec!["4096", "4096", "1808"]);
+    }
+
+    #[tokio::test]
+    async fn test_put_file_under_part_size_is_single_put() {
+        let temp_file = write_temp_file("test data".as_bytes());
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage::new("test", client, 4096);
+
+        storage
+            .put_file("test", temp_file.path().to_str().unwrap())
+            .await
+            .unwrap();
+
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers().get("content-length"), Some("9"));
+    }
+
+    // Counts a part as no longer held once its UploadPart request has
+    // finished.
+    #[derive(Debug)]
+    struct PartsInFlight(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Intercept for PartsInFlight {
+        fn name(&self) -> &'static str {
+            "PartsInFlight"
+        }
+
+        fn read_after_execution(
+            &self,
+            context: &FinalizerInterceptorContextRef<'_>,
+            _runtime_components: &RuntimeComponents,
+            _cfg: &mut ConfigBag,
+        ) -> Result<(), BoxError> {
+            let is_part = matches!(
+                context.output_or_error(),
+                Some(Ok(output)) if output.downcast_ref::<UploadPartOutput>().is_some()
+            );
+            if is_part {
+                self.0.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_file_holds_bounded_parts() {
+        let contents = (0..40000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let temp_file = write_temp_file(&contents);
+        let mut events = vec![mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT)];
+        events.extend((0..10).map(|_| mock_event(200, "")));
+        events.push(mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT));
+        let http_client = StaticReplayClient::new(events);
+        let in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let client = aws_sdk_s3::Client::from_conf(
+            mock_s3_config(&http_client)
+                .interceptor(PartsInFlight(in_flight.clone()))
+                .build(),
+        );
+        let storage = S3Storage {
+            upload_concurrency: 2,
+            ..S3Storage::new("test", client, 4000)
+        };
+
+        // Reads parts as put_file does, counting each from the moment it is
+        // opened until its upload has finished.
+        let max_in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let path = temp_file.path().to_str().unwrap().to_string();
+        let (in_flight_ref, max_in_flight_ref) = (in_flight.clone(), max_in_flight.clone());
+        storage
+            .put_object(
+                "test",
+                contents.len(),
+                &PutOptions::default(),
+                move |range| {
+                    let now_in_flight =
+                        in_flight_ref.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                    max_in_flight_ref.fetch_max(now_in_flight, std::sync::atomic::Ordering::SeqCst);
+                    read_file_part(path.clone(), range)
+                },
+            )
+            .await
+            .unwrap();
+
+        let max_in_flight = max_in_flight.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(max_in_flight >= 1);
+        assert!(max_in_flight <= 2, "{} parts held at once", max_in_flight);
+        assert_eq!(in_flight.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert_eq!(http_client.actual_requests().count(), 12);
     }
 
-    pub async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), S3PutError> {
-        let bytes = Arc::new(Bytes::from(bytes));
+    fn chunk_stream(
+        chunks: Vec<Result<&'static str, &'static str>>,
+    ) -> impl Stream<Item = Result<Bytes, String>> + Send {
+        stream::iter(chunks.into_iter().map(|chunk| {
+            chunk
+                .map(|chunk| Bytes::from(chunk.as_bytes()))
+                .map_err(String::from)
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_put_stream_uploads_chunks_in_parts() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT),
+        ]);
+        let storage = S3Storage::new("test", client, 4);
 
-        self.put_object(key, bytes.len(), move |range| {
-            let bytes = bytes.clone();
-            async move { Ok(ByteStream::from(bytes.slice(range))) }.boxed()
+        // Spawned to check that the upload can run on another task.
+        tokio::spawn(async move {
+            storage
+                .put_stream(
+                    "test",
+                    chunk_stream(vec![Ok("012"), Ok("345"), Ok("67"), Ok("89")]),
+                )
+                .await
         })
         .await
+        .unwrap()
+        .unwrap();
+
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0].uri().contains("uploads"));
+        assert!(requests[1].uri().contains("partNumber=1"));
+        assert_eq!(requests[1].body().bytes().unwrap(), "012345".as_bytes());
+        assert!(requests[2].uri().contains("partNumber=2"));
+        assert_eq!(requests[2].body().bytes().unwrap(), "6789".as_bytes());
+        assert!(requests[3].uri().contains("uploadId=upload-id"));
     }
 
-    pub async fn put_file(&self, key: &str, path: &str) -> Result<(), S3PutError> {
-        let file_size = tokio::fs::metadata(path)
+    #[tokio::test]
+    async fn test_put_stream_small_object() {
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage::new("test", client, 1024);
+
+        storage
+            .put_stream("test", chunk_stream(vec![Ok("test "), Ok("data")]))
             .await
-            .map_err(|err| S3PutError::S3PutError(err.to_string()))?
-            .len();
+            .unwrap();
+
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 1);
+        assert!(!requests[0].uri().contains("uploads"));
+        assert_eq!(requests[0].body().bytes().unwrap(), "test data".as_bytes());
+    }
 
-        let path = path.to_string();
+    #[tokio::test]
+    async fn test_put_stream_aborts_on_stream_error() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, ""),
+            mock_event(204, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 4);
 
-        self.put_object(key, file_size as usize, move |range| {
-            let path = path.clone();
+        let res = storage
+            .put_stream("test", chunk_stream(vec![Ok("0123"), Err("broken")]))
+            .await;
+        assert!(matches!(res, Err(S3PutError::S3PutError(message)) if message.contains("broken")));
 
-            async move {
-                ByteStream::read_from()
-                    .path(path)
-                    .offset(range.start as u64)
-                    .length(Length::Exact(range.len() as u64))
-                    .build()
-                    .await
-                    .map_err(|err| S3PutError::S3PutError(err.to_string()))
-            }
-            .boxed()
-        })
-        .await
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].method(), "DELETE");
+        assert!(requests[2].uri().contains("uploadId=upload-id"));
     }
 
-    async fn put_object(
-        &self,
-        key: &str,
-        total_size_bytes: usize,
-        create_bytestream_fn: impl Fn(
-            Range<usize>,
-        ) -> BoxFuture<'static, Result<ByteStream, S3PutError>>,
-    ) -> Result<(), S3PutError> {
-        if total_size_bytes < self.upload_part_size_bytes {
-            return self
-                .oneshot_upload(key, total_size_bytes, create_bytestream_fn)
-                .await;
-        }
+    // The Content-Length header and the body of a request.
+    type ReceivedPut = (Option<String>, Vec<u8>);
 
-        self.multipart_upload(key, total_size_bytes, create_bytestream_fn)
-            .await
+    // Reads the body of every request as a server would, and answers each
+    // with an empty 200. A body that fails to read fails the request.
+    #[derive(Clone, Debug, Default)]
+    struct StreamingPutClient {
+        received: Arc<std::sync::Mutex<Vec<ReceivedPut>>>,
     }
 
-    async fn oneshot_upload(
-        &self,
-        key: &str,
-        total_size_bytes: usize,
-        create_bytestream_fn: impl Fn(
-            Range<usize>,
-        ) -> BoxFuture<'static, Result<ByteStream, S3PutError>>,
-    ) -> Result<(), S3PutError> {
-        self.client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .body(create_bytestream_fn(0..total_size_bytes).await?)
-            .send()
-            .await
-            .map_err(|err| S3PutError::S3PutError(err.to_string()))?;
+    impl StreamingPutClient {
+        fn client(&self) -> aws_sdk_s3::Client {
+            let config = mock_s3_config(&StaticReplayClient::new(vec![]))
+                .http_client(self.clone())
+                .build();
+            aws_sdk_s3::Client::from_conf(config)
+        }
+    }
 
-        Ok(())
+    impl aws_smithy_runtime_api::client::http::HttpConnector for StreamingPutClient {
+        fn call(
+            &self,
+            request: HttpRequest,
+        ) -> aws_smithy_runtime_api::client::http::HttpConnectorFuture {
+            let received = self.received.clone();
+            aws_smithy_runtime_api::client::http::HttpConnectorFuture::new(async move {
+                let content_length = request.headers().get("content-length").map(str::to_string);
+                let mut body = request.into_body();
+                let mut bytes = Vec::new();
+                while let Some(chunk) = http_body::Body::data(&mut body).await {
+                    let chunk = chunk
+                        .map_err(aws_smithy_runtime_api::client::result::ConnectorError::io)?;
+                    bytes.extend_from_slice(&chunk);
+                }
+                received.lock().unwrap().push((content_length, bytes));
+                Ok(HttpResponse::new(200.try_into().unwrap(), SdkBody::empty()))
+            })
+        }
     }
 
-    async fn multipart_upload(
-        &self,
-        key: &str,
-        total_size_bytes: usize,
-        create_bytestream_fn: impl Fn(
-            Range<usize>,
-        ) -> BoxFuture<'static, Result<ByteStream, S3PutError>>,
-    ) -> Result<(), S3PutError> {
-        let mut part_count = (total_size_bytes / self.upload_part_size_bytes) + 1;
-        let mut size_of_last_part = total_size_bytes % self.upload_part_size_bytes;
-        if size_of_last_part == 0 {
-            size_of_last_part = self.upload_part_size_bytes;
-            part_count -= 1;
+    impl aws_smithy_runtime_api::client::http::HttpClient for StreamingPutClient {
+        fn http_connector(
+            &self,
+            _: &aws_smithy_runtime_api::client::http::HttpConnectorSettings,
+            _: &RuntimeComponents,
+        ) -> aws_smithy_runtime_api::client::http::SharedHttpConnector {
+            aws_smithy_runtime_api::client::http::SharedHttpConnector::new(self.clone())
         }
+    }
 
-        let upload_id = match self
-            .client
-            .create_multipart_upload()
-            .bucket(&self.bucket)
-            .key(key)
-            .send()
-            .await
-            .map_err(|err| S3PutError::S3PutError(err.to_string()))?
-            .upload_id
-        {
-            Some(upload_id) => upload_id,
-            None => {
-                return Err(S3PutError::S3PutError(
-                    "Multipart upload creation response missing upload ID".to_string(),
-                ));
-            }
-        };
+    #[tokio::test]
+    async fn test_put_stream_sized_sends_single_put() {
+        let http_client = StreamingPutClient::default();
+        // Small parts, to show a sized stream is never split.
+        let storage = S3Storage::new("test", http_client.client(), 4);
 
-        let mut upload_parts = Vec::new();
-        for part_index in 0..part_count {
-            let this_part = if part_count - 1 == part_index {
-                size_of_last_part
-            } else {
-                self.upload_part_size_bytes
-            };
-            let part_number = part_index as i32 + 1; // Part numbers start at 1
-            let offset = part_index * self.upload_part_size_bytes;
-            let length = this_part;
+        storage
+            .put_stream_sized("test", chunk_stream(vec![Ok("test "), Ok("data")]), 9)
+            .await
+            .unwrap();
+        let received = http_client.received.lock().unwrap().clone();
+        assert_eq!(
+            received,
+            vec![(Some("9".to_string()), "test data".as_bytes().to_vec())]
+        );
+    }
 
-            let stream = create_bytestream_fn(offset..(offset + length)).await?;
+    #[tokio::test]
+    async fn test_put_stream_sized_rejects_length_mismatch() {
+        let http_client = StreamingPutClient::default();
+        let storage = S3Storage::new("test", http_client.client(), 1024 * 1024 * 8);
 
-            let upload_part_res = self
-                .client
-                .upload_part()
-                .key(key)
-                .bucket(&self.bucket)
-                .upload_id(&upload_id)
-                .body(stream)
-                .part_number(part_number)
-                .send()
-                .await
-                .map_err(|err| S3PutError::S3PutError(err.to_string()))?;
+        let res = storage
+            .put_stream_sized("test", chunk_stream(vec![Ok("test "), Ok("data")]), 12)
+            .await;
+        assert!(matches!(
+            res,
+            Err(S3PutError::ContentLengthMismatch {
+                expected: 12,
+                actual: 9
+            })
+        ));
+        let res = storage
+            .put_stream_sized("test", chunk_stream(vec![Ok("test "), Ok("data")]), 5)
+            .await;
+        assert!(matches!(
+            res,
+            Err(S3PutError::ContentLengthMismatch {
+                expected: 5,
+                actual: 9
+            })
+        ));
+        let res = storage
+            .put_stream_sized("test", chunk_stream(vec![Ok("test "), Err("broken")]), 9)
+            .await;
+        assert!(matches!(res, Err(S3PutError::S3PutError(message)) if message.contains("broken")));
+        // None of the bodies was sent in full.
+        assert!(http_client.received.lock().unwrap().is_empty());
+    }
 
-            upload_parts.push(
-                CompletedPart::builder()
-                    .e_tag(upload_part_res.e_tag.unwrap_or_default())
-                    .part_number(part_number)
-                    .build(),
-            );
-        }
+    #[tokio::test]
+    async fn test_dropped_put_stream_aborts_upload() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, ""),
+            mock_event(204, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 4);
 
-        self.client
-            .complete_multipart_upload()
-            .bucket(&self.bucket)
-            .key(key)
-            .multipart_upload(
-                CompletedMultipartUpload::builder()
-                    .set_parts(Some(upload_parts))
-                    .build(),
-            )
-            .upload_id(&upload_id)
-            .send()
-            .await
-            .map_err(|err| S3PutError::S3PutError(err.to_string()))?;
+        // The first part is uploaded, then the stream never yields the rest.
+        let stream = chunk_stream(vec![Ok("0123")]).chain(stream::pending());
+        let res = tokio::time::timeout(
+            Duration::from_millis(100),
+            storage.put_stream("test", stream),
+        )
+        .await;
+        assert!(res.is_err());
 
-        Ok(())
+        // The abort is issued in the background once the put is dropped.
+        let start = std::time::Instant::now();
+        while http_client.actual_requests().count() < 3 {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert!(requests[1].uri().contains("partNumber=1"));
+        assert_eq!(requests[2].method(), "DELETE");
+        assert!(requests[2].uri().contains("uploadId=upload-id"));
     }
-}
 
-#[derive(Error, Debug)]
-pub enum StorageConfigError {
-    #[error("Invalid storage config")]
-    InvalidStorageConfig,
-    #[error("Failed to create bucket: {0}")]
-    FailedToCreateBucket(String),
-}
+    #[tokio::test]
+    async fn test_completed_put_stream_is_not_aborted() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(200, ""),
+            mock_event(200, ""),
+            mock_event(200, COMPLETE_MULTIPART_UPLOAD_RESULT),
+        ]);
+        let storage = S3Storage::new("test", client, 4);
 
-impl ChromaError for StorageConfigError {
-    fn code(&self) -> ErrorCodes {
-        match self {
-            StorageConfigError::InvalidStorageConfig => ErrorCodes::InvalidArgument,
-            StorageConfigError::FailedToCreateBucket(_) => ErrorCodes::Internal,
-        }
+        storage
+            .put_stream("test", chunk_stream(vec![Ok("0123"), Ok("45")]))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(http_client.actual_requests().count(), 4);
     }
-}
-
-#[async_trait]
-impl Configurable<StorageConfig> for S3Storage {
-    async fn try_from_config(config: &StorageConfig) -> Result<Self, Box<dyn ChromaError>> {
-        match &config {
-            StorageConfig::S3(s3_config) => {
-                let client = match &s3_config.credentials {
-                    super::config::S3CredentialsConfig::Minio => {
-                        // Set up credentials assuming minio is running locally
-                        let cred = aws_sdk_s3::config::Credentials::new(
-                            "minio",
-                            "minio123",
-                            None,
-                            None,
-                            "loaded-from-env",
-                        );
 
-                        let timeout_config_builder = TimeoutConfigBuilder::default()
-                            .connect_timeout(Duration::from_millis(s3_config.connect_timeout_ms))
-                            .read_timeout(Duration::from_millis(s3_config.request_timeout_ms));
-                        let retry_config = RetryConfig::standard();
+    #[tokio::test]
+    async fn test_put_multipart_threshold() {
+        let (client, http_client) = get_mock_s3_client(vec![mock_event(200, "")]);
+        let storage = S3Storage {
+            multipart_threshold_bytes: 16,
+            ..S3Storage::new("test", client, 4)
+        };
 
-                        // Set up s3 client
-                        let config = aws_sdk_s3::config::Builder::new()
-                            .endpoint_url("http://minio.chroma:9000".to_string())
-                            .credentials_provider(cred)
-                            .behavior_version_latest()
-                            .region(aws_sdk_s3::config::Region::new("us-east-1"))
-                            .force_path_style(true)
-                            .timeout_config(timeout_config_builder.build())
-                            .retry_config(retry_config)
-                            .build();
-                        aws_sdk_s3::Client::from_conf(config)
-                    }
-                    super::config::S3CredentialsConfig::AWS => {
-                        let config = aws_config::load_from_env().await;
-                        let timeout_config_builder = TimeoutConfigBuilder::default()
-                            .connect_timeout(Duration::from_millis(s3_config.connect_timeout_ms))
-                            .read_timeout(Duration::from_millis(s3_config.request_timeout_ms));
-                        let retry_config = RetryConfig::standard();
-                        let config = config
-                            .to_builder()
-                            .timeout_config(timeout_config_builder.build())
-                            .retry_config(retry_config)
-                            .build();
-                        aws_sdk_s3::Client::new(&config)
-                    }
-                };
-                let storage =
-                    S3Storage::new(&s3_config.bucket, client, s3_config.upload_part_size_bytes);
-                // for minio we create the bucket since it is only used for testing
-                match &s3_config.credentials {
-                    super::config::S3CredentialsConfig::Minio => {
-                        let res = storage.create_bucket().await;
-                        match res {
-                            Ok(_) => {}
-                            Err(e) => {
-                                return Err(Box::new(StorageConfigError::FailedToCreateBucket(e)));
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+        storage
+            .put_bytes("test", "0123456789".as_bytes().to_vec())
+            .await
+            .unwrap();
 
-                return Ok(storage);
-            }
-            _ => {
-                return Err(Box::new(StorageConfigError::InvalidStorageConfig));
-            }
-        }
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 1);
+        assert!(!requests[0].uri().contains("uploads"));
+        assert_eq!(requests[0].body().bytes().unwrap(), "0123456789".as_bytes());
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures::StreamExt;
-    use rand::{Rng, SeedableRng};
-    use std::io::Write;
-    use tempfile::{tempdir, NamedTempFile};
 
-    fn get_s3_client() -> aws_sdk_s3::Client {
-        // Set up credentials assuming minio is running locally
-        let cred = aws_sdk_s3::config::Credentials::new(
-            "minio",
-            "minio123",
-            None,
-            None,
-            "loaded-from-env",
-        );
+    #[tokio::test]
+    async fn test_put_aborts_failed_multipart_upload() {
+        let internal_error =
+            "<Error><Code>InternalError</Code><Message>internal error</Message></Error>";
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(200, CREATE_MULTIPART_UPLOAD_RESULT),
+            mock_event(500, internal_error),
+            mock_event(204, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 4);
 
-        // Set up s3 client
-        let config = aws_sdk_s3::config::Builder::new()
-            .endpoint_url("http://127.0.0.1:9000".to_string())
-            .credentials_provider(cred)
-            .behavior_version_latest()
-            .region(aws_sdk_s3::config::Region::new("us-east-1"))
-            .force_path_style(true)
-            .build();
+        let res = storage
+            .put_bytes("test", "0123456789".as_bytes().to_vec())
+            .await;
+        assert!(matches!(res, Err(S3PutError::ServiceError { .. })));
 
-        aws_sdk_s3::Client::from_conf(config)
+        let requests = http_client.actual_requests().collect::<Vec<_>>();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].method(), "DELETE");
+        assert!(requests[2].uri().contains("uploadId=upload-id"));
     }
 
     #[tokio::test]
@@ -446,11 +9104,7 @@ mod tests {
     async fn test_put_get_key() {
         let client = get_s3_client();
 
-        let storage = S3Storage {
-            bucket: "test".to_string(),
-            client,
-            upload_part_size_bytes: 1024 * 1024 * 8,
-        };
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
         storage.create_bucket().await.unwrap();
 
         let test_data = "test data";
@@ -477,13 +9131,145 @@ mod tests {
         assert_eq!(buf, test_data);
     }
 
-    async fn test_put_file(file_size: usize, upload_part_size_bytes: usize) {
+    #[tokio::test]
+    async fn test_rate_limited_gets() {
+        let events = (0..15).map(|_| mock_event(200, "test data")).collect();
+        let (client, _) = get_mock_s3_client(events);
+        let clock = Arc::new(crate::clock::ManualClock::new());
+        let storage = S3Storage {
+            rate_limiter: Some(RateLimiter::with_clock(10, clock.clone())),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        let granted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        for i in 0..15 {
+            tokio::spawn({
+                let (storage, granted) = (storage.clone(), granted.clone());
+                async move {
+                    drop(storage.get("test").await.unwrap());
+                    granted.lock().unwrap().push(i);
+                }
+            });
+            // Each get either completes or waits for its token before the
+            // next one starts, so that they ask for tokens in order.
+            while granted.lock().unwrap().len() + clock.pending_sleeps() <= i {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        // The burst covers the first 10 gets. Each of the other 5 waits a
+        // tenth of a second longer than the one before it...
+        assert_eq!(*granted.lock().unwrap(), (0..10).collect::<Vec<_>>());
+        let waits = (1..=5)
+            .map(|i| Duration::from_millis(100 * i))
+            .collect::<Vec<_>>();
+        assert_eq!(clock.sleeps(), waits);
+
+        // ...and they are admitted one at a time, in the order they started.
+        for admitted in 11..=15 {
+            clock.advance(Duration::from_millis(100));
+            while granted.lock().unwrap().len() < admitted {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(*granted.lock().unwrap(), (0..admitted).collect::<Vec<_>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_are_bounded() {
+        let events = (0..3).map(|_| mock_event(200, "test data")).collect();
+        let (client, _) = get_mock_s3_client(events);
+        let storage = S3Storage {
+            request_semaphore: Some(Arc::new(Semaphore::new(2))),
+            ..S3Storage::new("test", client, 1024 * 1024 * 8)
+        };
+
+        // A get stays in flight until its stream is drained or dropped.
+        let first = storage.get("a").await.unwrap();
+        let _second = storage.get("b").await.unwrap();
+        let third = tokio::spawn({
+            let storage = storage.clone();
+            async move { storage.get("c").await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!third.is_finished(), "third get should block on the limit");
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), third)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_range() {
+        let http_client = RangeServingClient::new("0123456789".as_bytes().to_vec());
+        let storage = S3Storage::new("test", http_client.client(), 1024 * 1024 * 8);
+
+        // Mid-object range
+        let bytes = storage.get_range("test", 2, 6).await.unwrap();
+        assert_eq!(bytes.as_slice(), "2345".as_bytes());
+
+        // A range that runs past the end of the object is truncated
+        let bytes = storage.get_range("test", 8, 20).await.unwrap();
+        assert_eq!(bytes.as_slice(), "89".as_bytes());
+
+        // A range that starts past the end of the object is unsatisfiable
+        let res = storage.get_range("test", 10, 20).await;
+        assert!(matches!(res, Err(S3GetError::RangeNotSatisfiable(_))));
+
+        // Zero-length range, answered without a request
+        let bytes = storage.get_range("test", 4, 4).await.unwrap();
+        assert!(bytes.is_empty());
+        assert_eq!(http_client.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let (client, http_client) = get_mock_s3_client(vec![
+            mock_event(204, ""),
+            mock_event(
+                404,
+                "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>",
+            ),
+            mock_event(204, ""),
+            mock_event(200, "test data"),
+            mock_event(204, ""),
+        ]);
+        let storage = S3Storage::new("test", client, 1024 * 1024 * 8);
+
+        storage.delete("test").await.unwrap();
+        let res = storage.get("test").await;
+        assert!(matches!(res, Err(S3GetError::NoSuchKey(_))));
+        // S3 answers a delete of a missing key as it does any other.
+        storage.delete("test").await.unwrap();
+
+        // A get whose stream is already open still reads the whole object.
+        let mut stream = storage.get("test").await.unwrap();
+        storage.delete("test").await.unwrap();
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(buf, "test data".as_bytes());
+
+        let methods = http_client
+            .actual_requests()
+            .map(|request| request.method().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(methods, ["DELETE", "GET", "DELETE", "GET", "DELETE"]);
+    }
+
+    async fn test_put_file(
+        file_size: usize,
+        upload_part_size_bytes: usize,
+        upload_concurrency: usize,
+    ) {
         let client = get_s3_client();
 
         let storage = S3Storage {
-            bucket: "test".to_string(),
-            client,
-            upload_part_size_bytes,
+            upload_concurrency,
+            ..S3Storage::new("test", client, upload_part_size_bytes)
         };
         storage.create_bucket().await.unwrap();
 
@@ -529,17 +9315,26 @@ mod tests {
         let test_upload_part_size_bytes = 1024 * 1024 * 8; // 8MB
 
         // Under part size
-        test_put_file(1024, test_upload_part_size_bytes).await;
+        test_put_file(1024, test_upload_part_size_bytes, 1).await;
         // At part size
         test_put_file(
             test_upload_part_size_bytes as usize,
             test_upload_part_size_bytes,
+            1,
         )
         .await;
         // Over part size
         test_put_file(
             (test_upload_part_size_bytes as f64 * 2.5) as usize,
             test_upload_part_size_bytes,
+            1,
+        )
+        .await;
+        // Over part size, uploading parts concurrently
+        test_put_file(
+            (test_upload_part_size_bytes as f64 * 2.5) as usize,
+            test_upload_part_size_bytes,
+            4,
         )
         .await;
     }
@@ -0,0 +1,88 @@
+// Tracks the operations in flight against a storage backend so that it can be
+// shut down gracefully: once shutdown starts no new operations are admitted,
+// and the shutdown waits for those already in flight to finish. Operations
+// hold an InFlight guard for as long as they run and release it on drop, so
+// an operation that is cancelled is no longer waited for.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+#[derive(Default)]
+pub(crate) struct RequestTracker {
+    shutting_down: AtomicBool,
+    in_flight: AtomicUsize,
+    drained: Notify,
+}
+
+pub(crate) struct InFlight(Arc<RequestTracker>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+impl RequestTracker {
+    /// Admits an operation, or returns None once shutdown has started.
+    pub(crate) fn begin(self: &Arc<Self>) -> Option<InFlight> {
+        // Counted before checking the flag, so that a shutdown that has not
+        // seen this operation's count is seen by the check.
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(self.clone());
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(in_flight)
+    }
+
+    /// Stops admitting operations and waits up to `timeout` for those in
+    /// flight to finish. Returns whether they all finished in time.
+    pub(crate) async fn shutdown(&self, timeout: Duration) -> bool {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        tokio::time::timeout(timeout, async {
+            loop {
+                // Registered before the check so that a drain in between is
+                // not missed.
+                let drained = self.drained.notified();
+                if self.in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                drained.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_operations_in_flight() {
+        let tracker = Arc::new(RequestTracker::default());
+        let in_flight = tracker.begin().unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(in_flight);
+        });
+
+        assert!(tracker.shutdown(Duration::from_secs(5)).await);
+        assert!(tracker.begin().is_none());
+        release.await.unwrap();
+        // Rejected operations are not counted as in flight.
+        assert!(tracker.shutdown(Duration::ZERO).await);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_times_out() {
+        let tracker = Arc::new(RequestTracker::default());
+        let _in_flight = tracker.begin().unwrap();
+        assert!(!tracker.shutdown(Duration::from_millis(10)).await);
+    }
+}
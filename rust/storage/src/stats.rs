@@ -0,0 +1,117 @@
+// Counters describing how the object cache is being used, kept as atomics so
+// that recording them never takes a lock. Operators export them by
+// periodically calling drain, which returns the counts accumulated since the
+// previous drain and zeroes them in the same step. Each counter is swapped
+// individually, so an increment racing a drain is counted by exactly one
+// drain; the counters of one snapshot are not a consistent cut across
+// counters, though. Alongside the counters, a snapshot carries the current
+// size of the cache, which is read from the cache itself and is never
+// zeroed by a drain.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A point-in-time copy of the storage counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_insertions: u64,
+    pub cache_evictions: u64,
+    pub cache_invalidations: u64,
+    // The bytes and the number of objects cached when the snapshot was taken.
+    pub cache_size_bytes: u64,
+    pub cache_entries: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    pub(crate) cache_hits: AtomicU64,
+    pub(crate) cache_misses: AtomicU64,
+    pub(crate) cache_insertions: AtomicU64,
+    pub(crate) cache_evictions: AtomicU64,
+    pub(crate) cache_invalidations: AtomicU64,
+}
+
+impl StatsCounters {
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StorageStats {
+        self.read_with(|counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn drain(&self) -> StorageStats {
+        self.read_with(|counter| counter.swap(0, Ordering::Relaxed))
+    }
+
+    fn read_with(&self, read: impl Fn(&AtomicU64) -> u64) -> StorageStats {
+        StorageStats {
+            cache_hits: read(&self.cache_hits),
+            cache_misses: read(&self.cache_misses),
+            cache_insertions: read(&self.cache_insertions),
+            cache_evictions: read(&self.cache_evictions),
+            cache_invalidations: read(&self.cache_invalidations),
+            ..StorageStats::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_drain_resets_counters() {
+        let counters = StatsCounters::default();
+        StatsCounters::increment(&counters.cache_hits);
+        StatsCounters::increment(&counters.cache_hits);
+        StatsCounters::increment(&counters.cache_misses);
+
+        let expected = StorageStats {
+            cache_hits: 2,
+            cache_misses: 1,
+            ..StorageStats::default()
+        };
+        assert_eq!(counters.snapshot(), expected);
+        assert_eq!(counters.drain(), expected);
+        assert_eq!(counters.snapshot(), StorageStats::default());
+    }
+
+    #[test]
+    fn test_drain_under_load_loses_no_counts() {
+        let counters = Arc::new(StatsCounters::default());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let drainer = {
+            let counters = counters.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut drained = 0;
+                while !done.load(Ordering::SeqCst) {
+                    drained += counters.drain().cache_hits;
+                }
+                drained
+            })
+        };
+        let writers = (0..4)
+            .map(|_| {
+                let counters = counters.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100_000 {
+                        StatsCounters::increment(&counters.cache_hits);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+
+        let drained = drainer.join().unwrap() + counters.drain().cache_hits;
+        assert_eq!(drained, 400_000);
+    }
+}
@@ -1,11 +1,23 @@
+use super::clock::ClockSleep;
+use super::metrics::{StorageMetrics, StorageOperation, StorageOutcome};
+use super::rate_limit::RateLimiter;
 use super::s3::S3GetError;
+use super::shutdown::InFlight;
 use super::GetError;
 use aws_sdk_s3::primitives::ByteStream as AWSS3ByteStream;
+use aws_smithy_runtime::client::http::body::content_length_enforcement::ContentLengthError;
 use futures::stream::Stream;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::future::Future;
 use std::io::Read;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::AsyncRead;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Sleep};
 
 pub type ByteStreamItem = Result<Vec<u8>, GetError>;
 
@@ -99,13 +111,211 @@ impl ByteStream for tokio::fs::File {
     }
 }
 
+/// Reads a stream ahead of its consumer: a background task pulls chunks from
+/// the inner stream into a channel of up to `chunks` chunks, so that the
+/// connection keeps flowing while the consumer works on earlier ones.
+/// Dropping the stream stops the background task and drops the inner stream.
+pub struct ReadAheadStream {
+    receiver: mpsc::Receiver<ByteStreamItem>,
+    task: JoinHandle<()>,
+}
+
+impl ReadAheadStream {
+    /// `chunks` must be greater than zero. Must be called within a Tokio
+    /// runtime.
+    pub fn new(
+        mut inner: Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>,
+        chunks: usize,
+    ) -> ReadAheadStream {
+        let (sender, receiver) = mpsc::channel(chunks);
+        let task = tokio::spawn(async move {
+            while let Some(item) = inner.next().await {
+                if sender.send(item).await.is_err() {
+                    // The consumer has gone away.
+                    return;
+                }
+            }
+        });
+        ReadAheadStream { receiver, task }
+    }
+}
+
+impl Drop for ReadAheadStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Stream for ReadAheadStream {
+    type Item = ByteStreamItem;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
 pub struct S3ByteStream {
     inner: AWSS3ByteStream,
+    // Held until the stream is drained or dropped so that the request counts
+    // against the storage concurrency limit for as long as it is in flight.
+    permit: Option<OwnedSemaphorePermit>,
+    // Held likewise, so that a graceful shutdown waits for the body to be
+    // read.
+    in_flight: Option<InFlight>,
+    checksum: Option<ChecksumVerifier>,
+    length: Option<LengthValidator>,
+    // Bounds the time until the body is fully read, not just until the
+    // response headers arrive.
+    deadline: Option<Pin<Box<Sleep>>>,
+    timed_out: bool,
+    metrics: Option<StreamMetrics>,
+    throttle: Option<Throttle>,
+}
+
+// Paces the body to the rate of `limiter`: after every chunk, the next is
+// only read once the limiter has refilled the bytes of this one.
+struct Throttle {
+    limiter: RateLimiter,
+    wait: Option<ClockSleep>,
+}
+
+// Records the get once the body has been read to the end or has failed. A
+// stream dropped before either happens records nothing.
+struct StreamMetrics {
+    metrics: Arc<dyn StorageMetrics>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl StreamMetrics {
+    fn record(self, outcome: StorageOutcome) {
+        self.metrics.record(
+            StorageOperation::Get,
+            outcome,
+            self.start.elapsed(),
+            self.bytes,
+        );
+    }
+}
+
+// Hashes the body as it is read and compares the digest against the checksum
+// stored with the object once the body is exhausted.
+struct ChecksumVerifier {
+    hasher: Sha256,
+    expected: String,
+}
+
+// Counts the bytes of the body as it is read and compares the count against
+// the Content-Length of the response once the body is exhausted.
+struct LengthValidator {
+    expected: u64,
+    actual: u64,
 }
 
 impl S3ByteStream {
     pub fn new(body: AWSS3ByteStream) -> Self {
-        S3ByteStream { inner: body }
+        S3ByteStream {
+            inner: body,
+            permit: None,
+            in_flight: None,
+            checksum: None,
+            length: None,
+            deadline: None,
+            timed_out: false,
+            metrics: None,
+            throttle: None,
+        }
+    }
+
+    pub(crate) fn with_permit(body: AWSS3ByteStream, permit: Option<OwnedSemaphorePermit>) -> Self {
+        S3ByteStream {
+            inner: body,
+            permit,
+            in_flight: None,
+            checksum: None,
+            length: None,
+            deadline: None,
+            timed_out: false,
+            metrics: None,
+            throttle: None,
+        }
+    }
+
+    pub(crate) fn with_in_flight(mut self, in_flight: InFlight) -> Self {
+        self.in_flight = Some(in_flight);
+        self
+    }
+
+    /// Verifies the body against the hex encoded SHA-256 `expected`. A
+    /// mismatch is reported as an error after the last chunk.
+    pub(crate) fn verify_checksum(mut self, expected: String) -> Self {
+        self.checksum = Some(ChecksumVerifier {
+            hasher: Sha256::new(),
+            expected,
+        });
+        self
+    }
+
+    /// Verifies that the body is `expected` bytes long. A mismatch is
+    /// reported as `S3GetError::TruncatedResponse` after the last chunk.
+    pub(crate) fn validate_length(mut self, expected: u64) -> Self {
+        self.length = Some(LengthValidator {
+            expected,
+            actual: 0,
+        });
+        self
+    }
+
+    /// Fails the stream with `S3GetError::Timeout` if it has not been fully
+    /// read by `deadline`.
+    pub(crate) fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(Box::pin(tokio::time::sleep_until(deadline)));
+        self
+    }
+
+    /// Records the get in `metrics` once the stream completes, with latency
+    /// measured from `start`.
+    pub(crate) fn with_metrics(mut self, metrics: Arc<dyn StorageMetrics>, start: Instant) -> Self {
+        self.metrics = Some(StreamMetrics {
+            metrics,
+            start,
+            bytes: 0,
+        });
+        self
+    }
+
+    /// Limits the rate at which the body is read to that of `limiter`.
+    pub(crate) fn with_throttle(mut self, limiter: RateLimiter) -> Self {
+        self.throttle = Some(Throttle {
+            limiter,
+            wait: None,
+        });
+        self
+    }
+
+    fn record(&mut self, outcome: StorageOutcome) {
+        if let Some(metrics) = self.metrics.take() {
+            metrics.record(outcome);
+        }
+    }
+
+    // Called while the body has nothing to yield: fails the stream if its
+    // deadline has passed.
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> Poll<Option<ByteStreamItem>> {
+        let expired = match self.deadline.as_mut() {
+            Some(deadline) => deadline.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if !expired {
+            return Poll::Pending;
+        }
+        self.timed_out = true;
+        self.permit = None;
+        self.in_flight = None;
+        self.record(StorageOutcome::Error);
+        Poll::Ready(Some(Err(GetError::S3Error(S3GetError::Timeout(
+            "timed out reading body".to_string(),
+        )))))
     }
 }
 
@@ -114,17 +324,197 @@ impl Stream for S3ByteStream {
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let me = self.get_mut();
+        if me.timed_out {
+            return Poll::Ready(None);
+        }
+        if let Some(wait) = me
+            .throttle
+            .as_mut()
+            .and_then(|throttle| throttle.wait.as_mut())
+        {
+            if wait.as_mut().poll(cx).is_pending() {
+                return me.poll_deadline(cx);
+            }
+            if let Some(throttle) = me.throttle.as_mut() {
+                throttle.wait = None;
+            }
+        }
         match Pin::new(&mut me.inner).poll_next(cx) {
             Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(throttle) = me.throttle.as_mut() {
+                    throttle.wait = throttle.limiter.reserve(chunk.len() as u64);
+                }
+                if let Some(checksum) = me.checksum.as_mut() {
+                    checksum.hasher.update(&chunk);
+                }
+                if let Some(length) = me.length.as_mut() {
+                    length.actual += chunk.len() as u64;
+                }
+                if let Some(metrics) = me.metrics.as_mut() {
+                    metrics.bytes += chunk.len() as u64;
+                }
                 let mut data = Vec::new();
                 data.extend_from_slice(&chunk);
                 Poll::Ready(Some(Ok(data)))
             }
-            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(GetError::S3Error(
-                S3GetError::ByteStreamError(e.to_string()),
-            )))),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
+            Poll::Ready(Some(Err(e))) => {
+                me.record(StorageOutcome::Error);
+                // The SDK fails a body that ends before its Content-Length
+                // with an opaque streaming error.
+                if let Some(LengthValidator { expected, actual }) = me.length.take() {
+                    if is_content_length_error(&e) {
+                        return Poll::Ready(Some(Err(GetError::S3Error(
+                            S3GetError::TruncatedResponse { expected, actual },
+                        ))));
+                    }
+                }
+                Poll::Ready(Some(Err(GetError::S3Error(S3GetError::ByteStreamError(
+                    e.to_string(),
+                )))))
+            }
+            Poll::Ready(None) => {
+                me.permit = None;
+                me.in_flight = None;
+                // Checked before the checksum, which a truncated body fails
+                // too.
+                if let Some(LengthValidator { expected, actual }) = me.length.take() {
+                    if actual != expected {
+                        me.record(StorageOutcome::Error);
+                        return Poll::Ready(Some(Err(GetError::S3Error(
+                            S3GetError::TruncatedResponse { expected, actual },
+                        ))));
+                    }
+                }
+                if let Some(checksum) = me.checksum.take() {
+                    let actual = hex::encode(checksum.hasher.finalize());
+                    if actual != checksum.expected {
+                        me.record(StorageOutcome::Error);
+                        return Poll::Ready(Some(Err(GetError::S3Error(
+                            S3GetError::ChecksumMismatch(format!(
+                                "expected {}, got {}",
+                                checksum.expected, actual
+                            )),
+                        ))));
+                    }
+                }
+                me.record(StorageOutcome::Success);
+                Poll::Ready(None)
+            }
+            Poll::Pending => me.poll_deadline(cx),
+        }
+    }
+}
+
+fn is_content_length_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if e.is::<ContentLengthError>() {
+            return true;
         }
+        source = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // A stream of `chunks` chunks that takes `delay` to produce each one,
+    // recording how many it has produced and whether it has been dropped.
+    struct SlowStream {
+        produced: Arc<AtomicUsize>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Drop for SlowStream {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn slow_stream(
+        chunks: usize,
+        delay: Duration,
+    ) -> (
+        Box<dyn Stream<Item = ByteStreamItem> + Unpin + Send>,
+        Arc<AtomicUsize>,
+        Arc<AtomicBool>,
+    ) {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicBool::new(false));
+        let state = SlowStream {
+            produced: produced.clone(),
+            dropped: dropped.clone(),
+        };
+        let stream = stream::unfold(state, move |state| async move {
+            if state.produced.load(Ordering::SeqCst) == chunks {
+                return None;
+            }
+            tokio::time::sleep(delay).await;
+            let index = state.produced.fetch_add(1, Ordering::SeqCst);
+            Some((Ok(vec![index as u8]), state))
+        });
+        (Box::new(Box::pin(stream)), produced, dropped)
+    }
+
+    async fn consume_slowly(
+        mut stream: impl Stream<Item = ByteStreamItem> + Unpin,
+        delay: Duration,
+    ) -> Vec<u8> {
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buf.extend(chunk.unwrap());
+            tokio::time::sleep(delay).await;
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_overlaps_reads_with_consumer() {
+        let delay = Duration::from_millis(10);
+        let expected = (0..20).collect::<Vec<u8>>();
+
+        let (stream, _, _) = slow_stream(20, delay);
+        let start = Instant::now();
+        assert_eq!(consume_slowly(stream, delay).await, expected);
+        let unbuffered = start.elapsed();
+
+        let (stream, _, _) = slow_stream(20, delay);
+        let start = Instant::now();
+        let stream = ReadAheadStream::new(stream, 4);
+        assert_eq!(consume_slowly(stream, delay).await, expected);
+        let read_ahead = start.elapsed();
+
+        // Reading and consuming take turns without read-ahead, and overlap
+        // with it.
+        assert!(unbuffered >= Duration::from_millis(400));
+        assert!(
+            read_ahead < unbuffered * 3 / 4,
+            "{:?} with read-ahead, {:?} without",
+            read_ahead,
+            unbuffered
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_is_bounded_and_stops_on_drop() {
+        let (stream, produced, dropped) = slow_stream(100, Duration::from_millis(1));
+        let mut stream = ReadAheadStream::new(stream, 4);
+        assert!(stream.next().await.is_some());
+
+        // With the consumer idle, the background task fills the channel and
+        // then waits.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let buffered = produced.load(Ordering::SeqCst);
+        assert!(buffered <= 1 + 4 + 1, "{} chunks produced", buffered);
+
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(dropped.load(Ordering::SeqCst));
+        assert_eq!(produced.load(Ordering::SeqCst), buffered);
     }
 }
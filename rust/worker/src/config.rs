@@ -692,9 +692,9 @@ mod tests {
                         s.credentials,
                         chroma_storage::config::S3CredentialsConfig::AWS
                     );
-                    assert_eq!(s.connect_timeout_ms, 5000);
-                    assert_eq!(s.request_timeout_ms, 1000);
-                    assert_eq!(s.upload_part_size_bytes, 1024 * 1024 * 8);
+                    assert_eq!(s.connect_timeout_ms, Some(5000));
+                    assert_eq!(s.read_timeout_ms, Some(1000));
+                    assert_eq!(s.upload_part_size_bytes, Some(1024 * 1024 * 8));
                 }
                 _ => panic!("Invalid storage config"),
             }